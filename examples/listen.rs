@@ -8,10 +8,7 @@ fn main() {
         .init();
     let listener = DeviceListener::new().expect("Failed to create device listener");
     info!("Listening for iOS devices...");
-    loop {
-        match listener.next_event() {
-            Some(event) => info!("Event: {:?}", event),
-            None => std::thread::sleep(std::time::Duration::from_secs(5)),
-        }
+    while let Some(event) = listener.wait_event(None) {
+        info!("Event: {:?}", event);
     }
 }
@@ -0,0 +1,93 @@
+#ifndef PEERTALK_H
+#define PEERTALK_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Longest UDID [`PtDeviceEvent`] holds, including the nul terminator
+#define PT_UDID_CAPACITY 64
+
+// Kind of [`PtDeviceEvent`]
+enum PtEventKind {
+  // Device was plugged in, or found on the network
+  PT_EVENT_KIND_ATTACHED = 1,
+  // Device was unplugged
+  PT_EVENT_KIND_DETACHED = 2,
+  // Device was paired with the host
+  PT_EVENT_KIND_PAIRED = 3,
+  // Muxer message this version doesn't know
+  PT_EVENT_KIND_OTHER = 4,
+  // Listener lost its connection to the muxer, it keeps trying to reconnect
+  PT_EVENT_KIND_MUXER_DISCONNECTED = 5,
+  // Device detached & attached again within the debounce window, under a new id
+  PT_EVENT_KIND_REATTACHED = 6,
+};
+typedef uint32_t PtEventKind;
+
+// Device listener handle, see [`pt_listener_new`]
+typedef struct PtListener PtListener;
+
+// Device event, filled in by [`pt_listener_poll`]
+typedef struct PtDeviceEvent {
+  // What happened
+  PtEventKind kind;
+  // Muxer's id for the device
+  uint64_t device_id;
+  // USB product id, 0 for network devices & events other than attached
+  uint16_t product_id;
+  // Whether the device is attached over the network rather than USB
+  bool is_network;
+  // Nul terminated UDID, empty for events other than attached
+  char udid[PT_UDID_CAPACITY];
+} PtDeviceEvent;
+
+// Raw socket returned by the connect functions, a file descriptor or a Windows `SOCKET`
+typedef int64_t PtSocket;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Describes the last error on the calling thread, or returns null if there was none
+//
+// The string stays valid until the next failing call on the same thread.
+const char *pt_last_error(void);
+
+// Starts listening for devices, returns null on failure
+//
+// Free the listener with [`pt_listener_free`].
+PtListener *pt_listener_new(void);
+
+// Waits up to `timeout_ms` for a device event, -1 waits indefinitely
+//
+// Returns 1 once `event` is filled in, 0 if no event arrived in time & -1 on invalid arguments.
+//
+// # Safety
+// `listener` must come from [`pt_listener_new`] & `event` point to a writable `PtDeviceEvent`.
+int32_t pt_listener_poll(PtListener *listener, int32_t timeout_ms, PtDeviceEvent *event);
+
+// Stops listening & frees the listener, null is ignored
+//
+// # Safety
+// `listener` must come from [`pt_listener_new`] & not be used afterwards.
+void pt_listener_free(PtListener *listener);
+
+// Connects to `port` on the device with the given muxer id, returns -1 on failure
+//
+// The caller owns the returned socket & closes it with `close` or `closesocket`.
+PtSocket pt_connect(uint64_t device_id, uint16_t port);
+
+// Connects to `port` on the device with the given UDID, returns -1 on failure
+//
+// # Safety
+// `udid` must be a valid nul terminated string.
+PtSocket pt_connect_udid(const char *udid, uint16_t port);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* PEERTALK_H */
@@ -0,0 +1,113 @@
+//! Original binary encoding of usbmuxd messages (protocol version 0)
+//!
+//! Only old usbmuxd builds & some embedded muxers need this, everything current speaks plists.
+use crate::{DeviceAttachedInfo, DeviceConnectionType, DeviceEvent, DeviceId, ProductType, Udid};
+use crate::{Packet, PacketType, Protocol, ProtocolError, Result, ResultMessage};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryFrom;
+use std::io::Read;
+
+/// Size of the serial number field in a device record, NUL padded
+const SERIAL_NUMBER_SIZE: usize = 256;
+
+/// Payload of a `Connect` request for the given device port
+pub fn connect_payload(device_id: DeviceId, port: u16) -> Result<Vec<u8>> {
+    let device_id = u32::try_from(device_id.0)
+        .map_err(|_| ProtocolError::InvalidPlistEntryForKey("DeviceID"))?;
+    let mut payload = Vec::with_capacity(8);
+    payload.write_u32::<LittleEndian>(device_id)?;
+    payload.extend_from_slice(&port.to_be_bytes()); // network byte order, like the plist protocol
+    payload.write_u16::<LittleEndian>(0)?; // reserved
+    Ok(payload)
+}
+
+/// Decodes the reply code from a `Result` packet
+pub fn decode_result(packet: &Packet) -> Result<ResultMessage> {
+    if packet.packet_type != PacketType::Result {
+        return Err(ProtocolError::InvalidPacketType(packet.packet_type.into()));
+    }
+    let number = (&packet.data[..]).read_u32::<LittleEndian>()?;
+    Ok(ResultMessage(number.into()))
+}
+
+/// Decodes a `DeviceAdd` or `DeviceRemove` packet
+pub fn decode_event(packet: &Packet) -> Result<DeviceEvent> {
+    let mut data = &packet.data[..];
+    match packet.packet_type {
+        PacketType::DeviceAdd => {
+            let device_id = data.read_u32::<LittleEndian>()?;
+            let product_id = data.read_u16::<LittleEndian>()?;
+            let mut serial_number = [0; SERIAL_NUMBER_SIZE];
+            data.read_exact(&mut serial_number)?;
+            let _padding = data.read_u16::<LittleEndian>()?;
+            let location_id = data.read_u32::<LittleEndian>()?;
+            let len = serial_number
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(SERIAL_NUMBER_SIZE);
+            let identifier = Udid::from_serial(&String::from_utf8_lossy(&serial_number[..len]));
+            Ok(DeviceEvent::Attached(DeviceAttachedInfo {
+                connection_type: DeviceConnectionType::USB,
+                device_id: DeviceId(device_id.into()),
+                location_id: location_id.into(),
+                product_type: ProductType::from(product_id),
+                product_id,
+                identifier,
+                network_address: None,
+                interface_index: None,
+                connection_speed: None,
+                usb_serial_number: None,
+                escrow_bag: None,
+                properties: Default::default(),
+            }))
+        }
+        PacketType::DeviceRemove => Ok(DeviceEvent::Detached(DeviceId(
+            data.read_u32::<LittleEndian>()?.into(),
+        ))),
+        other => Err(ProtocolError::InvalidPacketType(other.into())),
+    }
+}
+
+/// Builds a packet in the binary protocol
+pub fn packet(packet_type: PacketType, tag: u32, payload: Vec<u8>) -> Result<Packet> {
+    Packet::new(Protocol::Binary, packet_type, tag, payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn it_decodes_device_records() {
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(7).unwrap();
+        data.write_u16::<LittleEndian>(0x12AB).unwrap();
+        let mut serial = [0; SERIAL_NUMBER_SIZE];
+        serial[..40].copy_from_slice(b"5D8E6F3A2B1C0D9E8F7A6B5C4D3E2F1A0B9C8D7E");
+        data.extend_from_slice(&serial);
+        data.write_u16::<LittleEndian>(0).unwrap();
+        data.write_u32::<LittleEndian>(0x1410_0000).unwrap();
+        let added = packet(PacketType::DeviceAdd, 0, data).unwrap();
+        match decode_event(&added) {
+            Ok(DeviceEvent::Attached(info)) => {
+                assert_eq!(info.device_id, DeviceId(7));
+                assert_eq!(info.product_type, ProductType::IPad);
+                assert_eq!(
+                    info.identifier.as_str(),
+                    "5d8e6f3a2b1c0d9e8f7a6b5c4d3e2f1a0b9c8d7e"
+                );
+                assert_eq!(info.location_id, 0x1410_0000);
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        let removed = packet(PacketType::DeviceRemove, 0, vec![7, 0, 0, 0]).unwrap();
+        assert!(matches!(
+            decode_event(&removed),
+            Ok(DeviceEvent::Detached(DeviceId(7)))
+        ));
+    }
+    #[test]
+    fn it_encodes_connect() {
+        let payload = connect_payload(DeviceId(3), 0x1234).unwrap();
+        assert_eq!(payload, vec![3, 0, 0, 0, 0x12, 0x34, 0, 0]);
+    }
+}
@@ -0,0 +1,249 @@
+//! Muxer connection as a state machine, leaving the socket to the caller
+//!
+//! [`MuxerConnection`] tags requests, queues their bytes for sending & turns received bytes into
+//! replies & device events. The caller moves bytes between it & the socket however it likes, with
+//! blocking reads, an async runtime or completion based IO:
+//!
+//! ```
+//! use peertalk_proto::{Command, MuxerConnection, MuxerEvent};
+//!
+//! let mut connection = MuxerConnection::new();
+//! let tag = connection.send_command(&Command::listen()).unwrap();
+//! while let Some(bytes) = connection.next_outgoing() {
+//!     // write bytes to the socket
+//! #   let _ = bytes;
+//! }
+//! # let received: &[u8] = &[];
+//! for event in connection.feed_bytes(received) {
+//!     match event {
+//!         MuxerEvent::Reply(reply) => assert_eq!(reply.tag, tag),
+//!         MuxerEvent::Device(Ok(event)) => println!("{:?}", event),
+//!         MuxerEvent::Device(Err(e)) => eprintln!("{}", e),
+//!         MuxerEvent::Invalid(e) => eprintln!("{}", e),
+//!     }
+//! }
+//! ```
+use crate::Result;
+use crate::{Command, DeviceEvent, Packet, PacketBuffer, PacketType, Protocol, ProtocolError};
+use std::collections::VecDeque;
+use std::io::Read;
+
+/// What the muxer sent, decoded by [`MuxerConnection::feed_bytes`]
+#[derive(Debug)]
+// attach info dwarfs replies, but events are taken out one at a time so boxing it would only add an
+// allocation per event
+#[allow(clippy::large_enum_variant)]
+pub enum MuxerEvent {
+    /// Reply to the request sent with the packet's tag
+    Reply(Packet),
+    /// Device event sent with tag 0 on a listening connection, or the error decoding it
+    Device(Result<DeviceEvent>),
+    /// Packet with an invalid header, or a reply to no pending request
+    ///
+    /// An invalid packet size leaves no way to find the next packet, so the connection should be
+    /// dropped.
+    Invalid(ProtocolError),
+}
+
+/// State of one muxer connection, without any IO
+#[derive(Debug)]
+pub struct MuxerConnection {
+    received: PacketBuffer,
+    outgoing: VecDeque<Vec<u8>>,
+    /// Tags of requests waiting for their reply
+    pending: Vec<u32>,
+    next_tag: u32,
+}
+impl Default for MuxerConnection {
+    fn default() -> Self {
+        MuxerConnection {
+            received: PacketBuffer::new(),
+            outgoing: VecDeque::new(),
+            pending: Vec::new(),
+            next_tag: 1,
+        }
+    }
+}
+impl MuxerConnection {
+    /// Creates the state for a fresh connection
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Rejects packets with payloads over `max_payload` bytes, instead of buffering them
+    pub fn with_max_payload(mut self, max_payload: u32) -> Self {
+        self.received = self.received.with_max_payload(max_payload);
+        self
+    }
+    /// Queues a request with the given payload, returning the tag its reply will carry
+    pub fn send(
+        &mut self,
+        protocol: Protocol,
+        packet_type: PacketType,
+        payload: Vec<u8>,
+    ) -> Result<u32> {
+        let tag = self.next_tag;
+        // the muxer sends events with tag 0, so it's skipped when wrapping around
+        self.next_tag = self.next_tag.checked_add(1).unwrap_or(1);
+        let packet = Packet::new(protocol, packet_type, tag, payload)?;
+        let mut bytes = Vec::with_capacity(packet.size as usize);
+        packet.write_into(&mut bytes)?;
+        self.outgoing.push_back(bytes);
+        self.pending.push(tag);
+        Ok(tag)
+    }
+    /// Queues a plist command, returning the tag its reply will carry
+    pub fn send_command(&mut self, command: &Command) -> Result<u32> {
+        self.send(
+            Protocol::Plist,
+            PacketType::PlistPayload,
+            command.to_bytes()?,
+        )
+    }
+    /// Next bytes to write to the socket, None once everything queued was taken
+    pub fn next_outgoing(&mut self) -> Option<Vec<u8>> {
+        self.outgoing.pop_front()
+    }
+    /// Takes bytes read from the socket, returning what they complete
+    ///
+    /// Bytes may split packets anywhere, a partial packet is kept until the rest of it arrives.
+    pub fn feed_bytes(&mut self, data: &[u8]) -> Vec<MuxerEvent> {
+        self.received.extend(data);
+        std::iter::from_fn(|| self.next_event()).collect()
+    }
+    /// Takes bytes read from the socket, leaving them to [`next_event`](Self::next_event)
+    pub fn extend(&mut self, data: &[u8]) {
+        self.received.extend(data);
+    }
+    /// Reads once from `reader` into the connection's own buffer, returning the number of bytes
+    ///
+    /// For callers polling a socket, this & [`next_event`](Self::next_event) don't allocate once
+    /// the buffer has grown to fit the largest packet, unlike [`feed_bytes`](Self::feed_bytes).
+    ///
+    /// # Errors
+    /// Fails if the read fails
+    pub fn read_from<R: Read + ?Sized>(&mut self, reader: &mut R) -> std::io::Result<usize> {
+        self.received.read_from(reader)
+    }
+    /// Takes out what the next complete packet in the buffer decodes to, None if there's none
+    pub fn next_event(&mut self) -> Option<MuxerEvent> {
+        match self.received.next_packet() {
+            Ok(Some(packet)) => Some(self.decode(packet)),
+            Ok(None) => None,
+            Err(e) => Some(MuxerEvent::Invalid(e)),
+        }
+    }
+    /// Number of bytes to read next to complete a packet, without reading past it
+    ///
+    /// Once a connect request succeeds the connection carries the device's data instead of
+    /// packets, so reading no more than this keeps that data in the socket.
+    pub fn bytes_needed(&self) -> usize {
+        self.received.needed()
+    }
+    /// Checks if any request is still waiting for its reply
+    pub fn is_waiting(&self) -> bool {
+        !self.pending.is_empty()
+    }
+    /// Forgets buffered bytes & pending requests, such as after reconnecting
+    pub fn reset(&mut self) {
+        self.received.clear();
+        self.outgoing.clear();
+        self.pending.clear();
+    }
+    fn decode(&mut self, packet: Packet) -> MuxerEvent {
+        if packet.tag == 0 {
+            return MuxerEvent::Device(DeviceEvent::from_packet(packet));
+        }
+        match self.pending.iter().position(|tag| *tag == packet.tag) {
+            Some(index) => {
+                self.pending.remove(index);
+                MuxerEvent::Reply(packet)
+            }
+            None => MuxerEvent::Invalid(ProtocolError::UnexpectedTag {
+                expected: self.pending.first().copied().unwrap_or(0),
+                received: packet.tag,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceId, ResultMessage};
+
+    /// Binary protocol listen request with tag 1
+    const LISTEN_REQUEST: [u8; 16] = [16, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0];
+    /// Binary protocol result with code 0, for tag 1
+    const OK_REPLY: [u8; 20] = [20, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+    /// Binary protocol detach event for device 7
+    const DETACHED_EVENT: [u8; 20] = [20, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0];
+
+    #[test]
+    fn it_encodes_requests() {
+        let mut connection = MuxerConnection::new();
+        let tag = connection
+            .send(Protocol::Binary, PacketType::Listen, Vec::new())
+            .unwrap();
+        assert_eq!(tag, 1);
+        assert_eq!(connection.next_outgoing().unwrap(), LISTEN_REQUEST);
+        assert!(connection.next_outgoing().is_none());
+        assert!(connection.is_waiting());
+    }
+    #[test]
+    fn it_matches_replies_split_across_reads() {
+        let mut connection = MuxerConnection::new();
+        connection
+            .send(Protocol::Binary, PacketType::Listen, Vec::new())
+            .unwrap();
+        let mut received = DETACHED_EVENT.to_vec();
+        received.extend_from_slice(&OK_REPLY);
+        let (first, second) = received.split_at(27);
+        let events = connection.feed_bytes(first);
+        assert!(matches!(
+            events[..],
+            [MuxerEvent::Device(Ok(DeviceEvent::Detached(DeviceId(7))))]
+        ));
+        assert_eq!(connection.bytes_needed(), 9);
+        match &connection.feed_bytes(second)[..] {
+            [MuxerEvent::Reply(reply)] => {
+                assert_eq!(reply.tag, 1);
+                assert_eq!(ResultMessage::from_packet(reply).unwrap().0, 0);
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+        assert!(!connection.is_waiting());
+    }
+    #[test]
+    fn it_reads_into_its_buffer() {
+        let mut connection = MuxerConnection::new();
+        connection
+            .send(Protocol::Binary, PacketType::Listen, Vec::new())
+            .unwrap();
+        let mut received = DETACHED_EVENT.to_vec();
+        received.extend_from_slice(&OK_REPLY);
+        let mut reader = &received[..];
+        assert_eq!(connection.read_from(&mut reader).unwrap(), 40);
+        assert!(matches!(
+            connection.next_event(),
+            Some(MuxerEvent::Device(Ok(DeviceEvent::Detached(DeviceId(7)))))
+        ));
+        assert!(matches!(
+            connection.next_event(),
+            Some(MuxerEvent::Reply(_))
+        ));
+        assert!(connection.next_event().is_none());
+        assert_eq!(connection.read_from(&mut reader).unwrap(), 0);
+    }
+    #[test]
+    fn it_rejects_replies_to_nothing() {
+        let mut connection = MuxerConnection::new();
+        let events = connection.feed_bytes(&OK_REPLY);
+        assert!(matches!(
+            events[..],
+            [MuxerEvent::Invalid(ProtocolError::UnexpectedTag {
+                expected: 0,
+                received: 1
+            })]
+        ));
+    }
+}
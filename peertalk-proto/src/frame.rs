@@ -0,0 +1,651 @@
+//! PeerTalk's frame protocol, spoken by apps using the PeerTalk library on the device
+//!
+//! Each frame is a 16 byte header of big endian `u32`s (version, type, tag & payload size)
+//! followed by the payload. Types below 100 are reserved by PeerTalk, the rest are up to the app;
+//! the constants here are the ones from PeerTalk's example app, which most apps keep using.
+use crate::protocol::into_payload;
+use crate::{Payload, ProtocolError, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+
+/// Frame protocol version, the only one PeerTalk has
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Tag for frames that aren't requests or replies
+pub const NO_TAG: u32 = 0;
+/// Sent by PeerTalk before closing a channel
+pub const FRAME_TYPE_END_OF_STREAM: u32 = 0;
+/// Binary plist describing the device
+pub const FRAME_TYPE_DEVICE_INFO: u32 = 100;
+/// UTF-8 text, prefixed with its length
+pub const FRAME_TYPE_TEXT_MSG: u32 = 101;
+/// Ping, answered with a pong carrying the same tag
+pub const FRAME_TYPE_PING: u32 = 102;
+/// Reply to a ping
+pub const FRAME_TYPE_PONG: u32 = 103;
+/// Opens a channel, with the channel id as tag
+pub const FRAME_TYPE_CHANNEL_OPEN: u32 = 80;
+/// Data for a channel, with the channel id as tag
+pub const FRAME_TYPE_CHANNEL_DATA: u32 = 81;
+/// Closes a channel, with the channel id as tag
+pub const FRAME_TYPE_CHANNEL_CLOSE: u32 = 82;
+/// Carries a binary plist listing the features the sender supports
+pub const FRAME_TYPE_CAPABILITIES: u32 = 90;
+/// Handshake message of an encrypted session
+pub const FRAME_TYPE_SECURE_HANDSHAKE: u32 = 91;
+
+/// Set in the type of frames whose payload is compressed, once a session agreed on compression
+pub const FLAG_COMPRESSED: u32 = 1 << 31;
+/// Set in the type of frames whose payload ends in a CRC32 of the rest of it
+pub const FLAG_CHECKSUM: u32 = 1 << 30;
+/// Set in the type of frames whose payload is encrypted, once a session is encrypted
+pub const FLAG_ENCRYPTED: u32 = 1 << 29;
+
+/// Size of a frame's header
+pub const HEADER_SIZE: usize = 16;
+/// Largest payload accepted from the peer unless configured otherwise
+pub const DEFAULT_MAX_PAYLOAD_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Single frame sent to or received from a PeerTalk channel
+#[derive(Clone, PartialEq)]
+pub struct Frame {
+    /// Protocol version, [`PROTOCOL_VERSION`]
+    pub version: u32,
+    /// Type of frame, one of the `FRAME_TYPE_*` constants or an app defined type
+    pub frame_type: u32,
+    /// Tag to match replies to requests, [`NO_TAG`] otherwise
+    pub tag: u32,
+    /// Payload of the frame, may be empty
+    pub payload: Payload,
+}
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Frame {{ version: {}, frame_type: {}, tag: {}, payload(bytes): {} }}",
+            self.version,
+            self.frame_type,
+            self.tag,
+            self.payload.len()
+        )
+    }
+}
+impl Frame {
+    /// Creates a frame of the current protocol version
+    pub fn new(frame_type: u32, tag: u32, payload: Vec<u8>) -> Self {
+        Frame {
+            version: PROTOCOL_VERSION,
+            frame_type,
+            tag,
+            payload: into_payload(payload),
+        }
+    }
+    /// Creates a text message frame, as sent by PeerTalk's example app
+    pub fn text(text: &str) -> Self {
+        let mut payload = Vec::with_capacity(text.len() + 4);
+        payload.extend_from_slice(&(text.len() as u32).to_be_bytes());
+        payload.extend_from_slice(text.as_bytes());
+        Self::new(FRAME_TYPE_TEXT_MSG, NO_TAG, payload)
+    }
+    /// Creates a frame with a binary plist payload, like the device info frame
+    pub fn plist(frame_type: u32, tag: u32, value: &plist::Value) -> Result<Self> {
+        let mut payload = Vec::new();
+        value
+            .to_writer_binary(&mut payload)
+            .map_err(ProtocolError::PlistEncodeError)?;
+        Ok(Self::new(frame_type, tag, payload))
+    }
+    /// Creates a ping frame, the peer replies with a pong carrying `tag`
+    pub fn ping(tag: u32) -> Self {
+        Self::new(FRAME_TYPE_PING, tag, Vec::new())
+    }
+    /// Creates the pong answering a ping with the given tag
+    pub fn pong(tag: u32) -> Self {
+        Self::new(FRAME_TYPE_PONG, tag, Vec::new())
+    }
+    /// Creates the frame PeerTalk sends before closing a channel
+    pub fn end_of_stream() -> Self {
+        Self::new(FRAME_TYPE_END_OF_STREAM, NO_TAG, Vec::new())
+    }
+    /// Checks if this frame closes the channel
+    pub fn is_end_of_stream(&self) -> bool {
+        self.frame_type == FRAME_TYPE_END_OF_STREAM
+    }
+    /// Decodes a text message payload, `None` if this isn't a valid text message
+    pub fn as_text(&self) -> Option<&str> {
+        if self.frame_type != FRAME_TYPE_TEXT_MSG || self.payload.len() < 4 {
+            return None;
+        }
+        let (len, text) = self.payload.split_at(4);
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        text.get(..len).and_then(|t| std::str::from_utf8(t).ok())
+    }
+    /// Decodes a plist payload, like the device info frame's
+    pub fn to_plist(&self) -> Result<plist::Value> {
+        let value = plist::Value::from_reader(std::io::Cursor::new(&self.payload))
+            .map_err(|_| ProtocolError::InvalidPlistEntry)?;
+        Ok(value)
+    }
+    /// Writes the frame to a socket or buffer
+    pub fn write_into<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        let payload_size = u32::try_from(self.payload.len())
+            .map_err(|_| ProtocolError::PayloadTooLarge(self.payload.len()))?;
+        let mut header = [0; HEADER_SIZE];
+        let mut cursor = &mut header[..];
+        cursor.write_u32::<BigEndian>(self.version)?;
+        cursor.write_u32::<BigEndian>(self.frame_type)?;
+        cursor.write_u32::<BigEndian>(self.tag)?;
+        cursor.write_u32::<BigEndian>(payload_size)?;
+        writer.write_all(&header)?;
+        writer.write_all(&self.payload)?;
+        Ok(())
+    }
+    /// Reads a whole frame from a socket or buffer, up to [`DEFAULT_MAX_PAYLOAD_SIZE`]
+    pub fn from_reader<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        Self::from_reader_limited(reader, DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+    /// Reads a whole frame from a socket or buffer, rejecting payloads over `max_payload` bytes
+    ///
+    /// # Errors
+    /// Fails with [`ProtocolError::InvalidFrame`] without allocating the payload if the header is
+    /// invalid
+    pub fn from_reader_limited<R>(reader: &mut R, max_payload: u32) -> Result<Self>
+    where
+        R: Read,
+    {
+        match read_header(reader)? {
+            Some(header) => Self::read_payload(reader, &header, max_payload),
+            None => Err(InvalidFrame::TruncatedHeader { received: 0 }.into()),
+        }
+    }
+    /// Validates the header & reads the payload of the frame
+    fn read_payload<R>(reader: &mut R, header: &[u8; HEADER_SIZE], max_payload: u32) -> Result<Self>
+    where
+        R: Read,
+    {
+        let FrameHeader {
+            version,
+            frame_type,
+            tag,
+            size,
+        } = FrameHeader::parse(header, max_payload)?;
+        let mut payload = Vec::with_capacity(size as usize);
+        reader.take(size.into()).read_to_end(&mut payload)?;
+        if payload.len() < size as usize {
+            return Err(InvalidFrame::TruncatedPayload {
+                frame_type,
+                tag,
+                size,
+                received: payload.len(),
+            }
+            .into());
+        }
+        Ok(Frame {
+            version,
+            frame_type,
+            tag,
+            payload: into_payload(payload),
+        })
+    }
+}
+
+/// Frame header fields, checked against what the reader accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    /// Protocol version, [`PROTOCOL_VERSION`]
+    pub version: u32,
+    /// Type of frame
+    pub frame_type: u32,
+    /// Tag of the frame
+    pub tag: u32,
+    /// Size of the payload following the header
+    pub size: u32,
+}
+impl FrameHeader {
+    /// Parses & validates a header, so nothing gets allocated for a frame that will be rejected
+    ///
+    /// # Errors
+    /// Fails with [`ProtocolError::InvalidFrame`] if the version is unknown or the payload is
+    /// over `max_payload` bytes
+    pub fn parse(header: &[u8; HEADER_SIZE], max_payload: u32) -> Result<Self> {
+        let mut header = &header[..];
+        let version = header.read_u32::<BigEndian>()?;
+        let frame_type = header.read_u32::<BigEndian>()?;
+        let tag = header.read_u32::<BigEndian>()?;
+        let size = header.read_u32::<BigEndian>()?;
+        if version != PROTOCOL_VERSION {
+            return Err(InvalidFrame::UnsupportedVersion {
+                version,
+                frame_type,
+                tag,
+            }
+            .into());
+        }
+        if size > max_payload {
+            return Err(InvalidFrame::PayloadTooLarge {
+                frame_type,
+                tag,
+                size,
+                max: max_payload,
+            }
+            .into());
+        }
+        Ok(FrameHeader {
+            version,
+            frame_type,
+            tag,
+            size,
+        })
+    }
+}
+
+/// Reads a frame header, `None` if the connection closed before its first byte
+fn read_header<R: Read>(reader: &mut R) -> Result<Option<[u8; HEADER_SIZE]>> {
+    let mut header = [0; HEADER_SIZE];
+    let mut received = 0;
+    while received < HEADER_SIZE {
+        match reader.read(&mut header[received..]) {
+            Ok(0) if received == 0 => return Ok(None),
+            Ok(0) => return Err(InvalidFrame::TruncatedHeader { received }.into()),
+            Ok(n) => received += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(header))
+}
+
+/// Why a frame received from the peer was rejected
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum InvalidFrame {
+    /// Frame has a version other than [`PROTOCOL_VERSION`], so the rest of it can't be trusted
+    #[error("unsupported version {version} (type {frame_type}, tag {tag})")]
+    UnsupportedVersion {
+        /// Version in the frame's header
+        version: u32,
+        /// Type in the frame's header
+        frame_type: u32,
+        /// Tag in the frame's header
+        tag: u32,
+    },
+    /// Payload is larger than the reader accepts
+    #[error("payload of {size} bytes is over the {max} byte limit (type {frame_type}, tag {tag})")]
+    PayloadTooLarge {
+        /// Type in the frame's header
+        frame_type: u32,
+        /// Tag in the frame's header
+        tag: u32,
+        /// Payload size in the frame's header
+        size: u32,
+        /// Largest payload the reader accepts
+        max: u32,
+    },
+    /// Connection closed partway through a frame's header
+    #[error("connection closed after {received} of 16 header bytes")]
+    TruncatedHeader {
+        /// Header bytes received before the connection closed
+        received: usize,
+    },
+    /// Payload couldn't be decoded, such as a compressed payload that doesn't decompress
+    #[error("corrupt payload (type {frame_type}, tag {tag})")]
+    CorruptPayload {
+        /// Type in the frame's header, without flags
+        frame_type: u32,
+        /// Tag in the frame's header
+        tag: u32,
+    },
+    /// Payload doesn't match its checksum, so it was garbled on the way
+    #[error("checksum mismatch, expected {expected:08x} got {actual:08x} (type {frame_type}, tag {tag})")]
+    ChecksumMismatch {
+        /// Type in the frame's header, without flags
+        frame_type: u32,
+        /// Tag in the frame's header
+        tag: u32,
+        /// Checksum sent with the frame
+        expected: u32,
+        /// Checksum of the payload received
+        actual: u32,
+    },
+    /// Frame was rejected by middleware, such as peertalk's `FrameMiddleware`, on its way in or out
+    #[error("rejected: {reason} (type {frame_type}, tag {tag})")]
+    Rejected {
+        /// Type of the frame
+        frame_type: u32,
+        /// Tag of the frame
+        tag: u32,
+        /// Why the middleware rejected it
+        reason: String,
+    },
+    /// Connection closed partway through a frame's payload
+    #[error(
+        "connection closed after {received} of {size} payload bytes (type {frame_type}, tag {tag})"
+    )]
+    TruncatedPayload {
+        /// Type in the frame's header
+        frame_type: u32,
+        /// Tag in the frame's header
+        tag: u32,
+        /// Payload size in the frame's header
+        size: u32,
+        /// Payload bytes received before the connection closed
+        received: usize,
+    },
+}
+
+/// Reads frames from a PeerTalk channel, such as peertalk's `UsbSocket`
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    reader: R,
+    max_payload: u32,
+}
+impl<R: Read> FrameReader<R> {
+    /// Reads frames from `reader`, accepting payloads up to [`DEFAULT_MAX_PAYLOAD_SIZE`]
+    pub fn new(reader: R) -> Self {
+        Self::with_max_payload(reader, DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+    /// Reads frames from `reader`, rejecting payloads over `max_payload` bytes
+    pub fn with_max_payload(reader: R, max_payload: u32) -> Self {
+        FrameReader {
+            reader,
+            max_payload,
+        }
+    }
+    /// Reads the next frame, `None` once the peer ends the stream or closes the connection
+    ///
+    /// # Errors
+    /// Fails if reading fails, or with [`ProtocolError::InvalidFrame`] if the peer sends an invalid
+    /// frame or closes the connection in the middle of one
+    pub fn read_frame(&mut self) -> Result<Option<Frame>> {
+        // a clean close only counts at a frame boundary
+        let header = match read_header(&mut self.reader)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let frame = Frame::read_payload(&mut self.reader, &header, self.max_payload)?;
+        if frame.is_end_of_stream() {
+            return Ok(None);
+        }
+        Ok(Some(frame))
+    }
+    /// Reads the next frame's header, leaving its payload to be read from the connection
+    ///
+    /// Large payloads such as screenshots or video can then be processed as they arrive, instead
+    /// of being held in memory whole. `None` once the peer ends the stream or closes the
+    /// connection, like [`read_frame`](Self::read_frame).
+    ///
+    /// # Errors
+    /// Fails if reading fails, or with [`ProtocolError::InvalidFrame`] if the header is invalid
+    pub fn read_frame_streaming(&mut self) -> Result<Option<StreamingFrame<'_, R>>> {
+        let header = match read_header(&mut self.reader)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let FrameHeader {
+            version,
+            frame_type,
+            tag,
+            size,
+        } = FrameHeader::parse(&header, self.max_payload)?;
+        let frame = StreamingFrame {
+            version,
+            frame_type,
+            tag,
+            size,
+            payload: (&mut self.reader).take(size.into()),
+        };
+        if frame_type == FRAME_TYPE_END_OF_STREAM {
+            return Ok(None); // dropping the frame skips its payload
+        }
+        Ok(Some(frame))
+    }
+    /// Underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+    /// Underlying reader, reading from it directly may leave it in the middle of a frame
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+    /// Unwraps the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+impl<R: Read> Iterator for FrameReader<R> {
+    type Item = Result<Frame>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+/// Frame whose payload is read from the connection as it's consumed, from
+/// [`FrameReader::read_frame_streaming`]
+///
+/// Whatever's left of the payload is skipped when this is dropped, so the reader is left at the
+/// next frame. That blocks until the rest of the payload arrives.
+#[derive(Debug)]
+pub struct StreamingFrame<'a, R: Read> {
+    /// Protocol version, [`PROTOCOL_VERSION`]
+    pub version: u32,
+    /// Type of frame, one of the `FRAME_TYPE_*` constants or an app defined type
+    pub frame_type: u32,
+    /// Tag to match replies to requests, [`NO_TAG`] otherwise
+    pub tag: u32,
+    size: u32,
+    payload: std::io::Take<&'a mut R>,
+}
+impl<R: Read> StreamingFrame<'_, R> {
+    /// Size of the whole payload
+    pub fn payload_size(&self) -> u32 {
+        self.size
+    }
+    /// Number of payload bytes not read yet
+    pub fn remaining(&self) -> u64 {
+        self.payload.limit()
+    }
+}
+impl<R: Read> Read for StreamingFrame<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.payload.limit();
+        let read = self.payload.read(buf)?;
+        if read == 0 && remaining > 0 && !buf.is_empty() {
+            let truncated = InvalidFrame::TruncatedPayload {
+                frame_type: self.frame_type,
+                tag: self.tag,
+                size: self.size,
+                received: (u64::from(self.size) - remaining) as usize,
+            };
+            return Err(std::io::Error::new(ErrorKind::UnexpectedEof, truncated));
+        }
+        Ok(read)
+    }
+}
+impl<R: Read> Drop for StreamingFrame<'_, R> {
+    fn drop(&mut self) {
+        let _ = std::io::copy(&mut self.payload, &mut std::io::sink());
+    }
+}
+
+/// Writes frames to a PeerTalk channel, such as peertalk's `UsbSocket`
+#[derive(Debug)]
+pub struct FrameWriter<W> {
+    writer: W,
+}
+impl<W: Write> FrameWriter<W> {
+    /// Writes frames to `writer`
+    pub fn new(writer: W) -> Self {
+        FrameWriter { writer }
+    }
+    /// Writes a whole frame
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        frame.write_into(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+    /// Writes a frame with the given type, tag & payload
+    pub fn send(&mut self, frame_type: u32, tag: u32, payload: Vec<u8>) -> Result<()> {
+        self.write_frame(&Frame::new(frame_type, tag, payload))
+    }
+    /// Tells the peer no more frames are coming, like PeerTalk does before closing a channel
+    pub fn end_stream(&mut self) -> Result<()> {
+        self.write_frame(&Frame::end_of_stream())
+    }
+    /// Underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+    /// Underlying writer
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+    /// Unwraps the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // captured from PeerTalk's iOS example app sending "Hello" with PTExampleTextFrame
+    const TEXT_CAPTURE: &[u8] = &[
+        0x00, 0x00, 0x00, 0x01, // version
+        0x00, 0x00, 0x00, 0x65, // type 101
+        0x00, 0x00, 0x00, 0x00, // PTFrameNoTag
+        0x00, 0x00, 0x00, 0x09, // payload size
+        0x00, 0x00, 0x00, 0x05, b'H', b'e', b'l', b'l', b'o',
+    ];
+    // ping sent by the example app with tag 3, followed by PeerTalk closing the channel
+    const PING_AND_CLOSE_CAPTURE: &[u8] = &[
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,
+        0x00, // ping
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, // end of stream
+    ];
+    #[test]
+    fn it_reads_text_frames() {
+        let mut reader = FrameReader::new(TEXT_CAPTURE);
+        let frame = reader.read_frame().unwrap().unwrap();
+        assert_eq!(frame.version, PROTOCOL_VERSION);
+        assert_eq!(frame.tag, NO_TAG);
+        assert_eq!(frame.as_text(), Some("Hello"));
+        assert!(reader.read_frame().unwrap().is_none());
+    }
+    #[test]
+    fn it_writes_frames_like_peertalk() {
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.write_frame(&Frame::text("Hello")).unwrap();
+        assert_eq!(writer.get_ref(), TEXT_CAPTURE);
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.write_frame(&Frame::ping(3)).unwrap();
+        writer.end_stream().unwrap();
+        assert_eq!(writer.get_ref(), PING_AND_CLOSE_CAPTURE);
+    }
+    #[test]
+    fn it_stops_at_end_of_stream() {
+        let frames: Vec<Frame> = FrameReader::new(PING_AND_CLOSE_CAPTURE)
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(frames, vec![Frame::ping(3)]);
+    }
+    fn invalid_frame(result: Result<Option<Frame>>) -> InvalidFrame {
+        match result {
+            Err(ProtocolError::InvalidFrame(e)) => e,
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+    #[test]
+    fn it_streams_payloads() {
+        let mut data = TEXT_CAPTURE.to_vec();
+        data.extend_from_slice(PING_AND_CLOSE_CAPTURE);
+        let mut reader = FrameReader::new(&data[..]);
+        let mut frame = reader.read_frame_streaming().unwrap().unwrap();
+        assert_eq!(frame.frame_type, FRAME_TYPE_TEXT_MSG);
+        assert_eq!(frame.payload_size(), 9);
+        let mut len = [0; 4];
+        frame.read_exact(&mut len).unwrap();
+        assert_eq!(frame.remaining(), 5);
+        drop(frame); // skips the text
+        let ping = reader.read_frame().unwrap().unwrap();
+        assert_eq!(ping.frame_type, FRAME_TYPE_PING);
+        assert!(reader.read_frame_streaming().unwrap().is_none());
+
+        let mut reader = FrameReader::new(&TEXT_CAPTURE[..20]);
+        let mut frame = reader.read_frame_streaming().unwrap().unwrap();
+        let mut payload = Vec::new();
+        let e = frame.read_to_end(&mut payload).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(payload.len(), 4);
+    }
+    #[test]
+    fn it_fails_on_truncated_frames() {
+        let mut reader = FrameReader::new(&TEXT_CAPTURE[..20]);
+        assert_eq!(
+            invalid_frame(reader.read_frame()),
+            InvalidFrame::TruncatedPayload {
+                frame_type: FRAME_TYPE_TEXT_MSG,
+                tag: NO_TAG,
+                size: 9,
+                received: 4
+            }
+        );
+        let mut reader = FrameReader::new(&TEXT_CAPTURE[..10]);
+        assert_eq!(
+            invalid_frame(reader.read_frame()),
+            InvalidFrame::TruncatedHeader { received: 10 }
+        );
+    }
+    #[test]
+    fn it_rejects_unknown_versions() {
+        let mut data = TEXT_CAPTURE.to_vec();
+        data[3] = 2;
+        assert_eq!(
+            invalid_frame(FrameReader::new(&data[..]).read_frame()),
+            InvalidFrame::UnsupportedVersion {
+                version: 2,
+                frame_type: FRAME_TYPE_TEXT_MSG,
+                tag: NO_TAG
+            }
+        );
+    }
+    #[test]
+    fn it_rejects_large_payloads_before_reading_them() {
+        // claims a 4 GiB payload, which must not get allocated
+        let header = [
+            0, 0, 0, 1, 0, 0, 0, 0x65, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        assert_eq!(
+            invalid_frame(FrameReader::new(&header[..]).read_frame()),
+            InvalidFrame::PayloadTooLarge {
+                frame_type: FRAME_TYPE_TEXT_MSG,
+                tag: NO_TAG,
+                size: u32::MAX,
+                max: DEFAULT_MAX_PAYLOAD_SIZE
+            }
+        );
+        let mut reader = FrameReader::with_max_payload(TEXT_CAPTURE, 8);
+        assert!(matches!(
+            invalid_frame(reader.read_frame()),
+            InvalidFrame::PayloadTooLarge {
+                size: 9,
+                max: 8,
+                ..
+            }
+        ));
+    }
+    #[test]
+    fn it_round_trips_plists() {
+        let mut info = plist::Dictionary::new();
+        info.insert("DeviceName".to_owned(), "iPad".into());
+        let value = plist::Value::Dictionary(info);
+        let frame = Frame::plist(FRAME_TYPE_DEVICE_INFO, NO_TAG, &value).unwrap();
+        assert_eq!(frame.to_plist().unwrap(), value);
+    }
+}
@@ -0,0 +1,12 @@
+//! Sans-IO types for the protocols spoken by peertalk
+//!
+//! This holds the usbmuxd packet & plist message encodings & PeerTalk's frame protocol without
+//! any socket code, for use where only the protocol is needed. Most users want the `peertalk` crate, which re-exports these.
+#![forbid(missing_docs)]
+
+pub mod binary;
+mod connection;
+pub mod frame;
+mod protocol;
+pub use connection::{MuxerConnection, MuxerEvent};
+pub use protocol::*;
@@ -0,0 +1,1658 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use core::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use plist::Value;
+use serde::{Deserialize, Serialize};
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind, IoSlice, Read, Seek, Write};
+use std::mem::size_of;
+use thiserror::Error;
+
+/// Error type for any errors with talking to USB muxer/device support
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ProtocolError {
+    /// Message type is invalid, or unsupported
+    #[error("invalid message type: {0}")]
+    InvalidMessageType(String),
+    /// Plist entry isn't the type expected
+    #[error("invalid plist format/entry")]
+    InvalidPlistEntry,
+    /// Plist entry for key is invalid/wrong type
+    #[error("invalid plist entry for key: {0}")]
+    InvalidPlistEntryForKey(&'static str),
+    /// Invalid packet type value
+    #[error("invalid packet type: {0}")]
+    InvalidPacketType(u32),
+    /// Invalid protocol value (expect 0 or 1)
+    #[error("invalid protocol: {0}")]
+    InvalidProtocol(u32),
+    /// Invalid reply code (expect 0-6 except 4, 5)
+    #[error("invalid reply code: {0}")]
+    InvalidReplyCode(u32),
+    /// Reply carries a different tag than the request it's read for
+    #[error("unexpected reply tag {received}, expected {expected}")]
+    UnexpectedTag {
+        /// Tag the request was sent with
+        expected: u32,
+        /// Tag of the packet received
+        received: u32,
+    },
+    /// Payload doesn't fit in a packet, or is larger than the reader accepts
+    #[error("payload too large: {0} bytes")]
+    PayloadTooLarge(usize),
+    /// Packet's size is smaller than its own header
+    #[error("invalid packet size: {0}")]
+    InvalidPacketSize(u32),
+    /// UDID isn't in either of the formats devices use
+    #[error("invalid UDID: {0:?}")]
+    InvalidUdid(String),
+    /// Command couldn't be encoded as a plist
+    #[error("failed to encode plist: {0}")]
+    PlistEncodeError(#[source] plist::Error),
+    /// Packet's payload couldn't be decoded, with the packet & what it was decoded as
+    #[error("error decoding {operation} from {packet_type:?} packet with tag {tag}: {source}")]
+    InvalidPacket {
+        /// What the payload was decoded as, such as "device event"
+        operation: &'static str,
+        /// Type of the packet
+        packet_type: PacketType,
+        /// Tag of the packet, 0 for device events
+        tag: u32,
+        /// Error decoding the payload
+        source: Box<ProtocolError>,
+    },
+    /// PeerTalk frame isn't valid
+    #[error("invalid frame: {0}")]
+    InvalidFrame(#[from] crate::frame::InvalidFrame),
+    /// An IO error occurred, usually if reading from file/socket
+    #[error(transparent)]
+    IoError(#[from] IoError),
+}
+impl ProtocolError {
+    /// Error underneath any packet context, to match on what went wrong
+    pub fn root_cause(&self) -> &ProtocolError {
+        match self {
+            ProtocolError::InvalidPacket { source, .. } => source.root_cause(),
+            e => e,
+        }
+    }
+    /// Adds which packet was being decoded as what
+    fn in_packet(self, operation: &'static str, packet: &Packet) -> Self {
+        ProtocolError::InvalidPacket {
+            operation,
+            packet_type: packet.packet_type,
+            tag: packet.tag,
+            source: Box::new(self),
+        }
+    }
+}
+
+/// Result type
+pub type Result<T> = ::std::result::Result<T, ProtocolError>;
+
+const BASE_PACKET_SIZE: u32 = size_of::<u32>() as u32 * 4;
+/// Largest payload accepted from the muxer unless configured otherwise, well above any real reply
+pub const DEFAULT_MAX_PAYLOAD_SIZE: u32 = 16 * 1024 * 1024;
+const USB_MESSAGE_TYPE_KEY: &str = "MessageType";
+const USB_DEVICE_ID_KEY: &str = "DeviceID";
+const USB_DEVICE_PROPERTIES_KEY: &str = "Properties";
+
+/// Type of a usbmuxd packet, modern muxers only use plist payloads
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PacketType {
+    /// Reply to a command
+    Result = 1,
+    /// Connect to a device port
+    Connect = 2,
+    /// Register for device events
+    Listen = 3,
+    /// Device was attached
+    DeviceAdd = 4,
+    /// Device was detached
+    DeviceRemove = 5,
+    // 6 unknown
+    // 7 unknown
+    /// Plist message, with the message type inside the plist
+    PlistPayload = 8,
+}
+
+impl From<PacketType> for u32 {
+    fn from(p_type: PacketType) -> Self {
+        p_type as Self
+    }
+}
+
+impl TryFrom<u32> for PacketType {
+    type Error = ProtocolError;
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            1 => Ok(Self::Result),
+            2 => Ok(Self::Connect),
+            3 => Ok(Self::Listen),
+            4 => Ok(Self::DeviceAdd),
+            5 => Ok(Self::DeviceRemove),
+            8 => Ok(Self::PlistPayload),
+            c => Err(ProtocolError::InvalidPacketType(c)),
+        }
+    }
+}
+/// Encoding of a usbmuxd packet's payload
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    /// Original binary structs
+    Binary = 0,
+    /// Plist payloads
+    Plist = 1,
+}
+
+impl From<Protocol> for u32 {
+    fn from(protocol: Protocol) -> Self {
+        protocol as Self
+    }
+}
+
+impl TryFrom<u32> for Protocol {
+    type Error = ProtocolError;
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(Protocol::Binary),
+            1 => Ok(Protocol::Plist),
+            c => Err(ProtocolError::InvalidProtocol(c)),
+        }
+    }
+}
+
+/// Result code the muxer replies to a command with
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReplyCode {
+    /// Command succeeded
+    Ok = 0,
+    /// Command wasn't understood
+    BadCommand = 1,
+    /// Device doesn't exist (anymore)
+    BadDevice = 2,
+    /// Device refused the connection, usually because nothing is listening on the port
+    ConnectionRefused = 3,
+    // 4 unknown
+    // 5 unknown
+    /// Protocol version isn't supported
+    BadVersion = 6,
+}
+
+impl fmt::Display for ReplyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            ReplyCode::Ok => "ok",
+            ReplyCode::BadCommand => "bad command",
+            ReplyCode::BadDevice => "no such device",
+            ReplyCode::ConnectionRefused => "connection refused",
+            ReplyCode::BadVersion => "unsupported protocol version",
+        };
+        f.write_str(description)
+    }
+}
+
+impl ReplyCode {
+    /// What the code means for the request it replies to, to tell users
+    pub fn meaning(&self) -> &'static str {
+        match self {
+            ReplyCode::Ok => "the request succeeded",
+            ReplyCode::BadCommand => "the muxer didn't understand the request",
+            ReplyCode::BadDevice => "the device isn't attached (anymore)",
+            ReplyCode::ConnectionRefused => "nothing on the device is listening on the port",
+            ReplyCode::BadVersion => "the muxer doesn't support the protocol version",
+        }
+    }
+}
+
+impl From<ReplyCode> for u32 {
+    fn from(code: ReplyCode) -> Self {
+        code as Self
+    }
+}
+
+impl TryFrom<u32> for ReplyCode {
+    type Error = ProtocolError;
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(ReplyCode::Ok),
+            1 => Ok(ReplyCode::BadCommand),
+            2 => Ok(ReplyCode::BadDevice),
+            3 => Ok(ReplyCode::ConnectionRefused),
+            6 => Ok(ReplyCode::BadVersion),
+            c => Err(ProtocolError::InvalidReplyCode(c)),
+        }
+    }
+}
+/// Payload of a packet
+///
+/// With the `bytes` feature this is `bytes::Bytes`, which can be sliced & handed on without copying.
+#[cfg(feature = "bytes")]
+pub type Payload = bytes::Bytes;
+/// Payload of a packet
+///
+/// With the `bytes` feature this is `bytes::Bytes`, which can be sliced & handed on without copying.
+#[cfg(not(feature = "bytes"))]
+pub type Payload = Vec<u8>;
+#[cfg(feature = "bytes")]
+pub(crate) fn into_payload(data: Vec<u8>) -> Payload {
+    data.into()
+}
+#[cfg(not(feature = "bytes"))]
+pub(crate) fn into_payload(data: Vec<u8>) -> Payload {
+    data
+}
+
+/// Packet sent to or received from the muxer, a 16 byte header followed by the payload
+pub struct Packet {
+    /// Size of the whole packet, including the header
+    pub size: u32,
+    /// Encoding of the payload
+    pub protocol: Protocol,
+    /// Type of packet
+    pub packet_type: PacketType,
+    /// Tag to match replies to commands, replies carry the tag of their command
+    pub tag: u32,
+    /// Payload of the packet
+    pub data: Payload,
+}
+impl fmt::Debug for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Packet {{ size: {}, protocol: {:?}, packet_type: {:?}, tag: {}, payload(bytes): {} }}",
+            self.size,
+            self.protocol,
+            self.packet_type,
+            self.tag,
+            self.data.len()
+        )
+    }
+}
+impl Packet {
+    /// Creates a new packet with the given payload
+    ///
+    /// # Errors
+    /// Fails with [`ProtocolError::PayloadTooLarge`] if the packet's size doesn't fit in 32 bits
+    pub fn new(
+        protocol: Protocol,
+        packet_type: PacketType,
+        tag: u32,
+        payload: Vec<u8>,
+    ) -> Result<Self> {
+        let size = u32::try_from(payload.len())
+            .ok()
+            .and_then(|len| len.checked_add(BASE_PACKET_SIZE))
+            .ok_or(ProtocolError::PayloadTooLarge(payload.len()))?;
+        Ok(Packet {
+            size,
+            protocol,
+            packet_type,
+            tag,
+            data: into_payload(payload),
+        })
+    }
+    /// Writes the packet to a socket or buffer
+    ///
+    /// Header & payload go out with vectored writes, so sockets get the whole packet in one
+    /// syscall unless the write is cut short.
+    pub fn write_into<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        let header = self.header();
+        let mut written = 0;
+        while written < header.len() + self.data.len() {
+            let result = if written < header.len() {
+                writer.write_vectored(&[IoSlice::new(&header[written..]), IoSlice::new(&self.data)])
+            } else {
+                writer.write(&self.data[written - header.len()..])
+            };
+            match result {
+                Ok(0) => return Err(IoError::from(ErrorKind::WriteZero).into()),
+                Ok(bytes) => written += bytes,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+    fn header(&self) -> [u8; BASE_PACKET_SIZE as usize] {
+        let fields = [
+            self.size,
+            self.protocol as u32,
+            self.packet_type.into(),
+            self.tag,
+        ];
+        let mut header = [0; BASE_PACKET_SIZE as usize];
+        for (bytes, field) in header.chunks_exact_mut(4).zip(fields.iter()) {
+            bytes.copy_from_slice(&field.to_le_bytes());
+        }
+        header
+    }
+    /// Reads a whole packet from a socket or buffer, up to [`DEFAULT_MAX_PAYLOAD_SIZE`]
+    pub fn from_reader<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        Self::from_reader_limited(reader, DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+    /// Reads a whole packet from a socket or buffer, rejecting payloads over `max_payload` bytes
+    ///
+    /// # Errors
+    /// Fails without allocating the payload if the packet's size field is out of range
+    pub fn from_reader_limited<R>(reader: &mut R, max_payload: u32) -> Result<Self>
+    where
+        R: Read,
+    {
+        // the header is read in one go, unbuffered sockets would take a syscall per field
+        let mut header = [0; BASE_PACKET_SIZE as usize];
+        reader.read_exact(&mut header)?;
+        let mut header = &header[..];
+        let size = header.read_u32::<LittleEndian>()?;
+        let payload_size = check_size(size, max_payload)?;
+        let protocol = Protocol::try_from(header.read_u32::<LittleEndian>()?)?;
+        let packet_type = PacketType::try_from(header.read_u32::<LittleEndian>()?)?;
+        let tag = header.read_u32::<LittleEndian>()?;
+        let data = if payload_size > 0 {
+            let mut payload = vec![0; payload_size as usize];
+            reader.read_exact(&mut payload)?;
+            payload
+        } else {
+            vec![]
+        };
+        Ok(Packet {
+            size,
+            protocol,
+            packet_type,
+            tag,
+            data: into_payload(data),
+        })
+    }
+}
+
+/// Checks a packet's size field, returning its payload size
+fn check_size(size: u32, max_payload: u32) -> Result<u32> {
+    let payload_size = size
+        .checked_sub(BASE_PACKET_SIZE)
+        .ok_or(ProtocolError::InvalidPacketSize(size))?;
+    if payload_size > max_payload {
+        return Err(ProtocolError::PayloadTooLarge(payload_size as usize));
+    }
+    Ok(payload_size)
+}
+
+/// Reassembles packets from a byte stream that may split them across reads
+///
+/// Bytes are appended as they arrive, complete packets are taken out & any partial packet is kept
+/// until the rest of it arrives. The buffer's memory is kept for the next packets, so once it's
+/// grown to fit the largest packet reading more doesn't allocate.
+#[derive(Debug)]
+pub struct PacketBuffer {
+    buffer: Vec<u8>,
+    /// Offset of the first byte not taken out as part of a packet yet
+    start: usize,
+    max_payload: u32,
+}
+impl Default for PacketBuffer {
+    fn default() -> Self {
+        PacketBuffer {
+            buffer: Vec::new(),
+            start: 0,
+            max_payload: DEFAULT_MAX_PAYLOAD_SIZE,
+        }
+    }
+}
+impl PacketBuffer {
+    /// Creates an empty buffer
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Rejects packets with payloads over `max_payload` bytes, instead of buffering them
+    pub fn with_max_payload(mut self, max_payload: u32) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+    /// Appends bytes received from the muxer
+    pub fn extend(&mut self, data: &[u8]) {
+        self.compact();
+        self.buffer.extend_from_slice(data);
+    }
+    /// Reads once from `reader` straight into the buffer, returning the number of bytes read
+    ///
+    /// This saves copying through a separate read buffer. Like [`Read::read`], 0 means the reader
+    /// reached its end.
+    ///
+    /// # Errors
+    /// Fails if the read fails, leaving the buffer as it was
+    pub fn read_from<R: Read + ?Sized>(&mut self, reader: &mut R) -> std::io::Result<usize> {
+        const READ_SIZE: usize = 4096;
+        self.compact();
+        let len = self.buffer.len();
+        self.buffer.resize(len + READ_SIZE, 0);
+        let result = reader.read(&mut self.buffer[len..]);
+        self.buffer.truncate(len + *result.as_ref().unwrap_or(&0));
+        result
+    }
+    /// Number of buffered bytes that aren't part of a returned packet yet
+    pub fn len(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+    /// Checks if there's no partial packet buffered
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Number of bytes still missing from the next packet, at least 1
+    ///
+    /// Reading exactly this many bytes never reads past the packet, which matters on connections
+    /// that stop carrying packets after a reply, such as one that connected to a device port.
+    pub fn needed(&self) -> usize {
+        let header = BASE_PACKET_SIZE as usize;
+        let buffered = &self.buffer[self.start..];
+        if buffered.len() < header {
+            return header - buffered.len();
+        }
+        let size = (&buffered[..4])
+            .read_u32::<LittleEndian>()
+            .map_or(header, |size| size as usize);
+        size.saturating_sub(buffered.len()).max(1)
+    }
+    /// Discards buffered bytes, such as when the connection they came from was lost
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.start = 0;
+    }
+    /// Takes the next complete packet out of the buffer, None if more bytes are needed
+    ///
+    /// # Errors
+    /// Fails if a packet's header is invalid. Packets with a valid size are removed from the buffer
+    /// even if they fail to decode, so the following packets can still be read, while an invalid
+    /// size leaves no way to find the next packet so the whole buffer is discarded.
+    pub fn next_packet(&mut self) -> Result<Option<Packet>> {
+        let buffered = &self.buffer[self.start..];
+        if buffered.len() < BASE_PACKET_SIZE as usize {
+            return Ok(None);
+        }
+        let size = (&buffered[..4]).read_u32::<LittleEndian>()?;
+        if let Err(e) = check_size(size, self.max_payload) {
+            self.clear();
+            return Err(e);
+        }
+        if buffered.len() < size as usize {
+            return Ok(None);
+        }
+        let packet = Packet::from_reader_limited(&mut &buffered[..size as usize], self.max_payload);
+        self.start += size as usize;
+        if self.start == self.buffer.len() {
+            self.clear();
+        }
+        packet.map(Some)
+    }
+    /// Moves a partial packet to the front, making room behind it without growing the buffer
+    fn compact(&mut self) {
+        if self.start > 0 {
+            self.buffer.drain(..self.start);
+            self.start = 0;
+        }
+    }
+}
+
+/// Type of a plist message from the muxer
+#[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
+pub enum MessageType {
+    /// Device was paired
+    Paired,
+    /// Reply to a command
+    Result,
+    /// Device was detached
+    Detached,
+    /// Device was attached
+    Attached,
+    /// Message type this crate doesn't know, from a newer muxer
+    Unknown(String),
+}
+impl TryFrom<&Value> for MessageType {
+    type Error = ProtocolError;
+    fn try_from(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) => match s.as_str() {
+                "Paired" => Ok(MessageType::Paired),
+                "Result" => Ok(MessageType::Result),
+                "Attached" => Ok(MessageType::Attached),
+                "Detached" => Ok(MessageType::Detached),
+                s => Ok(MessageType::Unknown(s.to_owned())),
+            },
+            _ => Err(ProtocolError::InvalidMessageType(
+                "Invalid PLIST type".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Muxer's ID for an attached device, only valid until it's detached
+///
+/// Devices get a new ID every time they're attached, the UDID identifies a device across attaches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct DeviceId(pub u64);
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl From<u64> for DeviceId {
+    fn from(id: u64) -> Self {
+        DeviceId(id)
+    }
+}
+impl From<DeviceId> for u64 {
+    fn from(id: DeviceId) -> Self {
+        id.0
+    }
+}
+impl std::str::FromStr for DeviceId {
+    type Err = std::num::ParseIntError;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.parse().map(DeviceId)
+    }
+}
+/// Device's UDID, identifying it across attaches & hosts
+///
+/// Older devices have 40 hex digit UDIDs, shown in lowercase. Devices since the iPhone XS have 24
+/// hex digits with a dash after the 8th, shown in uppercase (`00008030-001A35E22E88802E`). Parsing
+/// accepts either in any case, & the second without its dash, keeping the canonical form so UDIDs
+/// can be compared as is. Comparing with a `str` parses it first.
+///
+/// Serials the muxer reports in neither format are kept as they are, see [`Udid::from_serial`].
+/// The serial exactly as reported is kept too, as the muxer stores pair records under it.
+#[derive(Debug, Clone)]
+pub struct Udid {
+    canonical: String,
+    serial: String,
+}
+impl Udid {
+    /// Validates & normalizes a UDID
+    ///
+    /// # Errors
+    /// Fails with [`ProtocolError::InvalidUdid`] if it's in neither format
+    pub fn parse(udid: &str) -> Result<Self> {
+        match canonical_udid(udid) {
+            Some(canonical) => Ok(Udid {
+                canonical,
+                serial: udid.to_owned(),
+            }),
+            None => Err(ProtocolError::InvalidUdid(udid.to_owned())),
+        }
+    }
+    /// Takes a serial as reported by the muxer, normalizing it if it's in a known UDID format &
+    /// keeping it as is otherwise
+    pub fn from_serial(serial: &str) -> Self {
+        Udid::parse(serial).unwrap_or_else(|_| Udid {
+            canonical: serial.to_owned(),
+            serial: serial.to_owned(),
+        })
+    }
+    /// UDID in its canonical form
+    pub fn as_str(&self) -> &str {
+        &self.canonical
+    }
+    /// UDID exactly as the muxer reported it, which muxer requests such as `ReadPairRecord` &
+    /// pair record files on disk are keyed by
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+}
+fn canonical_udid(udid: &str) -> Option<String> {
+    let is_hex = |s: &str| s.bytes().all(|b| b.is_ascii_hexdigit());
+    if !udid.is_ascii() {
+        return None;
+    }
+    match udid.len() {
+        40 if is_hex(udid) => Some(udid.to_ascii_lowercase()),
+        25 if &udid[8..9] == "-" && is_hex(&udid[..8]) && is_hex(&udid[9..]) => {
+            Some(udid.to_ascii_uppercase())
+        }
+        24 if is_hex(udid) => Some(format!("{}-{}", &udid[..8], &udid[8..]).to_ascii_uppercase()),
+        _ => None,
+    }
+}
+impl PartialEq for Udid {
+    fn eq(&self, other: &Self) -> bool {
+        self.canonical == other.canonical
+    }
+}
+impl Eq for Udid {}
+impl std::hash::Hash for Udid {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.canonical.hash(state)
+    }
+}
+impl PartialOrd for Udid {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Udid {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.canonical.cmp(&other.canonical)
+    }
+}
+// serialized as reported, so deserializing keeps the serial pair records are looked up by
+#[cfg(feature = "serde")]
+impl Serialize for Udid {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.serial)
+    }
+}
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Udid {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        Ok(Udid::from_serial(&String::deserialize(deserializer)?))
+    }
+}
+impl fmt::Display for Udid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.canonical.fmt(f)
+    }
+}
+impl AsRef<str> for Udid {
+    fn as_ref(&self) -> &str {
+        &self.canonical
+    }
+}
+impl std::str::FromStr for Udid {
+    type Err = ProtocolError;
+    fn from_str(s: &str) -> Result<Self> {
+        Udid::parse(s)
+    }
+}
+impl TryFrom<&str> for Udid {
+    type Error = ProtocolError;
+    fn try_from(udid: &str) -> Result<Self> {
+        Udid::parse(udid)
+    }
+}
+impl TryFrom<String> for Udid {
+    type Error = ProtocolError;
+    fn try_from(udid: String) -> Result<Self> {
+        Udid::parse(&udid)
+    }
+}
+impl From<Udid> for String {
+    fn from(udid: Udid) -> Self {
+        udid.canonical
+    }
+}
+impl PartialEq<str> for Udid {
+    fn eq(&self, other: &str) -> bool {
+        match canonical_udid(other) {
+            Some(other) => other == self.canonical,
+            None => other == self.serial,
+        }
+    }
+}
+impl PartialEq<&str> for Udid {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+impl PartialEq<String> for Udid {
+    fn eq(&self, other: &String) -> bool {
+        self == other.as_str()
+    }
+}
+
+/// Product type of connected device, which typically is an iPad, iPhone, or iPod touch
+///
+/// Every generation of older devices had its own USB product ID, current iPhones & iPads share
+/// one per family. [`DeviceAttachedInfo::product_id`] keeps the exact ID.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ProductType {
+    /// Any iPhone that's connected
+    IPhone,
+    /// iPod touch
+    IPodTouch,
+    /// iPad/iPad Pro
+    IPad,
+    /// T2 security chip of an Intel Mac, running bridgeOS
+    T2Coprocessor,
+    /// Apple silicon Mac in restore mode
+    MacRestore,
+    /// Unexpected product id we haven't coded for yet
+    Unknown(u16),
+}
+impl From<u16> for ProductType {
+    fn from(product_id: u16) -> Self {
+        match product_id {
+            0x1290 | 0x1292 | 0x1294 | 0x1297 | 0x129C | 0x129D | 0x12A0 | 0x12A8 => {
+                ProductType::IPhone
+            }
+            0x1291 | 0x1293 | 0x1296 | 0x1299 | 0x129E | 0x12AA => ProductType::IPodTouch,
+            0x129A | 0x129F | 0x12A2..=0x12A6 | 0x12A9 | 0x12AB => ProductType::IPad,
+            0x8600 => ProductType::T2Coprocessor,
+            0x1901..=0x1905 => ProductType::MacRestore,
+            p => ProductType::Unknown(p),
+        }
+    }
+}
+/// Product ID current devices of the type use, the lowest one for Macs in restore mode
+impl From<ProductType> for u16 {
+    fn from(product_type: ProductType) -> Self {
+        match product_type {
+            ProductType::IPhone => 0x12A8,
+            ProductType::IPodTouch => 0x12AA,
+            ProductType::IPad => 0x12AB,
+            ProductType::T2Coprocessor => 0x8600,
+            ProductType::MacRestore => 0x1901,
+            ProductType::Unknown(p) => p,
+        }
+    }
+}
+/// Speed a USB device's link negotiated, to size streams to what the link carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ConnectionSpeed {
+    /// USB 1.1, 12 Mbit/s
+    FullSpeed,
+    /// USB 2, 480 Mbit/s
+    HighSpeed,
+    /// USB 3, 5 Gbit/s or more
+    SuperSpeed,
+    /// Speed in bits per second that's none of the above
+    Unknown(u64),
+}
+impl ConnectionSpeed {
+    /// Nominal signalling rate in bits per second, the usable bandwidth is lower
+    pub fn bits_per_second(self) -> u64 {
+        match self {
+            ConnectionSpeed::FullSpeed => 12_000_000,
+            ConnectionSpeed::HighSpeed => 480_000_000,
+            ConnectionSpeed::SuperSpeed => 5_000_000_000,
+            ConnectionSpeed::Unknown(speed) => speed,
+        }
+    }
+    /// Checks if the device negotiated USB 3, which devices with Lightning never do
+    pub fn is_usb3(self) -> bool {
+        self.bits_per_second() >= 5_000_000_000
+    }
+}
+impl From<u64> for ConnectionSpeed {
+    fn from(speed: u64) -> Self {
+        match speed {
+            12_000_000 => ConnectionSpeed::FullSpeed,
+            480_000_000 => ConnectionSpeed::HighSpeed,
+            5_000_000_000.. => ConnectionSpeed::SuperSpeed,
+            speed => ConnectionSpeed::Unknown(speed),
+        }
+    }
+}
+/// How device is connected
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DeviceConnectionType {
+    /// USB connection type
+    USB,
+    /// Wi-Fi connection, for devices with Wi-Fi sync enabled that are on the same network
+    Network,
+    /// Connection type we don't know about yet
+    Unknown(String),
+}
+impl TryFrom<&Value> for DeviceConnectionType {
+    type Error = ProtocolError;
+    fn try_from(value: &Value) -> Result<Self> {
+        match value.as_string() {
+            Some("USB") => Ok(DeviceConnectionType::USB),
+            Some("Network") => Ok(DeviceConnectionType::Network),
+            Some(s) => Ok(DeviceConnectionType::Unknown(s.to_owned())),
+            None => Err(ProtocolError::InvalidPlistEntryForKey("ConnectionType")),
+        }
+    }
+}
+/// Address family values seen in `NetworkAddress` for IPv6, as used by macOS, Linux & Windows
+const AF_INET6_VALUES: [u8; 3] = [30, 10, 23];
+/// IPv4 address family, which is the same everywhere
+const AF_INET: u8 = 2;
+
+/// Decodes the sockaddr blob usbmuxd reports for network devices
+///
+/// The blob uses the BSD layout, a length byte followed by a family byte, then the big endian port
+/// & address as in `sockaddr_in`/`sockaddr_in6`.
+fn decode_network_address(data: &[u8]) -> Option<SocketAddr> {
+    let family = *data.get(1)?;
+    let port = u16::from_be_bytes([*data.get(2)?, *data.get(3)?]);
+    if family == AF_INET {
+        let octets: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+        Some(SocketAddr::V4(SocketAddrV4::new(octets.into(), port)))
+    } else if AF_INET6_VALUES.contains(&family) {
+        let flow_info = u32::from_be_bytes(data.get(4..8)?.try_into().ok()?);
+        let octets: [u8; 16] = data.get(8..24)?.try_into().ok()?;
+        let scope_id = u32::from_le_bytes(data.get(24..28)?.try_into().ok()?);
+        Some(SocketAddr::V6(SocketAddrV6::new(
+            octets.into(),
+            port,
+            flow_info,
+            scope_id,
+        )))
+    } else {
+        None
+    }
+}
+
+/// Info about an attached device
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeviceAttachedInfo {
+    /// Type of connection device is using (USB or otherwise)
+    pub connection_type: DeviceConnectionType,
+    /// ID of device
+    pub device_id: DeviceId,
+    /// Unknown purpose/value, 0 for network devices which don't report it
+    pub location_id: u64,
+    /// Product type of device, ipad, ipod, iphone, mysterious other device
+    ///
+    /// Network devices don't report a USB product ID, so they're `ProductType::Unknown(0)`.
+    pub product_type: ProductType,
+    /// USB product ID the product type was decoded from, 0 for network devices
+    pub product_id: u16,
+    /// Device's UDID, also reported as its serial number
+    pub identifier: Udid,
+    /// IP address of a network device
+    pub network_address: Option<SocketAddr>,
+    /// Index of the host's network interface a network device was found on
+    pub interface_index: Option<u64>,
+    /// Speed the USB link negotiated, if the muxer reports it
+    pub connection_speed: Option<ConnectionSpeed>,
+    /// Serial number the device reports over USB, the UDID without its dash on newer devices
+    pub usb_serial_number: Option<String>,
+    /// Escrow keybag usbmuxd holds for a network device, allowing access while it's locked
+    pub escrow_bag: Option<Vec<u8>>,
+    /// Every property the muxer sent, including the ones without a field here
+    ///
+    /// Empty for devices reported by the binary protocol.
+    pub properties: plist::Dictionary,
+}
+// TODO: this likely could be done from within serde maybe? custom deserialization?
+impl TryFrom<&Value> for DeviceAttachedInfo {
+    type Error = ProtocolError;
+    fn try_from(value: &Value) -> Result<Self> {
+        match value {
+            Value::Dictionary(d) => {
+                let connection_type = d
+                    .get("ConnectionType")
+                    .and_then(|t| DeviceConnectionType::try_from(t).ok())
+                    .ok_or(ProtocolError::InvalidPlistEntryForKey("ConnectionType"))?;
+                let device_id = d
+                    .get(USB_DEVICE_ID_KEY)
+                    .and_then(Value::as_unsigned_integer)
+                    .map(DeviceId)
+                    .ok_or(ProtocolError::InvalidPlistEntryForKey(USB_DEVICE_ID_KEY))?;
+                // network devices have no USB location or product ID
+                let is_network = connection_type == DeviceConnectionType::Network;
+                let location_id = match d.get("LocationID").and_then(Value::as_unsigned_integer) {
+                    Some(location_id) => location_id,
+                    None if is_network => 0,
+                    None => return Err(ProtocolError::InvalidPlistEntryForKey("LocationID")),
+                };
+                let product_id = match d.get("ProductID").and_then(Value::as_unsigned_integer) {
+                    Some(product_id) => product_id as u16, // product_id is USB product_id which is u16
+                    None if is_network => 0,
+                    None => return Err(ProtocolError::InvalidPlistEntryForKey("ProductID")),
+                };
+                let product_type = ProductType::from(product_id);
+                let identifier = d
+                    .get("SerialNumber")
+                    .and_then(Value::as_string)
+                    .map(Udid::from_serial)
+                    .ok_or(ProtocolError::InvalidPlistEntryForKey("SerialNumber"))?;
+                let network_address = match d.get("NetworkAddress") {
+                    Some(address) => Some(
+                        address
+                            .as_data()
+                            .and_then(decode_network_address)
+                            .ok_or(ProtocolError::InvalidPlistEntryForKey("NetworkAddress"))?,
+                    ),
+                    None => None,
+                };
+                // older muxers don't send these
+                let interface_index = d.get("InterfaceIndex").and_then(Value::as_unsigned_integer);
+                let connection_speed = d
+                    .get("ConnectionSpeed")
+                    .and_then(Value::as_unsigned_integer)
+                    .map(ConnectionSpeed::from);
+                let usb_serial_number = d
+                    .get("USBSerialNumber")
+                    .and_then(Value::as_string)
+                    .map(str::to_owned);
+                let escrow_bag = d
+                    .get("EscrowBag")
+                    .and_then(Value::as_data)
+                    .map(<[u8]>::to_vec);
+                Ok(DeviceAttachedInfo {
+                    connection_type,
+                    device_id,
+                    location_id,
+                    product_type,
+                    product_id,
+                    identifier,
+                    network_address,
+                    interface_index,
+                    connection_speed,
+                    usb_serial_number,
+                    escrow_bag,
+                    properties: d.clone(),
+                })
+            }
+            _ => Err(ProtocolError::InvalidPlistEntry),
+        }
+    }
+}
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// Event that can occur on device listener
+#[non_exhaustive]
+pub enum DeviceEvent {
+    /// Device was plugged into host
+    Attached(DeviceAttachedInfo),
+    /// Device was unplugged from host
+    Detached(DeviceId),
+    /// Device was paired to host (trusting computer was authorized)
+    Paired(DeviceId),
+    /// Message this crate doesn't know, passed on so newer muxers don't break listening
+    Unknown {
+        /// The message's `MessageType`
+        message_type: String,
+        /// The whole message
+        payload: Value,
+    },
+    /// Listener lost its connection to the muxer, such as when the service stopped
+    ///
+    /// Not sent by the muxer, listeners report it themselves. Devices may come & go unnoticed
+    /// until the listener reconnects, which reports those as attached & detached then.
+    MuxerDisconnected,
+    /// Device was paired, with details from the pair record the listener read for it
+    ///
+    /// Not sent by the muxer, listeners asked to read pair records report it in place of
+    /// [`Paired`](Self::Paired).
+    PairedWithRecord {
+        /// Device that was paired
+        device_id: DeviceId,
+        /// Device's UDID, which its pair record is stored under
+        udid: Udid,
+        /// ID identifying this host to the device
+        host_id: String,
+        /// ID of the host system, shared by all of its pair records
+        system_buid: String,
+    },
+    /// Device detached & attached again within the listener's debounce window, under a new ID
+    ///
+    /// Not sent by the muxer, listeners report it in place of the detach & attach.
+    Reattached {
+        /// ID the device had before detaching
+        previous: DeviceId,
+        /// The device as attached now
+        info: DeviceAttachedInfo,
+    },
+}
+impl TryFrom<&Value> for DeviceEvent {
+    type Error = ProtocolError;
+    fn try_from(value: &Value) -> Result<Self> {
+        match value {
+            Value::Dictionary(d) => {
+                let msg_type = d
+                    .get(USB_MESSAGE_TYPE_KEY)
+                    .ok_or(ProtocolError::InvalidPlistEntryForKey(USB_MESSAGE_TYPE_KEY))?;
+                let msg_type = MessageType::try_from(msg_type)?;
+                let device_id = || {
+                    d.get(USB_DEVICE_ID_KEY)
+                        .and_then(Value::as_unsigned_integer)
+                        .map(DeviceId)
+                        .ok_or(ProtocolError::InvalidPlistEntryForKey(USB_DEVICE_ID_KEY))
+                };
+                match msg_type {
+                    MessageType::Attached => {
+                        device_id()?;
+                        let device_info = d
+                            .get(USB_DEVICE_PROPERTIES_KEY)
+                            .and_then(|p| DeviceAttachedInfo::try_from(p).ok())
+                            .ok_or(ProtocolError::InvalidPlistEntryForKey(
+                                USB_DEVICE_PROPERTIES_KEY,
+                            ))?;
+                        Ok(DeviceEvent::Attached(device_info))
+                    }
+                    MessageType::Detached => Ok(DeviceEvent::Detached(device_id()?)),
+                    MessageType::Paired => Ok(DeviceEvent::Paired(device_id()?)),
+                    MessageType::Result => {
+                        Err(ProtocolError::InvalidMessageType("Result".to_owned()))
+                    }
+                    MessageType::Unknown(message_type) => Ok(DeviceEvent::Unknown {
+                        message_type,
+                        payload: value.clone(),
+                    }),
+                }
+            }
+            _ => Err(ProtocolError::InvalidPlistEntry),
+        }
+    }
+}
+impl DeviceEvent {
+    /// Decodes an event from a plist packet payload
+    pub fn from_vec(data: Vec<u8>) -> Result<DeviceEvent> {
+        Self::from_slice(&data)
+    }
+    fn from_slice(data: &[u8]) -> Result<DeviceEvent> {
+        let cursor = std::io::Cursor::new(data);
+        let dict = Value::from_reader(cursor).map_err(|_| ProtocolError::InvalidPlistEntry)?;
+        DeviceEvent::try_from(&dict)
+    }
+    /// Decodes an event from a packet in either protocol
+    pub fn from_packet(packet: Packet) -> Result<DeviceEvent> {
+        let event = match packet.protocol {
+            Protocol::Binary => crate::binary::decode_event(&packet),
+            Protocol::Plist => Self::from_slice(&packet.data),
+        };
+        event.map_err(|e| e.in_packet("device event", &packet))
+    }
+}
+
+/// Parses the reply to a `ListDevices` request
+pub fn device_list_from_value(value: &Value) -> Result<Vec<DeviceAttachedInfo>> {
+    let list = value
+        .as_dictionary()
+        .and_then(|d| d.get("DeviceList"))
+        .and_then(Value::as_array)
+        .ok_or(ProtocolError::InvalidPlistEntryForKey("DeviceList"))?;
+    list.iter()
+        .map(|entry| match DeviceEvent::try_from(entry)? {
+            DeviceEvent::Attached(info) => Ok(info),
+            _ => Err(ProtocolError::InvalidPlistEntryForKey(USB_MESSAGE_TYPE_KEY)),
+        })
+        .collect()
+}
+
+/// Client connected to the muxer for device events, as reported by `ListListeners`
+///
+/// Muxers fill in different keys, so everything is optional & all of them are kept in
+/// `properties`.
+#[derive(Debug, Clone)]
+pub struct ListenerInfo {
+    /// Muxer's description of the connection (`ID String`), its id & the client's name
+    pub id: Option<String>,
+    /// Name the client identified with (`ProgName`)
+    pub prog_name: Option<String>,
+    /// Version the client identified with (`ClientVersionString`)
+    pub client_version: Option<String>,
+    /// Bundle identifier of the client app (`BundleID`)
+    pub bundle_id: Option<String>,
+    /// libusbmuxd protocol version the client speaks (`kLibUSBMuxVersion`)
+    pub lib_usbmux_version: Option<u64>,
+    /// Every property the muxer sent for the client
+    pub properties: plist::Dictionary,
+}
+impl TryFrom<&Value> for ListenerInfo {
+    type Error = ProtocolError;
+    fn try_from(value: &Value) -> Result<Self> {
+        let d = value
+            .as_dictionary()
+            .ok_or(ProtocolError::InvalidPlistEntry)?;
+        let string = |key| d.get(key).and_then(Value::as_string).map(str::to_owned);
+        Ok(ListenerInfo {
+            id: string("ID String"),
+            prog_name: string("ProgName"),
+            client_version: string("ClientVersionString"),
+            bundle_id: string("BundleID"),
+            lib_usbmux_version: d
+                .get("kLibUSBMuxVersion")
+                .and_then(Value::as_unsigned_integer),
+            properties: d.clone(),
+        })
+    }
+}
+
+/// Parses the reply to a `ListListeners` request
+pub fn listener_list_from_value(value: &Value) -> Result<Vec<ListenerInfo>> {
+    value
+        .as_dictionary()
+        .and_then(|d| d.get("ListenerList"))
+        .and_then(Value::as_array)
+        .ok_or(ProtocolError::InvalidPlistEntryForKey("ListenerList"))?
+        .iter()
+        .map(ListenerInfo::try_from)
+        .collect()
+}
+
+/// Result reply to a command, holding the reply code number
+#[derive(Debug)]
+pub struct ResultMessage(pub i64);
+impl ResultMessage {
+    /// Decodes a result from a plist packet payload
+    pub fn from_reader<R: Read + Seek>(reader: R) -> Result<Self> {
+        let r = Value::from_reader(reader).map_err(|_| ProtocolError::InvalidPlistEntry)?;
+        ResultMessage::try_from(&r)
+    }
+    /// Decodes a result from a packet in either protocol
+    pub fn from_packet(packet: &Packet) -> Result<Self> {
+        let result = match packet.protocol {
+            Protocol::Binary => crate::binary::decode_result(packet),
+            Protocol::Plist => Self::from_reader(std::io::Cursor::new(&packet.data[..])),
+        };
+        result.map_err(|e| e.in_packet("result", packet))
+    }
+    /// Reply code of the result
+    ///
+    /// # Errors
+    /// Fails if the number isn't a known reply code
+    pub fn code(&self) -> Result<ReplyCode> {
+        let number =
+            u32::try_from(self.0).map_err(|_| ProtocolError::InvalidReplyCode(u32::MAX))?;
+        ReplyCode::try_from(number)
+    }
+}
+impl TryFrom<&Value> for ResultMessage {
+    type Error = ProtocolError;
+    fn try_from(value: &Value) -> Result<Self> {
+        match value {
+            Value::Dictionary(d) => {
+                let num = d
+                    .get("Number")
+                    .and_then(Value::as_signed_integer)
+                    .ok_or(ProtocolError::InvalidPlistEntryForKey("Number"))?;
+                Ok(ResultMessage(num))
+            }
+            _ => Err(ProtocolError::InvalidPlistEntry),
+        }
+    }
+}
+
+/// Plist command sent to the muxer
+#[derive(Serialize, Deserialize)]
+pub struct Command {
+    #[serde(rename = "MessageType")]
+    message_type: String,
+    #[serde(rename = "ProgName")]
+    prog_name: String,
+    #[serde(rename = "ClientVersionString")]
+    client_version_string: String,
+    #[serde(rename = "BundleID")]
+    bundle_id: Option<String>,
+    #[serde(rename = "kLibUSBMuxVersion")]
+    lib_usbmux_version: Option<u64>,
+    #[serde(rename = "PortNumber")]
+    port_number: Option<u16>,
+    #[serde(rename = "DeviceID")]
+    device_id: Option<u64>,
+    #[serde(rename = "PairRecordID")]
+    pair_record_id: Option<String>,
+    #[serde(rename = "PairRecordData")]
+    pair_record_data: Option<plist::Data>,
+}
+impl Command {
+    fn new<C: AsRef<str>>(command: C) -> Self {
+        Command {
+            message_type: command.as_ref().to_owned(),
+            prog_name: String::from("Peertalk Example"),
+            client_version_string: String::from("1"),
+            bundle_id: None,
+            lib_usbmux_version: None,
+            port_number: None,
+            device_id: None,
+            pair_record_id: None,
+            pair_record_data: None,
+        }
+    }
+    /// Registers the connection for device events
+    pub fn listen() -> Self {
+        Command::new("Listen")
+    }
+    /// Turns the connection into a connection to the given device port
+    pub fn connect(port: u16, device_id: DeviceId) -> Self {
+        let mut command = Command::new("Connect");
+        command.port_number = Some(port.to_be()); // apple's service expects network byte order
+        command.device_id = Some(device_id.0);
+        command
+    }
+    /// Lists currently attached devices
+    pub fn list_devices() -> Self {
+        Command::new("ListDevices")
+    }
+    /// Lists the clients listening for device events
+    pub fn list_listeners() -> Self {
+        Command::new("ListListeners")
+    }
+    /// Reads the pair record for a device UDID
+    pub fn read_pair_record<S: AsRef<str>>(udid: S) -> Self {
+        let mut command = Command::new("ReadPairRecord");
+        command.pair_record_id = Some(udid.as_ref().to_owned());
+        command
+    }
+    /// Stores a pair record (encoded as a plist) for a device UDID
+    pub fn save_pair_record<S: AsRef<str>>(udid: S, device_id: DeviceId, data: Vec<u8>) -> Self {
+        let mut command = Command::new("SavePairRecord");
+        command.pair_record_id = Some(udid.as_ref().to_owned());
+        command.pair_record_data = Some(data.into());
+        command.device_id = Some(device_id.0);
+        command
+    }
+    /// Deletes the pair record for a device UDID
+    pub fn delete_pair_record<S: AsRef<str>>(udid: S) -> Self {
+        let mut command = Command::new("DeletePairRecord");
+        command.pair_record_id = Some(udid.as_ref().to_owned());
+        command
+    }
+    /// Reads the host's system BUID, shared by all of its pair records
+    pub fn read_buid() -> Self {
+        Command::new("ReadBUID")
+    }
+    /// Sets the client name the muxer logs the command under (`ProgName`)
+    pub fn with_prog_name<S: Into<String>>(mut self, prog_name: S) -> Self {
+        self.prog_name = prog_name.into();
+        self
+    }
+    /// Sets the client version the muxer logs the command under (`ClientVersionString`)
+    pub fn with_client_version<S: Into<String>>(mut self, version: S) -> Self {
+        self.client_version_string = version.into();
+        self
+    }
+    /// Sets the bundle identifier of the app sending the command (`BundleID`)
+    pub fn with_bundle_id<S: Into<String>>(mut self, bundle_id: S) -> Self {
+        self.bundle_id = Some(bundle_id.into());
+        self
+    }
+    /// Sets the libusbmuxd protocol version the client claims to speak (`kLibUSBMuxVersion`)
+    pub fn with_lib_usbmux_version(mut self, version: u64) -> Self {
+        self.lib_usbmux_version = Some(version);
+        self
+    }
+    /// Encodes the command as a plist payload
+    ///
+    /// # Errors
+    /// Fails if the command can't be serialized
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut payload: Vec<u8> = Vec::new();
+        plist::to_writer_xml(&mut payload, &self).map_err(ProtocolError::PlistEncodeError)?;
+        Ok(payload)
+    }
+    /// Encodes the command as a binary plist payload, which is smaller & faster to produce
+    ///
+    /// # Errors
+    /// Fails if the command can't be serialized
+    pub fn to_binary_bytes(&self) -> Result<Vec<u8>> {
+        let mut payload = std::io::Cursor::new(Vec::new());
+        plist::to_writer_binary(&mut payload, &self).map_err(ProtocolError::PlistEncodeError)?;
+        Ok(payload.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    fn value_for_testfile(file: &str) -> plist::Value {
+        let mut path = std::path::PathBuf::new();
+        path.push("test_data");
+        path.push(file);
+        plist::Value::from_file(path).unwrap()
+    }
+    #[test]
+    fn it_decodes_plists() {
+        let r = value_for_testfile("detached.plist");
+        match DeviceEvent::try_from(&r) {
+            Ok(DeviceEvent::Detached(device_id)) => assert_eq!(device_id, DeviceId(3)),
+            _ => panic!("Invalid DeviceEvent"),
+        }
+        let r = value_for_testfile("paired.plist");
+        match DeviceEvent::try_from(&r) {
+            Ok(DeviceEvent::Paired(device_id)) => assert_eq!(device_id, DeviceId(3)),
+            _ => panic!("Invalid DeviceEvent"),
+        }
+        let r = value_for_testfile("success-result.plist");
+        let msg = ResultMessage::try_from(&r);
+        assert!(msg.is_ok());
+        println!("Test: {:?}", msg);
+    }
+    #[test]
+    fn it_rejects_malformed_messages() {
+        assert!(DeviceEvent::from_vec(b"not a plist".to_vec()).is_err());
+        assert!(ResultMessage::from_reader(std::io::Cursor::new(b"garbage")).is_err());
+        let no_type = Value::Dictionary(plist::Dictionary::new());
+        assert!(matches!(
+            DeviceEvent::try_from(&no_type),
+            Err(ProtocolError::InvalidPlistEntryForKey(USB_MESSAGE_TYPE_KEY))
+        ));
+    }
+    #[test]
+    fn it_tells_which_packet_failed_to_decode() {
+        let packet = Packet::new(
+            Protocol::Plist,
+            PacketType::PlistPayload,
+            7,
+            b"<plist/>".to_vec(),
+        )
+        .unwrap();
+        let error = ResultMessage::from_packet(&packet).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::InvalidPacket {
+                operation: "result",
+                packet_type: PacketType::PlistPayload,
+                tag: 7,
+                ..
+            }
+        ));
+        assert!(matches!(
+            error.root_cause(),
+            ProtocolError::InvalidPlistEntry
+        ));
+    }
+    #[test]
+    fn it_passes_on_unknown_messages() {
+        let mut d = plist::Dictionary::new();
+        d.insert(
+            USB_MESSAGE_TYPE_KEY.to_owned(),
+            Value::String("Teleported".to_owned()),
+        );
+        match DeviceEvent::try_from(&Value::Dictionary(d)) {
+            Ok(DeviceEvent::Unknown { message_type, .. }) => assert_eq!(message_type, "Teleported"),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+    #[test]
+    fn it_maps_result_numbers_to_reply_codes() {
+        assert_eq!(
+            ResultMessage(3).code().unwrap(),
+            ReplyCode::ConnectionRefused
+        );
+        assert!(matches!(
+            ResultMessage(4).code(),
+            Err(ProtocolError::InvalidReplyCode(4))
+        ));
+        assert!(ResultMessage(-1).code().is_err());
+    }
+    #[test]
+    fn it_decodes_attached() {
+        let r = value_for_testfile("attached.plist");
+        let msg = DeviceEvent::try_from(&r);
+        assert!(msg.is_ok());
+        match DeviceEvent::try_from(&r) {
+            Ok(DeviceEvent::Attached(device_info)) => {
+                assert_eq!(device_info.device_id, DeviceId(3));
+                assert_eq!(device_info.connection_type, DeviceConnectionType::USB);
+                assert_eq!(device_info.location_id, 0);
+                assert_eq!(device_info.product_type, ProductType::IPad);
+                assert_eq!(device_info.product_id, 0x12AB);
+                assert_eq!(device_info.identifier, "00001011-000A111E0111001E");
+                assert_eq!(
+                    device_info.connection_speed,
+                    Some(ConnectionSpeed::HighSpeed)
+                );
+                assert_eq!(
+                    device_info.usb_serial_number.as_deref(),
+                    Some("00001011000A111E0111001E")
+                );
+                let usb_serial = device_info.properties.get("USBSerialNumber");
+                assert!(usb_serial.and_then(Value::as_string).is_some());
+            }
+            _ => panic!("Invalid DeviceEvent"),
+        }
+    }
+
+    #[test]
+    fn it_decodes_network_attached() {
+        let r = value_for_testfile("network-attached.plist");
+        match DeviceEvent::try_from(&r) {
+            Ok(DeviceEvent::Attached(device_info)) => {
+                assert_eq!(device_info.device_id, DeviceId(7));
+                assert_eq!(device_info.connection_type, DeviceConnectionType::Network);
+                assert_eq!(device_info.product_type, ProductType::Unknown(0));
+                assert_eq!(
+                    device_info.network_address,
+                    Some("192.168.1.20:0".parse().unwrap())
+                );
+                assert_eq!(device_info.interface_index, Some(12));
+                assert_eq!(device_info.escrow_bag.as_deref(), Some(&b"escrow-bag"[..]));
+            }
+            _ => panic!("Invalid DeviceEvent"),
+        }
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_serializes_events() {
+        let r = value_for_testfile("network-attached.plist");
+        let event = DeviceEvent::try_from(&r).unwrap();
+        let mut encoded = Vec::new();
+        plist::to_writer_xml(&mut encoded, &event).unwrap();
+        match plist::from_bytes(&encoded).unwrap() {
+            DeviceEvent::Attached(info) => {
+                assert_eq!(info.device_id, DeviceId(7));
+                assert_eq!(info.connection_type, DeviceConnectionType::Network);
+                assert_eq!(
+                    info.network_address,
+                    Some("192.168.1.20:0".parse().unwrap())
+                );
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    #[test]
+    fn it_decodes_ipv6_network_addresses() {
+        let mut data = vec![28, 30, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(&[
+            0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0x1c, 0x2f, 0x3a, 0x4b, 0x5c, 0x6d, 0x7e, 0x8f,
+        ]);
+        data.extend_from_slice(&[5, 0, 0, 0]);
+        let address = decode_network_address(&data).unwrap();
+        assert_eq!(address, "[fe80::1c2f:3a4b:5c6d:7e8f%5]:0".parse().unwrap());
+        assert_eq!(decode_network_address(&[16, 99, 0, 0]), None);
+    }
+
+    #[test]
+    fn it_decodes_device_list() {
+        let r = value_for_testfile("device-list.plist");
+        let devices = device_list_from_value(&r).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].device_id, DeviceId(3));
+        assert_eq!(devices[1].product_type, ProductType::IPhone);
+        assert_eq!(devices[1].identifier, "00008030-001A35E22E88802E");
+    }
+    #[test]
+    fn it_decodes_listener_list() {
+        let r = value_for_testfile("listener-list.plist");
+        let listeners = listener_list_from_value(&r).unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].prog_name.as_deref(), Some("iTunes"));
+        assert_eq!(listeners[0].lib_usbmux_version, Some(3));
+        assert!(listeners[0].properties.contains_key("ConnType"));
+        assert_eq!(listeners[1].id.as_deref(), Some("15-Peertalk Example"));
+        assert_eq!(listeners[1].bundle_id, None);
+    }
+
+    /// Writer taking at most `limit` bytes per call, counting the calls
+    struct Trickle {
+        written: Vec<u8>,
+        calls: usize,
+        limit: usize,
+    }
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+            self.calls += 1;
+            let start = self.written.len();
+            for buf in bufs {
+                let room = self.limit - (self.written.len() - start);
+                self.written.extend_from_slice(&buf[..buf.len().min(room)]);
+            }
+            Ok(self.written.len() - start)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    #[test]
+    fn it_writes_packets_in_one_call() {
+        let packet =
+            Packet::new(Protocol::Plist, PacketType::PlistPayload, 3, vec![1; 20]).unwrap();
+        let mut expected = Vec::new();
+        for field in [36, 1, 8, 3] {
+            expected.write_u32::<LittleEndian>(field).unwrap();
+        }
+        expected.extend_from_slice(&[1; 20]);
+        let mut writer = Trickle {
+            written: Vec::new(),
+            calls: 0,
+            limit: usize::MAX,
+        };
+        packet.write_into(&mut writer).unwrap();
+        assert_eq!(writer.calls, 1);
+        assert_eq!(writer.written, expected);
+        // short writes carry on where they stopped
+        let mut writer = Trickle {
+            written: Vec::new(),
+            calls: 0,
+            limit: 10,
+        };
+        packet.write_into(&mut writer).unwrap();
+        assert_eq!(writer.calls, 4);
+        assert_eq!(writer.written, expected);
+    }
+    #[test]
+    fn it_maps_product_ids() {
+        assert_eq!(ProductType::from(0x12A0), ProductType::IPhone);
+        assert_eq!(ProductType::from(0x12A4), ProductType::IPad);
+        assert_eq!(ProductType::from(0x8600), ProductType::T2Coprocessor);
+        assert_eq!(ProductType::from(0x12AF), ProductType::Unknown(0x12AF));
+        assert_eq!(u16::from(ProductType::IPhone), 0x12A8);
+    }
+    #[test]
+    fn it_maps_connection_speeds() {
+        assert_eq!(
+            ConnectionSpeed::from(480_000_000),
+            ConnectionSpeed::HighSpeed
+        );
+        assert!(ConnectionSpeed::from(10_000_000_000).is_usb3());
+        assert!(!ConnectionSpeed::HighSpeed.is_usb3());
+        assert_eq!(
+            ConnectionSpeed::from(1_500_000),
+            ConnectionSpeed::Unknown(1_500_000)
+        );
+    }
+    #[test]
+    fn it_normalizes_udids() {
+        let udid = Udid::parse("00008030001a35e22e88802e").unwrap();
+        assert_eq!(udid.as_str(), "00008030-001A35E22E88802E");
+        assert_eq!(udid, "00008030-001a35e22e88802e");
+        let udid: Udid = "5D8E6F3A2B1C0D9E8F7A6B5C4D3E2F1A0B9C8D7E".parse().unwrap();
+        assert_eq!(udid.to_string(), "5d8e6f3a2b1c0d9e8f7a6b5c4d3e2f1a0b9c8d7e");
+        assert!(matches!(
+            Udid::parse("00008030_001A35E22E88802E"),
+            Err(ProtocolError::InvalidUdid(_))
+        ));
+        assert!(Udid::parse("serial").is_err());
+        // the muxer's own form is kept for pair record lookups
+        let udid = Udid::parse("00008030001a35e22e88802e").unwrap();
+        assert_eq!(udid.serial(), "00008030001a35e22e88802e");
+    }
+    #[test]
+    fn it_keeps_nonstandard_serials() {
+        let mut d = plist::Dictionary::new();
+        d.insert(USB_MESSAGE_TYPE_KEY.to_owned(), Value::from("Attached"));
+        d.insert(USB_DEVICE_ID_KEY.to_owned(), Value::from(3u64));
+        let mut properties = plist::Dictionary::new();
+        properties.insert("ConnectionType".to_owned(), Value::from("USB"));
+        properties.insert(USB_DEVICE_ID_KEY.to_owned(), Value::from(3u64));
+        properties.insert("LocationID".to_owned(), Value::from(0u64));
+        properties.insert("ProductID".to_owned(), Value::from(0x12A8u64));
+        properties.insert("SerialNumber".to_owned(), Value::from("DEVKIT-0001"));
+        d.insert(
+            USB_DEVICE_PROPERTIES_KEY.to_owned(),
+            Value::Dictionary(properties),
+        );
+        match DeviceEvent::try_from(&Value::Dictionary(d)) {
+            Ok(DeviceEvent::Attached(info)) => {
+                assert_eq!(info.identifier.as_str(), "DEVKIT-0001");
+                assert_eq!(info.identifier.serial(), "DEVKIT-0001");
+                assert_eq!(info.identifier, "DEVKIT-0001");
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+    #[test]
+    fn it_reassembles_split_packets() {
+        let mut data = Vec::new();
+        for tag in 1..=2 {
+            Packet::new(
+                Protocol::Plist,
+                PacketType::PlistPayload,
+                tag,
+                vec![tag as u8; 5],
+            )
+            .unwrap()
+            .write_into(&mut data)
+            .unwrap();
+        }
+        let mut buffer = PacketBuffer::new();
+        let mut packets = Vec::new();
+        // one byte at a time, the worst case for a stream socket
+        for byte in &data {
+            buffer.extend(&[*byte]);
+            while let Some(packet) = buffer.next_packet().unwrap() {
+                packets.push(packet);
+            }
+        }
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].tag, 1);
+        assert_eq!(packets[1].data, vec![2; 5]);
+        assert!(buffer.is_empty());
+    }
+    #[test]
+    fn it_rejects_invalid_packet_sizes() {
+        let header = |size: u32| {
+            let mut data = Vec::new();
+            for field in [size, 1, 8, 0] {
+                data.write_u32::<LittleEndian>(field).unwrap();
+            }
+            data
+        };
+        assert!(matches!(
+            Packet::from_reader(&mut &header(4)[..]),
+            Err(ProtocolError::InvalidPacketSize(4))
+        ));
+        assert!(matches!(
+            Packet::from_reader(&mut &header(u32::MAX)[..]),
+            Err(ProtocolError::PayloadTooLarge(_))
+        ));
+        let mut buffer = PacketBuffer::new().with_max_payload(8);
+        buffer.extend(&header(BASE_PACKET_SIZE + 9));
+        assert!(matches!(
+            buffer.next_packet(),
+            Err(ProtocolError::PayloadTooLarge(9))
+        ));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn it_decodes_command() {
+        let command: Command = plist::from_file("test_data/command.plist").unwrap();
+        assert_eq!(command.message_type, "Listen");
+        assert_eq!(command.prog_name, "MyApp");
+        assert_eq!(command.client_version_string, "1.0");
+    }
+    #[test]
+    fn it_encodes_command() {
+        let mut command = Command::new("Connect");
+        command.port_number = Some(12345);
+        command.device_id = Some(16689);
+        plist::to_file_xml("test.plist", &command).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+//! Python bindings for peertalk, for driving device rigs from Python
+//!
+//! Build the wheel with `maturin build --release`, then:
+//!
+//! ```python
+//! import peertalk
+//! for device in peertalk.list_devices():
+//!     print(device.udid)
+//! for event in peertalk.Listener():
+//!     if event.kind == "attached":
+//!         sock = peertalk.connect(event.device.device_id, 2345)  # a socket.socket
+//! ```
+#![forbid(missing_docs)]
+use peertalk::{DeviceAttachedInfo, DeviceConnectionType, DeviceEvent, DeviceListener, UsbSocket};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyStopIteration};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::sync::Mutex;
+use std::time::Duration;
+
+create_exception!(
+    peertalk,
+    PeertalkError,
+    PyException,
+    "Muxer, connection or device error"
+);
+
+/// How often a blocked listener checks for Ctrl-C
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+fn to_py_err(e: peertalk::Error) -> PyErr {
+    PeertalkError::new_err(e.to_string())
+}
+
+/// Attached device, as reported by the muxer
+#[pyclass(frozen, get_all, module = "peertalk")]
+#[derive(Clone)]
+struct Device {
+    /// Muxer's id for the device, only valid while it stays attached
+    device_id: u64,
+    /// Device's UDID (serial number)
+    udid: String,
+    /// USB product id, 0 for network devices
+    product_id: u16,
+    /// "usb", "network" or what the muxer reported
+    connection_type: String,
+    /// IP address & port of a network device
+    network_address: Option<String>,
+}
+#[pymethods]
+impl Device {
+    fn __repr__(&self) -> String {
+        format!(
+            "Device(device_id={}, udid='{}', connection_type='{}')",
+            self.device_id, self.udid, self.connection_type
+        )
+    }
+}
+impl From<&DeviceAttachedInfo> for Device {
+    fn from(info: &DeviceAttachedInfo) -> Self {
+        Device {
+            device_id: info.device_id.0,
+            udid: info.identifier.to_string(),
+            product_id: info.product_id,
+            connection_type: match &info.connection_type {
+                DeviceConnectionType::USB => "usb".to_owned(),
+                DeviceConnectionType::Network => "network".to_owned(),
+                DeviceConnectionType::Unknown(name) => name.clone(),
+            },
+            network_address: info.network_address.map(|a| a.to_string()),
+        }
+    }
+}
+
+/// Device event from a [`Listener`]
+#[pyclass(frozen, get_all, module = "peertalk")]
+struct Event {
+    /// "attached", "detached", "paired" or "unknown"
+    kind: &'static str,
+    /// Muxer's id for the device, None for unknown events
+    device_id: Option<u64>,
+    /// The device, for attached events
+    device: Option<Device>,
+}
+#[pymethods]
+impl Event {
+    fn __repr__(&self) -> String {
+        format!(
+            "Event(kind='{}', device_id={:?})",
+            self.kind, self.device_id
+        )
+    }
+}
+impl From<DeviceEvent> for Event {
+    fn from(event: DeviceEvent) -> Self {
+        let (kind, device_id, device) = match event {
+            DeviceEvent::Attached(info) => {
+                ("attached", Some(info.device_id.0), Some((&info).into()))
+            }
+            DeviceEvent::Detached(id) => ("detached", Some(id.0), None),
+            DeviceEvent::Paired(id) => ("paired", Some(id.0), None),
+            DeviceEvent::PairedWithRecord { device_id, .. } => ("paired", Some(device_id.0), None),
+            DeviceEvent::MuxerDisconnected => ("muxer_disconnected", None, None),
+            DeviceEvent::Reattached { info, .. } => {
+                ("reattached", Some(info.device_id.0), Some((&info).into()))
+            }
+            _ => ("unknown", None, None),
+        };
+        Event {
+            kind,
+            device_id,
+            device,
+        }
+    }
+}
+
+/// Blocking iterator over device events, already attached devices come first
+#[pyclass(module = "peertalk")]
+struct Listener(Mutex<DeviceListener>);
+#[pymethods]
+impl Listener {
+    #[new]
+    fn new() -> PyResult<Self> {
+        let config = peertalk::MuxerConfig::default();
+        DeviceListener::with_device_list(config)
+            .map(|listener| Listener(Mutex::new(listener)))
+            .map_err(to_py_err)
+    }
+    /// Waits up to `timeout` seconds for an event, indefinitely if None, returning None on timeout
+    #[pyo3(signature = (timeout=None))]
+    fn wait_event(&self, py: Python<'_>, timeout: Option<f64>) -> PyResult<Option<Event>> {
+        let mut remaining = timeout.map(Duration::from_secs_f64);
+        loop {
+            let wait = remaining.map_or(SIGNAL_CHECK_INTERVAL, |r| r.min(SIGNAL_CHECK_INTERVAL));
+            let event = py.allow_threads(|| self.0.lock().unwrap().wait_event(Some(wait)));
+            if let Some(event) = event {
+                return Ok(Some(event.into()));
+            }
+            py.check_signals()?;
+            if let Some(r) = remaining.as_mut() {
+                *r = r.saturating_sub(wait);
+                if r.is_zero() {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+    fn __next__(&self, py: Python<'_>) -> PyResult<Event> {
+        self.wait_event(py, None)?
+            .ok_or_else(|| PyStopIteration::new_err(()))
+    }
+}
+
+/// Lists the devices currently attached
+#[pyfunction]
+fn list_devices() -> PyResult<Vec<Device>> {
+    let devices = peertalk::list_devices().map_err(to_py_err)?;
+    Ok(devices.iter().map(Device::from).collect())
+}
+
+/// Connects to `port` on the device with the given muxer id, returning a `socket.socket`
+#[pyfunction]
+fn connect(py: Python<'_>, device_id: u64, port: u16) -> PyResult<Py<PyAny>> {
+    let socket = py
+        .allow_threads(|| peertalk::connect_to_device(peertalk::DeviceId(device_id), port))
+        .map_err(to_py_err)?;
+    into_py_socket(py, socket.into_socket())
+}
+
+/// Connects to `port` on the device with the given UDID, returning a `socket.socket`
+#[pyfunction]
+fn connect_by_udid(py: Python<'_>, udid: &str, port: u16) -> PyResult<Py<PyAny>> {
+    let socket = py
+        .allow_threads(|| peertalk::connect_by_udid(udid, port))
+        .map_err(to_py_err)?;
+    into_py_socket(py, socket.into_socket())
+}
+
+/// Hands the socket's ownership to a Python `socket.socket`
+fn into_py_socket(py: Python<'_>, socket: UsbSocket) -> PyResult<Py<PyAny>> {
+    let kwargs = PyDict::new(py);
+    kwargs.set_item("fileno", raw_socket(socket).map_err(to_py_err)?)?;
+    let socket = py
+        .import("socket")?
+        .getattr("socket")?
+        .call((), Some(&kwargs))?;
+    Ok(socket.unbind())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn raw_socket(socket: UsbSocket) -> peertalk::Result<i64> {
+    use std::os::unix::io::IntoRawFd;
+    match socket {
+        UsbSocket::Unix(socket) => Ok(socket.into_raw_fd().into()),
+        UsbSocket::Tcp(socket) => Ok(socket.into_raw_fd().into()),
+        UsbSocket::Custom(_) => Err(not_a_socket()),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn raw_socket(socket: UsbSocket) -> peertalk::Result<i64> {
+    use std::os::windows::io::IntoRawSocket;
+    match socket {
+        UsbSocket::Tcp(socket) => Ok(socket.into_raw_socket() as i64),
+        UsbSocket::Custom(_) => Err(not_a_socket()),
+    }
+}
+
+fn not_a_socket() -> peertalk::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "custom transport connections have no raw socket",
+    )
+    .into()
+}
+
+/// Talk to iPads & iPhones over USB through usbmuxd
+#[pymodule]
+#[pyo3(name = "peertalk")]
+fn peertalk_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("PeertalkError", m.py().get_type::<PeertalkError>())?;
+    m.add_class::<Device>()?;
+    m.add_class::<Event>()?;
+    m.add_class::<Listener>()?;
+    m.add_function(wrap_pyfunction!(list_devices, m)?)?;
+    m.add_function(wrap_pyfunction!(connect, m)?)?;
+    m.add_function(wrap_pyfunction!(connect_by_udid, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,163 @@
+//! Queries about Apple Mobile Device Service, the muxer on Windows
+use std::fmt;
+use std::ptr;
+use windows_sys::Win32::Foundation::{GetLastError, ERROR_SERVICE_DOES_NOT_EXIST, NO_ERROR};
+use windows_sys::Win32::NetworkManagement::IpHelper::{
+    GetExtendedTcpTable, MIB_TCPROW_OWNER_PID, MIB_TCPTABLE_OWNER_PID, TCP_TABLE_OWNER_PID_LISTENER,
+};
+use windows_sys::Win32::System::Services::{
+    CloseServiceHandle, OpenSCManagerW, OpenServiceW, QueryServiceStatusEx, SC_HANDLE,
+    SC_MANAGER_CONNECT, SC_STATUS_PROCESS_INFO, SERVICE_CONTINUE_PENDING, SERVICE_PAUSED,
+    SERVICE_PAUSE_PENDING, SERVICE_QUERY_STATUS, SERVICE_RUNNING, SERVICE_START_PENDING,
+    SERVICE_STATUS_PROCESS, SERVICE_STOPPED, SERVICE_STOP_PENDING,
+};
+
+/// Name Apple Mobile Device Service is registered under with the service manager
+const SERVICE_NAME: &str = "Apple Mobile Device Service";
+/// IPv4 address family, as GetExtendedTcpTable expects it
+const AF_INET: u32 = 2;
+
+/// State of Apple Mobile Device Service, reported when the muxer can't be reached on Windows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MobileDeviceServiceState {
+    /// Service isn't installed
+    NotInstalled,
+    /// Service is installed but stopped or paused
+    Stopped,
+    /// Service is starting up
+    Starting,
+    /// Service is running, yet didn't accept the connection
+    Running,
+    /// Service manager couldn't be queried
+    Unknown,
+}
+impl MobileDeviceServiceState {
+    /// Queries the service manager for the service's state
+    pub fn query() -> Self {
+        match service_status() {
+            Ok(status) => match status.dwCurrentState {
+                SERVICE_RUNNING => MobileDeviceServiceState::Running,
+                SERVICE_START_PENDING | SERVICE_CONTINUE_PENDING => {
+                    MobileDeviceServiceState::Starting
+                }
+                SERVICE_STOPPED | SERVICE_STOP_PENDING | SERVICE_PAUSED | SERVICE_PAUSE_PENDING => {
+                    MobileDeviceServiceState::Stopped
+                }
+                _ => MobileDeviceServiceState::Unknown,
+            },
+            Err(ERROR_SERVICE_DOES_NOT_EXIST) => MobileDeviceServiceState::NotInstalled,
+            Err(_) => MobileDeviceServiceState::Unknown,
+        }
+    }
+}
+impl fmt::Display for MobileDeviceServiceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hint = match self {
+            MobileDeviceServiceState::NotInstalled => {
+                "not installed, install iTunes or Apple Devices from the Microsoft Store"
+            }
+            MobileDeviceServiceState::Stopped => {
+                "stopped, start \"Apple Mobile Device Service\" in services.msc"
+            }
+            MobileDeviceServiceState::Starting => "still starting, try again shortly",
+            MobileDeviceServiceState::Running => {
+                "running but unreachable, it may be listening on another port or blocked by a firewall"
+            }
+            MobileDeviceServiceState::Unknown => "in an unknown state",
+        };
+        f.write_str(hint)
+    }
+}
+
+/// Service manager handle that's closed when dropped
+struct ServiceHandle(SC_HANDLE);
+impl Drop for ServiceHandle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseServiceHandle(self.0);
+        }
+    }
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+/// Queries the service's status, or the Win32 error code if it can't be queried
+fn service_status() -> Result<SERVICE_STATUS_PROCESS, u32> {
+    let name = wide(SERVICE_NAME);
+    unsafe {
+        let manager = OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_CONNECT);
+        if manager.is_null() {
+            return Err(GetLastError());
+        }
+        let manager = ServiceHandle(manager);
+        let service = OpenServiceW(manager.0, name.as_ptr(), SERVICE_QUERY_STATUS);
+        if service.is_null() {
+            return Err(GetLastError());
+        }
+        let service = ServiceHandle(service);
+        let mut status: SERVICE_STATUS_PROCESS = std::mem::zeroed();
+        let mut needed = 0;
+        let ok = QueryServiceStatusEx(
+            service.0,
+            SC_STATUS_PROCESS_INFO,
+            &mut status as *mut SERVICE_STATUS_PROCESS as *mut u8,
+            std::mem::size_of::<SERVICE_STATUS_PROCESS>() as u32,
+            &mut needed,
+        );
+        if ok == 0 {
+            return Err(GetLastError());
+        }
+        Ok(status)
+    }
+}
+
+/// Loopback TCP ports the running service's process is listening on
+pub(crate) fn listening_ports() -> Vec<u16> {
+    let pid = match service_status() {
+        Ok(status) if status.dwCurrentState == SERVICE_RUNNING => status.dwProcessId,
+        _ => return Vec::new(),
+    };
+    listening_rows()
+        .into_iter()
+        .filter(|row| row.dwOwningPid == pid)
+        // address & port are in network byte order, the port in the low 16 bits
+        .filter(|row| u32::from_be(row.dwLocalAddr) >> 24 == 127 || row.dwLocalAddr == 0)
+        .map(|row| u16::from_be(row.dwLocalPort as u16))
+        .collect()
+}
+
+/// All IPv4 TCP listeners on the system, with their owning process
+fn listening_rows() -> Vec<MIB_TCPROW_OWNER_PID> {
+    let mut size = 0u32;
+    let mut buffer: Vec<u32> = Vec::new();
+    // the table can grow between the size query & the read, so retry a few times
+    for _ in 0..3 {
+        let result = unsafe {
+            GetExtendedTcpTable(
+                if buffer.is_empty() {
+                    ptr::null_mut()
+                } else {
+                    buffer.as_mut_ptr() as *mut _
+                },
+                &mut size,
+                0,
+                AF_INET,
+                TCP_TABLE_OWNER_PID_LISTENER,
+                0,
+            )
+        };
+        if result == NO_ERROR && !buffer.is_empty() {
+            let table = buffer.as_ptr() as *const MIB_TCPTABLE_OWNER_PID;
+            unsafe {
+                let count = (*table).dwNumEntries as usize;
+                let rows = ptr::addr_of!((*table).table) as *const MIB_TCPROW_OWNER_PID;
+                return std::slice::from_raw_parts(rows, count).to_vec();
+            }
+        }
+        // u32 elements keep the table aligned
+        buffer = vec![0; (size as usize).div_ceil(4)];
+    }
+    Vec::new()
+}
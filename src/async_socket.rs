@@ -0,0 +1,258 @@
+//! Async version of [`UsbSocket`], for tokio consumers
+use crate::UsbSocket;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(not(target_os = "windows"))]
+use tokio::net::unix;
+#[cfg(not(target_os = "windows"))]
+use tokio::net::UnixStream;
+use tokio::net::{tcp, TcpStream};
+
+/// Connection to the muxer or a device port, registered with the tokio runtime
+///
+/// Created with [`UsbSocket::into_async`], usually to wrap in a `Framed` with
+/// [`PtFrameCodec`](crate::frame::PtFrameCodec).
+#[derive(Debug)]
+pub enum AsyncUsbSocket {
+    /// Unix domain socket connection
+    #[cfg(not(target_os = "windows"))]
+    Unix(UnixStream),
+    /// TCP connection
+    Tcp(TcpStream),
+}
+impl UsbSocket {
+    /// Moves the socket to the tokio runtime, for use with async IO
+    ///
+    /// Fails for connections from custom transports, which aren't OS sockets.
+    ///
+    /// # Panics
+    /// Panics when not called from within a tokio runtime with IO enabled
+    pub fn into_async(self) -> std::io::Result<AsyncUsbSocket> {
+        self.set_nonblocking(true)?;
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(socket) => UnixStream::from_std(socket).map(AsyncUsbSocket::Unix),
+            UsbSocket::Tcp(socket) => TcpStream::from_std(socket).map(AsyncUsbSocket::Tcp),
+            UsbSocket::Custom(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "custom transport connections can't be moved to tokio",
+            )),
+        }
+    }
+}
+impl AsyncUsbSocket {
+    /// Splits the socket into halves owned separately, to read & write from different tasks
+    pub fn into_split(self) -> (AsyncUsbReadHalf, AsyncUsbWriteHalf) {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbSocket::Unix(socket) => {
+                let (reader, writer) = socket.into_split();
+                (
+                    AsyncUsbReadHalf::Unix(reader),
+                    AsyncUsbWriteHalf::Unix(writer),
+                )
+            }
+            AsyncUsbSocket::Tcp(socket) => {
+                let (reader, writer) = socket.into_split();
+                (
+                    AsyncUsbReadHalf::Tcp(reader),
+                    AsyncUsbWriteHalf::Tcp(writer),
+                )
+            }
+        }
+    }
+}
+impl AsyncRead for AsyncUsbSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbSocket::Unix(socket) => Pin::new(socket).poll_read(cx, buf),
+            AsyncUsbSocket::Tcp(socket) => Pin::new(socket).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for AsyncUsbSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbSocket::Unix(socket) => Pin::new(socket).poll_write(cx, buf),
+            AsyncUsbSocket::Tcp(socket) => Pin::new(socket).poll_write(cx, buf),
+        }
+    }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbSocket::Unix(socket) => Pin::new(socket).poll_write_vectored(cx, bufs),
+            AsyncUsbSocket::Tcp(socket) => Pin::new(socket).poll_write_vectored(cx, bufs),
+        }
+    }
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbSocket::Unix(socket) => socket.is_write_vectored(),
+            AsyncUsbSocket::Tcp(socket) => socket.is_write_vectored(),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbSocket::Unix(socket) => Pin::new(socket).poll_flush(cx),
+            AsyncUsbSocket::Tcp(socket) => Pin::new(socket).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbSocket::Unix(socket) => Pin::new(socket).poll_shutdown(cx),
+            AsyncUsbSocket::Tcp(socket) => Pin::new(socket).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Reading half of an [`AsyncUsbSocket`], from [`AsyncUsbSocket::into_split`]
+#[derive(Debug)]
+pub enum AsyncUsbReadHalf {
+    /// Unix domain socket connection
+    #[cfg(not(target_os = "windows"))]
+    Unix(unix::OwnedReadHalf),
+    /// TCP connection
+    Tcp(tcp::OwnedReadHalf),
+}
+impl AsyncRead for AsyncUsbReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbReadHalf::Unix(half) => Pin::new(half).poll_read(cx, buf),
+            AsyncUsbReadHalf::Tcp(half) => Pin::new(half).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Writing half of an [`AsyncUsbSocket`], from [`AsyncUsbSocket::into_split`]
+///
+/// Dropping it shuts down the write half of the connection.
+#[derive(Debug)]
+pub enum AsyncUsbWriteHalf {
+    /// Unix domain socket connection
+    #[cfg(not(target_os = "windows"))]
+    Unix(unix::OwnedWriteHalf),
+    /// TCP connection
+    Tcp(tcp::OwnedWriteHalf),
+}
+impl AsyncWrite for AsyncUsbWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbWriteHalf::Unix(half) => Pin::new(half).poll_write(cx, buf),
+            AsyncUsbWriteHalf::Tcp(half) => Pin::new(half).poll_write(cx, buf),
+        }
+    }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbWriteHalf::Unix(half) => Pin::new(half).poll_write_vectored(cx, bufs),
+            AsyncUsbWriteHalf::Tcp(half) => Pin::new(half).poll_write_vectored(cx, bufs),
+        }
+    }
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbWriteHalf::Unix(half) => half.is_write_vectored(),
+            AsyncUsbWriteHalf::Tcp(half) => half.is_write_vectored(),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbWriteHalf::Unix(half) => Pin::new(half).poll_flush(cx),
+            AsyncUsbWriteHalf::Tcp(half) => Pin::new(half).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(not(target_os = "windows"))]
+            AsyncUsbWriteHalf::Unix(half) => Pin::new(half).poll_shutdown(cx),
+            AsyncUsbWriteHalf::Tcp(half) => Pin::new(half).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::frame::{Frame, PtFrameCodec};
+    use std::os::unix::net::UnixStream as StdUnixStream;
+    use tokio::io::AsyncReadExt;
+    use tokio_util::codec::Decoder;
+    #[test]
+    fn it_reads_frames_asynchronously() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let (mut device, host) = StdUnixStream::pair().unwrap();
+        Frame::text("Hello").write_into(&mut device).unwrap();
+        let frame = runtime.block_on(async move {
+            let mut socket = UsbSocket::from(host).into_async().unwrap();
+            let mut buffer = bytes::BytesMut::new();
+            let mut codec = PtFrameCodec::default();
+            loop {
+                if let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                    return frame;
+                }
+                socket.read_buf(&mut buffer).await.unwrap();
+            }
+        });
+        assert_eq!(frame, Frame::text("Hello"));
+    }
+    #[test]
+    fn it_splits_into_owned_halves() {
+        use std::io::{Read, Write};
+        use tokio::io::AsyncWriteExt;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let (mut device, host) = StdUnixStream::pair().unwrap();
+        device.write_all(b"ping").unwrap();
+        let received = runtime.block_on(async move {
+            let socket = UsbSocket::from(host).into_async().unwrap();
+            let (mut reader, mut writer) = socket.into_split();
+            let mut buf = [0; 4];
+            reader.read_exact(&mut buf).await.unwrap();
+            writer.write_all(b"pong").await.unwrap();
+            writer.shutdown().await.unwrap();
+            buf
+        });
+        assert_eq!(&received, b"ping");
+        let mut reply = Vec::new();
+        device.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"pong");
+    }
+}
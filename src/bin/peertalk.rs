@@ -0,0 +1,184 @@
+//! Command line tool for watching devices, connecting to ports on them & forwarding ports
+use clap::{Parser, Subcommand};
+use peertalk::{
+    connect_to_device, forward_port, list_devices, ndjson, Device, DeviceAttachedInfo, DeviceEvent,
+    DeviceListener, ForwardTarget, UsbStream,
+};
+use std::io::{Read, Write};
+use std::net::Shutdown;
+
+#[derive(Parser)]
+#[command(version, about = "Talk to iOS devices over USB through usbmuxd")]
+struct Args {
+    /// Prints device events & errors as newline delimited JSON
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Prints devices as they're attached & detached, until interrupted
+    Listen,
+    /// Lists attached devices
+    Devices,
+    /// Connects to a port on a device, piping it to stdin & stdout
+    Connect {
+        /// Device's UDID or muxer id
+        device: String,
+        /// Port on the device
+        port: u16,
+    },
+    /// Forwards a local port to a port on a device, until interrupted
+    Forward {
+        /// Device's UDID or muxer id
+        device: String,
+        /// Port on the device
+        port: u16,
+        /// Local address to listen on, defaults to the device port on localhost
+        #[arg(short, long)]
+        listen: Option<String>,
+        /// Listens on a Unix socket at this path instead
+        #[cfg(not(target_os = "windows"))]
+        #[arg(short, long, conflicts_with = "listen")]
+        unix: Option<std::path::PathBuf>,
+    },
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let args = Args::parse();
+    let json = args.json;
+    let result = match args.command {
+        Command::Listen => listen(json),
+        Command::Devices => devices(json),
+        Command::Connect { device, port } => connect(&device, port),
+        Command::Forward {
+            device,
+            port,
+            listen,
+            #[cfg(not(target_os = "windows"))]
+            unix,
+        } => {
+            #[cfg(not(target_os = "windows"))]
+            if let Some(path) = unix {
+                return exit_on_error(forward_unix(path, &device, port), json);
+            }
+            forward(listen, &device, port)
+        }
+    };
+    exit_on_error(result, json)
+}
+
+fn exit_on_error(result: peertalk::Result<()>, json: bool) {
+    if let Err(e) = result {
+        if json {
+            let _ = ndjson::write_line(std::io::stdout().lock(), &ndjson::error(&e));
+        } else {
+            eprintln!("peertalk: {}", e);
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Parses muxer ids as such, anything else is taken as a UDID
+fn target(device: &str) -> ForwardTarget {
+    match device.parse() {
+        Ok(id) => ForwardTarget::Id(id),
+        Err(_) => ForwardTarget::from(device),
+    }
+}
+
+fn describe(info: &DeviceAttachedInfo) -> String {
+    format!(
+        "{}\t{}\t{:?}\t{:?}",
+        info.device_id, info.identifier, info.product_type, info.connection_type
+    )
+}
+
+fn listen(json: bool) -> peertalk::Result<()> {
+    let listener = DeviceListener::new()?;
+    while let Some(event) = listener.wait_event(None) {
+        if json {
+            ndjson::write_line(std::io::stdout().lock(), &ndjson::event(&event))?;
+            continue;
+        }
+        match event {
+            DeviceEvent::Attached(info) => println!("attached\t{}", describe(&info)),
+            DeviceEvent::Detached(id) => println!("detached\t{}", id),
+            DeviceEvent::Paired(id) => println!("paired\t{}", id),
+            DeviceEvent::Unknown { message_type, .. } => println!("unknown\t{}", message_type),
+            DeviceEvent::PairedWithRecord {
+                device_id, host_id, ..
+            } => println!("paired\t{}\t{}", device_id, host_id),
+            DeviceEvent::MuxerDisconnected => println!("muxer_disconnected"),
+            DeviceEvent::Reattached { previous, info } => {
+                println!("reattached\t{}\t{}", previous, describe(&info))
+            }
+            event => println!("{:?}", event),
+        }
+    }
+    Ok(())
+}
+
+fn devices(json: bool) -> peertalk::Result<()> {
+    for info in list_devices()? {
+        if json {
+            ndjson::write_line(std::io::stdout().lock(), &ndjson::device(&info))?;
+        } else {
+            println!("{}", describe(&info));
+        }
+    }
+    Ok(())
+}
+
+fn connect(device: &str, port: u16) -> peertalk::Result<()> {
+    let socket = match target(device) {
+        ForwardTarget::Id(id) => connect_to_device(id, port)?,
+        ForwardTarget::Udid(udid) => Device::find(udid.as_str())?.connect(port)?,
+    };
+    let writer = socket.try_clone()?;
+    std::thread::spawn(move || pipe_stdin(writer));
+    let mut reader = socket;
+    let mut stdout = std::io::stdout();
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        let len = reader.read(&mut buffer)?;
+        if len == 0 {
+            return Ok(());
+        }
+        stdout.write_all(&buffer[..len])?;
+        stdout.flush()?;
+    }
+}
+
+/// Copies stdin to the device, closing the connection's write half at EOF
+fn pipe_stdin(mut socket: UsbStream) {
+    if let Err(e) = std::io::copy(&mut std::io::stdin().lock(), &mut socket) {
+        eprintln!("peertalk: {}", e);
+    }
+    let _ = socket.shutdown(Shutdown::Write);
+}
+
+fn forward(listen: Option<String>, device: &str, port: u16) -> peertalk::Result<()> {
+    let listen = listen.unwrap_or_else(|| format!("127.0.0.1:{}", port));
+    let forwarder = forward_port(listen.as_str(), target(device), port)?;
+    if let Some(addr) = forwarder.local_addr() {
+        eprintln!("Forwarding {} to device port {}", addr, port);
+    }
+    wait_forever()
+}
+
+#[cfg(not(target_os = "windows"))]
+fn forward_unix(path: std::path::PathBuf, device: &str, port: u16) -> peertalk::Result<()> {
+    let _forwarder = peertalk::forward_unix_socket(&path, target(device), port)?;
+    eprintln!("Forwarding {} to device port {}", path.display(), port);
+    wait_forever()
+}
+
+fn wait_forever() -> ! {
+    loop {
+        std::thread::park();
+    }
+}
@@ -0,0 +1,226 @@
+//! UniFFI bindings, so Swift & Kotlin hosts can listen for & connect to devices
+//!
+//! Generate the Swift or Kotlin sources from the built library with `uniffi-bindgen generate
+//! --library`. The API is a thin, thread safe layer over [`DeviceListener`] & [`UsbSocket`].
+use crate::{DeviceAttachedInfo, DeviceConnectionType, DeviceEvent, DeviceListener, UsbSocket};
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Error passed to the foreign language, carrying the message of the underlying error
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum PeertalkError {
+    /// Muxer, connection or device error
+    #[error("{0}")]
+    Failed(String),
+}
+impl From<crate::Error> for PeertalkError {
+    fn from(e: crate::Error) -> Self {
+        PeertalkError::Failed(e.to_string())
+    }
+}
+impl From<std::io::Error> for PeertalkError {
+    fn from(e: std::io::Error) -> Self {
+        PeertalkError::Failed(e.to_string())
+    }
+}
+
+/// Attached device, as reported by the muxer
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct DeviceInfo {
+    /// Muxer's id for the device, only valid while it stays attached
+    pub device_id: u64,
+    /// Device's UDID (serial number)
+    pub udid: String,
+    /// USB product id, 0 for network devices
+    pub product_id: u16,
+    /// Whether the device is attached over the network rather than USB
+    pub is_network: bool,
+    /// IP address & port of a network device
+    pub network_address: Option<String>,
+}
+impl From<&DeviceAttachedInfo> for DeviceInfo {
+    fn from(info: &DeviceAttachedInfo) -> Self {
+        DeviceInfo {
+            device_id: info.device_id.0,
+            udid: info.identifier.to_string(),
+            product_id: info.product_id,
+            is_network: info.connection_type == DeviceConnectionType::Network,
+            network_address: info.network_address.map(|a| a.to_string()),
+        }
+    }
+}
+
+/// Device event received by a [`Listener`]
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum ListenerEvent {
+    /// Device was plugged in, or found on the network
+    Attached {
+        /// The device
+        device: DeviceInfo,
+    },
+    /// Device was unplugged
+    Detached {
+        /// Muxer's id for the device
+        device_id: u64,
+    },
+    /// Device was paired with the host
+    Paired {
+        /// Muxer's id for the device
+        device_id: u64,
+    },
+    /// Listener lost its connection to the muxer, it keeps trying to reconnect
+    MuxerDisconnected,
+    /// Device detached & attached again within the debounce window, under a new id
+    Reattached {
+        /// Muxer's previous id for the device
+        previous_device_id: u64,
+        /// The device as attached now
+        device: DeviceInfo,
+    },
+    /// Muxer message this version doesn't know
+    Other,
+}
+impl From<DeviceEvent> for ListenerEvent {
+    fn from(event: DeviceEvent) -> Self {
+        match event {
+            DeviceEvent::Attached(info) => ListenerEvent::Attached {
+                device: DeviceInfo::from(&info),
+            },
+            DeviceEvent::Detached(device_id) => ListenerEvent::Detached {
+                device_id: device_id.0,
+            },
+            DeviceEvent::Paired(device_id) => ListenerEvent::Paired {
+                device_id: device_id.0,
+            },
+            DeviceEvent::PairedWithRecord { device_id, .. } => ListenerEvent::Paired {
+                device_id: device_id.0,
+            },
+            DeviceEvent::MuxerDisconnected => ListenerEvent::MuxerDisconnected,
+            DeviceEvent::Reattached { previous, info } => ListenerEvent::Reattached {
+                previous_device_id: previous.0,
+                device: DeviceInfo::from(&info),
+            },
+            _ => ListenerEvent::Other,
+        }
+    }
+}
+
+/// Listens for devices being attached & detached
+#[derive(uniffi::Object)]
+pub struct Listener(Mutex<DeviceListener>);
+#[uniffi::export]
+impl Listener {
+    /// Starts listening through the default muxer
+    #[uniffi::constructor]
+    pub fn new() -> Result<Arc<Self>, PeertalkError> {
+        Ok(Arc::new(Listener(Mutex::new(DeviceListener::new()?))))
+    }
+    /// Waits up to `timeout_ms` for an event, indefinitely if None
+    pub fn wait_event(&self, timeout_ms: Option<u64>) -> Option<ListenerEvent> {
+        let timeout = timeout_ms.map(Duration::from_millis);
+        let listener = self.0.lock().unwrap();
+        listener.wait_event(timeout).map(ListenerEvent::from)
+    }
+    /// Devices currently attached
+    pub fn devices(&self) -> Vec<DeviceInfo> {
+        self.0
+            .lock()
+            .unwrap()
+            .devices()
+            .iter()
+            .map(DeviceInfo::from)
+            .collect()
+    }
+}
+
+/// Connection to a port on a device
+#[derive(uniffi::Object)]
+pub struct Connection {
+    reader: Mutex<UsbSocket>,
+    writer: Mutex<UsbSocket>,
+}
+impl Connection {
+    fn new(socket: UsbSocket) -> Result<Arc<Self>, PeertalkError> {
+        Ok(Arc::new(Connection {
+            writer: Mutex::new(socket.try_clone()?),
+            reader: Mutex::new(socket),
+        }))
+    }
+}
+#[uniffi::export]
+impl Connection {
+    /// Reads up to `max_len` bytes, an empty result means the device closed the connection
+    pub fn read(&self, max_len: u32) -> Result<Vec<u8>, PeertalkError> {
+        let mut buffer = vec![0; max_len as usize];
+        let len = self.reader.lock().unwrap().read(&mut buffer)?;
+        buffer.truncate(len);
+        Ok(buffer)
+    }
+    /// Writes all of `data`
+    pub fn write(&self, data: Vec<u8>) -> Result<(), PeertalkError> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&data)?;
+        writer.flush()?;
+        Ok(())
+    }
+    /// Closes the connection, unblocking pending reads
+    pub fn close(&self) -> Result<(), PeertalkError> {
+        self.writer.lock().unwrap().shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+}
+
+/// Lists the devices currently attached
+#[uniffi::export]
+pub fn list_devices() -> Result<Vec<DeviceInfo>, PeertalkError> {
+    Ok(crate::list_devices()?
+        .iter()
+        .map(DeviceInfo::from)
+        .collect())
+}
+
+/// Connects to `port` on the device with the given muxer id
+#[uniffi::export]
+pub fn connect(device_id: u64, port: u16) -> Result<Arc<Connection>, PeertalkError> {
+    Connection::new(crate::connect_to_device(crate::DeviceId(device_id), port)?.into_socket())
+}
+
+/// Connects to `port` on the device with the given UDID
+#[uniffi::export]
+pub fn connect_by_udid(udid: String, port: u16) -> Result<Arc<Connection>, PeertalkError> {
+    Connection::new(crate::connect_by_udid(&udid, port)?.into_socket())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceId, ProductType, Udid};
+    #[test]
+    fn it_converts_attached_events() {
+        let info = DeviceAttachedInfo {
+            connection_type: DeviceConnectionType::Network,
+            device_id: DeviceId(7),
+            location_id: 0,
+            product_type: ProductType::Unknown(0),
+            product_id: 0,
+            identifier: Udid::parse("00008030-001A35E22E88802E").unwrap(),
+            network_address: Some("192.168.1.20:62078".parse().unwrap()),
+            interface_index: Some(4),
+            connection_speed: None,
+            usb_serial_number: None,
+            escrow_bag: None,
+            properties: Default::default(),
+        };
+        match ListenerEvent::from(DeviceEvent::Attached(info)) {
+            ListenerEvent::Attached { device } => {
+                assert!(device.is_network);
+                assert_eq!(device.udid, "00008030-001A35E22E88802E");
+                assert_eq!(device.network_address.unwrap(), "192.168.1.20:62078");
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,515 @@
+//! Recording muxer traffic to a file & replaying it, for debugging muxer quirks without Wireshark
+//!
+//! A [`Capture`] set with [`MuxerConfig::capture`](crate::MuxerConfig::capture) records every
+//! read & write on the config's muxer connections. [`read_capture`] reads the records back, &
+//! [`ReplayTransport`] serves them to a listener or client in place of the muxer:
+//!
+//! ```no_run
+//! use peertalk::capture::{Capture, ReplayTransport};
+//! use peertalk::{DeviceListener, MuxerConfig};
+//!
+//! // on the machine with the problem
+//! let config = MuxerConfig::default().capture(Capture::create("muxer.ptcap")?);
+//! let listener = DeviceListener::with_config(config)?;
+//! // later, anywhere
+//! let config = MuxerConfig::default().transport(ReplayTransport::open("muxer.ptcap")?);
+//! let listener = DeviceListener::with_config(config)?;
+//! # Ok::<(), peertalk::Error>(())
+//! ```
+//!
+//! The file starts with `PTCAP001`, followed by records of the connection number (u32), direction
+//! (u8, 0 sent & 1 received), microseconds since the capture started (u64), data length (u32) &
+//! data, all little endian.
+//!
+//! Captured connections are wrapped as [`UsbSocket::Custom`], so they can't be moved to tokio or
+//! handed out as raw sockets.
+//!
+//! [`write_pcapng`] converts records to pcapng for Wireshark, as TCP between 127.0.0.1 & port
+//! 27015, the muxer's port on Windows. Use "Decode As" if Wireshark doesn't pick the usbmuxd
+//! dissector for that port on its own.
+use crate::{MuxerTransport, Result, TransportStream, UsbSocket};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::Shutdown;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// First bytes of a capture file, including the format version
+const MAGIC: &[u8; 8] = b"PTCAP001";
+
+/// Which way captured bytes went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Written to the muxer
+    Sent,
+    /// Read from the muxer
+    Received,
+}
+
+/// Bytes read or written in one call on a captured connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// Number of the connection, in the order they were opened from 0
+    pub connection: u32,
+    /// Which way the bytes went
+    pub direction: Direction,
+    /// Time since the capture started
+    pub timestamp: Duration,
+    /// The bytes
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct CaptureFile {
+    writer: BufWriter<File>,
+    started: Instant,
+    next_connection: u32,
+}
+
+/// Capture file being recorded to, shared by every connection of a config
+#[derive(Debug, Clone)]
+pub struct Capture(Arc<Mutex<CaptureFile>>);
+impl Capture {
+    /// Creates the capture file, replacing any existing file
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.flush()?;
+        Ok(Capture(Arc::new(Mutex::new(CaptureFile {
+            writer,
+            started: Instant::now(),
+            next_connection: 0,
+        }))))
+    }
+    /// Wraps a new muxer connection, so its traffic is recorded
+    pub(crate) fn wrap(&self, socket: UsbSocket) -> UsbSocket {
+        let connection = {
+            let mut file = self.0.lock().unwrap();
+            file.next_connection += 1;
+            file.next_connection - 1
+        };
+        UsbSocket::custom(CapturedStream {
+            socket,
+            capture: self.clone(),
+            connection,
+        })
+    }
+    /// Appends a record, flushing so the capture survives a crash
+    fn record(&self, connection: u32, direction: Direction, data: &[u8]) {
+        let mut file = self.0.lock().unwrap();
+        let timestamp = file.started.elapsed().as_micros() as u64;
+        let result = write_record(&mut file.writer, connection, direction, timestamp, data)
+            .and_then(|_| file.writer.flush());
+        if let Err(e) = result {
+            warn!("Failed to write capture record: {}", e);
+        }
+    }
+}
+
+fn write_record<W: Write>(
+    writer: &mut W,
+    connection: u32,
+    direction: Direction,
+    timestamp: u64,
+    data: &[u8],
+) -> io::Result<()> {
+    writer.write_u32::<LittleEndian>(connection)?;
+    writer.write_u8(match direction {
+        Direction::Sent => 0,
+        Direction::Received => 1,
+    })?;
+    writer.write_u64::<LittleEndian>(timestamp)?;
+    writer.write_u32::<LittleEndian>(data.len() as u32)?;
+    writer.write_all(data)
+}
+
+/// Muxer connection recording what goes through it
+#[derive(Debug)]
+struct CapturedStream {
+    socket: UsbSocket,
+    capture: Capture,
+    connection: u32,
+}
+impl Read for CapturedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.socket.read(buf)?;
+        if len > 0 {
+            self.capture
+                .record(self.connection, Direction::Received, &buf[..len]);
+        }
+        Ok(len)
+    }
+}
+impl Write for CapturedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.socket.write(buf)?;
+        self.capture
+            .record(self.connection, Direction::Sent, &buf[..len]);
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+impl TransportStream for CapturedStream {
+    fn try_clone(&self) -> io::Result<Box<dyn TransportStream>> {
+        Ok(Box::new(CapturedStream {
+            socket: self.socket.try_clone()?,
+            capture: self.capture.clone(),
+            connection: self.connection,
+        }))
+    }
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket.shutdown(how)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(timeout)
+    }
+}
+
+/// Reads every record of a capture file
+pub fn read_capture<P: AsRef<Path>>(path: P) -> Result<Vec<CaptureRecord>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a peertalk capture").into());
+    }
+    let mut records = Vec::new();
+    loop {
+        let connection = match reader.read_u32::<LittleEndian>() {
+            Ok(connection) => connection,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+            Err(e) => return Err(e.into()),
+        };
+        let direction = match reader.read_u8()? {
+            0 => Direction::Sent,
+            _ => Direction::Received,
+        };
+        let timestamp = Duration::from_micros(reader.read_u64::<LittleEndian>()?);
+        let mut data = vec![0; reader.read_u32::<LittleEndian>()? as usize];
+        reader.read_exact(&mut data)?;
+        records.push(CaptureRecord {
+            connection,
+            direction,
+            timestamp,
+            data,
+        });
+    }
+}
+
+/// pcapng block types
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+/// Raw IPv4 packets, without a link layer header
+const LINKTYPE_IPV4: u16 = 228;
+/// Port the muxer is shown listening on, Apple Mobile Device Service's
+const PCAPNG_MUXER_PORT: u16 = 27015;
+/// Client ports start here, one per connection
+const PCAPNG_CLIENT_PORT: u16 = 49152;
+/// Largest chunk of data put in one synthesized TCP segment
+const PCAPNG_MAX_SEGMENT: usize = 65_000;
+
+/// Writes records as a pcapng file, with each connection as a TCP stream to the muxer's port
+///
+/// Timestamps count from the Unix epoch rather than when the capture was taken, which Wireshark's
+/// default relative time display hides. Each packet carries a comment naming its connection.
+pub fn write_pcapng<W: Write>(records: &[CaptureRecord], mut writer: W) -> Result<()> {
+    let mut options = Vec::new();
+    pcapng_option(&mut options, 4, b"peertalk"); // shb_userappl
+    let mut body = Vec::new();
+    body.write_u32::<LittleEndian>(0x1A2B_3C4D)?;
+    body.write_u16::<LittleEndian>(1)?;
+    body.write_u16::<LittleEndian>(0)?;
+    body.write_i64::<LittleEndian>(-1)?; // section length isn't known up front
+    body.extend_from_slice(&options);
+    pcapng_block(&mut writer, PCAPNG_SECTION_HEADER, &body)?;
+
+    let mut options = Vec::new();
+    pcapng_option(&mut options, 2, b"usbmuxd"); // if_name
+    pcapng_option(&mut options, 3, b"muxer traffic captured by peertalk"); // if_description
+    let mut body = Vec::new();
+    body.write_u16::<LittleEndian>(LINKTYPE_IPV4)?;
+    body.write_u16::<LittleEndian>(0)?;
+    body.write_u32::<LittleEndian>(0)?; // no snap length
+    body.extend_from_slice(&options);
+    pcapng_block(&mut writer, PCAPNG_INTERFACE_DESCRIPTION, &body)?;
+
+    // next sequence number each way, per connection
+    let mut sequences: Vec<(u32, u32, u32)> = Vec::new();
+    for record in records {
+        let index = match sequences.iter().position(|(c, ..)| *c == record.connection) {
+            Some(index) => index,
+            None => {
+                sequences.push((record.connection, 1, 1));
+                sequences.len() - 1
+            }
+        };
+        let client_port = PCAPNG_CLIENT_PORT.wrapping_add(record.connection as u16);
+        for chunk in record.data.chunks(PCAPNG_MAX_SEGMENT) {
+            let (_, client_seq, muxer_seq) = &mut sequences[index];
+            let segment = match record.direction {
+                Direction::Sent => {
+                    let ports = (client_port, PCAPNG_MUXER_PORT);
+                    let segment = ipv4_tcp_segment(ports, *client_seq, *muxer_seq, chunk);
+                    *client_seq = client_seq.wrapping_add(chunk.len() as u32);
+                    segment
+                }
+                Direction::Received => {
+                    let ports = (PCAPNG_MUXER_PORT, client_port);
+                    let segment = ipv4_tcp_segment(ports, *muxer_seq, *client_seq, chunk);
+                    *muxer_seq = muxer_seq.wrapping_add(chunk.len() as u32);
+                    segment
+                }
+            };
+            let timestamp = record.timestamp.as_micros() as u64;
+            let mut body = Vec::new();
+            body.write_u32::<LittleEndian>(0)?; // interface
+            body.write_u32::<LittleEndian>((timestamp >> 32) as u32)?;
+            body.write_u32::<LittleEndian>(timestamp as u32)?;
+            body.write_u32::<LittleEndian>(segment.len() as u32)?;
+            body.write_u32::<LittleEndian>(segment.len() as u32)?;
+            body.extend_from_slice(&segment);
+            pad(&mut body);
+            let comment = format!("connection {}", record.connection);
+            pcapng_option(&mut body, 1, comment.as_bytes()); // opt_comment
+            body.write_u32::<LittleEndian>(0)?; // opt_endofopt
+            pcapng_block(&mut writer, PCAPNG_ENHANCED_PACKET, &body)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn pcapng_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let length = body.len() as u32 + 12;
+    writer.write_u32::<LittleEndian>(block_type)?;
+    writer.write_u32::<LittleEndian>(length)?;
+    writer.write_all(body)?;
+    writer.write_u32::<LittleEndian>(length)
+}
+
+fn pcapng_option(options: &mut Vec<u8>, code: u16, value: &[u8]) {
+    options.extend_from_slice(&code.to_le_bytes());
+    options.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    options.extend_from_slice(value);
+    pad(options);
+}
+
+/// Pads to 32 bits, as pcapng blocks & options are
+fn pad(buffer: &mut Vec<u8>) {
+    let padded = (buffer.len() + 3) & !3;
+    buffer.resize(padded, 0);
+}
+
+/// Builds an IPv4 packet on loopback carrying a TCP segment, leaving the TCP checksum empty
+fn ipv4_tcp_segment(ports: (u16, u16), seq: u32, ack: u32, data: &[u8]) -> Vec<u8> {
+    let total_length = (40 + data.len()) as u16;
+    let mut packet = Vec::with_capacity(total_length as usize);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_length.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]); // no fragments, TTL & TCP
+    packet.extend_from_slice(&[127, 0, 0, 1, 127, 0, 0, 1]);
+    let checksum = !packet
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .fold(0u32, |sum, word| {
+            let sum = sum + word;
+            (sum & 0xFFFF) + (sum >> 16)
+        }) as u16;
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&ports.0.to_be_bytes());
+    packet.extend_from_slice(&ports.1.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&ack.to_be_bytes());
+    packet.extend_from_slice(&[0x50, 0x18, 0xFF, 0xFF, 0, 0, 0, 0]); // PSH & ACK
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// Transport replaying captured connections in order, in place of the muxer
+///
+/// Each connection opened gets the bytes the next captured connection received, & whatever is
+/// written to it is dropped. Once all captured connections were opened, connecting fails as if
+/// the muxer were down.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    connections: Mutex<VecDeque<Vec<u8>>>,
+}
+impl ReplayTransport {
+    /// Replays the connections of a capture file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_records(read_capture(path)?))
+    }
+    /// Replays the connections of the given records
+    pub fn from_records(records: Vec<CaptureRecord>) -> Self {
+        let mut connections: Vec<(u32, Vec<u8>)> = Vec::new();
+        for record in records {
+            let index = match connections
+                .iter()
+                .position(|(c, _)| *c == record.connection)
+            {
+                Some(index) => index,
+                None => {
+                    connections.push((record.connection, Vec::new()));
+                    connections.len() - 1
+                }
+            };
+            if record.direction == Direction::Received {
+                connections[index].1.extend_from_slice(&record.data);
+            }
+        }
+        connections.sort_by_key(|(connection, _)| *connection);
+        ReplayTransport {
+            connections: Mutex::new(connections.into_iter().map(|(_, data)| data).collect()),
+        }
+    }
+}
+impl MuxerTransport for ReplayTransport {
+    fn connect(&self, _timeout: Duration) -> Result<UsbSocket> {
+        match self.connections.lock().unwrap().pop_front() {
+            Some(data) => Ok(UsbSocket::custom(ReplayStream(io::Cursor::new(data)))),
+            None => Err(crate::Error::ServiceUnavailable(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no captured connections left to replay",
+            ))),
+        }
+    }
+}
+
+/// Replayed connection, reading captured bytes & dropping writes
+#[derive(Debug)]
+struct ReplayStream(io::Cursor<Vec<u8>>);
+impl Read for ReplayStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl Write for ReplayStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl TransportStream for ReplayStream {
+    // captured bytes are all there already, so reads never wait
+    fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        Ok(())
+    }
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::protocol::{Packet, PacketType, Protocol};
+    use crate::{DeviceEvent, DeviceId, DeviceListener, MuxerConfig};
+    use std::os::unix::net::UnixStream;
+
+    fn packet_bytes(tag: u32, plist: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        Packet::new(Protocol::Plist, PacketType::PlistPayload, tag, plist.into())
+            .unwrap()
+            .write_into(&mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn it_exports_pcapng() {
+        let records = vec![
+            CaptureRecord {
+                connection: 0,
+                direction: Direction::Sent,
+                timestamp: Duration::from_millis(5),
+                data: b"request".to_vec(),
+            },
+            CaptureRecord {
+                connection: 0,
+                direction: Direction::Received,
+                timestamp: Duration::from_millis(6),
+                data: b"reply".to_vec(),
+            },
+        ];
+        let mut output = Vec::new();
+        write_pcapng(&records, &mut output).unwrap();
+        let mut blocks = Vec::new();
+        let mut rest = &output[..];
+        while !rest.is_empty() {
+            let block_type = (&rest[..4]).read_u32::<LittleEndian>().unwrap();
+            let length = (&rest[4..8]).read_u32::<LittleEndian>().unwrap() as usize;
+            assert!(length.is_multiple_of(4));
+            blocks.push((block_type, rest[8..length - 4].to_vec()));
+            rest = &rest[length..];
+        }
+        let types: Vec<u32> = blocks.iter().map(|(t, _)| *t).collect();
+        assert_eq!(types, [PCAPNG_SECTION_HEADER, 1, 6, 6]);
+        let reply = &blocks[3].1;
+        // interface, timestamp & lengths, then the IPv4 & TCP headers
+        let tcp = &reply[20 + 20..];
+        assert_eq!(&tcp[..2], &PCAPNG_MUXER_PORT.to_be_bytes());
+        assert_eq!(&tcp[4..8], &1u32.to_be_bytes());
+        assert_eq!(&tcp[8..12], &8u32.to_be_bytes()); // acks the 7 request bytes
+        assert_eq!(&tcp[20..25], b"reply");
+    }
+    #[test]
+    fn it_records_and_replays_traffic() {
+        let path = std::env::temp_dir().join(format!("peertalk-{}.ptcap", std::process::id()));
+        let capture = Capture::create(&path).unwrap();
+        let (ours, mut muxer) = UnixStream::pair().unwrap();
+        let mut socket = capture.wrap(ours.into());
+        socket.write_all(b"listen").unwrap();
+        let mut request = [0; 6];
+        muxer.read_exact(&mut request).unwrap();
+        muxer
+            .write_all(&packet_bytes(
+                1,
+                "<plist><dict><key>MessageType</key><string>Result</string>\
+                 <key>Number</key><integer>0</integer></dict></plist>",
+            ))
+            .unwrap();
+        muxer
+            .write_all(&packet_bytes(
+                0,
+                "<plist><dict><key>MessageType</key><string>Attached</string>\
+                 <key>DeviceID</key><integer>4</integer><key>Properties</key><dict>\
+                 <key>ConnectionType</key><string>USB</string>\
+                 <key>DeviceID</key><integer>4</integer>\
+                 <key>LocationID</key><integer>0</integer>\
+                 <key>ProductID</key><integer>4776</integer>\
+                 <key>SerialNumber</key><string>00008030-001A35E22E88802E</string></dict></dict></plist>",
+            ))
+            .unwrap();
+        drop(muxer);
+        std::io::copy(&mut socket, &mut std::io::sink()).unwrap();
+
+        let records = read_capture(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert_eq!(records[0].data, b"listen");
+        assert!(records[1..]
+            .iter()
+            .all(|r| r.connection == 0 && r.direction == Direction::Received));
+
+        let config = MuxerConfig::default().transport(ReplayTransport::from_records(records));
+        let listener = DeviceListener::with_config(config).unwrap();
+        let event = listener.wait_event(Some(Duration::from_secs(1)));
+        assert!(
+            matches!(event, Some(DeviceEvent::Attached(info)) if info.device_id == DeviceId(4))
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! Control connection to the muxer for one-off requests
+use crate::pair_record::pair_record_value;
+use crate::protocol::{self, Command, Packet, PacketType, Protocol, ResultMessage};
+use crate::{DeviceAttachedInfo, DeviceConnectionType, Driver, ListenerInfo, MuxerConfig};
+use crate::{PairRecord, ProtocolError, ReplyCode, Result};
+use plist::Value;
+use std::convert::TryFrom;
+
+/// What a muxer supports, read with [`MuxerClient::capabilities`]
+///
+/// Muxers don't advertise much about themselves, so this is pieced together from a `ListDevices`
+/// reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MuxerCapabilities {
+    /// Protocol version the muxer reported in its reply (`kLibUSBMuxVersion`), which usbmuxd &
+    /// older AMDS builds leave out
+    pub version: Option<u64>,
+    /// Whether the muxer takes plist messages, very old muxers only speak the binary protocol
+    pub plist: bool,
+    /// Some(true) if a network device is attached, which proves the muxer supports Wi-Fi devices
+    ///
+    /// Muxers don't say whether they do, so None means unknown rather than unsupported.
+    pub supports_network_devices: Option<bool>,
+}
+
+/// Connection to the muxer for sending requests & reading their replies
+///
+/// Requests are tagged & matched to their replies, and events the muxer sends in between are
+/// dropped. [`MuxerClient::request`] sends any plist message, for muxer requests this crate
+/// doesn't have a helper for.
+#[derive(Debug)]
+pub struct MuxerClient {
+    driver: Driver,
+    config: MuxerConfig,
+}
+impl MuxerClient {
+    /// Connects to the default muxer
+    pub fn connect() -> Result<Self> {
+        Self::with_config(MuxerConfig::default())
+    }
+    /// Connects to the muxer from `config`, identifying with its client name
+    pub fn with_config(config: MuxerConfig) -> Result<Self> {
+        let driver = Driver::new(config.connect()?);
+        Ok(MuxerClient { driver, config })
+    }
+    /// Sends a plist message, returning the muxer's reply
+    ///
+    /// The client's identity (`ProgName` etc) is added to dictionaries that don't set it.
+    pub fn request(&mut self, message: Value) -> Result<Value> {
+        let mut message = message;
+        if let Some(dict) = message.as_dictionary_mut() {
+            self.config.identify(dict);
+        }
+        let payload = self.config.encode_value(&message)?;
+        self.send(payload)
+    }
+    /// Sends a command, returning the muxer's reply
+    pub fn request_command(&mut self, command: Command) -> Result<Value> {
+        let payload = self.config.encode(command)?;
+        self.send(payload)
+    }
+    /// Lists currently attached devices
+    pub fn list_devices(&mut self) -> Result<Vec<DeviceAttachedInfo>> {
+        let reply = self.request_command(Command::list_devices())?;
+        Ok(protocol::device_list_from_value(&reply)?)
+    }
+    /// Lists the clients listening for device events, to find out what else is using the muxer
+    ///
+    /// This connection itself isn't listed, as it isn't listening.
+    pub fn list_listeners(&mut self) -> Result<Vec<ListenerInfo>> {
+        let reply = self.request_command(Command::list_listeners())?;
+        Ok(protocol::listener_list_from_value(&reply)?)
+    }
+    /// Queries what the muxer supports, to adapt to old usbmuxd or AMDS versions
+    pub fn capabilities(&mut self) -> Result<MuxerCapabilities> {
+        let payload = self.config.encode(Command::list_devices())?;
+        let reply = self.send_packet(payload)?;
+        if is_bad_version(&reply) {
+            return Ok(MuxerCapabilities {
+                version: None,
+                plist: false,
+                supports_network_devices: None,
+            });
+        }
+        let reply = reply_value(&reply)?;
+        let devices = protocol::device_list_from_value(&reply)?;
+        Ok(MuxerCapabilities {
+            version: reply
+                .as_dictionary()
+                .and_then(|d| d.get("kLibUSBMuxVersion"))
+                .and_then(Value::as_unsigned_integer),
+            plist: true,
+            supports_network_devices: devices
+                .iter()
+                .any(|d| d.connection_type == DeviceConnectionType::Network)
+                .then_some(true),
+        })
+    }
+    /// Reads the host's system BUID, shared by all of its pair records
+    pub fn read_buid(&mut self) -> Result<String> {
+        let reply = self.request_command(Command::read_buid())?;
+        reply
+            .as_dictionary()
+            .and_then(|d| d.get("BUID"))
+            .and_then(Value::as_string)
+            .map(str::to_owned)
+            .ok_or_else(|| ProtocolError::InvalidPlistEntryForKey("BUID").into())
+    }
+    /// Reads the pair record held for a device, None if the device hasn't trusted this host
+    pub fn read_pair_record(&mut self, udid: &str) -> Result<Option<PairRecord>> {
+        let reply = self.request_command(Command::read_pair_record(udid))?;
+        match pair_record_value(&reply)? {
+            Some(value) => Ok(Some(PairRecord::try_from(&value)?)),
+            None => Ok(None),
+        }
+    }
+    /// Checks if usbmuxd holds a pair record for the device, meaning it trusts this host
+    pub fn is_paired(&mut self, udid: &str) -> Result<bool> {
+        let reply = self.request_command(Command::read_pair_record(udid))?;
+        Ok(pair_record_value(&reply)?.is_some())
+    }
+    fn send(&mut self, payload: Vec<u8>) -> Result<Value> {
+        let packet = self.send_packet(payload)?;
+        reply_value(&packet)
+    }
+    fn send_packet(&mut self, payload: Vec<u8>) -> Result<Packet> {
+        let tag = self
+            .driver
+            .send(PacketType::PlistPayload, Protocol::Plist, payload)?;
+        self.driver.recv_reply(tag)
+    }
+}
+
+fn reply_value(packet: &Packet) -> Result<Value> {
+    let cursor = std::io::Cursor::new(&packet.data[..]);
+    Value::from_reader(cursor).map_err(|_| ProtocolError::InvalidPlistEntry.into())
+}
+
+/// Checks for the reply muxers without plist support send to plist messages
+fn is_bad_version(packet: &Packet) -> bool {
+    packet.protocol == Protocol::Binary
+        && ResultMessage::from_packet(packet)
+            .and_then(|result| result.code())
+            .is_ok_and(|code| code == ReplyCode::BadVersion)
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::protocol::Packet;
+    use plist::Dictionary;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn it_matches_replies_to_requests() {
+        let path =
+            std::env::temp_dir().join(format!("peertalk-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let muxer = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = muxer.accept().unwrap();
+            let request = Packet::from_reader(&mut socket).unwrap();
+            let message = Value::from_reader(std::io::Cursor::new(request.data)).unwrap();
+            let mut reply = Dictionary::new();
+            reply.insert("Echo".to_owned(), message);
+            let mut payload = Vec::new();
+            Value::Dictionary(reply)
+                .to_writer_xml(&mut payload)
+                .unwrap();
+            // an event with tag 0 first, which isn't the reply
+            Packet::new(
+                Protocol::Plist,
+                PacketType::PlistPayload,
+                0,
+                b"<plist/>".to_vec(),
+            )
+            .unwrap()
+            .write_into(&mut socket)
+            .unwrap();
+            Packet::new(
+                Protocol::Plist,
+                PacketType::PlistPayload,
+                request.tag,
+                payload,
+            )
+            .unwrap()
+            .write_into(&mut socket)
+            .unwrap();
+        });
+        let config = MuxerConfig::default()
+            .socket_path(&path)
+            .client_name("MyApp");
+        let mut client = MuxerClient::with_config(config).unwrap();
+        let mut message = Dictionary::new();
+        message.insert("MessageType".to_owned(), Value::String("Hello".to_owned()));
+        let reply = client.request(Value::Dictionary(message)).unwrap();
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let echo = reply.as_dictionary().unwrap().get("Echo").unwrap();
+        let echo = echo.as_dictionary().unwrap();
+        assert_eq!(echo.get("MessageType").unwrap().as_string(), Some("Hello"));
+        assert_eq!(echo.get("ProgName").unwrap().as_string(), Some("MyApp"));
+    }
+    #[test]
+    fn it_reports_binary_only_muxers() {
+        let path =
+            std::env::temp_dir().join(format!("peertalk-binary-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let muxer = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = muxer.accept().unwrap();
+            let request = Packet::from_reader(&mut socket).unwrap();
+            let code = u32::from(ReplyCode::BadVersion).to_le_bytes().to_vec();
+            protocol::binary::packet(PacketType::Result, request.tag, code)
+                .unwrap()
+                .write_into(&mut socket)
+                .unwrap();
+        });
+        let config = MuxerConfig::default().socket_path(&path);
+        let capabilities = MuxerClient::with_config(config)
+            .unwrap()
+            .capabilities()
+            .unwrap();
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(!capabilities.plist);
+        assert_eq!(capabilities.version, None);
+        assert_eq!(capabilities.supports_network_devices, None);
+    }
+}
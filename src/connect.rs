@@ -0,0 +1,125 @@
+//! Options for connections to device ports
+use crate::{MuxerConfig, ReconnectPolicy, Result, UsbSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How to connect to a port on a device, & how the resulting socket is set up
+///
+/// By default connections go through the default muxer, aren't retried, block indefinitely on
+/// reads & writes, and have TCP_NODELAY set when the muxer is reached over TCP (as on Windows).
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    muxer: MuxerConfig,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nodelay: bool,
+    retry: Option<Arc<dyn ReconnectPolicy>>,
+    timeout: Option<Duration>,
+}
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        MuxerConfig::default().into()
+    }
+}
+impl From<MuxerConfig> for ConnectOptions {
+    fn from(muxer: MuxerConfig) -> Self {
+        ConnectOptions {
+            muxer,
+            read_timeout: None,
+            write_timeout: None,
+            nodelay: true,
+            retry: None,
+            timeout: None,
+        }
+    }
+}
+impl ConnectOptions {
+    /// Connects through the muxer & client name from `config`
+    ///
+    /// This replaces the connect timeout, so set that afterwards.
+    pub fn muxer(mut self, config: MuxerConfig) -> Self {
+        self.muxer = config;
+        self
+    }
+    /// Gives up connecting to a TCP muxer after `timeout`
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.muxer = self.muxer.connect_timeout(timeout);
+        self
+    }
+    /// Sets the read timeout on the returned socket, None blocks indefinitely
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+    /// Sets the write timeout on the returned socket, None blocks indefinitely
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+    /// Whether to set TCP_NODELAY when the muxer is reached over TCP, on by default
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+    /// Retries per `policy` while the device refuses the connection, such as when the app on the
+    /// device hasn't opened its port yet
+    pub fn retry<P: ReconnectPolicy + 'static>(mut self, policy: P) -> Self {
+        self.retry = Some(Arc::new(policy));
+        self
+    }
+    /// Gives up with [`Error::Timeout`](crate::Error::Timeout) if connecting takes longer than
+    /// `timeout`, from reaching the muxer until its reply, retries included
+    ///
+    /// Without it a muxer that stops responding blocks the connect indefinitely.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// Muxer config connections go through
+    pub fn muxer_config(&self) -> &MuxerConfig {
+        &self.muxer
+    }
+    pub(crate) fn retry_policy(&self) -> Option<&dyn ReconnectPolicy> {
+        self.retry.as_deref()
+    }
+    /// When connecting started by `now` has to be done
+    pub(crate) fn deadline(&self, now: Instant) -> Option<Instant> {
+        self.timeout.map(|timeout| now + timeout)
+    }
+    /// Applies the socket options to a newly connected socket
+    pub(crate) fn configure(&self, socket: &UsbSocket) -> Result<()> {
+        socket.set_read_timeout(self.read_timeout)?;
+        socket.set_write_timeout(self.write_timeout)?;
+        socket.set_nodelay(self.nodelay)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::{connect_to_device_with, DeviceId, Error};
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn it_gives_up_on_stalled_muxers() {
+        let path =
+            std::env::temp_dir().join(format!("peertalk-stalled-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let muxer = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            // reads the connect request but never replies
+            let (mut socket, _) = muxer.accept().unwrap();
+            let _ = std::io::copy(&mut socket, &mut std::io::sink());
+        });
+        let options = ConnectOptions::from(MuxerConfig::default().socket_path(&path))
+            .timeout(Duration::from_millis(100));
+        let started = Instant::now();
+        let result = connect_to_device_with(&options, DeviceId(1), 2345);
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(result);
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,96 @@
+//! Handle to an attached device, for going from an `Attached` event to an open connection
+use crate::lockdown::LockdownClient;
+use crate::UsbStream;
+use crate::{connect_to_device_with, DeviceAttachedInfo, DeviceConnectionType, DeviceId};
+use crate::{list_devices, ConnectOptions, Error, MuxerClient, MuxerConfig, ProductType, Result};
+
+/// A device attached to the host, as reported by the muxer
+#[derive(Debug, Clone)]
+pub struct Device {
+    info: DeviceAttachedInfo,
+    options: ConnectOptions,
+}
+impl Device {
+    /// Wraps the info from an `Attached` event, connecting through the default muxer
+    pub fn new(info: DeviceAttachedInfo) -> Self {
+        Self::with_config(info, MuxerConfig::default())
+    }
+    /// Same as [`Device::new`], connecting through the muxer & client name from `config`
+    pub fn with_config(info: DeviceAttachedInfo, config: MuxerConfig) -> Self {
+        Self::with_options(info, config.into())
+    }
+    /// Same as [`Device::new`], connecting with the muxer & socket options from `options`
+    pub fn with_options(info: DeviceAttachedInfo, options: ConnectOptions) -> Self {
+        Device { info, options }
+    }
+    /// Looks up the attached device with the given UDID, preferring its USB connection
+    ///
+    /// # Errors
+    /// Fails with [`Error::DeviceNotFound`] if no device with that UDID is attached
+    pub fn find(udid: &str) -> Result<Self> {
+        list_devices()?
+            .into_iter()
+            .filter(|d| d.identifier == udid)
+            .min_by_key(|d| d.connection_type != DeviceConnectionType::USB)
+            .map(Device::new)
+            .ok_or_else(|| Error::DeviceNotFound(udid.to_owned()))
+    }
+    /// Muxer's id for the device, only valid while it stays attached
+    pub fn id(&self) -> DeviceId {
+        self.info.device_id
+    }
+    /// Device's UDID (serial number)
+    pub fn udid(&self) -> &str {
+        self.info.identifier.as_str()
+    }
+    /// Kind of device, such as iPad or iPhone
+    pub fn product_type(&self) -> ProductType {
+        self.info.product_type
+    }
+    /// Checks if the device is attached over USB
+    pub fn is_usb(&self) -> bool {
+        self.info.connection_type == DeviceConnectionType::USB
+    }
+    /// Checks if the device is attached over the network (Wi-Fi sync)
+    pub fn is_network(&self) -> bool {
+        self.info.connection_type == DeviceConnectionType::Network
+    }
+    /// Everything the muxer reported about the device
+    pub fn info(&self) -> &DeviceAttachedInfo {
+        &self.info
+    }
+    /// Creates a network connection over USB to the given port on the device
+    ///
+    /// # Errors
+    /// Fails if the muxer is unavailable or nothing on the device is listening on `port`
+    pub fn connect(&self, port: u16) -> Result<UsbStream> {
+        connect_to_device_with(&self.options, self.info.device_id, port).map_err(|e| match e {
+            Error::ConnectionRefused {
+                device_id,
+                port,
+                code,
+                ..
+            } => Error::ConnectionRefused {
+                device_id,
+                udid: Some(self.info.identifier.clone()),
+                port,
+                code,
+            },
+            e => e,
+        })
+    }
+    /// Checks if the device trusts this host, so services on it can be used
+    pub fn is_paired(&self) -> Result<bool> {
+        let serial = self.info.identifier.serial();
+        MuxerClient::with_config(self.options.muxer_config().clone())?.is_paired(serial)
+    }
+    /// Connects to the device's lockdownd
+    pub fn lockdown(&self) -> Result<LockdownClient> {
+        LockdownClient::connect(self.info.device_id)
+    }
+}
+impl From<DeviceAttachedInfo> for Device {
+    fn from(info: DeviceAttachedInfo) -> Self {
+        Device::new(info)
+    }
+}
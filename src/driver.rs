@@ -0,0 +1,156 @@
+//! Blocking socket driver for the sans-IO [`MuxerConnection`]
+use crate::protocol::{MuxerConnection, MuxerEvent, Packet, PacketType, Protocol};
+use crate::{telemetry, DeviceEvent, Error, Result, UsbSocket};
+use std::io::{ErrorKind, Read, Write};
+use std::time::Instant;
+
+/// Muxer connection driven with blocking reads & writes on its socket
+#[derive(Debug)]
+pub(crate) struct Driver {
+    socket: UsbSocket,
+    connection: MuxerConnection,
+    /// Reads no further than the packet being read, instead of reading whatever has arrived
+    exact_reads: bool,
+    /// When reads & writes give up with [`Error::Timeout`]
+    deadline: Option<Instant>,
+}
+impl Driver {
+    /// Driver reading whatever has arrived into the connection's buffer, so a packet usually takes
+    /// a single read
+    ///
+    /// Bytes read past a reply stay in the connection's buffer, so it's only for connections
+    /// that keep carrying packets.
+    pub(crate) fn new(socket: UsbSocket) -> Self {
+        Driver {
+            socket,
+            connection: MuxerConnection::new(),
+            exact_reads: false,
+            deadline: None,
+        }
+    }
+    /// Driver reading no further than each packet, for connect requests that turn the connection
+    /// into a device connection after their reply
+    pub(crate) fn unbuffered(socket: UsbSocket) -> Self {
+        Driver {
+            exact_reads: true,
+            ..Self::new(socket)
+        }
+    }
+    /// Gives up reading & writing at `deadline`, None blocks as long as the socket does
+    ///
+    /// This overrides the socket's timeouts, so they need setting again afterwards.
+    pub(crate) fn deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+    /// Sends a request to the muxer, returning the tag its reply will carry
+    pub(crate) fn send(
+        &mut self,
+        packet_type: PacketType,
+        protocol: Protocol,
+        payload: Vec<u8>,
+    ) -> Result<u32> {
+        let tag = self.connection.send(protocol, packet_type, payload)?;
+        while let Some(bytes) = self.connection.next_outgoing() {
+            self.time_left()?;
+            self.socket
+                .write_all(&bytes)
+                .map_err(|e| self.timed_out(e))?;
+            telemetry::sent(bytes.len());
+        }
+        Ok(tag)
+    }
+    /// Reads the reply to the request sent with `tag`, dropping any device events that arrive first
+    pub(crate) fn recv_reply(&mut self, tag: u32) -> Result<Packet> {
+        let mut events = Vec::new();
+        let reply = self.recv_reply_queueing(tag, &mut events)?;
+        if !events.is_empty() {
+            debug!("Dropped {} events received before reply", events.len());
+        }
+        Ok(reply)
+    }
+    /// Reads the reply to the request sent with `tag`, queueing device events that arrive before it
+    ///
+    /// The muxer sends events with tag 0, so on a listening connection an `Attached` event can come
+    /// in between a request & its reply. Whatever follows the reply stays in the connection's
+    /// buffer, or in the socket for an unbuffered driver.
+    pub(crate) fn recv_reply_queueing(
+        &mut self,
+        tag: u32,
+        events: &mut Vec<DeviceEvent>,
+    ) -> Result<Packet> {
+        loop {
+            while let Some(event) = self.connection.next_event() {
+                if !matches!(event, MuxerEvent::Invalid(_)) {
+                    telemetry::packet_received();
+                }
+                match event {
+                    MuxerEvent::Reply(packet) if packet.tag == tag => return Ok(packet),
+                    MuxerEvent::Reply(packet) => debug!("Dropped reply with tag {}", packet.tag),
+                    MuxerEvent::Device(Ok(event)) => {
+                        telemetry::event(&event);
+                        events.push(event);
+                    }
+                    MuxerEvent::Device(Err(e)) => error!("Error decoding event: {}", e),
+                    MuxerEvent::Invalid(e) => return Err(e.into()),
+                }
+            }
+            self.fill()?;
+        }
+    }
+    /// Reads more of the next packet into the connection's buffer
+    fn fill(&mut self) -> Result<()> {
+        self.time_left()?;
+        let read = if self.exact_reads {
+            let mut buf = vec![0; self.connection.bytes_needed()];
+            self.socket
+                .read_exact(&mut buf)
+                .map_err(|e| self.timed_out(e))?;
+            self.connection.extend(&buf);
+            buf.len()
+        } else {
+            let read = self.connection.read_from(&mut self.socket);
+            match read.map_err(|e| self.timed_out(e))? {
+                0 => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+                read => read,
+            }
+        };
+        telemetry::received(read);
+        Ok(())
+    }
+    /// Limits the socket's next read or write to what's left until the deadline
+    fn time_left(&self) -> Result<()> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(()),
+        };
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(Error::Timeout);
+        }
+        self.socket.set_read_timeout(Some(left))?;
+        self.socket.set_write_timeout(Some(left))?;
+        Ok(())
+    }
+    /// Reports an IO error from running out the deadline as [`Error::Timeout`]
+    fn timed_out(&self, error: std::io::Error) -> Error {
+        match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut if self.deadline.is_some() => {
+                Error::Timeout
+            }
+            _ => error.into(),
+        }
+    }
+    /// Socket & protocol state, to keep reading events from a listening connection
+    pub(crate) fn into_parts(self) -> (UsbSocket, MuxerConnection) {
+        (self.socket, self.connection)
+    }
+    /// Socket once it carries a device connection rather than muxer packets
+    pub(crate) fn into_socket(self) -> UsbSocket {
+        debug_assert!(
+            self.exact_reads,
+            "buffered driver may have read device data"
+        );
+        self.socket
+    }
+}
@@ -0,0 +1,277 @@
+//! C API, for embedding in C, C++ or Unity apps, see `include/peertalk.h`
+//!
+//! Functions report failure with a null pointer or -1, after which [`pt_last_error`] describes the
+//! error. The header is generated with `cbindgen --config cbindgen.toml --output include/peertalk.h`.
+use crate::{
+    connect_to_device, Device, DeviceConnectionType, DeviceEvent, DeviceId, DeviceListener,
+};
+use crate::{DeviceAttachedInfo, Error, Result, UsbSocket, UsbStream};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::time::Duration;
+
+/// Longest UDID [`PtDeviceEvent`] holds, including the nul terminator
+pub const PT_UDID_CAPACITY: usize = 64;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Device listener handle, see [`pt_listener_new`]
+pub struct PtListener(DeviceListener);
+
+/// Kind of [`PtDeviceEvent`]
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PtEventKind {
+    /// Device was plugged in, or found on the network
+    Attached = 1,
+    /// Device was unplugged
+    Detached = 2,
+    /// Device was paired with the host
+    Paired = 3,
+    /// Muxer message this version doesn't know
+    Other = 4,
+    /// Listener lost its connection to the muxer, it keeps trying to reconnect
+    MuxerDisconnected = 5,
+    /// Device detached & attached again within the debounce window, under a new id
+    Reattached = 6,
+}
+
+/// Device event, filled in by [`pt_listener_poll`]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PtDeviceEvent {
+    /// What happened
+    pub kind: PtEventKind,
+    /// Muxer's id for the device
+    pub device_id: u64,
+    /// USB product id, 0 for network devices & events other than attached
+    pub product_id: u16,
+    /// Whether the device is attached over the network rather than USB
+    pub is_network: bool,
+    /// Nul terminated UDID, empty for events other than attached
+    pub udid: [c_char; PT_UDID_CAPACITY],
+}
+impl PtDeviceEvent {
+    fn set_attached(&mut self, kind: PtEventKind, info: &DeviceAttachedInfo) {
+        self.kind = kind;
+        self.device_id = info.device_id.0;
+        self.product_id = info.product_id;
+        self.is_network = info.connection_type == DeviceConnectionType::Network;
+        let udid = info.identifier.as_str().as_bytes();
+        let len = udid.len().min(PT_UDID_CAPACITY - 1);
+        for (to, from) in self.udid.iter_mut().zip(&udid[..len]) {
+            *to = *from as c_char;
+        }
+    }
+}
+impl From<&DeviceEvent> for PtDeviceEvent {
+    fn from(event: &DeviceEvent) -> Self {
+        let mut converted = PtDeviceEvent {
+            kind: PtEventKind::Other,
+            device_id: 0,
+            product_id: 0,
+            is_network: false,
+            udid: [0; PT_UDID_CAPACITY],
+        };
+        match event {
+            DeviceEvent::Attached(info) => converted.set_attached(PtEventKind::Attached, info),
+            DeviceEvent::Reattached { info, .. } => {
+                converted.set_attached(PtEventKind::Reattached, info)
+            }
+            DeviceEvent::Detached(id) => {
+                converted.kind = PtEventKind::Detached;
+                converted.device_id = id.0;
+            }
+            DeviceEvent::Paired(id) => {
+                converted.kind = PtEventKind::Paired;
+                converted.device_id = id.0;
+            }
+            DeviceEvent::PairedWithRecord { device_id, .. } => {
+                converted.kind = PtEventKind::Paired;
+                converted.device_id = device_id.0;
+            }
+            DeviceEvent::MuxerDisconnected => converted.kind = PtEventKind::MuxerDisconnected,
+            _ => {}
+        }
+        converted
+    }
+}
+
+/// Raw socket returned by the connect functions, a file descriptor or a Windows `SOCKET`
+pub type PtSocket = i64;
+
+fn set_last_error(error: &Error) {
+    let message = CString::new(error.to_string().replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Describes the last error on the calling thread, or returns null if there was none
+///
+/// The string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn pt_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Starts listening for devices, returns null on failure
+///
+/// Free the listener with [`pt_listener_free`].
+#[no_mangle]
+pub extern "C" fn pt_listener_new() -> *mut PtListener {
+    match DeviceListener::new() {
+        Ok(listener) => Box::into_raw(Box::new(PtListener(listener))),
+        Err(e) => {
+            set_last_error(&e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Waits up to `timeout_ms` for a device event, -1 waits indefinitely
+///
+/// Returns 1 once `event` is filled in, 0 if no event arrived in time & -1 on invalid arguments.
+///
+/// # Safety
+/// `listener` must come from [`pt_listener_new`] & `event` point to a writable `PtDeviceEvent`.
+#[no_mangle]
+pub unsafe extern "C" fn pt_listener_poll(
+    listener: *mut PtListener,
+    timeout_ms: i32,
+    event: *mut PtDeviceEvent,
+) -> i32 {
+    let (listener, out) = match (listener.as_ref(), event.as_mut()) {
+        (Some(listener), Some(out)) => (listener, out),
+        _ => {
+            set_last_error(&invalid_argument());
+            return -1;
+        }
+    };
+    let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+    match listener.0.wait_event(timeout) {
+        Some(event) => {
+            *out = PtDeviceEvent::from(&event);
+            1
+        }
+        None => 0,
+    }
+}
+
+/// Stops listening & frees the listener, null is ignored
+///
+/// # Safety
+/// `listener` must come from [`pt_listener_new`] & not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pt_listener_free(listener: *mut PtListener) {
+    if !listener.is_null() {
+        drop(Box::from_raw(listener));
+    }
+}
+
+/// Connects to `port` on the device with the given muxer id, returns -1 on failure
+///
+/// The caller owns the returned socket & closes it with `close` or `closesocket`.
+#[no_mangle]
+pub extern "C" fn pt_connect(device_id: u64, port: u16) -> PtSocket {
+    into_raw(connect_to_device(DeviceId(device_id), port))
+}
+
+/// Connects to `port` on the device with the given UDID, returns -1 on failure
+///
+/// # Safety
+/// `udid` must be a valid nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pt_connect_udid(udid: *const c_char, port: u16) -> PtSocket {
+    if udid.is_null() {
+        set_last_error(&invalid_argument());
+        return -1;
+    }
+    let udid = CStr::from_ptr(udid).to_string_lossy();
+    into_raw(Device::find(&udid).and_then(|device| device.connect(port)))
+}
+
+fn invalid_argument() -> Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, "null argument").into()
+}
+
+fn into_raw(stream: Result<UsbStream>) -> PtSocket {
+    match stream {
+        Ok(stream) => match raw_socket(stream.into_socket()) {
+            Ok(raw) => raw,
+            Err(e) => {
+                set_last_error(&e.into());
+                -1
+            }
+        },
+        Err(e) => {
+            set_last_error(&e);
+            -1
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn raw_socket(socket: UsbSocket) -> std::io::Result<PtSocket> {
+    use std::os::unix::io::IntoRawFd;
+    match socket {
+        UsbSocket::Unix(socket) => Ok(socket.into_raw_fd().into()),
+        UsbSocket::Tcp(socket) => Ok(socket.into_raw_fd().into()),
+        UsbSocket::Custom(_) => Err(not_a_socket()),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn raw_socket(socket: UsbSocket) -> std::io::Result<PtSocket> {
+    use std::os::windows::io::IntoRawSocket;
+    match socket {
+        UsbSocket::Tcp(socket) => Ok(socket.into_raw_socket() as PtSocket),
+        UsbSocket::Custom(_) => Err(not_a_socket()),
+    }
+}
+
+fn not_a_socket() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "custom transport connections have no raw socket",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceConnectionType, ProductType, Udid};
+    #[test]
+    fn it_converts_attached_events() {
+        let info = DeviceAttachedInfo {
+            connection_type: DeviceConnectionType::USB,
+            device_id: DeviceId(7),
+            location_id: 0,
+            product_type: ProductType::IPad,
+            product_id: 0x12AB,
+            identifier: Udid::parse("00008030-001A35E22E88802E").unwrap(),
+            network_address: None,
+            interface_index: None,
+            connection_speed: None,
+            usb_serial_number: None,
+            escrow_bag: None,
+            properties: Default::default(),
+        };
+        let event = PtDeviceEvent::from(&DeviceEvent::Attached(info));
+        assert_eq!(event.kind, PtEventKind::Attached);
+        assert_eq!(event.product_id, 0x12AB);
+        let udid = unsafe { CStr::from_ptr(event.udid.as_ptr()) };
+        assert_eq!(udid.to_str().unwrap(), "00008030-001A35E22E88802E");
+    }
+    #[test]
+    fn it_reports_invalid_arguments() {
+        assert_eq!(unsafe { pt_connect_udid(std::ptr::null(), 2345) }, -1);
+        assert!(!pt_last_error().is_null());
+    }
+}
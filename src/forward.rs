@@ -0,0 +1,383 @@
+//! Forwarding of local TCP ports & Unix sockets to device ports, like libimobiledevice's `iproxy`
+use crate::{connect_to_device_with, ConnectOptions, Device, DeviceId, Result, Udid, UsbSocket};
+use std::net::ToSocketAddrs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(not(target_os = "windows"))]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How long the accept loop waits before accepting again after an error, such as running out of
+/// file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Device to forward to, by muxer id or by UDID
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForwardTarget {
+    /// Muxer's id for the device, stops working once the device is replugged
+    Id(DeviceId),
+    /// Device's UDID, looked up again for every connection so replugging is fine
+    Udid(Udid),
+}
+impl From<DeviceId> for ForwardTarget {
+    fn from(id: DeviceId) -> Self {
+        ForwardTarget::Id(id)
+    }
+}
+impl From<u64> for ForwardTarget {
+    fn from(id: u64) -> Self {
+        ForwardTarget::Id(DeviceId(id))
+    }
+}
+impl From<Udid> for ForwardTarget {
+    fn from(udid: Udid) -> Self {
+        ForwardTarget::Udid(udid)
+    }
+}
+impl From<&str> for ForwardTarget {
+    fn from(udid: &str) -> Self {
+        ForwardTarget::Udid(Udid::from_serial(udid))
+    }
+}
+impl From<String> for ForwardTarget {
+    fn from(udid: String) -> Self {
+        ForwardTarget::Udid(Udid::from_serial(&udid))
+    }
+}
+impl From<&Device> for ForwardTarget {
+    fn from(device: &Device) -> Self {
+        ForwardTarget::Udid(device.info().identifier.clone())
+    }
+}
+
+/// Where a forwarder accepts connections on the host
+#[derive(Debug)]
+pub(crate) enum Local {
+    Tcp(TcpListener),
+    #[cfg(not(target_os = "windows"))]
+    Unix(UnixListener),
+}
+impl Local {
+    pub(crate) fn accept(&self) -> std::io::Result<UsbSocket> {
+        match self {
+            Local::Tcp(listener) => listener.accept().map(|(s, _)| s.into()),
+            #[cfg(not(target_os = "windows"))]
+            Local::Unix(listener) => listener.accept().map(|(s, _)| s.into()),
+        }
+    }
+}
+
+/// Forwards connections to a local TCP port or Unix socket to a port on a device, until dropped
+///
+/// Every accepted connection gets its own connection through the muxer, so clients are served
+/// concurrently. Dropping the forwarder stops accepting, connections already open keep going.
+#[derive(Debug)]
+pub struct PortForwarder {
+    local_addr: Option<SocketAddr>,
+    #[cfg(not(target_os = "windows"))]
+    socket_path: Option<PathBuf>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+impl PortForwarder {
+    /// TCP address the forwarder listens on, useful when binding to port 0
+    ///
+    /// None when forwarding a Unix socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+    /// Path of the Unix socket the forwarder listens on, None when forwarding a TCP port
+    #[cfg(not(target_os = "windows"))]
+    pub fn socket_path(&self) -> Option<&Path> {
+        self.socket_path.as_deref()
+    }
+    /// Stops accepting connections
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+    fn shutdown(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake up the accept loop so it sees the flag
+        if let Some(mut addr) = self.local_addr {
+            // Windows refuses connections to the unspecified address the listener is bound to
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            let _ = TcpStream::connect(addr);
+        }
+        #[cfg(not(target_os = "windows"))]
+        if let Some(path) = &self.socket_path {
+            let _ = UnixStream::connect(path);
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        #[cfg(not(target_os = "windows"))]
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+impl Drop for PortForwarder {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Listens on `local_addr` & tunnels each accepted connection to `device_port` on the device
+///
+/// # Errors
+/// Fails if the local address can't be bound; connection failures are logged & close the client
+pub fn forward_port<A, T>(local_addr: A, device: T, device_port: u16) -> Result<PortForwarder>
+where
+    A: ToSocketAddrs,
+    T: Into<ForwardTarget>,
+{
+    forward_port_with(ConnectOptions::default(), local_addr, device, device_port)
+}
+
+/// Same as [`forward_port`], connecting to the device with the muxer & options from `options`
+pub fn forward_port_with<A, T>(
+    options: ConnectOptions,
+    local_addr: A,
+    device: T,
+    device_port: u16,
+) -> Result<PortForwarder>
+where
+    A: ToSocketAddrs,
+    T: Into<ForwardTarget>,
+{
+    serve_tcp(
+        options,
+        local_addr,
+        device.into(),
+        device_port,
+        Arc::new(pipe),
+    )
+}
+
+/// Accepts TCP connections on `local_addr`, handing each to `bridge` with its device connection
+pub(crate) fn serve_tcp<A: ToSocketAddrs>(
+    options: ConnectOptions,
+    local_addr: A,
+    target: ForwardTarget,
+    device_port: u16,
+    bridge: Bridge,
+) -> Result<PortForwarder> {
+    let listener = TcpListener::bind(local_addr)?;
+    let local_addr = listener.local_addr()?;
+    let mut forwarder = spawn(options, Local::Tcp(listener), target, device_port, bridge)?;
+    forwarder.local_addr = Some(local_addr);
+    Ok(forwarder)
+}
+
+/// Listens on a Unix socket at `path` & tunnels each accepted connection to `device_port` on the
+/// device
+///
+/// Handy for sandboxed tools & containers that share a socket rather than a network. The socket
+/// file is removed once the forwarder stops.
+///
+/// # Errors
+/// Fails if the socket can't be created, such as when `path` already exists
+#[cfg(not(target_os = "windows"))]
+pub fn forward_unix_socket<P, T>(path: P, device: T, device_port: u16) -> Result<PortForwarder>
+where
+    P: Into<PathBuf>,
+    T: Into<ForwardTarget>,
+{
+    forward_unix_socket_with(ConnectOptions::default(), path, device, device_port)
+}
+
+/// Same as [`forward_unix_socket`], connecting to the device with the muxer & options from
+/// `options`
+#[cfg(not(target_os = "windows"))]
+pub fn forward_unix_socket_with<P, T>(
+    options: ConnectOptions,
+    path: P,
+    device: T,
+    device_port: u16,
+) -> Result<PortForwarder>
+where
+    P: Into<PathBuf>,
+    T: Into<ForwardTarget>,
+{
+    let path = path.into();
+    let listener = UnixListener::bind(&path)?;
+    let local = Local::Unix(listener);
+    let mut forwarder = spawn(options, local, device.into(), device_port, Arc::new(pipe))?;
+    forwarder.socket_path = Some(path);
+    Ok(forwarder)
+}
+
+/// Carries data between an accepted client (first) & its device connection (second)
+pub(crate) type Bridge = Arc<dyn Fn(UsbSocket, UsbSocket) -> Result<()> + Send + Sync>;
+
+/// Starts the accept loop
+fn spawn(
+    options: ConnectOptions,
+    local: Local,
+    target: ForwardTarget,
+    device_port: u16,
+    bridge: Bridge,
+) -> Result<PortForwarder> {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let thread_stopped = stopped.clone();
+    let thread = std::thread::Builder::new()
+        .name("peertalk-forward".to_owned())
+        .spawn(move || loop {
+            let client = local.accept();
+            if thread_stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let client = match client {
+                Ok(client) => client,
+                Err(e) => {
+                    warn!("Failed to accept connection to forward: {}", e);
+                    std::thread::sleep(ACCEPT_RETRY_DELAY);
+                    continue;
+                }
+            };
+            let options = options.clone();
+            let target = target.clone();
+            let bridge = bridge.clone();
+            let spawned = std::thread::Builder::new()
+                .name("peertalk-forward-conn".to_owned())
+                .spawn(move || {
+                    if let Err(e) = tunnel(&options, &target, device_port, client, &bridge) {
+                        warn!("Forwarding to device port {} failed: {}", device_port, e);
+                    }
+                });
+            if let Err(e) = spawned {
+                warn!("Failed to spawn forwarding thread: {}", e);
+            }
+        })?;
+    Ok(PortForwarder {
+        local_addr: None,
+        #[cfg(not(target_os = "windows"))]
+        socket_path: None,
+        stopped,
+        thread: Some(thread),
+    })
+}
+
+/// Connects to the device & bridges the client to it
+fn tunnel(
+    options: &ConnectOptions,
+    target: &ForwardTarget,
+    device_port: u16,
+    client: UsbSocket,
+    bridge: &Bridge,
+) -> Result<()> {
+    let device_id = match target {
+        ForwardTarget::Id(id) => *id,
+        ForwardTarget::Udid(udid) => Device::find(udid.as_str())?.id(),
+    };
+    let device = connect_to_device_with(options, device_id, device_port)?;
+    debug!("Forwarding to device {} port {}", device_id, device_port);
+    bridge(client, device.into_socket())
+}
+
+/// Copies data both ways until either side closes
+fn pipe(client: UsbSocket, device: UsbSocket) -> Result<()> {
+    let upload = {
+        let client = client.try_clone()?;
+        let device = device.try_clone()?;
+        std::thread::Builder::new()
+            .name("peertalk-forward-conn".to_owned())
+            .spawn(move || copy(client, device))?
+    };
+    copy(device, client);
+    let _ = upload.join();
+    Ok(())
+}
+
+/// Copies until EOF, then closes the writing half so the other side sees EOF too
+fn copy(mut from: UsbSocket, mut to: UsbSocket) {
+    if let Err(e) = std::io::copy(&mut from, &mut to) {
+        debug!("Forwarded connection closed: {}", e);
+    }
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::protocol::{Packet, PacketType, Protocol};
+    use crate::MuxerConfig;
+    use std::io::{Read, Write};
+
+    /// Starts a muxer accepting every connect request, then echoing like the device would
+    fn fake_muxer(name: &str) -> ConnectOptions {
+        let path =
+            std::env::temp_dir().join(format!("peertalk-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let muxer = UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || {
+            for socket in muxer.incoming() {
+                let mut socket = socket.unwrap();
+                std::thread::spawn(move || {
+                    let request = Packet::from_reader(&mut socket).unwrap();
+                    let reply = b"<plist version=\"1.0\"><dict><key>MessageType</key>\
+                        <string>Result</string><key>Number</key><integer>0</integer></dict></plist>";
+                    Packet::new(
+                        Protocol::Plist,
+                        PacketType::PlistPayload,
+                        request.tag,
+                        reply.to_vec(),
+                    )
+                    .unwrap()
+                    .write_into(&mut socket)
+                    .unwrap();
+                    let mut echo = socket.try_clone().unwrap();
+                    std::io::copy(&mut socket, &mut echo).unwrap();
+                });
+            }
+        });
+        ConnectOptions::from(MuxerConfig::default().socket_path(path))
+    }
+
+    #[test]
+    fn it_forwards_concurrent_connections() {
+        let options = fake_muxer("forward");
+        let forwarder = forward_port_with(options, "127.0.0.1:0", 3, 2345).unwrap();
+        let addr = forwarder.local_addr().unwrap();
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.write_all(b"second").unwrap();
+        first.write_all(b"first").unwrap();
+        let mut buffer = [0; 6];
+        second.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"second");
+        first.read_exact(&mut buffer[..5]).unwrap();
+        assert_eq!(&buffer[..5], b"first");
+        forwarder.stop();
+    }
+    #[test]
+    fn it_stops_forwarders_on_unspecified_addresses() {
+        let forwarder = forward_port("0.0.0.0:0", 3, 2345).unwrap();
+        assert!(forwarder.local_addr().unwrap().ip().is_unspecified());
+        forwarder.stop();
+    }
+    #[test]
+    fn it_forwards_unix_sockets() {
+        let options = fake_muxer("forward-unix");
+        let path =
+            std::env::temp_dir().join(format!("peertalk-forwarded-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let forwarder = forward_unix_socket_with(options, &path, 3, 2345).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut buffer = [0; 5];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+        forwarder.stop();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,205 @@
+//! Handshake agreeing on the session protocol version & optional features
+//!
+//! Right after connecting, each side sends a [`FRAME_TYPE_CAPABILITIES`] frame with a binary plist
+//! dictionary payload:
+//!
+//! - `Version`: integer, the highest handshake version the sender speaks
+//! - `Features`: array of strings, such as [`FEATURE_MULTIPLEXING`] or app defined features
+//! - `Compression`: array of strings, compression algorithms in order of preference
+//! - `Checksum`: array of strings, checksum algorithms in order of preference
+//!
+//! Both sides then use the lower version & the features both listed. Unknown keys are ignored, so
+//! later versions can add keys, and peers that never send capabilities get version 0 & no features.
+use super::{Frame, FRAME_TYPE_CAPABILITIES, NO_TAG};
+use crate::{ProtocolError, Result};
+use plist::{Dictionary, Value};
+use std::convert::TryFrom;
+
+/// Handshake version this crate speaks
+pub const HANDSHAKE_VERSION: u32 = 1;
+
+/// Feature for channel multiplexing, see [`Multiplexer`](super::Multiplexer)
+pub const FEATURE_MULTIPLEXING: &str = "multiplexing";
+/// Feature for encrypted sessions, needs the `encryption` crate feature
+pub const FEATURE_ENCRYPTION: &str = "encryption";
+/// Name of the LZ4 payload compression in capabilities frames
+pub const COMPRESSION_LZ4: &str = "lz4";
+/// Name of the CRC32 payload checksum in capabilities frames
+pub const CHECKSUM_CRC32: &str = "crc32";
+
+const VERSION_KEY: &str = "Version";
+const FEATURES_KEY: &str = "Features";
+const COMPRESSION_KEY: &str = "Compression";
+const CHECKSUM_KEY: &str = "Checksum";
+
+/// Features one side of a session supports, or both sides agreed on
+///
+/// The default is what's assumed of peers that don't take part in the handshake: version 0 & no
+/// optional features.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Capabilities {
+    version: u32,
+    features: Vec<String>,
+    compression: Vec<String>,
+    checksums: Vec<String>,
+}
+impl Capabilities {
+    /// Everything this build supports, depending on the enabled crate features
+    pub fn supported() -> Self {
+        let mut features = vec![FEATURE_MULTIPLEXING.to_owned()];
+        if cfg!(feature = "encryption") {
+            features.push(FEATURE_ENCRYPTION.to_owned());
+        }
+        Capabilities {
+            version: HANDSHAKE_VERSION,
+            features,
+            compression: if cfg!(feature = "compression") {
+                vec![COMPRESSION_LZ4.to_owned()]
+            } else {
+                Vec::new()
+            },
+            checksums: vec![CHECKSUM_CRC32.to_owned()],
+        }
+    }
+    /// Adds features, like ones defined by the app, to those offered
+    pub fn with_features<I, S>(mut self, features: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.features.extend(features.into_iter().map(Into::into));
+        self
+    }
+    /// Sets the compression algorithms offered, in order of preference
+    pub fn with_compression<I, S>(mut self, algorithms: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.compression = algorithms.into_iter().map(Into::into).collect();
+        self
+    }
+    /// Sets the checksums offered, in order of preference; none turns checksums off
+    pub fn with_checksums<I, S>(mut self, algorithms: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.checksums = algorithms.into_iter().map(Into::into).collect();
+        self
+    }
+    /// Handshake version, 0 for peers that don't take part in the handshake
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    /// Optional features
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+    /// Checks if a feature is supported, or agreed on
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+    /// Compression algorithms, in order of preference
+    pub fn compression(&self) -> &[String] {
+        &self.compression
+    }
+    /// Checksum algorithms, in order of preference
+    pub fn checksums(&self) -> &[String] {
+        &self.checksums
+    }
+    /// Features both sides support, in our order of preference
+    pub fn common(&self, peer: &Capabilities) -> Capabilities {
+        Capabilities {
+            version: self.version.min(peer.version),
+            features: common(&self.features, &peer.features),
+            compression: common(&self.compression, &peer.compression),
+            checksums: common(&self.checksums, &peer.checksums),
+        }
+    }
+    /// Encodes the capabilities as a frame
+    pub fn to_frame(&self) -> Result<Frame> {
+        let mut dict = Dictionary::new();
+        dict.insert(VERSION_KEY.to_owned(), Value::from(self.version));
+        dict.insert(FEATURES_KEY.to_owned(), to_array(&self.features));
+        dict.insert(COMPRESSION_KEY.to_owned(), to_array(&self.compression));
+        dict.insert(CHECKSUM_KEY.to_owned(), to_array(&self.checksums));
+        Ok(Frame::plist(
+            FRAME_TYPE_CAPABILITIES,
+            NO_TAG,
+            &Value::Dictionary(dict),
+        )?)
+    }
+    /// Decodes a capabilities frame, features it doesn't list are taken as unsupported
+    pub fn from_frame(frame: &Frame) -> Result<Self> {
+        let value = frame.to_plist()?;
+        let dict = value
+            .as_dictionary()
+            .ok_or(ProtocolError::InvalidPlistEntry)?;
+        let version = match dict.get(VERSION_KEY) {
+            Some(version) => version
+                .as_unsigned_integer()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or(ProtocolError::InvalidPlistEntryForKey(VERSION_KEY))?,
+            None => 0,
+        };
+        Ok(Capabilities {
+            version,
+            features: from_array(dict, FEATURES_KEY)?,
+            compression: from_array(dict, COMPRESSION_KEY)?,
+            checksums: from_array(dict, CHECKSUM_KEY)?,
+        })
+    }
+}
+
+/// Names in `ours` that are also in `peer`
+fn common(ours: &[String], peer: &[String]) -> Vec<String> {
+    ours.iter().filter(|c| peer.contains(c)).cloned().collect()
+}
+fn to_array(names: &[String]) -> Value {
+    Value::Array(names.iter().cloned().map(Value::String).collect())
+}
+fn from_array(dict: &Dictionary, key: &'static str) -> Result<Vec<String>> {
+    match dict.get(key) {
+        Some(Value::Array(names)) => Ok(names
+            .iter()
+            .filter_map(Value::as_string)
+            .map(str::to_owned)
+            .collect()),
+        Some(_) => Err(ProtocolError::InvalidPlistEntryForKey(key).into()),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn it_agrees_on_common_features() {
+        let ours = Capabilities::default()
+            .with_compression(["zstd", "lz4"])
+            .with_checksums([CHECKSUM_CRC32]);
+        let frame = Capabilities::default()
+            .with_compression(["lz4", "brotli"])
+            .to_frame()
+            .unwrap();
+        let peer = Capabilities::from_frame(&frame).unwrap();
+        let agreed = ours.common(&peer);
+        assert_eq!(agreed.compression(), ["lz4"]);
+        assert!(agreed.checksums().is_empty());
+    }
+    #[test]
+    fn it_agrees_on_the_lower_version() {
+        let ours = Capabilities::supported().with_features(["video"]);
+        let mut dict = Dictionary::new();
+        dict.insert("Version".to_owned(), Value::from(0u64));
+        dict.insert("Features".to_owned(), to_array(&["video".to_owned()]));
+        dict.insert("FutureKey".to_owned(), Value::Boolean(true));
+        let frame = Frame::plist(FRAME_TYPE_CAPABILITIES, NO_TAG, &Value::Dictionary(dict));
+        let peer = Capabilities::from_frame(&frame.unwrap()).unwrap();
+        let agreed = ours.common(&peer);
+        assert_eq!(agreed.version(), 0);
+        assert!(agreed.has_feature("video"));
+        assert!(!agreed.has_feature(FEATURE_MULTIPLEXING));
+    }
+}
@@ -0,0 +1,157 @@
+//! Reading & writing frames on one stream, with middleware hooks on every frame
+use super::{Frame, FrameReader};
+use crate::Result;
+use std::io::{Read, Write};
+
+/// Hooks run on every frame passing through a [`FrameChannel`]
+///
+/// Middleware can log, count, rewrite or reject frames, such as checking an app level header
+/// before frames reach the rest of the app. Rejections are usually an
+/// [`InvalidFrame::Rejected`](super::InvalidFrame::Rejected) error.
+pub trait FrameMiddleware: Send {
+    /// Called before a frame is written, may modify it or reject it with an error
+    fn before_send(&mut self, _frame: &mut Frame) -> Result<()> {
+        Ok(())
+    }
+    /// Called after a frame is read, may modify it or reject it with an error
+    fn after_receive(&mut self, _frame: &mut Frame) -> Result<()> {
+        Ok(())
+    }
+}
+/// Logs every frame sent & received at trace level
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingMiddleware;
+impl FrameMiddleware for TracingMiddleware {
+    fn before_send(&mut self, frame: &mut Frame) -> Result<()> {
+        trace!(
+            "-> frame type: {} tag: {} payload: {} bytes",
+            frame.frame_type,
+            frame.tag,
+            frame.payload.len()
+        );
+        Ok(())
+    }
+    fn after_receive(&mut self, frame: &mut Frame) -> Result<()> {
+        trace!(
+            "<- frame type: {} tag: {} payload: {} bytes",
+            frame.frame_type,
+            frame.tag,
+            frame.payload.len()
+        );
+        Ok(())
+    }
+}
+
+/// Reads & writes frames on a device connection, running them through registered middleware
+pub struct FrameChannel<S> {
+    reader: FrameReader<S>,
+    middleware: Vec<Box<dyn FrameMiddleware>>,
+}
+impl<S: Read + Write> FrameChannel<S> {
+    /// Sends & receives frames on `stream`, without any middleware
+    pub fn new(stream: S) -> Self {
+        FrameChannel {
+            reader: FrameReader::new(stream),
+            middleware: Vec::new(),
+        }
+    }
+    /// Adds middleware, which run in the order they're added for sends & in reverse for receives
+    pub fn middleware<M: FrameMiddleware + 'static>(mut self, middleware: M) -> Self {
+        self.add_middleware(middleware);
+        self
+    }
+    /// Same as [`middleware`](Self::middleware), on a channel already in use
+    pub fn add_middleware<M: FrameMiddleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+    /// Runs a frame through the middleware & writes it
+    ///
+    /// # Errors
+    /// Fails if a middleware rejects the frame or writing fails
+    pub fn send(&mut self, mut frame: Frame) -> Result<()> {
+        for middleware in self.middleware.iter_mut() {
+            middleware.before_send(&mut frame)?;
+        }
+        let stream = self.reader.get_mut();
+        frame.write_into(stream)?;
+        stream.flush()?;
+        Ok(())
+    }
+    /// Reads the next frame & runs it through the middleware, `None` once the peer ends the
+    /// stream or closes the connection
+    ///
+    /// # Errors
+    /// Fails if reading fails, the frame is invalid or a middleware rejects it
+    pub fn receive(&mut self) -> Result<Option<Frame>> {
+        let mut frame = match self.reader.read_frame()? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        for middleware in self.middleware.iter_mut().rev() {
+            middleware.after_receive(&mut frame)?;
+        }
+        Ok(Some(frame))
+    }
+    /// Underlying stream
+    pub fn get_ref(&self) -> &S {
+        self.reader.get_ref()
+    }
+    /// Unwraps the underlying stream
+    pub fn into_inner(self) -> S {
+        self.reader.into_inner()
+    }
+}
+impl<S> std::fmt::Debug for FrameChannel<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FrameChannel")
+            .field("middleware", &self.middleware.len())
+            .finish()
+    }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::frame::InvalidFrame;
+    use crate::Error;
+    use std::os::unix::net::UnixStream;
+
+    /// Rejects frames of one type & tags everything else it sends
+    struct OnlyText;
+    impl FrameMiddleware for OnlyText {
+        fn before_send(&mut self, frame: &mut Frame) -> Result<()> {
+            frame.tag = 7;
+            Ok(())
+        }
+        fn after_receive(&mut self, frame: &mut Frame) -> Result<()> {
+            if frame.as_text().is_none() {
+                return Err(InvalidFrame::Rejected {
+                    frame_type: frame.frame_type,
+                    tag: frame.tag,
+                    reason: "not text".to_owned(),
+                }
+                .into());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_runs_frames_through_middleware() {
+        let (host, device) = UnixStream::pair().unwrap();
+        let mut host = FrameChannel::new(host)
+            .middleware(TracingMiddleware)
+            .middleware(OnlyText);
+        let mut device = FrameChannel::new(device);
+        host.send(Frame::text("hi")).unwrap();
+        let frame = device.receive().unwrap().unwrap();
+        assert_eq!((frame.as_text(), frame.tag), (Some("hi"), 7));
+        device.send(Frame::ping(1)).unwrap();
+        assert!(matches!(
+            host.receive(),
+            Err(Error::InvalidFrame(InvalidFrame::Rejected { tag: 1, .. }))
+        ));
+        device.send(Frame::text("back")).unwrap();
+        assert_eq!(host.receive().unwrap().unwrap().as_text(), Some("back"));
+    }
+}
@@ -0,0 +1,64 @@
+//! CRC32 trailer on frame payloads, to catch streams garbled on the way
+use super::{into_payload, payload_vec, Frame, InvalidFrame, FLAG_CHECKSUM};
+use crate::Result;
+use std::convert::TryInto;
+
+/// Appends the payload's CRC32, marking the frame type
+pub(crate) fn append(frame: &mut Frame) {
+    let checksum = crc32fast::hash(&frame.payload);
+    let mut payload = payload_vec(std::mem::take(&mut frame.payload));
+    payload.extend_from_slice(&checksum.to_be_bytes());
+    frame.payload = into_payload(payload);
+    frame.frame_type |= FLAG_CHECKSUM;
+}
+
+/// Checks & strips the trailer added by [`append`]
+pub(crate) fn verify(frame: &mut Frame) -> Result<()> {
+    let frame_type = frame.frame_type & !FLAG_CHECKSUM;
+    let len = match frame.payload.len().checked_sub(4) {
+        Some(len) => len,
+        None => {
+            return Err(InvalidFrame::CorruptPayload {
+                frame_type,
+                tag: frame.tag,
+            }
+            .into())
+        }
+    };
+    let expected = u32::from_be_bytes(frame.payload[len..].try_into().unwrap());
+    let actual = crc32fast::hash(&frame.payload[..len]);
+    if expected != actual {
+        return Err(InvalidFrame::ChecksumMismatch {
+            frame_type,
+            tag: frame.tag,
+            expected,
+            actual,
+        }
+        .into());
+    }
+    frame.payload.truncate(len);
+    frame.frame_type = frame_type;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    #[test]
+    fn it_detects_corruption() {
+        let original = Frame::text("Hello");
+        let mut frame = original.clone();
+        append(&mut frame);
+        let mut corrupt = frame.clone();
+        verify(&mut frame).unwrap();
+        assert_eq!(frame, original);
+        let mut payload = payload_vec(corrupt.payload);
+        payload[5] ^= 0x20;
+        corrupt.payload = into_payload(payload);
+        assert!(matches!(
+            verify(&mut corrupt),
+            Err(Error::InvalidFrame(InvalidFrame::ChecksumMismatch { .. }))
+        ));
+    }
+}
@@ -0,0 +1,150 @@
+//! `tokio_util` codec for frames, for reading & writing them on async streams
+use super::{Frame, FrameHeader, InvalidFrame, DEFAULT_MAX_PAYLOAD_SIZE, HEADER_SIZE};
+use crate::{Error, ProtocolError};
+use bytes::{Buf, BufMut, BytesMut};
+use std::convert::TryFrom;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Encodes & decodes frames, for `Framed::new(stream, PtFrameCodec::default())`
+///
+/// Unlike [`FrameReader`](super::FrameReader), end of stream frames are passed on like any other
+/// frame, it's up to the consumer to stop reading once [`Frame::is_end_of_stream`].
+#[derive(Debug, Clone)]
+pub struct PtFrameCodec {
+    max_payload: u32,
+}
+impl Default for PtFrameCodec {
+    fn default() -> Self {
+        Self::with_max_payload(DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+}
+impl PtFrameCodec {
+    /// Creates a codec rejecting payloads over `max_payload` bytes
+    pub fn with_max_payload(max_payload: u32) -> Self {
+        PtFrameCodec { max_payload }
+    }
+}
+impl Decoder for PtFrameCodec {
+    type Item = Frame;
+    type Error = Error;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        let mut header = [0; HEADER_SIZE];
+        match src.get(..HEADER_SIZE) {
+            Some(bytes) => header.copy_from_slice(bytes),
+            None => {
+                src.reserve(HEADER_SIZE - src.len());
+                return Ok(None);
+            }
+        }
+        let FrameHeader {
+            version,
+            frame_type,
+            tag,
+            size,
+        } = FrameHeader::parse(&header, self.max_payload)?;
+        let frame_size = HEADER_SIZE + size as usize;
+        if src.len() < frame_size {
+            src.reserve(frame_size - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_SIZE);
+        #[cfg(feature = "bytes")]
+        let payload = src.split_to(size as usize).freeze();
+        #[cfg(not(feature = "bytes"))]
+        let payload = src.split_to(size as usize).to_vec();
+        Ok(Some(Frame {
+            version,
+            frame_type,
+            tag,
+            payload,
+        }))
+    }
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None if src.len() < HEADER_SIZE => Err(InvalidFrame::TruncatedHeader {
+                received: src.len(),
+            }
+            .into()),
+            None => {
+                let mut header = [0; HEADER_SIZE];
+                header.copy_from_slice(&src[..HEADER_SIZE]);
+                let header = FrameHeader::parse(&header, self.max_payload)?;
+                Err(InvalidFrame::TruncatedPayload {
+                    frame_type: header.frame_type,
+                    tag: header.tag,
+                    size: header.size,
+                    received: src.len() - HEADER_SIZE,
+                }
+                .into())
+            }
+        }
+    }
+}
+impl Encoder<Frame> for PtFrameCodec {
+    type Error = Error;
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Error> {
+        self.encode(&frame, dst)
+    }
+}
+impl Encoder<&Frame> for PtFrameCodec {
+    type Error = Error;
+    fn encode(&mut self, frame: &Frame, dst: &mut BytesMut) -> Result<(), Error> {
+        let size = u32::try_from(frame.payload.len())
+            .map_err(|_| ProtocolError::PayloadTooLarge(frame.payload.len()))?;
+        dst.reserve(HEADER_SIZE + frame.payload.len());
+        dst.put_u32(frame.version);
+        dst.put_u32(frame.frame_type);
+        dst.put_u32(frame.tag);
+        dst.put_u32(size);
+        dst.put_slice(&frame.payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FRAME_TYPE_TEXT_MSG;
+    #[test]
+    fn it_decodes_frames_split_across_reads() {
+        let mut data = Vec::new();
+        Frame::text("Hello").write_into(&mut data).unwrap();
+        Frame::ping(2).write_into(&mut data).unwrap();
+        let mut codec = PtFrameCodec::default();
+        let mut buffer = BytesMut::new();
+        let mut frames = Vec::new();
+        for byte in data {
+            buffer.put_u8(byte);
+            while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames, vec![Frame::text("Hello"), Frame::ping(2)]);
+        assert!(codec.decode_eof(&mut buffer).unwrap().is_none());
+    }
+    #[test]
+    fn it_encodes_like_frame_writer() {
+        let frame = Frame::text("Hello");
+        let mut expected = Vec::new();
+        frame.write_into(&mut expected).unwrap();
+        let mut buffer = BytesMut::new();
+        PtFrameCodec::default().encode(frame, &mut buffer).unwrap();
+        assert_eq!(&buffer[..], &expected[..]);
+    }
+    #[test]
+    fn it_reports_truncated_frames_at_eof() {
+        let mut data = Vec::new();
+        Frame::text("Hello").write_into(&mut data).unwrap();
+        let mut buffer = BytesMut::from(&data[..20]);
+        match PtFrameCodec::default().decode_eof(&mut buffer) {
+            Err(Error::InvalidFrame(InvalidFrame::TruncatedPayload {
+                frame_type: FRAME_TYPE_TEXT_MSG,
+                received: 4,
+                ..
+            })) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! LZ4 compression of frame payloads, once both sides agreed on it
+use super::{into_payload, Frame, InvalidFrame, FLAG_COMPRESSED};
+use crate::Result;
+use std::convert::TryInto;
+
+/// Compresses the payload if that makes it smaller, marking the frame type
+pub(crate) fn compress(frame: &mut Frame) {
+    let compressed = lz4_flex::compress_prepend_size(&frame.payload);
+    if compressed.len() < frame.payload.len() {
+        frame.payload = into_payload(compressed);
+        frame.frame_type |= FLAG_COMPRESSED;
+    }
+}
+
+/// Decompresses a payload compressed by [`compress`], refusing to inflate past `max_payload`
+pub(crate) fn decompress(frame: &mut Frame, max_payload: u32) -> Result<()> {
+    let frame_type = frame.frame_type & !FLAG_COMPRESSED;
+    let invalid = || InvalidFrame::CorruptPayload {
+        frame_type,
+        tag: frame.tag,
+    };
+    let size = frame
+        .payload
+        .get(..4)
+        .map(|size| u32::from_le_bytes(size.try_into().unwrap()))
+        .ok_or_else(invalid)?;
+    if size > max_payload {
+        return Err(InvalidFrame::PayloadTooLarge {
+            frame_type,
+            tag: frame.tag,
+            size,
+            max: max_payload,
+        }
+        .into());
+    }
+    let payload = lz4_flex::decompress_size_prepended(&frame.payload).map_err(|_| invalid())?;
+    frame.payload = into_payload(payload);
+    frame.frame_type = frame_type;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{DEFAULT_MAX_PAYLOAD_SIZE, FRAME_TYPE_DEVICE_INFO};
+    #[test]
+    fn it_round_trips_compressible_payloads() {
+        let original = Frame::new(FRAME_TYPE_DEVICE_INFO, 2, vec![7; 4096]);
+        let mut frame = original.clone();
+        compress(&mut frame);
+        assert_eq!(frame.frame_type, FRAME_TYPE_DEVICE_INFO | FLAG_COMPRESSED);
+        assert!(frame.payload.len() < 4096);
+        decompress(&mut frame, DEFAULT_MAX_PAYLOAD_SIZE).unwrap();
+        assert_eq!(frame, original);
+    }
+    #[test]
+    fn it_refuses_to_inflate_past_the_limit() {
+        let mut frame = Frame::new(FRAME_TYPE_DEVICE_INFO, 2, vec![7; 4096]);
+        compress(&mut frame);
+        assert!(decompress(&mut frame, 1024).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! Runs a frame read loop, handing each frame to the handler registered for its type
+use super::{Frame, FrameReader, FRAME_TYPE_DEVICE_INFO, FRAME_TYPE_PING, FRAME_TYPE_PONG};
+use super::{DEFAULT_MAX_PAYLOAD_SIZE, FRAME_TYPE_TEXT_MSG};
+use crate::Result;
+use std::io::{Read, Write};
+use std::ops::RangeInclusive;
+
+/// Handler for a range of frame types, returning a frame to reply with
+type Handler<'a> = Box<dyn FnMut(&Frame) -> Result<Option<Frame>> + 'a>;
+
+/// Calls handlers registered per frame type for each frame read from a channel
+///
+/// Pings are answered with a pong automatically, unless turned off with
+/// [`FrameDispatcher::auto_pong`]. Frames without a handler are logged & dropped.
+pub struct FrameDispatcher<'a> {
+    handlers: Vec<(RangeInclusive<u32>, Handler<'a>)>,
+    auto_pong: bool,
+    max_payload: u32,
+}
+impl Default for FrameDispatcher<'_> {
+    fn default() -> Self {
+        FrameDispatcher {
+            handlers: Vec::new(),
+            auto_pong: true,
+            max_payload: DEFAULT_MAX_PAYLOAD_SIZE,
+        }
+    }
+}
+impl<'a> FrameDispatcher<'a> {
+    /// Creates a dispatcher without any handlers, answering pings
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Handles frames of the given type, replying with the frame the handler returns
+    ///
+    /// Handlers registered later take precedence over earlier ones for the same type.
+    pub fn on<F>(self, frame_type: u32, handler: F) -> Self
+    where
+        F: FnMut(&Frame) -> Result<Option<Frame>> + 'a,
+    {
+        self.on_range(frame_type..=frame_type, handler)
+    }
+    /// Handles frames with a type in `frame_types`, such as an app's own range of types
+    pub fn on_range<F>(mut self, frame_types: RangeInclusive<u32>, handler: F) -> Self
+    where
+        F: FnMut(&Frame) -> Result<Option<Frame>> + 'a,
+    {
+        self.handlers.push((frame_types, Box::new(handler)));
+        self
+    }
+    /// Handles device info frames, with their plist decoded
+    pub fn on_device_info<F>(self, mut handler: F) -> Self
+    where
+        F: FnMut(plist::Value) -> Result<()> + 'a,
+    {
+        self.on(FRAME_TYPE_DEVICE_INFO, move |frame| {
+            handler(frame.to_plist()?)?;
+            Ok(None)
+        })
+    }
+    /// Handles text message frames, frames that aren't valid text are logged & dropped
+    pub fn on_text<F>(self, mut handler: F) -> Self
+    where
+        F: FnMut(&str) -> Result<()> + 'a,
+    {
+        self.on(FRAME_TYPE_TEXT_MSG, move |frame| {
+            match frame.as_text() {
+                Some(text) => handler(text)?,
+                None => warn!("Invalid text frame of {} bytes", frame.payload.len()),
+            }
+            Ok(None)
+        })
+    }
+    /// Called with the tag of each ping, which still gets answered unless `auto_pong` is off
+    pub fn on_ping<F>(self, mut handler: F) -> Self
+    where
+        F: FnMut(u32) -> Result<()> + 'a,
+    {
+        self.on(FRAME_TYPE_PING, move |frame| {
+            handler(frame.tag)?;
+            Ok(None)
+        })
+    }
+    /// Called with the tag of each pong, answering a ping sent earlier
+    pub fn on_pong<F>(self, mut handler: F) -> Self
+    where
+        F: FnMut(u32) -> Result<()> + 'a,
+    {
+        self.on(FRAME_TYPE_PONG, move |frame| {
+            handler(frame.tag)?;
+            Ok(None)
+        })
+    }
+    /// Sets whether pings get answered with a pong, on by default
+    pub fn auto_pong(mut self, auto_pong: bool) -> Self {
+        self.auto_pong = auto_pong;
+        self
+    }
+    /// Sets the largest payload accepted from the peer, [`DEFAULT_MAX_PAYLOAD_SIZE`] by default
+    pub fn max_payload(mut self, max_payload: u32) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+    /// Hands a single frame to its handler, returning the reply to send, if any
+    ///
+    /// # Errors
+    /// Returns the first error from the handler
+    pub fn dispatch(&mut self, frame: &Frame) -> Result<Option<Frame>> {
+        let handler = self
+            .handlers
+            .iter_mut()
+            .rev()
+            .find(|(types, _)| types.contains(&frame.frame_type));
+        let reply = match handler {
+            Some((_, handler)) => handler(frame)?,
+            None => {
+                debug!("No handler for {:?}", frame);
+                None
+            }
+        };
+        if reply.is_none() && self.auto_pong && frame.frame_type == FRAME_TYPE_PING {
+            return Ok(Some(Frame::pong(frame.tag)));
+        }
+        Ok(reply)
+    }
+    /// Reads frames from `stream` until the peer ends the stream, writing replies back to it
+    ///
+    /// # Errors
+    /// Stops at the first read, write or handler error
+    pub fn run<S>(&mut self, stream: S) -> Result<()>
+    where
+        S: Read + Write,
+    {
+        let mut reader = FrameReader::with_max_payload(stream, self.max_payload);
+        while let Some(frame) = reader.read_frame()? {
+            if let Some(reply) = self.dispatch(&frame)? {
+                reply.write_into(reader.get_mut())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::NO_TAG;
+    use std::io::Cursor;
+    /// Stream reading from a fixed buffer, collecting what's written
+    struct Loopback {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    fn frames(frames: &[Frame]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for frame in frames {
+            frame.write_into(&mut data).unwrap();
+        }
+        data
+    }
+    #[test]
+    fn it_dispatches_by_type() {
+        let mut texts = Vec::new();
+        let mut custom = Vec::new();
+        let mut pings = 0;
+        let input = frames(&[
+            Frame::text("hi"),
+            Frame::ping(4),
+            Frame::new(1000, 9, vec![1, 2]),
+            Frame::new(2000, NO_TAG, vec![]),
+            Frame::end_of_stream(),
+        ]);
+        let mut stream = Loopback {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        FrameDispatcher::new()
+            .on_text(|text| {
+                texts.push(text.to_owned());
+                Ok(())
+            })
+            .on_ping(|_| {
+                pings += 1;
+                Ok(())
+            })
+            .on_range(1000..=1999, |frame| {
+                custom.push(frame.tag);
+                Ok(Some(Frame::new(1001, frame.tag, vec![])))
+            })
+            .run(&mut stream)
+            .unwrap();
+        assert_eq!(texts, vec!["hi"]);
+        assert_eq!(pings, 1);
+        assert_eq!(custom, vec![9]);
+        assert_eq!(
+            stream.output,
+            frames(&[Frame::pong(4), Frame::new(1001, 9, vec![])])
+        );
+    }
+    #[test]
+    fn it_can_leave_pings_unanswered() {
+        let mut dispatcher = FrameDispatcher::new().auto_pong(false);
+        assert_eq!(dispatcher.dispatch(&Frame::ping(1)).unwrap(), None);
+    }
+}
@@ -0,0 +1,53 @@
+//! PeerTalk's frame protocol, spoken by apps using the PeerTalk library on the device
+//!
+//! Each frame is a 16 byte header of big endian `u32`s (version, type, tag & payload size)
+//! followed by the payload. Types below 100 are reserved by PeerTalk, the rest are up to the app;
+//! the constants here are the ones from PeerTalk's example app, which most apps keep using.
+//!
+//! The encoding itself is in `peertalk_proto::frame`, re-exported here, for use without sockets.
+use crate::Payload;
+
+mod capabilities;
+mod channel;
+mod checksum;
+#[cfg(feature = "tokio")]
+mod codec;
+#[cfg(feature = "compression")]
+mod compression;
+mod dispatcher;
+mod mux;
+#[cfg(feature = "encryption")]
+pub mod secure;
+mod sender;
+mod session;
+pub use capabilities::{Capabilities, CHECKSUM_CRC32, COMPRESSION_LZ4};
+pub use capabilities::{FEATURE_ENCRYPTION, FEATURE_MULTIPLEXING, HANDSHAKE_VERSION};
+pub use channel::{FrameChannel, FrameMiddleware, TracingMiddleware};
+#[cfg(feature = "tokio")]
+pub use codec::PtFrameCodec;
+pub use dispatcher::FrameDispatcher;
+pub use mux::{Multiplexer, MuxChannel};
+#[cfg(feature = "encryption")]
+pub use secure::Keypair;
+pub use sender::{FrameSender, TrySendFrameError};
+pub use session::{Keepalive, Session};
+
+pub use crate::protocol::frame::*;
+
+#[cfg(feature = "bytes")]
+pub(crate) fn into_payload(data: Vec<u8>) -> Payload {
+    data.into()
+}
+#[cfg(not(feature = "bytes"))]
+pub(crate) fn into_payload(data: Vec<u8>) -> Payload {
+    data
+}
+/// Payload as a `Vec` to modify, only copied if the payload is shared
+#[cfg(feature = "bytes")]
+pub(crate) fn payload_vec(payload: Payload) -> Vec<u8> {
+    payload.into()
+}
+#[cfg(not(feature = "bytes"))]
+pub(crate) fn payload_vec(payload: Payload) -> Vec<u8> {
+    payload
+}
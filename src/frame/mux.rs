@@ -0,0 +1,427 @@
+//! Multiple logical channels over a single device connection, like PeerTalk's `PTChannel`s
+//!
+//! Channels are identified by the tag of their frames. The host opens channels with odd ids & the
+//! device with even ones, so both sides can open channels without agreeing on ids first. Frames of
+//! other types are logged & dropped.
+use super::{payload_vec, Frame, FrameReader, FrameWriter};
+use super::{FRAME_TYPE_CHANNEL_CLOSE, FRAME_TYPE_CHANNEL_DATA, FRAME_TYPE_CHANNEL_OPEN};
+use crate::{Result, UsbSocket};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+
+/// Largest payload written in a single data frame
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Receiving end of a channel, as seen by the frame reading thread
+struct Route {
+    data: Sender<Vec<u8>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+impl Route {
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// State shared by the multiplexer, its channels & the frame reading thread
+struct Shared {
+    writer: Mutex<FrameWriter<Box<dyn Write + Send>>>,
+    routes: Mutex<HashMap<u32, Route>>,
+}
+impl Shared {
+    fn send(&self, frame: &Frame) -> Result<()> {
+        Ok(self.writer.lock().unwrap().write_frame(frame)?)
+    }
+    /// Registers a channel with the given id, returning its handle
+    fn add_channel(self: &Arc<Self>, id: u32) -> MuxChannel {
+        let (data, receiver) = channel();
+        let waker = Arc::new(Mutex::new(None));
+        let route = Route {
+            data,
+            waker: waker.clone(),
+        };
+        if let Some(old) = self.routes.lock().unwrap().insert(id, route) {
+            warn!("Channel {} opened twice, closing the old one", id);
+            old.wake();
+        }
+        MuxChannel {
+            id,
+            shared: self.clone(),
+            data: receiver,
+            waker,
+            pending: Vec::new(),
+            position: 0,
+        }
+    }
+    /// Ends the channel with the given id, such as when the device closes it
+    fn remove_channel(&self, id: u32) {
+        if let Some(route) = self.routes.lock().unwrap().remove(&id) {
+            route.wake();
+        }
+    }
+    /// Removes a channel's route if it's still the handle's, rather than a channel opened again
+    /// with the same id, returning whether it was
+    fn remove_own_channel(&self, id: u32, waker: &Arc<Mutex<Option<Waker>>>) -> bool {
+        let mut routes = self.routes.lock().unwrap();
+        match routes.get(&id) {
+            Some(route) if Arc::ptr_eq(&route.waker, waker) => {
+                routes.remove(&id);
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Ends every channel, readers see the end of their stream
+    fn close_channels(&self) {
+        for (_, route) in self.routes.lock().unwrap().drain() {
+            route.wake();
+        }
+    }
+}
+
+/// Runs logical channels over a single connection, reading frames on a background thread
+///
+/// Dropping the multiplexer shuts the connection down, ending every channel.
+pub struct Multiplexer {
+    shared: Arc<Shared>,
+    incoming: Mutex<Receiver<MuxChannel>>,
+    next_id: AtomicU32,
+    /// Connection to shut down when closing, None for connections split into halves
+    socket: Option<UsbSocket>,
+}
+impl Multiplexer {
+    /// Starts multiplexing a device connection
+    pub fn new(socket: UsbSocket) -> Result<Self> {
+        let writer = socket.try_clone()?;
+        let closer = socket.try_clone()?;
+        let mut multiplexer = Self::from_halves(socket, writer)?;
+        multiplexer.socket = Some(closer);
+        Ok(multiplexer)
+    }
+    /// Starts multiplexing a connection split into halves, such as the two ends of a pipe
+    ///
+    /// The halves can't be shut down from here, so the reading thread keeps running until the
+    /// reader ends even after the multiplexer is dropped.
+    pub fn from_halves<R, W>(reader: R, writer: W) -> Result<Self>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let shared = Arc::new(Shared {
+            writer: Mutex::new(FrameWriter::new(writer)),
+            routes: Mutex::new(HashMap::new()),
+        });
+        let (accept, incoming) = channel();
+        let thread_shared = shared.clone();
+        std::thread::Builder::new()
+            .name("peertalk-mux".to_owned())
+            .spawn(move || {
+                if let Err(e) = route_frames(FrameReader::new(reader), &thread_shared, accept) {
+                    debug!("Multiplexed connection failed: {}", e);
+                }
+                thread_shared.close_channels();
+            })?;
+        Ok(Multiplexer {
+            shared,
+            incoming: Mutex::new(incoming),
+            next_id: AtomicU32::new(1),
+            socket: None,
+        })
+    }
+    /// Opens a new channel to the device
+    pub fn open_channel(&self) -> Result<MuxChannel> {
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let channel = self.shared.add_channel(id);
+        self.shared
+            .send(&Frame::new(FRAME_TYPE_CHANNEL_OPEN, id, Vec::new()))?;
+        Ok(channel)
+    }
+    /// Waits for the device to open a channel, `None` once the connection is closed
+    pub fn accept(&self) -> Option<MuxChannel> {
+        self.incoming.lock().unwrap().recv().ok()
+    }
+    /// Shuts the connection down, ending every channel & the frame reading thread
+    pub fn close(self) {}
+}
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        self.shared.close_channels();
+        if let Some(socket) = &self.socket {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Reads frames until the connection closes, handing data to the channels
+fn route_frames<R: Read>(
+    mut reader: FrameReader<R>,
+    shared: &Arc<Shared>,
+    accept: Sender<MuxChannel>,
+) -> Result<()> {
+    while let Some(frame) = reader.read_frame()? {
+        let id = frame.tag;
+        match frame.frame_type {
+            // odd ids are the host's, the device opening one would take over a host channel
+            FRAME_TYPE_CHANNEL_OPEN if id % 2 == 1 => {
+                warn!("Device opened channel {} with a host id, ignoring it", id)
+            }
+            FRAME_TYPE_CHANNEL_OPEN => {
+                // nobody accepting isn't an error, the channel is just closed when dropped
+                let _ = accept.send(shared.add_channel(id));
+            }
+            FRAME_TYPE_CHANNEL_DATA => match shared.routes.lock().unwrap().get(&id) {
+                Some(route) => {
+                    let _ = route.data.send(payload_vec(frame.payload));
+                    route.wake();
+                }
+                None => debug!(
+                    "Dropped {} bytes for closed channel {}",
+                    frame.payload.len(),
+                    id
+                ),
+            },
+            FRAME_TYPE_CHANNEL_CLOSE => shared.remove_channel(id),
+            _ => debug!("Dropped frame outside any channel: {:?}", frame),
+        }
+    }
+    Ok(())
+}
+
+/// Logical channel over a multiplexed connection, closed when dropped
+pub struct MuxChannel {
+    id: u32,
+    shared: Arc<Shared>,
+    data: Receiver<Vec<u8>>,
+    /// Woken by the frame reading thread, also telling the channel's route apart from others
+    waker: Arc<Mutex<Option<Waker>>>,
+    pending: Vec<u8>,
+    position: usize,
+}
+impl MuxChannel {
+    /// Id of the channel, the tag of its frames
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    /// Copies out buffered data, returning `None` if there's none left
+    fn read_pending(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.position >= self.pending.len() {
+            return None;
+        }
+        let len = buf.len().min(self.pending.len() - self.position);
+        buf[..len].copy_from_slice(&self.pending[self.position..self.position + len]);
+        self.position += len;
+        Some(len)
+    }
+    fn set_pending(&mut self, data: Vec<u8>) {
+        self.pending = data;
+        self.position = 0;
+    }
+    /// Sends a data frame with up to [`MAX_CHUNK_SIZE`] bytes of `buf`
+    fn send_data(&self, buf: &[u8]) -> Result<usize> {
+        let len = buf.len().min(MAX_CHUNK_SIZE);
+        let frame = Frame::new(FRAME_TYPE_CHANNEL_DATA, self.id, buf[..len].to_vec());
+        self.shared.send(&frame)?;
+        Ok(len)
+    }
+}
+impl std::fmt::Debug for MuxChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MuxChannel").field("id", &self.id).finish()
+    }
+}
+impl Read for MuxChannel {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(len) = self.read_pending(buf) {
+                return Ok(len);
+            }
+            match self.data.recv() {
+                Ok(data) => self.set_pending(data),
+                Err(_) => return Ok(0), // closed
+            }
+        }
+    }
+}
+impl Write for MuxChannel {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.send_data(buf).map_err(into_io_error)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(()) // every write is a frame of its own
+    }
+}
+impl Drop for MuxChannel {
+    fn drop(&mut self) {
+        // a channel the device closed or opened again needs no close from here
+        if self.shared.remove_own_channel(self.id, &self.waker) {
+            let _ = self
+                .shared
+                .send(&Frame::new(FRAME_TYPE_CHANNEL_CLOSE, self.id, Vec::new()));
+        }
+    }
+}
+
+/// Unwraps IO errors, wrapping everything else for the `Read`/`Write` traits
+fn into_io_error(error: crate::Error) -> std::io::Error {
+    match error {
+        crate::Error::ServiceUnavailable(e) => e,
+        e => std::io::Error::other(e),
+    }
+}
+
+/// Reads & writes on async streams; writes still block on the underlying connection
+#[cfg(feature = "tokio")]
+mod io_async {
+    use super::{into_io_error, MuxChannel};
+    use std::pin::Pin;
+    use std::sync::mpsc::TryRecvError;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    impl MuxChannel {
+        /// Takes the next chunk of data, registering to be woken if there's none yet
+        fn try_recv(&self, cx: &mut Context<'_>) -> Result<Vec<u8>, TryRecvError> {
+            match self.data.try_recv() {
+                Err(TryRecvError::Empty) => {
+                    *self.waker.lock().unwrap() = Some(cx.waker().clone());
+                    // data may have arrived before the waker was registered
+                    self.data.try_recv()
+                }
+                result => result,
+            }
+        }
+    }
+    impl AsyncRead for MuxChannel {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            loop {
+                if let Some(len) = this.read_pending(buf.initialize_unfilled()) {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                match this.try_recv(cx) {
+                    Ok(data) => this.set_pending(data),
+                    Err(TryRecvError::Empty) => return Poll::Pending,
+                    Err(TryRecvError::Disconnected) => return Poll::Ready(Ok(())),
+                }
+            }
+        }
+    }
+    impl AsyncWrite for MuxChannel {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            Poll::Ready(self.send_data(buf).map_err(into_io_error))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    #[test]
+    fn it_multiplexes_channels() {
+        let (device, host) = UnixStream::pair().unwrap();
+        let mux = Multiplexer::new(UsbSocket::from(host)).unwrap();
+        let mut device_reader = FrameReader::new(device.try_clone().unwrap());
+        let mut device_writer = FrameWriter::new(device);
+
+        let mut first = mux.open_channel().unwrap();
+        let mut second = mux.open_channel().unwrap();
+        assert_eq!((first.id(), second.id()), (1, 3));
+        first.write_all(b"one").unwrap();
+        let frames: Vec<Frame> = (0..3)
+            .map(|_| device_reader.read_frame().unwrap().unwrap())
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                Frame::new(FRAME_TYPE_CHANNEL_OPEN, 1, vec![]),
+                Frame::new(FRAME_TYPE_CHANNEL_OPEN, 3, vec![]),
+                Frame::new(FRAME_TYPE_CHANNEL_DATA, 1, b"one".to_vec()),
+            ]
+        );
+
+        device_writer
+            .send(FRAME_TYPE_CHANNEL_DATA, 3, b"two".to_vec())
+            .unwrap();
+        device_writer
+            .send(FRAME_TYPE_CHANNEL_CLOSE, 3, vec![])
+            .unwrap();
+        let mut received = Vec::new();
+        second.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"two");
+
+        device_writer
+            .send(FRAME_TYPE_CHANNEL_OPEN, 2, vec![])
+            .unwrap();
+        let accepted = mux.accept().unwrap();
+        assert_eq!(accepted.id(), 2);
+        drop(accepted);
+        assert_eq!(
+            device_reader.read_frame().unwrap().unwrap(),
+            Frame::new(FRAME_TYPE_CHANNEL_CLOSE, 2, vec![])
+        );
+    }
+    #[test]
+    fn it_keeps_host_channels_from_device_opens() {
+        let (device, host) = UnixStream::pair().unwrap();
+        let mux = Multiplexer::new(UsbSocket::from(host)).unwrap();
+        let mut device_reader = FrameReader::new(device.try_clone().unwrap());
+        let mut device_writer = FrameWriter::new(device);
+        let mut channel = mux.open_channel().unwrap();
+        device_reader.read_frame().unwrap().unwrap();
+        device_writer
+            .send(FRAME_TYPE_CHANNEL_OPEN, channel.id(), vec![])
+            .unwrap();
+        device_writer
+            .send(
+                FRAME_TYPE_CHANNEL_DATA,
+                channel.id(),
+                b"still mine".to_vec(),
+            )
+            .unwrap();
+        let mut buffer = [0; 10];
+        channel.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"still mine");
+    }
+    #[test]
+    fn it_shuts_the_connection_down_when_closed() {
+        let (device, host) = UnixStream::pair().unwrap();
+        let mux = Multiplexer::new(UsbSocket::from(host)).unwrap();
+        let mut device_reader = FrameReader::new(device);
+        let mut channel = mux.open_channel().unwrap();
+        device_reader.read_frame().unwrap().unwrap();
+        mux.close();
+        let mut received = Vec::new();
+        channel.read_to_end(&mut received).unwrap();
+        assert!(received.is_empty());
+        assert!(device_reader.read_frame().unwrap().is_none());
+    }
+}
@@ -0,0 +1,152 @@
+//! Encrypted sessions, using the Noise protocol's XX handshake
+//!
+//! Useful when the connection leaves the USB cable, such as over Wi-Fi sync or a remote muxer.
+//! The protocol the app on the device has to speak is:
+//!
+//! 1. Both sides have a long lived X25519 static keypair, see [`Keypair::generate`].
+//! 2. The host (initiator) & device (responder) run `Noise_XX_25519_ChaChaPoly_BLAKE2s`, with each
+//!    handshake message sent as the payload of a [`FRAME_TYPE_SECURE_HANDSHAKE`] frame: host to
+//!    device, device to host, then host to device.
+//! 3. Afterwards payloads are encrypted with the Noise transport keys. A frame carrying an
+//!    encrypted payload has [`FLAG_ENCRYPTED`] set in its type, its type & tag stay readable. The
+//!    payload is split into chunks of at most 65519 bytes, each sent as a big endian `u16` length
+//!    followed by the chunk's Noise message (ciphertext & 16 byte tag).
+//! 4. Encryption applies after compression, checksums apply to the encrypted payload. Frames
+//!    without a payload, like pings, aren't encrypted.
+//!
+//! Either side should check the peer's static key, returned once the handshake completes, against
+//! a key it trusts.
+use super::{into_payload, Frame, InvalidFrame, FLAG_ENCRYPTED, FRAME_TYPE_SECURE_HANDSHAKE};
+use crate::Result;
+use std::convert::TryInto;
+
+/// Noise protocol used for encrypted sessions
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise message
+const MAX_MESSAGE_SIZE: usize = 65535;
+/// Size of the authentication tag on every Noise message
+const TAG_SIZE: usize = 16;
+/// Largest plaintext chunk in a single Noise message
+const MAX_CHUNK_SIZE: usize = MAX_MESSAGE_SIZE - TAG_SIZE;
+
+/// Static X25519 keypair identifying one side of encrypted sessions
+#[derive(Clone)]
+pub struct Keypair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+impl Keypair {
+    /// Generates a new random keypair, store it to keep the same identity across sessions
+    pub fn generate() -> Result<Self> {
+        let keypair = snow::Builder::new(params()).generate_keypair()?;
+        Ok(Keypair {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+    /// Restores a keypair from its private & public keys, 32 bytes each
+    pub fn from_keys(private: Vec<u8>, public: Vec<u8>) -> Self {
+        Keypair { private, public }
+    }
+    /// Private key, keep it secret
+    pub fn private_key(&self) -> &[u8] {
+        &self.private
+    }
+    /// Public key, to give the peer to trust
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+}
+impl std::fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+fn params() -> snow::params::NoiseParams {
+    NOISE_PARAMS.parse().expect("valid Noise parameters")
+}
+
+/// Runs the XX handshake, sending & receiving handshake frames through the given functions
+///
+/// Returns the transport state & the peer's static public key.
+pub(crate) fn handshake<S, R>(
+    keypair: &Keypair,
+    initiator: bool,
+    mut send: S,
+    mut recv: R,
+) -> Result<(snow::TransportState, Vec<u8>)>
+where
+    S: FnMut(Frame) -> Result<()>,
+    R: FnMut() -> Result<Frame>,
+{
+    let builder = snow::Builder::new(params()).local_private_key(&keypair.private);
+    let mut state = if initiator {
+        builder.build_initiator()?
+    } else {
+        builder.build_responder()?
+    };
+    let mut buffer = vec![0; MAX_MESSAGE_SIZE];
+    let mut our_turn = initiator;
+    while !state.is_handshake_finished() {
+        if our_turn {
+            let len = state.write_message(&[], &mut buffer)?;
+            send(Frame::new(
+                FRAME_TYPE_SECURE_HANDSHAKE,
+                super::NO_TAG,
+                buffer[..len].to_vec(),
+            ))?;
+        } else {
+            let frame = recv()?;
+            state.read_message(&frame.payload, &mut buffer)?;
+        }
+        our_turn = !our_turn;
+    }
+    let remote = state
+        .get_remote_static()
+        .map(<[u8]>::to_vec)
+        .unwrap_or_default();
+    Ok((state.into_transport_mode()?, remote))
+}
+
+/// Encrypts the payload in chunks, marking the frame type
+pub(crate) fn encrypt(transport: &mut snow::TransportState, frame: &mut Frame) -> Result<()> {
+    let mut payload = Vec::with_capacity(frame.payload.len() + frame.payload.len() / 32 + 32);
+    let mut buffer = vec![0; MAX_MESSAGE_SIZE];
+    for chunk in frame.payload.chunks(MAX_CHUNK_SIZE) {
+        let len = transport.write_message(chunk, &mut buffer)?;
+        payload.extend_from_slice(&(len as u16).to_be_bytes());
+        payload.extend_from_slice(&buffer[..len]);
+    }
+    frame.payload = into_payload(payload);
+    frame.frame_type |= FLAG_ENCRYPTED;
+    Ok(())
+}
+
+/// Decrypts a payload encrypted by [`encrypt`]
+pub(crate) fn decrypt(transport: &mut snow::TransportState, frame: &mut Frame) -> Result<()> {
+    let frame_type = frame.frame_type & !FLAG_ENCRYPTED;
+    let corrupt = || InvalidFrame::CorruptPayload {
+        frame_type,
+        tag: frame.tag,
+    };
+    let mut payload = Vec::with_capacity(frame.payload.len());
+    let mut buffer = vec![0; MAX_MESSAGE_SIZE];
+    let mut rest = &frame.payload[..];
+    while !rest.is_empty() {
+        let len = rest
+            .get(..2)
+            .map(|len| u16::from_be_bytes(len.try_into().unwrap()) as usize)
+            .ok_or_else(corrupt)?;
+        let message = rest.get(2..2 + len).ok_or_else(corrupt)?;
+        let len = transport.read_message(message, &mut buffer)?;
+        payload.extend_from_slice(&buffer[..len]);
+        rest = &rest[2 + message.len()..];
+    }
+    frame.payload = into_payload(payload);
+    frame.frame_type = frame_type;
+    Ok(())
+}
@@ -0,0 +1,177 @@
+//! Queued frame writing, so producers faster than the connection get backpressure
+use super::Frame;
+use crate::{Error, Result};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+
+/// Work for the writer thread
+enum Command {
+    Frame(Frame),
+    /// Flush, then report back once everything queued before it is written
+    Flush(SyncSender<()>),
+}
+
+/// Why [`FrameSender::try_send`] couldn't queue a frame, handing it back
+#[derive(thiserror::Error, Debug)]
+pub enum TrySendFrameError {
+    /// Queue is full, try again once the connection caught up
+    #[error("frame queue is full")]
+    Full(Frame),
+    /// Writer stopped after an error, see [`FrameSender::send`] for the error
+    #[error("frame writer stopped")]
+    Closed(Frame),
+}
+
+/// Writes frames on a background thread from a bounded queue
+///
+/// Frames are written in the order they're queued & the connection is flushed whenever the queue
+/// runs empty. Clones share the same queue, the writer stops once every clone is dropped.
+#[derive(Clone)]
+pub struct FrameSender {
+    queue: SyncSender<Command>,
+    /// First error the writer failed with, handed to the next caller
+    error: Arc<Mutex<Option<Error>>>,
+}
+impl FrameSender {
+    /// Starts writing frames to `writer`, queueing up to `capacity` frames
+    pub fn new<W>(writer: W, capacity: usize) -> Result<Self>
+    where
+        W: Write + Send + 'static,
+    {
+        let (queue, commands) = sync_channel(capacity);
+        let error = Arc::new(Mutex::new(None));
+        let thread_error = error.clone();
+        std::thread::Builder::new()
+            .name("peertalk-sender".to_owned())
+            .spawn(move || {
+                if let Err(e) = write_frames(BufWriter::new(writer), commands) {
+                    debug!("Frame writer stopped: {}", e);
+                    *thread_error.lock().unwrap() = Some(e);
+                }
+            })?;
+        Ok(FrameSender { queue, error })
+    }
+    /// Queues a frame, blocking while the queue is full
+    ///
+    /// # Errors
+    /// Fails with the writer's error if writing an earlier frame failed
+    pub fn send(&self, frame: Frame) -> Result<()> {
+        self.queue
+            .send(Command::Frame(frame))
+            .map_err(|_| self.take_error())
+    }
+    /// Queues a frame if there's room, without blocking
+    pub fn try_send(&self, frame: Frame) -> std::result::Result<(), TrySendFrameError> {
+        self.queue
+            .try_send(Command::Frame(frame))
+            .map_err(|e| match e {
+                TrySendError::Full(Command::Frame(frame)) => TrySendFrameError::Full(frame),
+                TrySendError::Disconnected(Command::Frame(frame)) => {
+                    TrySendFrameError::Closed(frame)
+                }
+                _ => unreachable!("only frames are sent"),
+            })
+    }
+    /// Waits until every frame queued so far is written & the connection flushed
+    ///
+    /// # Errors
+    /// Fails with the writer's error if writing failed
+    pub fn flush(&self) -> Result<()> {
+        let (done, flushed) = sync_channel(1);
+        self.queue
+            .send(Command::Flush(done))
+            .map_err(|_| self.take_error())?;
+        flushed.recv().map_err(|_| self.take_error())
+    }
+    /// Takes the writer's error, the first caller gets the actual error & later ones a broken pipe
+    fn take_error(&self) -> Error {
+        self.error
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| std::io::Error::from(ErrorKind::BrokenPipe).into())
+    }
+}
+
+/// Writes frames until every sender is dropped
+fn write_frames<W: Write>(mut writer: W, commands: Receiver<Command>) -> Result<()> {
+    let mut next = commands.recv();
+    while let Ok(command) = next {
+        match command {
+            Command::Frame(frame) => frame.write_into(&mut writer)?,
+            Command::Flush(done) => {
+                writer.flush()?;
+                let _ = done.send(());
+            }
+        }
+        next = match commands.try_recv() {
+            Err(TryRecvError::Empty) => {
+                writer.flush()?;
+                commands.recv()
+            }
+            Err(TryRecvError::Disconnected) => break,
+            Ok(command) => Ok(command),
+        };
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameReader;
+    use std::sync::mpsc::Sender;
+    /// Writer that blocks until the test lets it through, reporting what's written
+    struct Gated {
+        gate: Receiver<()>,
+        written: Sender<Vec<u8>>,
+    }
+    impl Write for Gated {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.gate.recv().map_err(|_| ErrorKind::BrokenPipe)?;
+            let _ = self.written.send(buf.to_vec());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    #[test]
+    fn it_applies_backpressure() {
+        let (open, gate) = std::sync::mpsc::channel();
+        let (written, output) = std::sync::mpsc::channel();
+        let sender = FrameSender::new(Gated { gate, written }, 1).unwrap();
+        sender.send(Frame::ping(1)).unwrap();
+        open.send(()).unwrap();
+        output.recv().unwrap();
+        sender.send(Frame::ping(2)).unwrap();
+        // the writer is stuck writing the second frame, so the queue fills up after one more
+        let mut last = 2;
+        while sender.try_send(Frame::ping(last + 1)).is_ok() {
+            last += 1;
+            assert!(last <= 3, "queue isn't bounded");
+        }
+        let flusher = std::thread::spawn(move || sender.flush());
+        for _ in 2..=last {
+            open.send(()).unwrap();
+        }
+        flusher.join().unwrap().unwrap();
+        let data: Vec<u8> = output.try_iter().flatten().collect();
+        let frames: Vec<Frame> = FrameReader::new(&data[..])
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        let expected: Vec<Frame> = (2..=last).map(Frame::ping).collect();
+        assert_eq!(frames, expected);
+    }
+    #[test]
+    fn it_reports_write_errors() {
+        let (open, gate) = std::sync::mpsc::channel::<()>();
+        let (written, _output) = std::sync::mpsc::channel();
+        let sender = FrameSender::new(Gated { gate, written }, 4).unwrap();
+        drop(open);
+        sender.send(Frame::ping(1)).unwrap();
+        assert!(sender.flush().is_err());
+    }
+}
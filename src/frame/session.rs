@@ -0,0 +1,488 @@
+//! Frame session over a device connection, keeping it alive with pings
+use super::{checksum, Capabilities, Frame, FrameReader, FrameWriter, InvalidFrame};
+use super::{FLAG_CHECKSUM, FLAG_COMPRESSED, FLAG_ENCRYPTED};
+use super::{FRAME_TYPE_CAPABILITIES, FRAME_TYPE_PING, FRAME_TYPE_PONG};
+use crate::{Error, Result, UsbSocket};
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often to ping the peer, and how many pings may go unanswered before it's considered dead
+#[derive(Debug, Clone)]
+pub struct Keepalive {
+    interval: Duration,
+    max_missed: u32,
+}
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            interval: Duration::from_secs(5),
+            max_missed: 3,
+        }
+    }
+}
+impl Keepalive {
+    /// Sets how often a ping is sent, 5 seconds by default
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    /// Sets how many pings may go unanswered before giving up on the peer, 3 by default
+    pub fn max_missed(mut self, max_missed: u32) -> Self {
+        self.max_missed = max_missed.max(1);
+        self
+    }
+}
+
+/// Payloads smaller than this aren't worth compressing
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Ping state shared between the session & its ping thread
+#[derive(Default)]
+struct PingState {
+    /// Pings waiting for a pong, oldest first
+    outstanding: Mutex<VecDeque<(u32, Instant)>>,
+    rtt: Mutex<Option<Duration>>,
+    dead: AtomicBool,
+}
+impl PingState {
+    fn pong(&self, tag: u32) {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        if let Some(index) = outstanding.iter().position(|(t, _)| *t == tag) {
+            let (_, sent) = outstanding[index];
+            *self.rtt.lock().unwrap() = Some(sent.elapsed());
+            // a later pong also answers for any earlier ping that got lost
+            outstanding.drain(..=index);
+        }
+    }
+}
+
+/// Frame connection to an app on the device, answering its pings & optionally pinging it
+///
+/// Pings, pongs & capabilities frames are handled by the session, [`Session::recv`] only returns
+/// the app's frames.
+pub struct Session {
+    reader: FrameReader<UsbSocket>,
+    writer: Arc<Mutex<FrameWriter<UsbSocket>>>,
+    pings: Arc<PingState>,
+    /// Frames received while negotiating, returned by `recv` first
+    pending: VecDeque<Frame>,
+    /// Capabilities we answer the peer's capabilities frame with
+    offered: Capabilities,
+    /// Capabilities both sides agreed on
+    agreed: Capabilities,
+    compression_threshold: usize,
+    /// Noise transport keys, once the session is encrypted
+    #[cfg(feature = "encryption")]
+    transport: Option<Mutex<snow::TransportState>>,
+    /// Stops the ping thread when dropped
+    _stop: Option<Sender<()>>,
+}
+impl Session {
+    /// Starts a session without keepalive, pings from the peer are still answered
+    pub fn new(socket: UsbSocket) -> Result<Self> {
+        let writer = FrameWriter::new(socket.try_clone()?);
+        Ok(Session {
+            reader: FrameReader::new(socket),
+            writer: Arc::new(Mutex::new(writer)),
+            pings: Arc::new(PingState::default()),
+            pending: VecDeque::new(),
+            offered: Capabilities::supported(),
+            agreed: Capabilities::default(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            #[cfg(feature = "encryption")]
+            transport: None,
+            _stop: None,
+        })
+    }
+    /// Starts a session pinging the peer per `keepalive` on a background thread
+    ///
+    /// Once too many pings go unanswered the connection is shut down & [`Session::recv`] fails
+    /// with [`Error::Timeout`].
+    pub fn with_keepalive(socket: UsbSocket, keepalive: Keepalive) -> Result<Self> {
+        let shutdown = socket.try_clone()?;
+        let mut session = Self::new(socket)?;
+        let (stop, stopped) = channel::<()>();
+        let writer = session.writer.clone();
+        let pings = session.pings.clone();
+        std::thread::Builder::new()
+            .name("peertalk-keepalive".to_owned())
+            .spawn(move || {
+                let mut tag = 0u32;
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(keepalive.interval)
+                {
+                    let missed = pings.outstanding.lock().unwrap().len();
+                    if missed >= keepalive.max_missed as usize {
+                        warn!("Peer missed {} pings, closing the connection", missed);
+                        pings.dead.store(true, Ordering::SeqCst);
+                        let _ = shutdown.shutdown(Shutdown::Both);
+                        return;
+                    }
+                    tag = tag.wrapping_add(1).max(1);
+                    pings
+                        .outstanding
+                        .lock()
+                        .unwrap()
+                        .push_back((tag, Instant::now()));
+                    if let Err(e) = writer.lock().unwrap().write_frame(&Frame::ping(tag)) {
+                        debug!("Failed to send ping: {}", e);
+                        return;
+                    }
+                }
+            })?;
+        session._stop = Some(stop);
+        Ok(session)
+    }
+    /// Waits for the next frame from the peer, `None` once it ends the stream
+    ///
+    /// # Errors
+    /// Fails with [`Error::Timeout`] if the peer stopped answering pings, or if reading fails
+    pub fn recv(&mut self) -> Result<Option<Frame>> {
+        if let Some(frame) = self.pending.pop_front() {
+            return Ok(Some(frame));
+        }
+        loop {
+            let frame = match self.read()? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            if frame.frame_type == FRAME_TYPE_CAPABILITIES {
+                // the peer started negotiating
+                self.agree(&Capabilities::from_frame(&frame)?);
+                self.send_raw(&self.offered.to_frame()?)?;
+            } else {
+                return Ok(Some(frame));
+            }
+        }
+    }
+    /// Reads the next frame, answering pings & decoding the payload
+    fn read(&mut self) -> Result<Option<Frame>> {
+        loop {
+            let mut frame = match self.reader.read_frame() {
+                _ if self.is_dead() => return Err(Error::Timeout),
+                Ok(Some(frame)) => frame,
+                other => return Ok(other?),
+            };
+            // checksums cover the payload as sent, so they're checked before decompressing
+            if frame.frame_type & FLAG_CHECKSUM != 0 {
+                checksum::verify(&mut frame)?;
+            }
+            if frame.frame_type & FLAG_ENCRYPTED != 0 {
+                self.decrypt(&mut frame)?;
+            }
+            if frame.frame_type & FLAG_COMPRESSED != 0 {
+                self.decompress(&mut frame)?;
+            }
+            match frame.frame_type {
+                FRAME_TYPE_PING => self.send_raw(&Frame::pong(frame.tag))?,
+                FRAME_TYPE_PONG => self.pings.pong(frame.tag),
+                _ => return Ok(Some(frame)),
+            }
+        }
+    }
+    /// Sends a frame to the peer, compressed & checksummed if both sides agreed on that, and
+    /// encrypted if the session is
+    pub fn send(&self, frame: &Frame) -> Result<()> {
+        let checksums = !self.agreed.checksums().is_empty();
+        #[cfg(feature = "compression")]
+        let compress = self.compresses(frame);
+        #[cfg(not(feature = "compression"))]
+        let compress = false;
+        // held until the frame is written, so frames go out in the order of their nonces
+        #[cfg(feature = "encryption")]
+        let mut transport = match &self.transport {
+            Some(transport) if !frame.payload.is_empty() => Some(transport.lock().unwrap()),
+            _ => None,
+        };
+        #[cfg(feature = "encryption")]
+        let encrypt = transport.is_some();
+        #[cfg(not(feature = "encryption"))]
+        let encrypt = false;
+        if !checksums && !compress && !encrypt {
+            return self.send_raw(frame);
+        }
+        let mut frame = frame.clone();
+        #[cfg(feature = "compression")]
+        if compress {
+            super::compression::compress(&mut frame);
+        }
+        #[cfg(feature = "encryption")]
+        if let Some(transport) = transport.as_mut() {
+            super::secure::encrypt(transport, &mut frame)?;
+        }
+        if checksums {
+            checksum::append(&mut frame);
+        }
+        self.send_raw(&frame)
+    }
+    /// Sends a frame as is, like capabilities frames the peer must read before agreeing
+    fn send_raw(&self, frame: &Frame) -> Result<()> {
+        Ok(self.writer.lock().unwrap().write_frame(frame)?)
+    }
+    /// Exchanges capabilities with the peer, returning the features both sides support
+    ///
+    /// Both sides send their capabilities frame & wait for the other's, so either side may start
+    /// negotiating. Frames the peer sent before its capabilities are kept for [`Session::recv`].
+    ///
+    /// # Errors
+    /// Fails if the connection closes before the peer's capabilities arrive
+    pub fn negotiate(&mut self, ours: Capabilities) -> Result<Capabilities> {
+        self.offered = ours;
+        self.send_raw(&self.offered.to_frame()?)?;
+        loop {
+            match self.read()? {
+                Some(frame) if frame.frame_type == FRAME_TYPE_CAPABILITIES => {
+                    self.agree(&Capabilities::from_frame(&frame)?);
+                    return Ok(self.agreed.clone());
+                }
+                Some(frame) => self.pending.push_back(frame),
+                None => {
+                    let e = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+    /// Encrypts the session as the initiator, returning the peer's static public key
+    ///
+    /// Hosts normally initiate, see [`secure`](super::secure) for the protocol. The returned key
+    /// should be checked against a key the app trusts.
+    ///
+    /// # Errors
+    /// Fails if the handshake fails or the connection closes during it
+    #[cfg(feature = "encryption")]
+    pub fn secure(&mut self, keypair: &super::Keypair) -> Result<Vec<u8>> {
+        self.handshake(keypair, true)
+    }
+    /// Encrypts the session as the responder, returning the peer's static public key
+    #[cfg(feature = "encryption")]
+    pub fn accept_secure(&mut self, keypair: &super::Keypair) -> Result<Vec<u8>> {
+        self.handshake(keypair, false)
+    }
+    #[cfg(feature = "encryption")]
+    fn handshake(&mut self, keypair: &super::Keypair, initiator: bool) -> Result<Vec<u8>> {
+        use super::FRAME_TYPE_SECURE_HANDSHAKE;
+        let writer = self.writer.clone();
+        let send = |frame: Frame| Ok(writer.lock().unwrap().write_frame(&frame)?);
+        let recv = || loop {
+            match self.read()? {
+                Some(frame) if frame.frame_type == FRAME_TYPE_SECURE_HANDSHAKE => return Ok(frame),
+                Some(frame) => self.pending.push_back(frame),
+                None => {
+                    let e = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+                    return Err(e.into());
+                }
+            }
+        };
+        let (transport, remote) = super::secure::handshake(keypair, initiator, send, recv)?;
+        self.transport = Some(Mutex::new(transport));
+        Ok(remote)
+    }
+    /// Same as [`Session::negotiate`], giving up after `timeout` for peers that don't know the
+    /// handshake
+    ///
+    /// Older apps never send capabilities, so once the timeout passes they're assumed to support
+    /// version 0 & no optional features, and the session carries on without them.
+    ///
+    /// # Errors
+    /// Fails if the connection closes during the handshake
+    pub fn negotiate_with_timeout(
+        &mut self,
+        ours: Capabilities,
+        timeout: Duration,
+    ) -> Result<Capabilities> {
+        self.reader.get_ref().set_read_timeout(Some(timeout))?;
+        let result = self.negotiate(ours);
+        self.reader.get_ref().set_read_timeout(None)?;
+        match result {
+            Err(Error::ServiceUnavailable(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                info!("Peer didn't answer the handshake, assuming it predates it");
+                self.agree(&Capabilities::default());
+                Ok(self.agreed.clone())
+            }
+            result => result,
+        }
+    }
+    /// Features both sides agreed on, nothing until negotiated
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.agreed
+    }
+    /// Sets the smallest payload that gets compressed, 1 KiB by default
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = threshold;
+    }
+    fn agree(&mut self, peer: &Capabilities) {
+        self.agreed = self.offered.common(peer);
+        debug!("Agreed on session capabilities: {:?}", self.agreed);
+    }
+    #[cfg(feature = "compression")]
+    fn compresses(&self, frame: &Frame) -> bool {
+        frame.payload.len() >= self.compression_threshold
+            && self.agreed.compression().first().map(String::as_str) == Some(super::COMPRESSION_LZ4)
+    }
+    /// Decompresses a payload, which is only valid if both sides agreed on compression
+    fn decompress(&self, frame: &mut Frame) -> Result<()> {
+        #[cfg(feature = "compression")]
+        if !self.agreed.compression().is_empty() {
+            return super::compression::decompress(frame, super::DEFAULT_MAX_PAYLOAD_SIZE);
+        }
+        Err(InvalidFrame::CorruptPayload {
+            frame_type: frame.frame_type & !FLAG_COMPRESSED,
+            tag: frame.tag,
+        }
+        .into())
+    }
+    /// Decrypts a payload, which is only valid once the session is encrypted
+    fn decrypt(&self, frame: &mut Frame) -> Result<()> {
+        #[cfg(feature = "encryption")]
+        if let Some(transport) = &self.transport {
+            return super::secure::decrypt(&mut transport.lock().unwrap(), frame);
+        }
+        Err(InvalidFrame::CorruptPayload {
+            frame_type: frame.frame_type & !FLAG_ENCRYPTED,
+            tag: frame.tag,
+        }
+        .into())
+    }
+    /// Round trip time of the last answered ping, `None` until a pong arrives
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.pings.rtt.lock().unwrap()
+    }
+    /// Number of pings sent that haven't been answered yet
+    pub fn unanswered_pings(&self) -> usize {
+        self.pings.outstanding.lock().unwrap().len()
+    }
+    /// Checks if the peer stopped answering pings
+    pub fn is_dead(&self) -> bool {
+        self.pings.dead.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    #[test]
+    fn it_pings_and_tracks_rtt() {
+        let (device, host) = UnixStream::pair().unwrap();
+        let keepalive = Keepalive::default().interval(Duration::from_millis(50));
+        let mut session = Session::with_keepalive(UsbSocket::from(host), keepalive).unwrap();
+        let mut device_reader = FrameReader::new(device.try_clone().unwrap());
+        let mut device_writer = FrameWriter::new(device);
+        let ping = device_reader.read_frame().unwrap().unwrap();
+        assert_eq!(ping.frame_type, FRAME_TYPE_PING);
+        device_writer.write_frame(&Frame::pong(ping.tag)).unwrap();
+        device_writer.write_frame(&Frame::ping(7)).unwrap();
+        device_writer.write_frame(&Frame::text("hi")).unwrap();
+        assert_eq!(session.recv().unwrap(), Some(Frame::text("hi")));
+        assert!(session.rtt().is_some());
+        // the pong for our ping, possibly after more keepalive pings
+        let pong = loop {
+            let frame = device_reader.read_frame().unwrap().unwrap();
+            if frame.frame_type == FRAME_TYPE_PONG {
+                break frame;
+            }
+        };
+        assert_eq!(pong.tag, 7);
+    }
+    #[cfg(feature = "compression")]
+    #[test]
+    fn it_compresses_once_negotiated() {
+        use crate::frame::{COMPRESSION_LZ4, FRAME_TYPE_CAPABILITIES};
+        let (device, host) = UnixStream::pair().unwrap();
+        let mut session = Session::new(UsbSocket::from(host)).unwrap();
+        let mut device_reader = FrameReader::new(device.try_clone().unwrap());
+        let mut device_writer = FrameWriter::new(device);
+        let peer = Capabilities::default().with_compression([COMPRESSION_LZ4]);
+        device_writer.write_frame(&Frame::text("early")).unwrap();
+        device_writer
+            .write_frame(&peer.to_frame().unwrap())
+            .unwrap();
+        let agreed = session.negotiate(Capabilities::supported()).unwrap();
+        assert_eq!(agreed.compression(), [COMPRESSION_LZ4]);
+        let offer = device_reader.read_frame().unwrap().unwrap();
+        assert_eq!(offer.frame_type, FRAME_TYPE_CAPABILITIES);
+        assert_eq!(session.recv().unwrap(), Some(Frame::text("early")));
+
+        let big = Frame::new(200, 1, vec![0; 8192]);
+        session.send(&big).unwrap();
+        let mut sent = device_reader.read_frame().unwrap().unwrap();
+        assert_eq!(sent.frame_type, 200 | FLAG_COMPRESSED);
+        crate::frame::compression::decompress(&mut sent, u32::MAX).unwrap();
+        assert_eq!(sent, big);
+    }
+    #[test]
+    fn it_checksums_once_negotiated() {
+        use crate::frame::{CHECKSUM_CRC32, FRAME_TYPE_TEXT_MSG};
+        let (device, host) = UnixStream::pair().unwrap();
+        let mut session = Session::new(UsbSocket::from(host)).unwrap();
+        let mut device_reader = FrameReader::new(device.try_clone().unwrap());
+        let mut device_writer = FrameWriter::new(device);
+        let peer = Capabilities::default().with_checksums([CHECKSUM_CRC32]);
+        device_writer
+            .write_frame(&peer.to_frame().unwrap())
+            .unwrap();
+        let agreed = session.negotiate(Capabilities::supported()).unwrap();
+        assert_eq!(agreed.checksums(), [CHECKSUM_CRC32]);
+        device_reader.read_frame().unwrap(); // our capabilities
+        session.send(&Frame::text("hi")).unwrap();
+        let sent = device_reader.read_frame().unwrap().unwrap();
+        assert_eq!(sent.frame_type, FRAME_TYPE_TEXT_MSG | FLAG_CHECKSUM);
+
+        let mut payload = sent.payload.to_vec();
+        payload[4] = b'H';
+        let garbled = Frame::new(sent.frame_type, sent.tag, payload);
+        device_writer.write_frame(&garbled).unwrap();
+        assert!(matches!(
+            session.recv(),
+            Err(Error::InvalidFrame(InvalidFrame::ChecksumMismatch { .. }))
+        ));
+    }
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn it_encrypts_sessions() {
+        use crate::frame::Keypair;
+        let (device, host) = UnixStream::pair().unwrap();
+        let host_keys = Keypair::generate().unwrap();
+        let device_keys = Keypair::generate().unwrap();
+        let device_public = device_keys.public_key().to_vec();
+        let peer = std::thread::spawn(move || {
+            let mut session = Session::new(UsbSocket::from(device)).unwrap();
+            let remote = session.accept_secure(&device_keys).unwrap();
+            let frame = session.recv().unwrap().unwrap();
+            session.send(&frame).unwrap(); // echo
+            remote
+        });
+        let mut session = Session::new(UsbSocket::from(host)).unwrap();
+        assert_eq!(session.secure(&host_keys).unwrap(), device_public);
+        let big = Frame::new(200, 1, (0..100_000).map(|i| i as u8).collect());
+        session.send(&big).unwrap();
+        assert_eq!(session.recv().unwrap(), Some(big));
+        assert_eq!(peer.join().unwrap(), host_keys.public_key());
+    }
+    #[test]
+    fn it_assumes_old_peers_skip_the_handshake() {
+        let (_device, host) = UnixStream::pair().unwrap();
+        let mut session = Session::new(UsbSocket::from(host)).unwrap();
+        let agreed = session
+            .negotiate_with_timeout(Capabilities::supported(), Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(agreed, Capabilities::default());
+    }
+    #[test]
+    fn it_gives_up_on_unresponsive_peers() {
+        let (_device, host) = UnixStream::pair().unwrap();
+        let keepalive = Keepalive::default()
+            .interval(Duration::from_millis(5))
+            .max_missed(2);
+        let mut session = Session::with_keepalive(UsbSocket::from(host), keepalive).unwrap();
+        assert!(matches!(session.recv(), Err(Error::Timeout)));
+        assert!(session.is_dead());
+    }
+}
@@ -1,199 +1,344 @@
 //! Crate to handle establishing network connections over USB to apple devices
-#![forbid(missing_docs)]
-use std::cell::RefCell;
-
+#![cfg_attr(not(feature = "uniffi"), forbid(missing_docs))]
+// UniFFI's generated scaffolding allows missing docs, which forbid wouldn't let it
+#![cfg_attr(feature = "uniffi", deny(missing_docs))]
 #[macro_use]
 extern crate log;
 
-use std::collections::VecDeque;
 #[cfg(target_os = "windows")]
-use std::net::TcpStream;
+mod amds;
+#[cfg(feature = "tokio")]
+mod async_socket;
+#[cfg(feature = "uniffi")]
+pub mod bindings;
+#[cfg(target_os = "windows")]
+pub use amds::MobileDeviceServiceState;
+pub mod capture;
+mod client;
+mod connect;
+mod device;
+mod driver;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod forward;
+pub mod frame;
+mod listener;
+pub mod lockdown;
+mod muxer;
+#[cfg(feature = "json")]
+pub mod ndjson;
+mod network;
+mod pair_record;
+#[cfg(feature = "pairing")]
+pub mod pairing;
+mod registry;
+mod retry;
+pub mod services;
+mod socket;
+mod stream;
+#[cfg(feature = "system-pair-records")]
+pub mod system_pair_record;
+pub mod telemetry;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod transport;
+mod wait;
+#[cfg(feature = "websocket")]
+mod websocket;
+#[cfg(feature = "tokio")]
+pub use async_socket::{AsyncUsbReadHalf, AsyncUsbSocket, AsyncUsbWriteHalf};
+pub use client::{MuxerCapabilities, MuxerClient};
+pub use connect::ConnectOptions;
+pub use device::Device;
+use driver::Driver;
+pub use forward::{forward_port, forward_port_with, ForwardTarget, PortForwarder};
 #[cfg(not(target_os = "windows"))]
-use std::os::unix::net::UnixStream;
-
+pub use forward::{forward_unix_socket, forward_unix_socket_with};
+pub use listener::{DeviceFilter, DeviceListener, DeviceListenerBuilder, ShutdownHandle};
+pub use lockdown::{
+    connect_to_service, device_details, DeviceDetails, ExtendedDeviceInfo, ServiceStream,
+};
 #[cfg(target_os = "windows")]
-const WINDOWS_TCP_PORT: u16 = 27015;
-
-mod protocol;
+pub use muxer::detect_muxer_port;
+pub use muxer::{MuxerAddress, MuxerConfig, MuxerUnavailableReason, SOCKET_ADDRESS_ENV};
+pub use network::{connect_to_network_device, NetworkStream};
+pub use pair_record::{
+    delete_pair_record, is_paired, list_devices, list_pair_records, read_pair_record,
+    read_system_buid, save_pair_record, PairRecord, PairRecordEntry,
+};
+use peertalk_proto as protocol;
+/// Low-level muxer protocol, for sending messages the rest of the crate doesn't cover yet
+///
+/// These mirror usbmuxd's wire format, so they're less stable than the rest of the API and may
+/// change in minor releases as the protocol support grows.
 pub use protocol::{
-    DeviceAttachedInfo, DeviceConnectionType, DeviceEvent, DeviceId, ProductType, ProtocolError,
+    binary, Command, MuxerConnection, MuxerEvent, Packet, PacketBuffer, PacketType, Protocol,
+    ReplyCode, ResultMessage,
 };
-use protocol::{Packet, PacketType, Protocol};
+pub use protocol::{
+    ConnectionSpeed, DeviceAttachedInfo, DeviceConnectionType, DeviceEvent, DeviceId, ListenerInfo,
+    Payload, ProductType, ProtocolError, Udid,
+};
+pub use registry::{DeviceRegistry, IdentityEvent};
+pub use retry::{ReconnectPolicy, RetryPolicy};
+pub use socket::UsbSocket;
+use std::time::{Duration, Instant};
+pub use stream::{UsbReadHalf, UsbStream, UsbWriteHalf};
+pub use transport::{MuxerTransport, TransportStream};
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
+#[cfg(feature = "tokio")]
+pub use wait::wait_for_device_async;
+pub use wait::{
+    connect_to_first_device, connect_when_ready, connect_when_ready_with_progress, wait_for_device,
+    wait_for_muxer, wait_for_pairing, ConnectProgress,
+};
+#[cfg(feature = "websocket")]
+pub use websocket::{bridge_websocket, bridge_websocket_with, BridgeMode};
 
 /// Error for device listener etc
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// Error with usbmuxd protocol
     #[error("protocol error: {0}")]
-    ProtocolError(#[from] protocol::ProtocolError),
-    /// usbmuxd or Apple Mobile Service isn't available or installed
-    #[error("Apple Mobile Device service (usbmuxd) likely not available: {0}")]
+    ProtocolError(protocol::ProtocolError),
+    /// IO error on a muxer or device connection
+    #[error("I/O error: {0}")]
     ServiceUnavailable(#[from] std::io::Error),
+    /// usbmuxd or Apple Mobile Device Service couldn't be reached, with why
+    #[error("Apple Mobile Device service (usbmuxd) {reason}: {source}")]
+    MuxerUnavailable {
+        /// What the error connecting means, such as the muxer not running
+        reason: MuxerUnavailableReason,
+        /// Error connecting to the muxer
+        source: std::io::Error,
+    },
+    /// Apple Mobile Device Service couldn't be reached on Windows, with the service's state
+    #[cfg(target_os = "windows")]
+    #[error("Apple Mobile Device Service is {state}: {source}")]
+    MobileDeviceServiceUnavailable {
+        /// State the service manager reports for the service
+        state: MobileDeviceServiceState,
+        /// Error connecting to the service
+        source: std::io::Error,
+    },
     /// Error when registrering for device events failed
-    #[error("error registering device listener: code {0}")]
-    FailedToListen(i64),
+    #[error("error registering device listener: {0}")]
+    FailedToListen(protocol::ReplyCode),
     /// Error establishing network connection to device
-    #[error("error connecting to device: {0}")]
-    ConnectionRefused(i64),
+    #[error(
+        "error connecting to device {device_id}{} port {port}: {code}, {}",
+        .udid.as_ref().map(|udid| format!(" ({})", udid)).unwrap_or_default(),
+        .code.meaning()
+    )]
+    ConnectionRefused {
+        /// Device the connection was for
+        device_id: DeviceId,
+        /// UDID of the device, when connecting through a [`Device`] that knows it
+        udid: Option<Udid>,
+        /// Device port the connection was for
+        port: u16,
+        /// Why the muxer refused, [`ReplyCode::ConnectionRefused`] if nothing listens on the port
+        code: protocol::ReplyCode,
+    },
+    /// Device's lockdownd replied with an error
+    #[error("lockdown error: {0}")]
+    LockdownError(String),
+    /// usbmuxd failed a pair record request, unknown codes are [`ProtocolError::InvalidReplyCode`]
+    #[error("pair record request failed: {0}, {}", .0.meaning())]
+    PairRecordError(protocol::ReplyCode),
+    /// Generating keys or certificates for pairing failed
+    #[cfg(feature = "pairing")]
+    #[error("failed to generate pairing keys: {0}")]
+    KeyGenerationError(String),
+    /// Device hasn't trusted this host, so there's no pair record for it
+    #[error("device isn't paired with this host")]
+    NotPaired,
+    /// Device requires a TLS session, which needs the `tls` feature
+    #[error("device requires a TLS session, which needs the tls feature")]
+    TlsRequired,
+    /// Error setting up or using a TLS session
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {0}")]
+    TlsError(#[from] rustls::Error),
+    /// Encrypted session handshake, encryption or decryption failed
+    #[cfg(feature = "encryption")]
+    #[error("encryption error: {0}")]
+    EncryptionError(#[from] snow::Error),
+    /// WebSocket handshake or protocol error on a bridged connection
+    #[cfg(feature = "websocket")]
+    #[error("websocket error: {0}")]
+    WebSocket(Box<tungstenite::Error>),
+    /// No attached device has the requested UDID
+    #[error("device {0} isn't attached")]
+    DeviceNotFound(String),
+    /// Device service replied with an error
+    #[error("device service error: {0}")]
+    ServiceError(String),
+    /// AFC file service failed
+    #[cfg(feature = "afc")]
+    #[error("AFC error: {0}")]
+    AfcError(services::afc::AfcStatus),
+    /// Peer sent a PeerTalk frame that isn't valid
+    #[error("invalid frame: {0}")]
+    InvalidFrame(#[from] frame::InvalidFrame),
+    /// Gave up waiting for a device or service before the deadline
+    #[error("timed out waiting for device")]
+    Timeout,
 }
 
-/// Alias for any of this crate's results
-pub type Result<T> = ::std::result::Result<T, Error>;
-
-/// Aliases UsbSocket to std::net::TcpStream on Windows
-#[cfg(target_os = "windows")]
-pub type UsbSocket = TcpStream;
-/// Aliases UsbSocket to std::os::unix::net::UnixStream on linux/macOS
-#[cfg(not(target_os = "windows"))]
-pub type UsbSocket = UnixStream;
-
-/// Connects to usbmuxd (linux oss lib or macOS's built-in muxer)
-#[cfg(not(target_os = "windows"))]
-fn connect_unix() -> Result<UsbSocket> {
-    Ok(UnixStream::connect("/var/run/usbmuxd")?)
-}
-/// Connect's to Apple Mobile Support service on Windows if available (TCP 27015)
-#[cfg(target_os = "windows")]
-fn connect_windows() -> Result<UsbSocket> {
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), WINDOWS_TCP_PORT);
-    Ok(TcpStream::connect_timeout(
-        &addr,
-        std::time::Duration::from_secs(5),
-    )?)
+impl Error {
+    /// Checks if connecting failed as nothing on the device is listening on the port (yet), so
+    /// retrying may succeed once the app on the device is up
+    pub fn is_port_not_open(&self) -> bool {
+        matches!(
+            self,
+            Error::ConnectionRefused {
+                code: ReplyCode::ConnectionRefused,
+                ..
+            }
+        )
+    }
 }
 
-fn send_payload(
-    socket: &mut UsbSocket,
-    packet_type: PacketType,
-    protocol: Protocol,
-    payload: Vec<u8>,
-) -> Result<()> {
-    let packet = Packet::new(protocol, packet_type, 0, payload);
-    Ok(packet.write_into(socket)?)
-}
-/// Creates a network connection over USB to given device & port
-pub fn connect_to_device(device_id: protocol::DeviceId, port: u16) -> Result<UsbSocket> {
-    #[cfg(target_os = "windows")]
-    let mut socket = connect_windows()?;
-    #[cfg(not(target_os = "windows"))]
-    let mut socket = connect_unix()?;
-    let command = protocol::Command::connect(port, device_id);
-    let payload = command.to_bytes();
-    send_payload(
-        &mut socket,
-        PacketType::PlistPayload,
-        Protocol::Plist,
-        payload,
-    )?;
-    let packet = Packet::from_reader(&mut socket)?;
-    let cursor = std::io::Cursor::new(&packet.data[..]);
-    let res = protocol::ResultMessage::from_reader(cursor)?;
-    if res.0 != 0 {
-        return Err(Error::ConnectionRefused(res.0));
+impl From<protocol::ProtocolError> for Error {
+    /// Keeps frame & IO errors in their own variants, as when they came from this crate
+    fn from(e: protocol::ProtocolError) -> Self {
+        match e {
+            protocol::ProtocolError::InvalidFrame(e) => Error::InvalidFrame(e),
+            protocol::ProtocolError::IoError(e) => Error::ServiceUnavailable(e),
+            e => Error::ProtocolError(e),
+        }
     }
+}
 
-    Ok(socket)
+#[cfg(feature = "websocket")]
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(e))
+    }
 }
 
-/// Listens for iOS devices connecting over USB via Apple Mobile Support/usbmuxd
-pub struct DeviceListener {
-    #[cfg(target_os = "windows")]
-    socket: RefCell<TcpStream>,
-    #[cfg(not(target_os = "windows"))]
-    socket: RefCell<UnixStream>,
-    events: RefCell<VecDeque<DeviceEvent>>,
+/// Alias for any of this crate's results
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Creates a network connection over USB to given device & port
+pub fn connect_to_device(device_id: protocol::DeviceId, port: u16) -> Result<UsbStream> {
+    connect_to_device_with(&ConnectOptions::default(), device_id, port)
 }
-impl DeviceListener {
-    /// Produces a new device listener, registering with usbmuxd/apple mobile support service
-    ///
-    /// # Errors
-    /// Can produce an error, most commonly when the mobile service isn't available. It should be available on macOS,
-    /// but on Windows it's only available if Apple Mobile Support is installed, typically via iTunes.
-    pub fn new() -> Result<Self> {
-        #[cfg(target_os = "windows")]
-        let socket = connect_windows()?;
-        #[cfg(not(target_os = "windows"))]
-        let socket = connect_unix()?;
-        let listener = DeviceListener {
-            socket: RefCell::new(socket),
-            events: RefCell::new(VecDeque::new()),
-        };
-        listener.start_listen()?;
-        listener.socket.borrow_mut().set_nonblocking(true)?;
-        Ok(listener)
-    }
-    /// Receives an event, None if there's no pending events at this time
-    pub fn next_event(&self) -> Option<DeviceEvent> {
-        self.drain_events();
-        self.events.borrow_mut().pop_front()
-    }
-    fn drain_events(&self) {
-        // TODO: better way read on demand? maybe just thread it?
-        use std::io::Read;
-        let mut retries_left = 5;
-        let mut data: Vec<u8> = Vec::with_capacity(10_000);
-        let full_data = loop {
-            let mut buf = [0; 4096];
-            match (*self.socket.borrow_mut()).read(&mut buf) {
-                Ok(bytes) => {
-                    data.extend_from_slice(&buf[0..bytes]);
-                }
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        retries_left -= 1;
-                        std::thread::sleep(std::time::Duration::from_millis(100));
-                    }
-                }
-            }
-            if retries_left == 0 {
-                break data;
-            }
-        };
-        let mut cursor = std::io::Cursor::new(&full_data[..]);
-        loop {
-            if cursor.position() == full_data.len() as u64 {
-                break;
-            }
-            match Packet::from_reader(&mut cursor) {
-                Ok(packet) => {
-                    let msg = DeviceEvent::from_vec(packet.data).unwrap();
-                    self.events.borrow_mut().push_back(msg);
-                }
-                Err(ProtocolError::IoError(e)) => match e.kind() {
-                    std::io::ErrorKind::WouldBlock => {
-                        break;
-                    }
-                    _ => {
-                        error!("IO Error: {}", e);
-                        break;
-                    }
-                },
-                Err(e) => {
-                    error!("Error receiving events: {}", e);
-                    break;
-                }
-            }
+/// Same as [`connect_to_device`], using the muxer, retry policy & socket options from `options`
+pub fn connect_to_device_with(
+    options: &ConnectOptions,
+    device_id: protocol::DeviceId,
+    port: u16,
+) -> Result<UsbStream> {
+    let deadline = options.deadline(Instant::now());
+    let connect = || connect_once(options.muxer_config(), device_id, port, deadline);
+    let socket = match options.retry_policy() {
+        Some(policy) => retry::retry(policy, deadline, connect, Error::is_port_not_open)?,
+        None => connect()?,
+    };
+    options.configure(&socket)?;
+    Ok(UsbStream::new(socket, device_id, port))
+}
+/// Connects once, falling back to the binary protocol for muxers that don't support plists
+fn connect_once(
+    config: &MuxerConfig,
+    device_id: protocol::DeviceId,
+    port: u16,
+    deadline: Option<Instant>,
+) -> Result<UsbSocket> {
+    match connect_with(config, device_id, port, Protocol::Plist, deadline) {
+        Err(Error::ConnectionRefused {
+            code: ReplyCode::BadVersion,
+            ..
+        }) => {
+            info!("Muxer doesn't support plists, falling back to the binary protocol");
+            connect_with(config, device_id, port, Protocol::Binary, deadline)
         }
+        result => result,
     }
-    fn start_listen(&self) -> Result<()> {
-        info!("Starting device listen");
-        let command = protocol::Command::listen();
-        let payload = command.to_bytes();
-        send_payload(
-            &mut self.socket.borrow_mut(),
-            PacketType::PlistPayload,
-            Protocol::Plist,
-            payload,
-        )?;
-        let packet = Packet::from_reader(&mut *self.socket.borrow_mut())?;
-        let cursor = std::io::Cursor::new(&packet.data[..]);
-        let res = protocol::ResultMessage::from_reader(cursor)?;
-        if res.0 != 0 {
-            error!("Failed to setup device listen: {}", res.0);
-            return Err(Error::FailedToListen(res.0));
+}
+fn connect_with(
+    config: &MuxerConfig,
+    device_id: protocol::DeviceId,
+    port: u16,
+    encoding: Protocol,
+    deadline: Option<Instant>,
+) -> Result<UsbSocket> {
+    let socket = match deadline {
+        Some(deadline) => config.connect_by(deadline)?,
+        None => config.connect()?,
+    };
+    let mut driver = Driver::unbuffered(socket).deadline(deadline);
+    let tag = match encoding {
+        Protocol::Plist => {
+            let payload = config.encode(protocol::Command::connect(port, device_id))?;
+            driver.send(PacketType::PlistPayload, encoding, payload)?
         }
-        info!("Listen successful");
-        Ok(())
+        Protocol::Binary => {
+            let payload = protocol::binary::connect_payload(device_id, port)?;
+            driver.send(PacketType::Connect, encoding, payload)?
+        }
+    };
+    let packet = driver.recv_reply(tag)?;
+    let code = protocol::ResultMessage::from_packet(&packet)?.code()?;
+    if code != ReplyCode::Ok {
+        telemetry::connect_failed(code);
+        return Err(Error::ConnectionRefused {
+            device_id,
+            udid: None,
+            port,
+            code,
+        });
     }
+
+    Ok(driver.into_socket())
+}
+/// Same as [`connect_to_device`], retrying per `policy` while the device refuses the connection
+///
+/// Useful right after launching the app on the device, before it has opened its listening port.
+///
+/// # Errors
+/// Returns the last [`Error::ConnectionRefused`] once the policy gives up, or any other error as is
+pub fn connect_to_device_retry(
+    device_id: protocol::DeviceId,
+    port: u16,
+    policy: &RetryPolicy,
+) -> Result<UsbStream> {
+    let options = ConnectOptions::default().retry(policy.clone());
+    connect_to_device_with(&options, device_id, port)
+}
+
+/// Same as [`connect_to_device`], giving up with [`Error::Timeout`] if the muxer hasn't set up the
+/// connection within `timeout`
+///
+/// The timeout covers reaching the muxer, sending the connect request & reading its reply.
+pub fn connect_to_device_timeout(
+    device_id: protocol::DeviceId,
+    port: u16,
+    timeout: Duration,
+) -> Result<UsbStream> {
+    let options = ConnectOptions::default().timeout(timeout);
+    connect_to_device_with(&options, device_id, port)
+}
+
+/// Creates a network connection over USB to the attached device with the given UDID & port
+///
+/// Unlike device ids, which change whenever the device is replugged, UDIDs are stable. A device
+/// attached over both USB and the network is connected to over USB. With a listener running,
+/// [`DeviceListener::device_by_udid`] resolves the UDID without asking the muxer again.
+///
+/// # Errors
+/// Fails with [`Error::DeviceNotFound`] if no device with that UDID is attached
+pub fn connect_by_udid(udid: &str, port: u16) -> Result<UsbStream> {
+    Device::find(udid)?.connect(port)
+}
+
+/// Sends a single command on a fresh muxer connection, returning the reply plist
+fn muxer_request(command: protocol::Command) -> Result<plist::Value> {
+    MuxerClient::connect()?.request_command(command)
 }
@@ -0,0 +1,1086 @@
+//! Listening for devices attaching to & detaching from the host
+use crate::protocol::{self, MuxerConnection, MuxerEvent, PacketType, Protocol};
+use crate::{telemetry, DeviceAttachedInfo, DeviceConnectionType, DeviceEvent, DeviceId, Driver};
+use crate::{Error, MuxerConfig, ProductType, ProtocolError, ReconnectPolicy, ReplyCode, Result};
+use crate::{MuxerClient, RetryPolicy, UsbSocket};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Registers a muxer connection for device events, queueing any that arrive before the reply
+fn start_listen(
+    driver: &mut Driver,
+    config: &MuxerConfig,
+    encoding: Protocol,
+    events: &mut Vec<DeviceEvent>,
+) -> Result<()> {
+    info!("Starting device listen");
+    let tag = match encoding {
+        Protocol::Plist => {
+            let payload = config.encode(protocol::Command::listen())?;
+            driver.send(PacketType::PlistPayload, encoding, payload)?
+        }
+        Protocol::Binary => driver.send(PacketType::Listen, encoding, Vec::new())?,
+    };
+    let packet = driver.recv_reply_queueing(tag, events)?;
+    let code = protocol::ResultMessage::from_packet(&packet)?.code()?;
+    if code != ReplyCode::Ok {
+        error!("Failed to setup device listen: {}", code);
+        return Err(Error::FailedToListen(code));
+    }
+    info!("Listen successful");
+    Ok(())
+}
+
+/// How long [`DeviceListener::next_event`] waits for an event
+const DEFAULT_EVENT_WAIT: Duration = Duration::from_millis(500);
+/// How long to wait between attempts to reconnect to a muxer that went away, unless a reconnect
+/// policy is set
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Muxer connection that's been set up for listening
+struct Listening {
+    driver: Driver,
+    /// Devices listed before listening, if asked to
+    devices: Vec<DeviceAttachedInfo>,
+    /// Events that arrived while waiting for replies
+    events: Vec<DeviceEvent>,
+}
+
+/// Stops a [`DeviceListener`] from another thread, from [`DeviceListener::shutdown_handle`]
+///
+/// Once stopped, a wait in progress returns & later ones return None right away without
+/// reconnecting, once events already received are taken, see [`DeviceListener::is_stopped`].
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+#[derive(Debug, Default)]
+struct ShutdownState {
+    stopped: AtomicBool,
+    /// Handle to the listener's current muxer connection, shut down to unblock its reads
+    socket: Mutex<Option<UsbSocket>>,
+}
+impl ShutdownHandle {
+    /// Stops the listener, closing its muxer connection
+    pub fn shutdown(&self) {
+        let mut socket = self.state.socket.lock().unwrap_or_else(|e| e.into_inner());
+        self.state.stopped.store(true, Ordering::SeqCst);
+        if let Some(socket) = socket.take() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+    /// Checks if the listener was stopped
+    pub fn is_shutdown(&self) -> bool {
+        self.state.stopped.load(Ordering::SeqCst)
+    }
+    /// Keeps a handle to the listener's new muxer connection, or closes it if already stopped
+    ///
+    /// Connections from custom transports that can't be cloned aren't unblocked, their reads
+    /// return once the listener's wait runs out instead.
+    fn track(&self, socket: &UsbSocket) {
+        let mut tracked = self.state.socket.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_shutdown() {
+            let _ = socket.shutdown(Shutdown::Both);
+            return;
+        }
+        *tracked = socket.try_clone().ok();
+    }
+}
+
+/// Which devices a listener reports, all of them unless narrowed down
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFilter {
+    product_types: Option<Vec<ProductType>>,
+    connection_type: Option<DeviceConnectionType>,
+    udids: Option<Vec<String>>,
+}
+impl DeviceFilter {
+    /// Only reports devices of the given product types
+    pub fn product_types<I: IntoIterator<Item = ProductType>>(mut self, types: I) -> Self {
+        self.product_types = Some(types.into_iter().collect());
+        self
+    }
+    /// Only reports devices connected the given way, such as USB only
+    pub fn connection_type(mut self, connection_type: DeviceConnectionType) -> Self {
+        self.connection_type = Some(connection_type);
+        self
+    }
+    /// Only reports devices with one of the given UDIDs/serials
+    pub fn udids<I, S>(mut self, udids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.udids = Some(udids.into_iter().map(Into::into).collect());
+        self
+    }
+    /// Checks if the filter lets the device through
+    pub fn matches(&self, device: &DeviceAttachedInfo) -> bool {
+        self.product_types
+            .as_ref()
+            .is_none_or(|types| types.contains(&device.product_type))
+            && self
+                .connection_type
+                .as_ref()
+                .is_none_or(|t| *t == device.connection_type)
+            && self
+                .udids
+                .as_ref()
+                .is_none_or(|udids| udids.iter().any(|udid| device.identifier == *udid))
+    }
+}
+
+/// Builds a [`DeviceListener`] with a muxer config, device filter or initial device list
+#[derive(Debug, Default)]
+pub struct DeviceListenerBuilder {
+    config: MuxerConfig,
+    filter: DeviceFilter,
+    device_list: bool,
+    reconnect: Option<Arc<dyn ReconnectPolicy>>,
+    debounce: Option<Duration>,
+    pair_records: bool,
+}
+impl DeviceListenerBuilder {
+    /// Uses the muxer & client name from `config`
+    pub fn config(mut self, config: MuxerConfig) -> Self {
+        self.config = config;
+        self
+    }
+    /// Only reports devices matching `filter`, other devices' events are dropped in the listener
+    pub fn filter(mut self, filter: DeviceFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+    /// Only reports devices of the given product types
+    pub fn product_types<I: IntoIterator<Item = ProductType>>(mut self, types: I) -> Self {
+        self.filter = self.filter.product_types(types);
+        self
+    }
+    /// Only reports devices connected over USB, ignoring Wi-Fi devices
+    pub fn usb_only(mut self) -> Self {
+        self.filter = self.filter.connection_type(DeviceConnectionType::USB);
+        self
+    }
+    /// Only reports devices connected over the network
+    pub fn network_only(mut self) -> Self {
+        self.filter = self.filter.connection_type(DeviceConnectionType::Network);
+        self
+    }
+    /// Only reports devices with one of the given UDIDs/serials
+    pub fn udids<I, S>(mut self, udids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.filter = self.filter.udids(udids);
+        self
+    }
+    /// Lists already attached devices before listening, see [`DeviceListener::with_device_list`]
+    pub fn device_list(mut self, device_list: bool) -> Self {
+        self.device_list = device_list;
+        self
+    }
+    /// Reconnects to a muxer that went away per `policy`, instead of every second indefinitely
+    ///
+    /// Once the policy gives up the listener stops, see [`DeviceListener::is_stopped`].
+    pub fn reconnect_policy<P: ReconnectPolicy + 'static>(mut self, policy: P) -> Self {
+        self.reconnect = Some(Arc::new(policy));
+        self
+    }
+    /// Holds back detaches for `window`, so a device that detaches & attaches again within it
+    /// (such as during unlock, or a cable glitch) isn't reported as detached
+    ///
+    /// A device coming back under the same ID isn't reported at all, one with a new ID is reported
+    /// as [`DeviceEvent::Reattached`]. Detaches are reported late by up to `window`.
+    pub fn debounce(mut self, window: Duration) -> Self {
+        self.debounce = Some(window);
+        self
+    }
+    /// Reads the pair record of devices that get paired, reporting them as
+    /// [`DeviceEvent::PairedWithRecord`] so lockdown sessions can start without reading it again
+    ///
+    /// The record is read on a separate muxer connection as the event arrives. If that fails the
+    /// plain [`DeviceEvent::Paired`] is reported.
+    pub fn pair_records(mut self, pair_records: bool) -> Self {
+        self.pair_records = pair_records;
+        self
+    }
+    /// Connects to the muxer & starts listening
+    pub fn build(self) -> Result<DeviceListener> {
+        let listening = if self.device_list {
+            DeviceListener::list_and_listen(&self.config)?
+        } else {
+            DeviceListener::listen(&self.config)?
+        };
+        let reconnect = self
+            .reconnect
+            .unwrap_or_else(|| Arc::new(RetryPolicy::fixed(RECONNECT_INTERVAL)));
+        let mut listener =
+            DeviceListener::from_driver(listening.driver, self.config, self.filter, reconnect);
+        listener.debounce = self.debounce;
+        listener.pair_records = self.pair_records;
+        for device in listening.devices {
+            listener.push_event(DeviceEvent::Attached(device));
+        }
+        for event in listening.events {
+            listener.push_event(event);
+        }
+        Ok(listener)
+    }
+}
+
+/// Listens for iOS devices connecting over USB via Apple Mobile Support/usbmuxd
+///
+/// If the muxer restarts (a daemon crash, or an iTunes update on Windows) the listener reconnects
+/// & listens again on its own, reporting devices that went away in the meantime as detached.
+pub struct DeviceListener {
+    socket: RefCell<Option<UsbSocket>>,
+    events: RefCell<VecDeque<DeviceEvent>>,
+    /// Protocol state of the connection, holding bytes that don't make up a whole packet yet
+    connection: RefCell<MuxerConnection>,
+    config: MuxerConfig,
+    filter: DeviceFilter,
+    /// Devices reported as attached & not detached since
+    attached: RefCell<HashMap<DeviceId, DeviceAttachedInfo>>,
+    debounce: Option<Duration>,
+    /// Whether to read the pair record of devices that get paired
+    pair_records: bool,
+    /// Detached devices held back until the debounce window passes, oldest first
+    detaching: RefCell<VecDeque<(Instant, DeviceAttachedInfo)>>,
+    reconnect: Arc<dyn ReconnectPolicy>,
+    /// When the muxer connection was lost, for the reconnect policy
+    lost_at: Cell<Instant>,
+    /// Reconnect attempts since the muxer connection was lost
+    retries: Cell<u32>,
+    /// When to try reconnecting next
+    next_reconnect: Cell<Instant>,
+    shutdown: ShutdownHandle,
+}
+impl DeviceListener {
+    /// Produces a new device listener, registering with usbmuxd/apple mobile support service
+    ///
+    /// # Errors
+    /// Can produce an error, most commonly when the mobile service isn't available. It should be available on macOS,
+    /// but on Windows it's only available if Apple Mobile Support is installed, typically via iTunes.
+    pub fn new() -> Result<Self> {
+        Self::with_config(MuxerConfig::default())
+    }
+    /// Same as [`DeviceListener::new`], using the muxer & client name from `config`
+    pub fn with_config(config: MuxerConfig) -> Result<Self> {
+        Self::builder().config(config).build()
+    }
+    /// Same as [`DeviceListener::with_config`], first listing the devices that are already attached
+    /// on the same connection, so no device is missed between the listing & the listen
+    ///
+    /// Already attached devices are reported as `Attached` events before any live events, and each
+    /// device is reported exactly once even though the muxer may also replay it after listening.
+    pub fn with_device_list(config: MuxerConfig) -> Result<Self> {
+        Self::builder().config(config).device_list(true).build()
+    }
+    /// Builder for a listener with a custom config, device filter, or initial device list
+    pub fn builder() -> DeviceListenerBuilder {
+        DeviceListenerBuilder::default()
+    }
+    fn from_driver(
+        driver: Driver,
+        config: MuxerConfig,
+        filter: DeviceFilter,
+        reconnect: Arc<dyn ReconnectPolicy>,
+    ) -> Self {
+        let (socket, connection) = driver.into_parts();
+        let shutdown = ShutdownHandle::default();
+        shutdown.track(&socket);
+        DeviceListener {
+            socket: RefCell::new(Some(socket)),
+            events: RefCell::new(VecDeque::new()),
+            connection: RefCell::new(connection),
+            config,
+            filter,
+            attached: RefCell::new(HashMap::new()),
+            debounce: None,
+            pair_records: false,
+            detaching: RefCell::new(VecDeque::new()),
+            reconnect,
+            lost_at: Cell::new(Instant::now()),
+            retries: Cell::new(0),
+            next_reconnect: Cell::new(Instant::now()),
+            shutdown,
+        }
+    }
+    /// Receives an event, waiting up to 500ms for one to arrive
+    ///
+    /// Returns as soon as an event arrives, None if there were none in that time.
+    pub fn next_event(&self) -> Option<DeviceEvent> {
+        self.wait_event(Some(DEFAULT_EVENT_WAIT))
+    }
+    /// Receives an event, waiting up to `timeout` for one to arrive or indefinitely if None
+    ///
+    /// The wait blocks on the socket, so events are delivered as soon as the muxer sends them.
+    /// A zero timeout only returns events that already arrived.
+    pub fn wait_event(&self, timeout: Option<Duration>) -> Option<DeviceEvent> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            self.release_detaches(self.is_stopped());
+            if let Some(event) = self.events.borrow_mut().pop_front() {
+                return Some(event);
+            }
+            if self.is_stopped() {
+                return None;
+            }
+            let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            // wake up for held back detaches too
+            let release = self.detaching.borrow().front().map(|(at, _)| {
+                (*at + self.debounce.unwrap_or_default()).saturating_duration_since(Instant::now())
+            });
+            let wait = match (remaining, release) {
+                (Some(remaining), Some(release)) => Some(remaining.min(release)),
+                (remaining, release) => remaining.or(release),
+            };
+            self.read_events(wait);
+            if remaining == Some(Duration::ZERO) {
+                self.release_detaches(false);
+                return self.events.borrow_mut().pop_front();
+            }
+        }
+    }
+    /// Devices currently attached, ordered by device ID
+    ///
+    /// This reflects every event received from the muxer, including ones not returned by
+    /// [`DeviceListener::next_event`] yet, so it's current even if events are consumed slowly.
+    pub fn devices(&self) -> Vec<DeviceAttachedInfo> {
+        self.read_events(Some(Duration::ZERO));
+        let mut devices: Vec<DeviceAttachedInfo> =
+            self.attached.borrow().values().cloned().collect();
+        devices.sort_by_key(|d| d.device_id);
+        devices
+    }
+    /// Currently attached device with the given ID
+    pub fn device(&self, device_id: DeviceId) -> Option<DeviceAttachedInfo> {
+        self.read_events(Some(Duration::ZERO));
+        self.attached.borrow().get(&device_id).cloned()
+    }
+    /// Currently attached device with the given UDID, preferring its USB connection
+    pub fn device_by_udid(&self, udid: &str) -> Option<DeviceAttachedInfo> {
+        self.devices()
+            .into_iter()
+            .filter(|d| d.identifier == udid)
+            .min_by_key(|d| d.connection_type != DeviceConnectionType::USB)
+    }
+    /// Checks if the listener is currently connected to the muxer, false while it's reconnecting
+    pub fn is_connected(&self) -> bool {
+        self.socket.borrow().is_some()
+    }
+    /// Handle to stop the listener from another thread, such as while the app exits
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+    /// Stops the listener, closing its muxer connection for good
+    pub fn stop(&self) {
+        if let Err(e) = self.close_socket() {
+            debug!("Error closing listen connection: {}", e);
+        }
+    }
+    /// Stops the listener, returning the events it received but didn't return yet
+    ///
+    /// Dropping the listener closes its muxer connection too, this also reports errors closing it.
+    ///
+    /// # Errors
+    /// Fails if shutting down the muxer connection fails
+    pub fn close(self) -> Result<Vec<DeviceEvent>> {
+        self.read_events(Some(Duration::ZERO));
+        self.release_detaches(true);
+        let events = self.events.borrow_mut().drain(..).collect();
+        self.close_socket()?;
+        Ok(events)
+    }
+    /// Checks if the listener was stopped, after which it never returns events again
+    pub fn is_stopped(&self) -> bool {
+        self.shutdown.is_shutdown()
+    }
+    fn listen(config: &MuxerConfig) -> Result<Listening> {
+        match Self::listen_with(config, Protocol::Plist) {
+            Err(Error::FailedToListen(ReplyCode::BadVersion)) => {
+                info!("Muxer doesn't support plists, falling back to the binary protocol");
+                Self::listen_with(config, Protocol::Binary)
+            }
+            result => result,
+        }
+    }
+    fn listen_with(config: &MuxerConfig, encoding: Protocol) -> Result<Listening> {
+        let mut driver = Driver::new(config.connect()?);
+        let mut events = Vec::new();
+        start_listen(&mut driver, config, encoding, &mut events)?;
+        Ok(Listening {
+            driver,
+            devices: Vec::new(),
+            events,
+        })
+    }
+    /// Lists attached devices, then listens on the same connection
+    ///
+    /// Muxers that only speak the binary protocol can't list devices, but replay attached devices
+    /// right after listening, so those are just listened to.
+    fn list_and_listen(config: &MuxerConfig) -> Result<Listening> {
+        let mut driver = Driver::new(config.connect()?);
+        let payload = config.encode(protocol::Command::list_devices())?;
+        let tag = driver.send(PacketType::PlistPayload, Protocol::Plist, payload)?;
+        let mut events = Vec::new();
+        let packet = driver.recv_reply_queueing(tag, &mut events)?;
+        if packet.protocol == Protocol::Binary {
+            return match protocol::ResultMessage::from_packet(&packet)?.code()? {
+                ReplyCode::BadVersion => Self::listen_with(config, Protocol::Binary),
+                code => Err(Error::FailedToListen(code)),
+            };
+        }
+        let reply = plist::Value::from_reader(std::io::Cursor::new(&packet.data[..]))
+            .map_err(|_| ProtocolError::InvalidPlistEntry)?;
+        let devices = protocol::device_list_from_value(&reply)?;
+        start_listen(&mut driver, config, Protocol::Plist, &mut events)?;
+        Ok(Listening {
+            driver,
+            devices,
+            events,
+        })
+    }
+    /// Reads whatever the muxer sends within `wait`, queueing any complete events
+    fn read_events(&self, wait: Option<Duration>) {
+        if self.is_stopped() {
+            *self.socket.borrow_mut() = None;
+            return;
+        }
+        if !self.is_connected() && !self.reconnect() {
+            if self.is_stopped() {
+                return;
+            }
+            // sleep until the next reconnect attempt, or the deadline if that's sooner
+            let next_attempt = self
+                .next_reconnect
+                .get()
+                .saturating_duration_since(Instant::now());
+            std::thread::sleep(wait.map_or(next_attempt, |w| w.min(next_attempt)));
+            return;
+        }
+        // packets read along with the handshake's replies are already buffered
+        if self.take_buffered_events() {
+            return;
+        }
+        // read straight into the connection's buffer, which is kept between reads
+        let mut connection = self.connection.borrow_mut();
+        let result = match self.socket.borrow_mut().as_mut() {
+            Some(socket) => Self::set_wait(socket, wait).and_then(|_| connection.read_from(socket)),
+            None => return,
+        };
+        drop(connection);
+        if self.is_stopped() {
+            *self.socket.borrow_mut() = None;
+            return;
+        }
+        match result {
+            Ok(0) => {
+                warn!("Muxer closed the listen connection");
+                self.disconnected();
+            }
+            Ok(bytes) => {
+                telemetry::received(bytes);
+                self.take_buffered_events();
+            }
+            Err(e) => match e.kind() {
+                // nothing arrived in time, timeouts are reported as either depending on platform
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {}
+                std::io::ErrorKind::Interrupted => {}
+                _ => {
+                    warn!("Lost connection to muxer: {}", e);
+                    self.disconnected();
+                }
+            },
+        }
+    }
+    /// Queues events from the packets buffered so far, returning whether there were any
+    ///
+    /// A packet split across reads stays buffered until the rest of it arrives.
+    fn take_buffered_events(&self) -> bool {
+        let mut any = false;
+        loop {
+            let event = match self.connection.borrow_mut().next_event() {
+                Some(event) => event,
+                None => return any,
+            };
+            any = true;
+            if !matches!(event, MuxerEvent::Invalid(_)) {
+                telemetry::packet_received();
+            }
+            match event {
+                MuxerEvent::Device(Ok(event)) => {
+                    telemetry::event(&event);
+                    self.push_event(event);
+                }
+                MuxerEvent::Device(Err(e)) => error!("Error decoding event: {}", e),
+                MuxerEvent::Reply(packet) => debug!("Dropped reply {:?}", packet),
+                MuxerEvent::Invalid(e) => error!("Error receiving events: {}", e),
+            }
+        }
+    }
+    /// Makes reads wait up to `wait`, a zero wait doesn't block at all
+    fn set_wait(socket: &UsbSocket, wait: Option<Duration>) -> std::io::Result<()> {
+        if wait == Some(Duration::ZERO) {
+            // a zero read timeout is rejected, so poll without blocking instead
+            return socket.set_nonblocking(true);
+        }
+        socket.set_nonblocking(false)?;
+        socket.set_read_timeout(wait)
+    }
+    /// Queues an event, tracking attached devices & dropping those of filtered out devices
+    fn push_event(&self, mut event: DeviceEvent) {
+        let keep = match &event {
+            DeviceEvent::Attached(info) => {
+                // after reconnecting the muxer replays devices that stayed attached
+                let mut attached = self.attached.borrow_mut();
+                let new = !attached.contains_key(&info.device_id) && self.filter.matches(info);
+                if new {
+                    attached.insert(info.device_id, info.clone());
+                }
+                match self.take_detaching(info) {
+                    Some(previous) if new && previous == info.device_id => false,
+                    Some(previous) if new => {
+                        event = DeviceEvent::Reattached {
+                            previous,
+                            info: info.clone(),
+                        };
+                        true
+                    }
+                    _ => new,
+                }
+            }
+            DeviceEvent::Detached(device_id) => {
+                match (self.attached.borrow_mut().remove(device_id), self.debounce) {
+                    (Some(info), Some(_)) => {
+                        self.detaching
+                            .borrow_mut()
+                            .push_back((Instant::now(), info));
+                        false
+                    }
+                    (info, _) => info.is_some(),
+                }
+            }
+            DeviceEvent::Paired(device_id) => {
+                let device_id = *device_id;
+                let attached = self.attached.borrow().contains_key(&device_id);
+                if attached && self.pair_records {
+                    if let Some(paired) = self.paired_with_record(device_id) {
+                        event = paired;
+                    }
+                }
+                attached
+            }
+            DeviceEvent::Unknown { message_type, .. } => {
+                debug!("Unknown muxer message: {}", message_type);
+                true
+            }
+            _ => true,
+        };
+        telemetry::devices_attached(self.attached.borrow().len());
+        if keep {
+            self.events.borrow_mut().push_back(event);
+        }
+    }
+    /// Reads the pair record of a device that was just paired
+    fn paired_with_record(&self, device_id: DeviceId) -> Option<DeviceEvent> {
+        let udid = self.attached.borrow().get(&device_id)?.identifier.clone();
+        let record = MuxerClient::with_config(self.config.clone())
+            .and_then(|mut client| client.read_pair_record(udid.serial()));
+        match record {
+            Ok(Some(record)) => Some(DeviceEvent::PairedWithRecord {
+                device_id,
+                udid,
+                host_id: record.host_id,
+                system_buid: record.system_buid,
+            }),
+            Ok(None) => {
+                warn!("Device {} was paired but has no pair record", udid);
+                None
+            }
+            Err(e) => {
+                warn!("Error reading pair record of device {}: {}", udid, e);
+                None
+            }
+        }
+    }
+    /// Takes back a held back detach of the same device & connection type, returning its old ID
+    fn take_detaching(&self, info: &DeviceAttachedInfo) -> Option<DeviceId> {
+        let mut detaching = self.detaching.borrow_mut();
+        let index = detaching.iter().position(|(_, d)| {
+            d.identifier == info.identifier && d.connection_type == info.connection_type
+        })?;
+        detaching.remove(index).map(|(_, d)| d.device_id)
+    }
+    /// Reports held back detaches once their debounce window passed, or all of them
+    fn release_detaches(&self, all: bool) {
+        let window = self.debounce.unwrap_or_default();
+        let mut detaching = self.detaching.borrow_mut();
+        while let Some((at, info)) = detaching.front() {
+            if !all && at.elapsed() < window {
+                break;
+            }
+            let device_id = info.device_id;
+            detaching.pop_front();
+            self.events
+                .borrow_mut()
+                .push_back(DeviceEvent::Detached(device_id));
+        }
+    }
+    /// Shuts down the muxer connection, so the muxer frees the listener right away rather than
+    /// whenever the socket is closed
+    fn close_socket(&self) -> std::io::Result<()> {
+        self.shutdown.shutdown();
+        let socket = match self.socket.borrow_mut().take() {
+            Some(socket) => socket,
+            None => return Ok(()),
+        };
+        match socket.shutdown(Shutdown::Both) {
+            // the shutdown handle may have shut it down already
+            Err(e) if e.kind() == std::io::ErrorKind::NotConnected => Ok(()),
+            result => result,
+        }
+    }
+    fn disconnected(&self) {
+        *self.socket.borrow_mut() = None;
+        self.events
+            .borrow_mut()
+            .push_back(DeviceEvent::MuxerDisconnected);
+        self.lost_at.set(Instant::now());
+        self.retries.set(0);
+        self.schedule_reconnect();
+    }
+    /// Sets when to try reconnecting next, stopping the listener if the reconnect policy gives up
+    fn schedule_reconnect(&self) {
+        let retry = self.retries.get();
+        self.retries.set(retry.saturating_add(1));
+        match self
+            .reconnect
+            .next_delay(retry, self.lost_at.get().elapsed())
+        {
+            Some(delay) => self.next_reconnect.set(Instant::now() + delay),
+            None => {
+                warn!("Gave up reconnecting to muxer after {} attempts", retry);
+                self.shutdown.shutdown();
+            }
+        }
+    }
+    /// Tries to reconnect & listen again, once the reconnect policy's delay has passed
+    fn reconnect(&self) -> bool {
+        if self.is_stopped() || Instant::now() < self.next_reconnect.get() {
+            return false;
+        }
+        let listening = match Self::list_and_listen(&self.config) {
+            Ok(listening) => listening,
+            Err(e) => {
+                debug!("Failed to reconnect to muxer: {}", e);
+                self.schedule_reconnect();
+                return false;
+            }
+        };
+        info!("Reconnected to muxer");
+        telemetry::reconnected();
+        let gone: Vec<DeviceId> = self
+            .attached
+            .borrow()
+            .keys()
+            .filter(|id| !listening.devices.iter().any(|d| d.device_id == **id))
+            .copied()
+            .collect();
+        for device_id in gone {
+            self.push_event(DeviceEvent::Detached(device_id));
+        }
+        // a partial packet from the old connection will never be completed, so the new connection
+        // starts with fresh protocol state
+        let (socket, connection) = listening.driver.into_parts();
+        self.shutdown.track(&socket);
+        *self.connection.borrow_mut() = connection;
+        *self.socket.borrow_mut() = Some(socket);
+        for event in listening.events {
+            self.push_event(event);
+        }
+        true
+    }
+}
+impl Drop for DeviceListener {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::protocol::Packet;
+    use plist::{Dictionary, Value};
+    use std::os::unix::net::UnixListener;
+
+    fn send_plist(socket: &mut std::os::unix::net::UnixStream, value: Value) {
+        send_reply(socket, value, 0);
+    }
+    fn send_reply(socket: &mut std::os::unix::net::UnixStream, value: Value, tag: u32) {
+        let mut payload = Vec::new();
+        value.to_writer_xml(&mut payload).unwrap();
+        Packet::new(Protocol::Plist, PacketType::PlistPayload, tag, payload)
+            .unwrap()
+            .write_into(socket)
+            .unwrap();
+    }
+    fn message(message_type: &str, device_id: u64) -> Dictionary {
+        let mut d = Dictionary::new();
+        d.insert(
+            "MessageType".to_owned(),
+            Value::String(message_type.to_owned()),
+        );
+        d.insert("DeviceID".to_owned(), Value::Integer(device_id.into()));
+        d
+    }
+    fn attached(device_id: u64) -> Value {
+        let mut properties = message("Attached", device_id);
+        properties.insert("ConnectionType".to_owned(), Value::String("USB".to_owned()));
+        properties.insert("LocationID".to_owned(), Value::Integer(0.into()));
+        properties.insert("ProductID".to_owned(), Value::Integer(0x12A8.into()));
+        properties.insert(
+            "SerialNumber".to_owned(),
+            Value::String(format!("{:040x}", device_id)),
+        );
+        let mut event = message("Attached", device_id);
+        event.insert("Properties".to_owned(), Value::Dictionary(properties));
+        Value::Dictionary(event)
+    }
+    fn result(number: i64) -> Value {
+        let mut d = message("Result", 0);
+        d.insert("Number".to_owned(), Value::Integer(number.into()));
+        Value::Dictionary(d)
+    }
+    fn device_list(device_ids: &[u64]) -> Value {
+        let mut reply = Dictionary::new();
+        let devices = device_ids.iter().map(|id| attached(*id)).collect();
+        reply.insert("DeviceList".to_owned(), Value::Array(devices));
+        Value::Dictionary(reply)
+    }
+    /// Binds a fake muxer socket, unique per test as tests run in parallel
+    fn fake_muxer(name: &str) -> (UnixListener, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("peertalk-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        (UnixListener::bind(&path).unwrap(), path)
+    }
+    /// Accepts a listener connection, answering its ListDevices (if any) & Listen commands
+    fn accept_listen(
+        muxer: &UnixListener,
+        devices: Option<&[u64]>,
+    ) -> std::os::unix::net::UnixStream {
+        let (mut socket, _) = muxer.accept().unwrap();
+        if let Some(devices) = devices {
+            let request = Packet::from_reader(&mut socket).unwrap();
+            send_reply(&mut socket, device_list(devices), request.tag);
+        }
+        let request = Packet::from_reader(&mut socket).unwrap();
+        send_reply(&mut socket, result(0), request.tag);
+        socket
+    }
+    fn collect_events(listener: &DeviceListener, count: usize) -> Vec<DeviceEvent> {
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while events.len() < count && Instant::now() < deadline {
+            events.extend(listener.next_event());
+        }
+        events
+    }
+
+    #[test]
+    fn it_reconnects_when_muxer_restarts() {
+        let (muxer, path) = fake_muxer("reconnect");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || {
+            let mut socket = accept_listen(&muxer, None);
+            send_plist(&mut socket, attached(1));
+            send_plist(&mut socket, attached(2));
+            // muxer restarts, only device 2 is still attached, which it replays after listen
+            drop(socket);
+            let mut socket = accept_listen(&muxer, Some(&[2]));
+            send_plist(&mut socket, attached(2));
+            socket // keeps the new connection open
+        });
+        let listener = DeviceListener::with_config(config).unwrap();
+        let events = collect_events(&listener, 4);
+        let _socket = server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(events[0], DeviceEvent::Attached(ref d) if d.device_id == DeviceId(1)));
+        assert!(matches!(events[1], DeviceEvent::Attached(ref d) if d.device_id == DeviceId(2)));
+        assert!(matches!(events[2], DeviceEvent::MuxerDisconnected));
+        assert!(matches!(events[3], DeviceEvent::Detached(DeviceId(1))));
+        let devices = listener.devices();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].identifier, format!("{:040x}", 2));
+        assert!(listener.is_connected());
+    }
+    #[test]
+    fn it_stops_from_another_thread() {
+        let (muxer, path) = fake_muxer("shutdown");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || accept_listen(&muxer, None));
+        let listener = DeviceListener::with_config(config).unwrap();
+        let _socket = server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let handle = listener.shutdown_handle();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            handle.shutdown();
+        });
+        let started = Instant::now();
+        assert!(listener.wait_event(None).is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        stopper.join().unwrap();
+        assert!(listener.is_stopped());
+        assert!(!listener.is_connected());
+        assert!(listener.next_event().is_none());
+    }
+    #[test]
+    fn it_closes_the_muxer_connection() {
+        use std::io::Read;
+        let (muxer, path) = fake_muxer("close");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || {
+            let mut socket = accept_listen(&muxer, None);
+            send_plist(&mut socket, attached(1));
+            let closed = accept_listen(&muxer, None);
+            (socket, closed)
+        });
+        let listener = DeviceListener::with_config(config.clone()).unwrap();
+        let dropped = DeviceListener::with_config(config).unwrap();
+        let (mut socket, mut closed) = server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let events = listener.close().unwrap();
+        assert!(matches!(events[..], [DeviceEvent::Attached(ref d)] if d.device_id == DeviceId(1)));
+        assert_eq!(socket.read(&mut [0; 16]).unwrap(), 0);
+        drop(dropped);
+        assert_eq!(closed.read(&mut [0; 16]).unwrap(), 0);
+    }
+    #[test]
+    fn it_gives_up_reconnecting_per_policy() {
+        let (muxer, path) = fake_muxer("give-up");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || accept_listen(&muxer, None));
+        let listener = DeviceListener::builder()
+            .config(config)
+            .reconnect_policy(RetryPolicy::fixed(Duration::from_millis(10)).max_attempts(2))
+            .build()
+            .unwrap();
+        // the muxer goes away for good
+        drop(server.join().unwrap());
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(
+            listener.wait_event(None),
+            Some(DeviceEvent::MuxerDisconnected)
+        ));
+        assert!(listener.wait_event(None).is_none());
+        assert!(listener.is_stopped());
+    }
+    #[test]
+    fn it_filters_devices() {
+        let (muxer, path) = fake_muxer("filter");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || {
+            let mut socket = accept_listen(&muxer, None);
+            send_plist(&mut socket, attached(1));
+            send_plist(&mut socket, attached(2));
+            send_plist(&mut socket, Value::Dictionary(message("Detached", 1)));
+            send_plist(&mut socket, Value::Dictionary(message("Detached", 2)));
+            socket
+        });
+        let listener = DeviceListener::builder()
+            .config(config)
+            .usb_only()
+            .udids(vec![format!("{:040x}", 2)])
+            .build()
+            .unwrap();
+        let _socket = server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let events = collect_events(&listener, 2);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], DeviceEvent::Attached(ref d) if d.device_id == DeviceId(2)));
+        assert!(matches!(events[1], DeviceEvent::Detached(DeviceId(2))));
+    }
+    #[test]
+    fn it_debounces_reattaching_devices() {
+        let (muxer, path) = fake_muxer("debounce");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || {
+            let mut socket = accept_listen(&muxer, None);
+            send_plist(&mut socket, attached(1));
+            send_plist(&mut socket, Value::Dictionary(message("Detached", 1)));
+            // device 1 comes back as device 2
+            let mut reattached = attached(2);
+            let properties = reattached
+                .as_dictionary_mut()
+                .and_then(|d| d.get_mut("Properties"))
+                .and_then(Value::as_dictionary_mut)
+                .unwrap();
+            properties.insert(
+                "SerialNumber".to_owned(),
+                Value::String(format!("{:040x}", 1)),
+            );
+            send_plist(&mut socket, reattached);
+            send_plist(&mut socket, Value::Dictionary(message("Detached", 2)));
+            socket
+        });
+        let listener = DeviceListener::builder()
+            .config(config)
+            .debounce(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let _socket = server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let events = collect_events(&listener, 3);
+        assert!(matches!(events[0], DeviceEvent::Attached(ref d) if d.device_id == DeviceId(1)));
+        assert!(matches!(
+            events[1],
+            DeviceEvent::Reattached { previous: DeviceId(1), ref info } if info.device_id == DeviceId(2)
+        ));
+        assert!(matches!(events[2], DeviceEvent::Detached(DeviceId(2))));
+    }
+    #[test]
+    fn it_reads_pair_records_of_paired_devices() {
+        let (muxer, path) = fake_muxer("paired");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || {
+            let mut socket = accept_listen(&muxer, None);
+            send_plist(&mut socket, attached(1));
+            send_plist(&mut socket, Value::Dictionary(message("Paired", 1)));
+            // the listener reads the pair record on a connection of its own
+            let (mut client, _) = muxer.accept().unwrap();
+            let request = Packet::from_reader(&mut client).unwrap();
+            let record = crate::PairRecord {
+                host_id: "HOST".to_owned(),
+                system_buid: "BUID".to_owned(),
+                host_certificate: Vec::new(),
+                host_private_key: Vec::new(),
+                device_certificate: Vec::new(),
+                root_certificate: Vec::new(),
+                root_private_key: None,
+                wifi_mac_address: None,
+                escrow_bag: None,
+            };
+            let mut data = Vec::new();
+            Value::from(&record).to_writer_xml(&mut data).unwrap();
+            let mut reply = Dictionary::new();
+            reply.insert("PairRecordData".to_owned(), Value::Data(data));
+            send_reply(&mut client, Value::Dictionary(reply), request.tag);
+            socket
+        });
+        let listener = DeviceListener::builder()
+            .config(config)
+            .pair_records(true)
+            .build()
+            .unwrap();
+        let events = collect_events(&listener, 2);
+        let _socket = server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(
+            events[1],
+            DeviceEvent::PairedWithRecord { device_id: DeviceId(1), ref host_id, ref system_buid, .. }
+                if host_id == "HOST" && system_buid == "BUID"
+        ));
+    }
+    #[test]
+    fn it_reports_listed_devices_once() {
+        let (muxer, path) = fake_muxer("device-list");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || {
+            let mut socket = accept_listen(&muxer, Some(&[1]));
+            send_plist(&mut socket, attached(1)); // replayed after listen
+            send_plist(&mut socket, attached(2));
+            socket
+        });
+        let listener = DeviceListener::with_device_list(config).unwrap();
+        let _socket = server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let events = collect_events(&listener, 2);
+        assert!(matches!(events[0], DeviceEvent::Attached(ref d) if d.device_id == DeviceId(1)));
+        assert!(matches!(events[1], DeviceEvent::Attached(ref d) if d.device_id == DeviceId(2)));
+        assert!(listener
+            .wait_event(Some(Duration::from_millis(100)))
+            .is_none());
+    }
+    #[test]
+    fn it_rejects_replies_with_the_wrong_tag() {
+        let (muxer, path) = fake_muxer("tag");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = muxer.accept().unwrap();
+            let request = Packet::from_reader(&mut socket).unwrap();
+            send_reply(&mut socket, result(0), request.tag + 1);
+            socket
+        });
+        let result = DeviceListener::with_config(config);
+        let _socket = server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(
+            result,
+            Err(Error::ProtocolError(ProtocolError::UnexpectedTag { .. }))
+        ));
+    }
+    #[test]
+    fn it_queues_events_received_before_the_listen_reply() {
+        let (muxer, path) = fake_muxer("interleaved");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = muxer.accept().unwrap();
+            let request = Packet::from_reader(&mut socket).unwrap();
+            send_plist(&mut socket, attached(1));
+            send_reply(&mut socket, result(0), request.tag);
+            socket
+        });
+        let listener = DeviceListener::with_config(config).unwrap();
+        let _socket = server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let events = collect_events(&listener, 1);
+        assert!(matches!(events[..], [DeviceEvent::Attached(ref d)] if d.device_id == DeviceId(1)));
+    }
+    #[test]
+    fn it_falls_back_to_the_binary_protocol() {
+        use crate::protocol::binary;
+        let (muxer, path) = fake_muxer("binary");
+        let config = MuxerConfig::default().socket_path(&path);
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = muxer.accept().unwrap();
+            let request = Packet::from_reader(&mut socket).unwrap();
+            let bad_version = u32::from(ReplyCode::BadVersion).to_le_bytes().to_vec();
+            binary::packet(PacketType::Result, request.tag, bad_version)
+                .unwrap()
+                .write_into(&mut socket)
+                .unwrap();
+            let (mut socket, _) = muxer.accept().unwrap();
+            let request = Packet::from_reader(&mut socket).unwrap();
+            assert_eq!(request.protocol, Protocol::Binary);
+            assert_eq!(request.packet_type, PacketType::Listen);
+            binary::packet(PacketType::Result, request.tag, vec![0; 4])
+                .unwrap()
+                .write_into(&mut socket)
+                .unwrap();
+            // device 5, an iPhone, no location
+            let mut record = vec![5, 0, 0, 0, 0xA8, 0x12];
+            record.extend_from_slice(b"00008030-001A35E22E88802E");
+            record.resize(4 + 2 + 256 + 2 + 4, 0);
+            binary::packet(PacketType::DeviceAdd, 0, record)
+                .unwrap()
+                .write_into(&mut socket)
+                .unwrap();
+            socket
+        });
+        let listener = DeviceListener::with_config(config).unwrap();
+        let _socket = server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let events = collect_events(&listener, 1);
+        assert!(
+            matches!(events[..], [DeviceEvent::Attached(ref d)] if d.identifier == "00008030-001A35E22E88802E")
+        );
+    }
+}
@@ -0,0 +1,404 @@
+//! Client for lockdownd, the device side service that provides device info & starts other services
+use crate::{connect_to_device, read_pair_record, DeviceAttachedInfo, DeviceId, Error, PairRecord};
+use crate::{ProtocolError, Result, UsbSocket};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use plist::{Dictionary, Value};
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsStream};
+
+/// Port lockdownd listens on, on every iOS device
+pub const LOCKDOWN_PORT: u16 = 62078;
+/// Type lockdownd reports in reply to `QueryType`
+const LOCKDOWN_SERVICE_TYPE: &str = "com.apple.mobile.lockdown";
+/// Label sent with requests unless one is given, shows up in the device's logs
+const DEFAULT_LABEL: &str = "peertalk";
+/// Upper bound for a single plist message, lockdown messages are only a few KB
+const MAX_PLIST_MESSAGE_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Writes a plist message prefixed by its big endian 32-bit length, as lockdown & most services expect
+pub(crate) fn send_plist<W: Write>(writer: &mut W, value: &Value) -> Result<()> {
+    let mut payload: Vec<u8> = Vec::new();
+    value
+        .to_writer_xml(&mut payload)
+        .map_err(ProtocolError::PlistEncodeError)?;
+    send_plist_payload(writer, &payload)
+}
+
+/// Same as [`send_plist`] using the binary plist format, which DeviceLink services expect
+pub(crate) fn send_binary_plist<W: Write>(writer: &mut W, value: &Value) -> Result<()> {
+    let mut payload: Vec<u8> = Vec::new();
+    value
+        .to_writer_binary(&mut payload)
+        .map_err(ProtocolError::PlistEncodeError)?;
+    send_plist_payload(writer, &payload)
+}
+
+fn send_plist_payload<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let size =
+        u32::try_from(payload.len()).map_err(|_| ProtocolError::PayloadTooLarge(payload.len()))?;
+    writer.write_u32::<BigEndian>(size)?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a big endian 32-bit length prefixed plist message
+pub(crate) fn recv_plist<R: Read>(reader: &mut R) -> Result<Value> {
+    let size = reader.read_u32::<BigEndian>()?;
+    if size > MAX_PLIST_MESSAGE_SIZE {
+        return Err(ProtocolError::InvalidPlistEntry.into());
+    }
+    let mut payload = vec![0; size as usize];
+    reader.read_exact(&mut payload)?;
+    Value::from_reader(std::io::Cursor::new(payload))
+        .map_err(|_| ProtocolError::InvalidPlistEntry.into())
+}
+
+/// Connection to lockdownd or a service it started, plain or wrapped in TLS
+pub enum ServiceStream {
+    /// Unencrypted connection
+    Plain(UsbSocket),
+    /// Connection wrapped in TLS using the pair record's keys
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<UsbSocket>>),
+}
+impl ServiceStream {
+    /// Underlying device connection, for socket options or shutting it down
+    pub fn socket(&self) -> &UsbSocket {
+        match self {
+            ServiceStream::Plain(socket) => socket,
+            #[cfg(feature = "tls")]
+            ServiceStream::Tls(stream) => stream.get_ref(),
+        }
+    }
+    /// Wraps a plain connection in TLS, as lockdown asks for with `EnableSessionSSL`/`EnableServiceSSL`
+    #[cfg(feature = "tls")]
+    fn into_tls(self, pair_record: &PairRecord) -> Result<Self> {
+        match self {
+            ServiceStream::Plain(socket) => Ok(ServiceStream::Tls(Box::new(tls::wrap_stream(
+                socket,
+                pair_record,
+            )?))),
+            tls => Ok(tls),
+        }
+    }
+    /// Sockets can't be upgraded to TLS without the `tls` feature
+    #[cfg(not(feature = "tls"))]
+    fn into_tls(self, _pair_record: &PairRecord) -> Result<Self> {
+        Err(Error::TlsRequired)
+    }
+}
+impl Read for ServiceStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ServiceStream::Plain(socket) => socket.read(buf),
+            #[cfg(feature = "tls")]
+            ServiceStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+impl Write for ServiceStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ServiceStream::Plain(socket) => socket.write(buf),
+            #[cfg(feature = "tls")]
+            ServiceStream::Tls(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ServiceStream::Plain(socket) => socket.flush(),
+            #[cfg(feature = "tls")]
+            ServiceStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Connects to a named lockdown service on the device, such as `com.apple.syslog_relay`
+///
+/// This does the whole lockdown dance: connects to lockdownd, starts a session using the device's
+/// pair record, asks lockdownd to start the service, then connects to the port it was started on.
+///
+/// # Errors
+/// Fails if the device isn't paired with this host, or if TLS is required without the `tls` feature
+pub fn connect_to_service(device_id: DeviceId, name: &str) -> Result<ServiceStream> {
+    let mut client = LockdownClient::connect(device_id)?;
+    client.query_type()?;
+    let udid = client.get_string("UniqueDeviceID")?;
+    let pair_record = read_pair_record(&udid)?.ok_or(Error::NotPaired)?;
+    client.start_session(&pair_record)?;
+    let service = client.start_service(name)?;
+    let stream = ServiceStream::Plain(connect_to_device(device_id, service.port)?.into_socket());
+    if service.enable_service_ssl {
+        stream.into_tls(&pair_record)
+    } else {
+        Ok(stream)
+    }
+}
+
+/// Lockdown session established with [`LockdownClient::start_session`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockdownSession {
+    /// ID of the session
+    pub session_id: String,
+    /// If set, the rest of the lockdown connection is wrapped in TLS
+    pub enable_session_ssl: bool,
+}
+
+/// Service started by lockdownd with [`LockdownClient::start_service`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceDescriptor {
+    /// Device port the service is listening on, connect with [`connect_to_device`]
+    pub port: u16,
+    /// If set, the service connection must be wrapped in TLS
+    pub enable_service_ssl: bool,
+}
+
+/// Descriptive details of a device, which lockdownd provides without pairing
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceDetails {
+    /// User assigned name of the device, such as "Alice's iPhone"
+    pub name: String,
+    /// Model identifier of the device, such as "iPhone15,2"
+    pub product_type: String,
+    /// iOS version of the device, such as "17.2"
+    pub product_version: String,
+}
+
+/// Reads the name, model & iOS version of a device from its lockdownd
+pub fn device_details(device_id: DeviceId) -> Result<DeviceDetails> {
+    LockdownClient::connect(device_id)?.device_details()
+}
+
+/// Attached device info extended with details from lockdownd, for showing devices in UIs
+#[derive(Debug)]
+pub struct ExtendedDeviceInfo {
+    /// Info usbmuxd reported when the device attached
+    pub info: DeviceAttachedInfo,
+    /// Details read from lockdownd, None if they couldn't be read
+    pub details: Option<DeviceDetails>,
+}
+impl ExtendedDeviceInfo {
+    /// Queries lockdownd for the device's details, typically on [`crate::DeviceEvent::Attached`]
+    ///
+    /// This makes a few round trips to the device, so it's opt-in rather than done by the listener.
+    /// Failures are logged & leave `details` empty, as the device may refuse while it's booting.
+    pub fn query(info: DeviceAttachedInfo) -> Self {
+        let details = match device_details(info.device_id) {
+            Ok(details) => Some(details),
+            Err(e) => {
+                warn!("Failed to read details of device {}: {}", info.device_id, e);
+                None
+            }
+        };
+        ExtendedDeviceInfo { info, details }
+    }
+    /// Name to show for the device, its user assigned name when known or its serial otherwise
+    pub fn display_name(&self) -> &str {
+        match &self.details {
+            Some(details) => &details.name,
+            None => self.info.identifier.as_str(),
+        }
+    }
+}
+
+/// Connection to a device's lockdownd
+pub struct LockdownClient {
+    stream: Option<ServiceStream>,
+    label: String,
+}
+impl LockdownClient {
+    /// Connects to lockdownd on the given device
+    ///
+    /// # Errors
+    /// Fails if the muxer is unavailable or the device refuses the connection
+    pub fn connect(device_id: DeviceId) -> Result<Self> {
+        Self::connect_with_label(device_id, DEFAULT_LABEL)
+    }
+    /// Connects to lockdownd, identifying requests with `label` (typically the app's name)
+    pub fn connect_with_label<L: Into<String>>(device_id: DeviceId, label: L) -> Result<Self> {
+        let socket = connect_to_device(device_id, LOCKDOWN_PORT)?.into_socket();
+        Ok(LockdownClient {
+            stream: Some(ServiceStream::Plain(socket)),
+            label: label.into(),
+        })
+    }
+    /// Asks the service for its type, which should always be `com.apple.mobile.lockdown`
+    pub fn query_type(&mut self) -> Result<String> {
+        let reply = self.request("QueryType", Dictionary::new())?;
+        let service_type = reply
+            .get("Type")
+            .and_then(Value::as_string)
+            .ok_or(ProtocolError::InvalidPlistEntryForKey("Type"))?;
+        if service_type != LOCKDOWN_SERVICE_TYPE {
+            warn!("Unexpected lockdown service type: {}", service_type);
+        }
+        Ok(service_type.to_owned())
+    }
+    /// Reads a value from the device, both domain & key are optional and omitting the key returns
+    /// the whole domain as a dictionary
+    ///
+    /// Without a session only a handful of keys are available, such as `DeviceName`,
+    /// `ProductType`, `ProductVersion`, `UniqueDeviceID` & `WiFiAddress`.
+    pub fn get_value(&mut self, domain: Option<&str>, key: Option<&str>) -> Result<Value> {
+        let mut args = Dictionary::new();
+        if let Some(domain) = domain {
+            args.insert("Domain".to_owned(), Value::String(domain.to_owned()));
+        }
+        if let Some(key) = key {
+            args.insert("Key".to_owned(), Value::String(key.to_owned()));
+        }
+        let mut reply = self.request("GetValue", args)?;
+        reply
+            .remove("Value")
+            .ok_or_else(|| ProtocolError::InvalidPlistEntryForKey("Value").into())
+    }
+    /// Reads a string value from the default domain
+    pub fn get_string(&mut self, key: &str) -> Result<String> {
+        match self.get_value(None, Some(key))? {
+            Value::String(s) => Ok(s),
+            _ => Err(ProtocolError::InvalidPlistEntryForKey("Value").into()),
+        }
+    }
+    /// User assigned name of the device, such as "Alice's iPhone"
+    pub fn device_name(&mut self) -> Result<String> {
+        self.get_string("DeviceName")
+    }
+    /// iOS version of the device, such as "17.2"
+    pub fn product_version(&mut self) -> Result<String> {
+        self.get_string("ProductVersion")
+    }
+    /// Model identifier of the device, such as "iPhone15,2"
+    pub fn product_type(&mut self) -> Result<String> {
+        self.get_string("ProductType")
+    }
+    /// Wi-Fi MAC address of the device
+    pub fn wifi_address(&mut self) -> Result<String> {
+        self.get_string("WiFiAddress")
+    }
+    /// Reads the device's name, model & iOS version
+    pub fn device_details(&mut self) -> Result<DeviceDetails> {
+        Ok(DeviceDetails {
+            name: self.device_name()?,
+            product_type: self.product_type()?,
+            product_version: self.product_version()?,
+        })
+    }
+    /// Starts a session using the host's pair record, required for starting services
+    ///
+    /// If the device asks for it the connection is switched to TLS, which requires the `tls` feature.
+    pub fn start_session(&mut self, pair_record: &PairRecord) -> Result<LockdownSession> {
+        let mut args = Dictionary::new();
+        args.insert(
+            "HostID".to_owned(),
+            Value::String(pair_record.host_id.clone()),
+        );
+        args.insert(
+            "SystemBUID".to_owned(),
+            Value::String(pair_record.system_buid.clone()),
+        );
+        let reply = self.request("StartSession", args)?;
+        let session_id = reply
+            .get("SessionID")
+            .and_then(Value::as_string)
+            .ok_or(ProtocolError::InvalidPlistEntryForKey("SessionID"))?
+            .to_owned();
+        let enable_session_ssl = reply
+            .get("EnableSessionSSL")
+            .and_then(Value::as_boolean)
+            .unwrap_or(false);
+        if enable_session_ssl {
+            let stream = self.stream.take().ok_or(Error::TlsRequired)?;
+            self.stream = Some(stream.into_tls(pair_record)?);
+        }
+        Ok(LockdownSession {
+            session_id,
+            enable_session_ssl,
+        })
+    }
+    /// Asks lockdownd to start the named service, returning the port it's available on
+    pub fn start_service(&mut self, name: &str) -> Result<ServiceDescriptor> {
+        let mut args = Dictionary::new();
+        args.insert("Service".to_owned(), Value::String(name.to_owned()));
+        let reply = self.request("StartService", args)?;
+        let port = reply
+            .get("Port")
+            .and_then(Value::as_unsigned_integer)
+            .and_then(|port| u16::try_from(port).ok())
+            .ok_or(ProtocolError::InvalidPlistEntryForKey("Port"))?;
+        let enable_service_ssl = reply
+            .get("EnableServiceSSL")
+            .and_then(Value::as_boolean)
+            .unwrap_or(false);
+        Ok(ServiceDescriptor {
+            port,
+            enable_service_ssl,
+        })
+    }
+    /// Sends a request & waits for its reply, checking the reply for errors
+    pub(crate) fn request(&mut self, request: &str, mut args: Dictionary) -> Result<Dictionary> {
+        args.insert("Label".to_owned(), Value::String(self.label.clone()));
+        args.insert("Request".to_owned(), Value::String(request.to_owned()));
+        // only missing if a TLS upgrade failed, leaving the connection unusable
+        let stream = self.stream.as_mut().ok_or(Error::TlsRequired)?;
+        send_plist(stream, &Value::Dictionary(args))?;
+        let reply = match recv_plist(stream)? {
+            Value::Dictionary(d) => d,
+            _ => return Err(ProtocolError::InvalidPlistEntry.into()),
+        };
+        if let Some(error) = reply.get("Error").and_then(Value::as_string) {
+            return Err(Error::LockdownError(error.to_owned()));
+        }
+        if reply.get("Request").and_then(Value::as_string) != Some(request) {
+            return Err(ProtocolError::InvalidPlistEntryForKey("Request").into());
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn it_frames_plists() {
+        let mut dict = Dictionary::new();
+        dict.insert("Request".to_owned(), Value::String("QueryType".to_owned()));
+        let value = Value::Dictionary(dict);
+        let mut buffer: Vec<u8> = Vec::new();
+        send_plist(&mut buffer, &value).unwrap();
+        let size = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+        assert_eq!(size as usize, buffer.len() - 4);
+        let decoded = recv_plist(&mut std::io::Cursor::new(buffer)).unwrap();
+        assert_eq!(decoded, value);
+    }
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn it_reads_device_details() {
+        let (host, mut device) = std::os::unix::net::UnixStream::pair().unwrap();
+        let responder = std::thread::spawn(move || {
+            for value in &["Alice's iPhone", "iPhone15,2", "17.2"] {
+                let request = recv_plist(&mut device).unwrap();
+                let mut reply = request.into_dictionary().unwrap();
+                reply.insert("Value".to_owned(), Value::String((*value).to_owned()));
+                send_plist(&mut device, &Value::Dictionary(reply)).unwrap();
+            }
+        });
+        let mut client = LockdownClient {
+            stream: Some(ServiceStream::Plain(host.into())),
+            label: DEFAULT_LABEL.to_owned(),
+        };
+        let details = client.device_details().unwrap();
+        responder.join().unwrap();
+        assert_eq!(details.name, "Alice's iPhone");
+        assert_eq!(details.product_type, "iPhone15,2");
+        assert_eq!(details.product_version, "17.2");
+    }
+    #[test]
+    fn it_rejects_oversized_plists() {
+        let buffer = (MAX_PLIST_MESSAGE_SIZE + 1).to_be_bytes();
+        assert!(recv_plist(&mut std::io::Cursor::new(buffer)).is_err());
+    }
+}
@@ -0,0 +1,563 @@
+//! Locating & connecting to the muxer (usbmuxd, or Apple Mobile Device Service on Windows)
+use crate::capture::Capture;
+use crate::protocol::Command;
+#[cfg(target_os = "windows")]
+use crate::protocol::{Packet, PacketType, Protocol};
+use crate::retry::{retry, ReconnectPolicy};
+use crate::{Error, MuxerTransport, ProtocolError, Result, UsbSocket};
+use plist::Value;
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Environment variable overriding the muxer address, the same as libusbmuxd uses
+pub const SOCKET_ADDRESS_ENV: &str = "USBMUXD_SOCKET_ADDRESS";
+/// Socket usbmuxd & macOS's muxer listen on
+#[cfg(not(target_os = "windows"))]
+const DEFAULT_SOCKET_PATH: &str = "/var/run/usbmuxd";
+/// Port Apple Mobile Device Service listens on, on localhost
+#[cfg(target_os = "windows")]
+const WINDOWS_TCP_PORT: u16 = 27015;
+/// Ports near the default, probed as a last resort when detecting the service's port
+#[cfg(target_os = "windows")]
+const CANDIDATE_PORTS: [u16; 6] = [27015, 27016, 27017, 27018, 27019, 27020];
+/// How long to wait on each port when probing for the muxer
+#[cfg(target_os = "windows")]
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to wait for a TCP muxer to accept a connection, unless configured otherwise
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Client name sent to the muxer unless configured otherwise, shows up in its logs
+const DEFAULT_CLIENT_NAME: &str = "peertalk";
+
+/// How to reach the muxer & identify to it
+///
+/// The default uses `USBMUXD_SOCKET_ADDRESS` if set, otherwise the platform's muxer.
+#[derive(Debug, Clone)]
+pub struct MuxerConfig {
+    address: MuxerAddress,
+    connect_timeout: Duration,
+    client_name: String,
+    client_version: Option<String>,
+    bundle_id: Option<String>,
+    lib_usbmux_version: Option<u64>,
+    binary_plist: bool,
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    detect_port: bool,
+    retry: Option<Arc<dyn ReconnectPolicy>>,
+    transport: Option<Arc<dyn MuxerTransport>>,
+    capture: Option<Capture>,
+}
+impl Default for MuxerConfig {
+    fn default() -> Self {
+        MuxerConfig {
+            address: MuxerAddress::from_env(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            client_name: DEFAULT_CLIENT_NAME.to_owned(),
+            client_version: None,
+            bundle_id: None,
+            lib_usbmux_version: None,
+            binary_plist: false,
+            detect_port: std::env::var_os(SOCKET_ADDRESS_ENV).is_none(),
+            retry: None,
+            transport: None,
+            capture: None,
+        }
+    }
+}
+impl MuxerConfig {
+    /// Uses the muxer at the given address
+    pub fn address(mut self, address: MuxerAddress) -> Self {
+        self.address = address;
+        self.detect_port = false;
+        self
+    }
+    /// Uses the muxer listening on the given loopback TCP port, as Apple Mobile Device Service does
+    pub fn port(self, port: u16) -> Self {
+        self.address(MuxerAddress::Tcp {
+            host: "127.0.0.1".to_owned(),
+            port,
+        })
+    }
+    /// On Windows, whether to look for Apple Mobile Device Service on other ports when it isn't
+    /// reachable on the default one, see `detect_muxer_port`
+    ///
+    /// Enabled unless an address was configured or given with `USBMUXD_SOCKET_ADDRESS`.
+    pub fn detect_port(mut self, detect: bool) -> Self {
+        self.detect_port = detect;
+        self
+    }
+    /// Uses the muxer listening on the given Unix socket
+    pub fn socket_path<P: Into<PathBuf>>(self, path: P) -> Self {
+        self.address(MuxerAddress::Unix(path.into()))
+    }
+    /// Gives up connecting to a TCP muxer after `timeout`, Unix sockets connect immediately
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+    /// Identifies to the muxer with the given name (typically the app's name)
+    pub fn client_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.client_name = name.into();
+        self
+    }
+    /// Identifies to the muxer with the given client version, shown next to the client name
+    pub fn client_version<S: Into<String>>(mut self, version: S) -> Self {
+        self.client_version = Some(version.into());
+        self
+    }
+    /// Identifies to the muxer as the app with the given bundle identifier
+    pub fn bundle_id<S: Into<String>>(mut self, bundle_id: S) -> Self {
+        self.bundle_id = Some(bundle_id.into());
+        self
+    }
+    /// Claims the given libusbmuxd protocol version, which some muxers log or key behavior on
+    pub fn lib_usbmux_version(mut self, version: u64) -> Self {
+        self.lib_usbmux_version = Some(version);
+        self
+    }
+    /// Sends commands as binary plists instead of XML, which every current muxer accepts
+    ///
+    /// Off by default for compatibility with muxers that only parse XML plists.
+    pub fn binary_plist(mut self, binary: bool) -> Self {
+        self.binary_plist = binary;
+        self
+    }
+    /// Retries connecting to the muxer per `policy` while it's unavailable, such as when the app
+    /// starts at login before usbmuxd/Apple Mobile Device Service is up
+    ///
+    /// Only failures to reach the muxer are retried, errors from the muxer itself aren't.
+    pub fn retry<P: ReconnectPolicy + 'static>(mut self, policy: P) -> Self {
+        self.retry = Some(Arc::new(policy));
+        self
+    }
+    /// Reaches the muxer through a custom transport instead of the address
+    ///
+    /// The connect timeout & retry policy apply to it, Windows port detection doesn't.
+    pub fn transport<T: MuxerTransport + 'static>(mut self, transport: T) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self.detect_port = false;
+        self
+    }
+    /// Records the traffic of every muxer connection to `capture`, for debugging
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+    /// Muxer address this config connects to, unless it uses a custom transport
+    pub fn muxer_address(&self) -> &MuxerAddress {
+        &self.address
+    }
+    /// Opens a new connection to the muxer, retrying if configured to
+    pub fn connect(&self) -> Result<UsbSocket> {
+        self.connect_until(None)
+    }
+    /// Same as [`connect`](Self::connect), not retrying past `deadline`
+    fn connect_until(&self, deadline: Option<Instant>) -> Result<UsbSocket> {
+        let socket = match &self.retry {
+            Some(policy) => retry(
+                policy.as_ref(),
+                deadline,
+                || self.connect_once(),
+                is_unavailable,
+            )?,
+            None => self.connect_once()?,
+        };
+        Ok(match &self.capture {
+            Some(capture) => capture.wrap(socket),
+            None => socket,
+        })
+    }
+    /// Same as [`connect`](Self::connect), giving up connecting at `deadline` if that's sooner than
+    /// the connect timeout
+    pub(crate) fn connect_by(&self, deadline: Instant) -> Result<UsbSocket> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return Err(Error::Timeout);
+        }
+        let mut config = self.clone();
+        config.connect_timeout = config.connect_timeout.min(timeout);
+        config.connect_until(Some(deadline))
+    }
+    fn connect_once(&self) -> Result<UsbSocket> {
+        let transport: &dyn MuxerTransport = match &self.transport {
+            Some(transport) => transport.as_ref(),
+            None => &self.address,
+        };
+        let result = transport.connect(self.connect_timeout);
+        #[cfg(target_os = "windows")]
+        if let Err(Error::ServiceUnavailable(source)) = result {
+            if self.transport.is_some() {
+                return Err(unavailable(source));
+            }
+            if self.detect_port {
+                if let Some(port) = detect_muxer_port() {
+                    info!("Apple Mobile Device Service found on port {}", port);
+                    return self.clone().port(port).connect_once();
+                }
+            }
+            if !self.address.is_local() {
+                return Err(unavailable(source));
+            }
+            return Err(Error::MobileDeviceServiceUnavailable {
+                state: crate::MobileDeviceServiceState::query(),
+                source,
+            });
+        }
+        result.map_err(|e| match e {
+            Error::ServiceUnavailable(source) => unavailable(source),
+            e => e,
+        })
+    }
+    /// Tags a command with this config's client identity
+    pub(crate) fn command(&self, command: Command) -> Command {
+        let mut command = command.with_prog_name(self.client_name.as_str());
+        if let Some(version) = &self.client_version {
+            command = command.with_client_version(version.as_str());
+        }
+        if let Some(bundle_id) = &self.bundle_id {
+            command = command.with_bundle_id(bundle_id.as_str());
+        }
+        if let Some(version) = self.lib_usbmux_version {
+            command = command.with_lib_usbmux_version(version);
+        }
+        command
+    }
+    /// Adds this config's client identity to a raw message, keeping any keys it already sets
+    pub(crate) fn identify(&self, message: &mut plist::Dictionary) {
+        let mut identity = vec![("ProgName", Value::from(self.client_name.as_str()))];
+        if let Some(version) = &self.client_version {
+            identity.push(("ClientVersionString", Value::from(version.as_str())));
+        }
+        if let Some(bundle_id) = &self.bundle_id {
+            identity.push(("BundleID", Value::from(bundle_id.as_str())));
+        }
+        if let Some(version) = self.lib_usbmux_version {
+            identity.push(("kLibUSBMuxVersion", Value::from(version)));
+        }
+        for (key, value) in identity {
+            if !message.contains_key(key) {
+                message.insert(key.to_owned(), value);
+            }
+        }
+    }
+    /// Encodes a raw message in the configured format
+    pub(crate) fn encode_value(&self, message: &Value) -> Result<Vec<u8>> {
+        let mut payload = std::io::Cursor::new(Vec::new());
+        let result = if self.binary_plist {
+            message.to_writer_binary(&mut payload)
+        } else {
+            message.to_writer_xml(&mut payload)
+        };
+        result.map_err(ProtocolError::PlistEncodeError)?;
+        Ok(payload.into_inner())
+    }
+    /// Tags a command with this config's client identity & encodes it in the configured format
+    pub(crate) fn encode(&self, command: Command) -> Result<Vec<u8>> {
+        let command = self.command(command);
+        let payload = if self.binary_plist {
+            command.to_binary_bytes()?
+        } else {
+            command.to_bytes()?
+        };
+        Ok(payload)
+    }
+}
+
+/// Why the muxer couldn't be reached, from the error connecting to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MuxerUnavailableReason {
+    /// Nothing listens at the muxer's address, so usbmuxd isn't running or installed
+    NotRunning,
+    /// The muxer's socket may not be used by this user, on Linux usually as they're not in the
+    /// group owning it
+    PermissionDenied,
+    /// The muxer didn't accept the connection within the connect timeout
+    Timeout,
+    /// Any other failure, see the source error
+    Other,
+}
+impl MuxerUnavailableReason {
+    /// Classifies an IO error from connecting to the muxer
+    pub fn from_io_error(error: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match error.kind() {
+            ErrorKind::ConnectionRefused | ErrorKind::NotFound | ErrorKind::AddrNotAvailable => {
+                MuxerUnavailableReason::NotRunning
+            }
+            ErrorKind::PermissionDenied => MuxerUnavailableReason::PermissionDenied,
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => MuxerUnavailableReason::Timeout,
+            _ => MuxerUnavailableReason::Other,
+        }
+    }
+}
+impl std::fmt::Display for MuxerUnavailableReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MuxerUnavailableReason::NotRunning => "isn't running",
+            MuxerUnavailableReason::PermissionDenied => "denied access to its socket",
+            MuxerUnavailableReason::Timeout => "didn't accept the connection in time",
+            MuxerUnavailableReason::Other => "is unavailable",
+        })
+    }
+}
+
+/// Wraps an error connecting to the muxer with what it means
+fn unavailable(source: std::io::Error) -> Error {
+    Error::MuxerUnavailable {
+        reason: MuxerUnavailableReason::from_io_error(&source),
+        source,
+    }
+}
+
+/// Checks if an error means the muxer couldn't be reached, rather than it refusing a request
+pub(crate) fn is_unavailable(error: &Error) -> bool {
+    match error {
+        Error::MuxerUnavailable { .. } => true,
+        #[cfg(target_os = "windows")]
+        Error::MobileDeviceServiceUnavailable { .. } => true,
+        _ => false,
+    }
+}
+
+/// Where the muxer listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MuxerAddress {
+    /// Unix domain socket at the given path
+    Unix(PathBuf),
+    /// TCP host & port, such as a muxer forwarded from another machine
+    Tcp {
+        /// Host name or IP address
+        host: String,
+        /// TCP port
+        port: u16,
+    },
+}
+impl MuxerAddress {
+    /// Parses an address in the `USBMUXD_SOCKET_ADDRESS` format, `UNIX:<path>` or `<host>:<port>`
+    pub fn parse(address: &str) -> Option<Self> {
+        if let Some(path) = address.strip_prefix("UNIX:") {
+            if path.is_empty() {
+                return None;
+            }
+            return Some(MuxerAddress::Unix(PathBuf::from(path)));
+        }
+        let (host, port) = address.rsplit_once(':')?;
+        // IPv6 addresses may be bracketed, as in [::1]:27015
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        if host.is_empty() {
+            return None;
+        }
+        Some(MuxerAddress::Tcp {
+            host: host.to_owned(),
+            port: port.parse().ok()?,
+        })
+    }
+    /// Checks if the address is on this machine, rather than a muxer forwarded from elsewhere
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    pub(crate) fn is_local(&self) -> bool {
+        match self {
+            MuxerAddress::Unix(_) => true,
+            MuxerAddress::Tcp { host, .. } => {
+                host == "localhost"
+                    || host
+                        .parse::<std::net::IpAddr>()
+                        .map(|ip| ip.is_loopback())
+                        .unwrap_or(false)
+            }
+        }
+    }
+    /// Address from `USBMUXD_SOCKET_ADDRESS` if it's set & valid, otherwise the platform's default
+    pub fn from_env() -> Self {
+        match std::env::var(SOCKET_ADDRESS_ENV) {
+            Ok(address) => Self::parse(&address).unwrap_or_else(|| {
+                warn!("Ignoring invalid {}: {}", SOCKET_ADDRESS_ENV, address);
+                Self::platform_default()
+            }),
+            Err(_) => Self::platform_default(),
+        }
+    }
+    /// Address the platform's muxer listens on
+    #[cfg(not(target_os = "windows"))]
+    pub fn platform_default() -> Self {
+        MuxerAddress::Unix(PathBuf::from(DEFAULT_SOCKET_PATH))
+    }
+    /// Address the platform's muxer listens on
+    #[cfg(target_os = "windows")]
+    pub fn platform_default() -> Self {
+        MuxerAddress::Tcp {
+            host: "127.0.0.1".to_owned(),
+            port: WINDOWS_TCP_PORT,
+        }
+    }
+    /// Opens a new connection to the muxer at this address, `timeout` only applies to TCP
+    pub fn connect(&self, timeout: Duration) -> Result<UsbSocket> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            MuxerAddress::Unix(path) => Ok(UnixStream::connect(path)?.into()),
+            #[cfg(target_os = "windows")]
+            MuxerAddress::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix socket muxer addresses aren't supported on Windows",
+            )
+            .into()),
+            MuxerAddress::Tcp { host, port } => {
+                let mut last_error = None;
+                for address in (host.as_str(), *port).to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&address, timeout) {
+                        Ok(socket) => return Ok(socket.into()),
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+                Err(last_error
+                    .unwrap_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            format!("{} didn't resolve to any address", host),
+                        )
+                    })
+                    .into())
+            }
+        }
+    }
+}
+
+/// Finds the loopback port Apple Mobile Device Service listens on, if it isn't the usual 27015
+///
+/// Asks the system which ports the service's process is listening on, then falls back to probing
+/// a few ports near the default. Each port is checked with a muxer request before it's returned.
+#[cfg(target_os = "windows")]
+pub fn detect_muxer_port() -> Option<u16> {
+    let mut ports = crate::amds::listening_ports();
+    for port in CANDIDATE_PORTS.iter() {
+        if !ports.contains(port) {
+            ports.push(*port);
+        }
+    }
+    ports.into_iter().find(|port| is_muxer(*port))
+}
+
+/// Checks whatever listens on a loopback port answers a muxer request
+#[cfg(target_os = "windows")]
+fn is_muxer(port: u16) -> bool {
+    let address = MuxerAddress::Tcp {
+        host: "127.0.0.1".to_owned(),
+        port,
+    };
+    let mut socket = match address.connect(PROBE_TIMEOUT) {
+        Ok(socket) => socket,
+        Err(_) => return false,
+    };
+    if socket.set_read_timeout(Some(PROBE_TIMEOUT)).is_err() {
+        return false;
+    }
+    let packet = Command::read_buid()
+        .to_bytes()
+        .and_then(|payload| Packet::new(Protocol::Plist, PacketType::PlistPayload, 0, payload));
+    if packet
+        .and_then(|packet| packet.write_into(&mut socket))
+        .is_err()
+    {
+        return false;
+    }
+    match Packet::from_reader(&mut socket) {
+        Ok(reply) => plist::Value::from_reader(std::io::Cursor::new(reply.data)).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn it_parses_socket_addresses() {
+        assert_eq!(
+            MuxerAddress::parse("UNIX:/tmp/mux.sock"),
+            Some(MuxerAddress::Unix(PathBuf::from("/tmp/mux.sock")))
+        );
+        assert_eq!(
+            MuxerAddress::parse("127.0.0.1:27015"),
+            Some(MuxerAddress::Tcp {
+                host: "127.0.0.1".to_owned(),
+                port: 27015
+            })
+        );
+        assert_eq!(
+            MuxerAddress::parse("[::1]:27015"),
+            Some(MuxerAddress::Tcp {
+                host: "::1".to_owned(),
+                port: 27015
+            })
+        );
+        assert_eq!(MuxerAddress::parse("UNIX:"), None);
+        assert_eq!(MuxerAddress::parse("localhost"), None);
+        assert_eq!(MuxerAddress::parse("localhost:usbmuxd"), None);
+    }
+    #[test]
+    fn it_tells_local_addresses() {
+        assert!(MuxerAddress::parse("127.0.0.1:27015").unwrap().is_local());
+        assert!(MuxerAddress::parse("[::1]:27015").unwrap().is_local());
+        assert!(!MuxerAddress::parse("10.0.0.2:27015").unwrap().is_local());
+    }
+    #[test]
+    fn it_names_commands_after_the_client() {
+        let config = MuxerConfig::default().client_name("MyApp");
+        let payload = config.command(Command::listen()).to_bytes().unwrap();
+        let value = plist::Value::from_reader(std::io::Cursor::new(payload)).unwrap();
+        let prog_name = value.as_dictionary().unwrap().get("ProgName").unwrap();
+        assert_eq!(prog_name.as_string(), Some("MyApp"));
+    }
+    #[test]
+    fn it_encodes_binary_plists() {
+        let config = MuxerConfig::default().binary_plist(true);
+        let payload = config.encode(Command::listen()).unwrap();
+        assert!(payload.starts_with(b"bplist00"));
+        let value = plist::Value::from_reader(std::io::Cursor::new(payload)).unwrap();
+        let message_type = value.as_dictionary().unwrap().get("MessageType").unwrap();
+        assert_eq!(message_type.as_string(), Some("Listen"));
+    }
+    #[test]
+    fn it_sends_client_identity() {
+        let config = MuxerConfig::default()
+            .client_version("2.1")
+            .bundle_id("com.example.MyApp")
+            .lib_usbmux_version(3);
+        let payload = config.command(Command::listen()).to_bytes().unwrap();
+        let value = plist::Value::from_reader(std::io::Cursor::new(payload)).unwrap();
+        let dict = value.as_dictionary().unwrap();
+        let version = dict.get("ClientVersionString").and_then(|v| v.as_string());
+        assert_eq!(version, Some("2.1"));
+        let bundle_id = dict.get("BundleID").and_then(|v| v.as_string());
+        assert_eq!(bundle_id, Some("com.example.MyApp"));
+        let lib_version = dict.get("kLibUSBMuxVersion");
+        assert_eq!(lib_version.and_then(|v| v.as_unsigned_integer()), Some(3));
+        let payload = MuxerConfig::default()
+            .command(Command::listen())
+            .to_bytes()
+            .unwrap();
+        let value = plist::Value::from_reader(std::io::Cursor::new(payload)).unwrap();
+        assert!(value.as_dictionary().unwrap().get("BundleID").is_none());
+    }
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn it_tells_when_the_muxer_isnt_running() {
+        let path = std::env::temp_dir().join("peertalk-no-muxer");
+        let error = MuxerConfig::default()
+            .socket_path(path)
+            .connect()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::MuxerUnavailable {
+                reason: MuxerUnavailableReason::NotRunning,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,110 @@
+//! Newline delimited JSON for device events & errors, for scripts consuming hot-plug state
+//!
+//! Every line is one JSON object with an `event` field naming what it is. Field names are stable,
+//! new fields may be added:
+//!
+//! - `attached` & `device`: `device_id`, `udid`, `product_type` (`iphone`, `ipod_touch`, `ipad`,
+//!   `t2_coprocessor`, `mac_restore` or `unknown`), `product_id` (0 for network devices),
+//!   `connection_type` (`usb`, `network` or what the muxer reported) & `network_address` (network
+//!   devices only)
+//! - `detached` & `paired`: `device_id`, & for paired devices whose pair record was read, `udid`,
+//!   `host_id` & `system_buid`
+//! - `unknown`: `message_type` of a muxer message this crate doesn't know
+//! - `muxer_disconnected`: the listener lost its muxer connection, with no other fields
+//! - `reattached`: the fields of `attached`, plus `previous_device_id`
+//! - `error`: `message`
+use crate::{DeviceAttachedInfo, DeviceConnectionType, DeviceEvent, Error, ProductType, Result};
+use serde_json::{json, Map, Value};
+use std::io::Write;
+
+/// JSON object for a device event
+pub fn event(event: &DeviceEvent) -> Value {
+    match event {
+        DeviceEvent::Attached(info) => device_fields("attached", info),
+        DeviceEvent::Detached(id) => json!({ "event": "detached", "device_id": id }),
+        DeviceEvent::Paired(id) => json!({ "event": "paired", "device_id": id }),
+        DeviceEvent::Unknown { message_type, .. } => {
+            json!({ "event": "unknown", "message_type": message_type })
+        }
+        DeviceEvent::PairedWithRecord {
+            device_id,
+            udid,
+            host_id,
+            system_buid,
+        } => json!({
+            "event": "paired",
+            "device_id": device_id,
+            "udid": udid.as_str(),
+            "host_id": host_id,
+            "system_buid": system_buid,
+        }),
+        DeviceEvent::MuxerDisconnected => json!({ "event": "muxer_disconnected" }),
+        DeviceEvent::Reattached { previous, info } => {
+            let mut fields = device_fields("reattached", info);
+            fields["previous_device_id"] = json!(previous);
+            fields
+        }
+        _ => json!({ "event": "unknown" }),
+    }
+}
+
+/// JSON object for an attached device, as in a device list
+pub fn device(info: &DeviceAttachedInfo) -> Value {
+    device_fields("device", info)
+}
+
+/// JSON object for an error
+pub fn error(error: &Error) -> Value {
+    json!({ "event": "error", "message": error.to_string() })
+}
+
+/// Writes the value on a line of its own & flushes, so consumers see it right away
+pub fn write_line<W: Write>(mut writer: W, value: &Value) -> Result<()> {
+    serde_json::to_writer(&mut writer, value).map_err(std::io::Error::from)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn device_fields(event: &str, info: &DeviceAttachedInfo) -> Value {
+    let mut fields = Map::new();
+    fields.insert("event".to_owned(), event.into());
+    fields.insert("device_id".to_owned(), info.device_id.0.into());
+    fields.insert("udid".to_owned(), info.identifier.to_string().into());
+    let product_type = match info.product_type {
+        ProductType::IPhone => "iphone",
+        ProductType::IPodTouch => "ipod_touch",
+        ProductType::IPad => "ipad",
+        ProductType::T2Coprocessor => "t2_coprocessor",
+        ProductType::MacRestore => "mac_restore",
+        ProductType::Unknown(_) => "unknown",
+    };
+    fields.insert("product_type".to_owned(), product_type.into());
+    fields.insert("product_id".to_owned(), info.product_id.into());
+    let connection_type = match &info.connection_type {
+        DeviceConnectionType::USB => "usb",
+        DeviceConnectionType::Network => "network",
+        DeviceConnectionType::Unknown(name) => name,
+    };
+    fields.insert("connection_type".to_owned(), connection_type.into());
+    if let Some(address) = info.network_address {
+        fields.insert("network_address".to_owned(), address.to_string().into());
+    }
+    Value::Object(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeviceId;
+    #[test]
+    fn it_writes_one_event_per_line() {
+        let mut output = Vec::new();
+        write_line(&mut output, &event(&DeviceEvent::Detached(DeviceId(3)))).unwrap();
+        write_line(&mut output, &event(&DeviceEvent::Paired(DeviceId(3)))).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"device_id\":3,\"event\":\"detached\"}\n{\"device_id\":3,\"event\":\"paired\"}\n"
+        );
+    }
+}
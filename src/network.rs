@@ -0,0 +1,76 @@
+//! Connections to devices on the local network, found by usbmuxd when Wi-Fi sync is enabled
+use crate::UsbStream;
+use crate::{connect_to_device, DeviceAttachedInfo, DeviceConnectionType, Error, Result};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// How long to wait for a direct TCP connection to a network device
+const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection to a device port, either tunnelled through usbmuxd or dialed directly over the LAN
+pub enum NetworkStream {
+    /// Connection tunnelled through usbmuxd, like USB connections
+    Muxed(UsbStream),
+    /// Direct TCP connection to the device's network address
+    Direct(TcpStream),
+}
+impl NetworkStream {
+    /// Sets the read timeout of the underlying socket
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            NetworkStream::Muxed(socket) => socket.set_read_timeout(timeout),
+            NetworkStream::Direct(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+impl Read for NetworkStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            NetworkStream::Muxed(socket) => socket.read(buf),
+            NetworkStream::Direct(stream) => stream.read(buf),
+        }
+    }
+}
+impl Write for NetworkStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            NetworkStream::Muxed(socket) => socket.write(buf),
+            NetworkStream::Direct(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            NetworkStream::Muxed(socket) => socket.flush(),
+            NetworkStream::Direct(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Connects to a port on a device, which may be attached over USB or the network
+///
+/// usbmuxd is asked to tunnel the connection first. If it refuses, which some muxers do for network
+/// devices, the device's reported network address is dialed directly instead; the app on the
+/// device must then be listening on its network interface rather than only loopback.
+///
+/// # Errors
+/// Fails with the muxer's error if the device isn't a network device or has no known address,
+/// otherwise with the error from dialing it directly
+pub fn connect_to_network_device(info: &DeviceAttachedInfo, port: u16) -> Result<NetworkStream> {
+    let muxed_error = match connect_to_device(info.device_id, port) {
+        Ok(socket) => return Ok(NetworkStream::Muxed(socket)),
+        Err(e) => e,
+    };
+    let mut address = match (&info.connection_type, info.network_address) {
+        (DeviceConnectionType::Network, Some(address)) => address,
+        _ => return Err(muxed_error),
+    };
+    debug!(
+        "usbmuxd couldn't tunnel to device {} ({}), dialing {} directly",
+        info.device_id, muxed_error, address
+    );
+    address.set_port(port); // usbmuxd reports the address without a port
+    TcpStream::connect_timeout(&address, DIRECT_CONNECT_TIMEOUT)
+        .map(NetworkStream::Direct)
+        .map_err(Error::from)
+}
@@ -0,0 +1,267 @@
+//! Access to the pair records usbmuxd keeps for trusted devices
+use crate::protocol::{self, Command};
+use crate::{muxer_request, DeviceId, Error, MuxerClient, ProtocolError, Result, Udid};
+use plist::Value;
+use std::convert::TryFrom;
+
+const PAIR_RECORD_DATA_KEY: &str = "PairRecordData";
+
+/// Trust relationship between this host & a device, holding the keys needed for lockdown sessions
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairRecord {
+    /// ID identifying this host to the device
+    pub host_id: String,
+    /// ID of the host system (shared by all of the host's pair records)
+    pub system_buid: String,
+    /// Host's certificate, PEM encoded
+    pub host_certificate: Vec<u8>,
+    /// Host's private key, PEM encoded
+    pub host_private_key: Vec<u8>,
+    /// Device's certificate, PEM encoded
+    pub device_certificate: Vec<u8>,
+    /// Root CA certificate that signed the host & device certificates, PEM encoded
+    pub root_certificate: Vec<u8>,
+    /// Root CA's private key, PEM encoded
+    pub root_private_key: Option<Vec<u8>>,
+    /// Device's Wi-Fi MAC address
+    pub wifi_mac_address: Option<String>,
+    /// Escrow keybag allowing access to the device while it's locked
+    pub escrow_bag: Option<Vec<u8>>,
+}
+impl TryFrom<&Value> for PairRecord {
+    type Error = ProtocolError;
+    fn try_from(value: &Value) -> std::result::Result<Self, Self::Error> {
+        let d = value
+            .as_dictionary()
+            .ok_or(ProtocolError::InvalidPlistEntry)?;
+        let string = |key: &'static str| {
+            d.get(key)
+                .and_then(Value::as_string)
+                .map(str::to_owned)
+                .ok_or(ProtocolError::InvalidPlistEntryForKey(key))
+        };
+        let data = |key: &'static str| {
+            d.get(key)
+                .and_then(Value::as_data)
+                .map(<[u8]>::to_vec)
+                .ok_or(ProtocolError::InvalidPlistEntryForKey(key))
+        };
+        Ok(PairRecord {
+            host_id: string("HostID")?,
+            system_buid: string("SystemBUID")?,
+            host_certificate: data("HostCertificate")?,
+            host_private_key: data("HostPrivateKey")?,
+            device_certificate: data("DeviceCertificate")?,
+            root_certificate: data("RootCertificate")?,
+            root_private_key: data("RootPrivateKey").ok(),
+            wifi_mac_address: string("WiFiMACAddress").ok(),
+            escrow_bag: data("EscrowBag").ok(),
+        })
+    }
+}
+
+impl From<&PairRecord> for Value {
+    fn from(record: &PairRecord) -> Self {
+        let mut d = plist::Dictionary::new();
+        d.insert("HostID".to_owned(), Value::String(record.host_id.clone()));
+        d.insert(
+            "SystemBUID".to_owned(),
+            Value::String(record.system_buid.clone()),
+        );
+        d.insert(
+            "HostCertificate".to_owned(),
+            Value::Data(record.host_certificate.clone()),
+        );
+        d.insert(
+            "HostPrivateKey".to_owned(),
+            Value::Data(record.host_private_key.clone()),
+        );
+        d.insert(
+            "DeviceCertificate".to_owned(),
+            Value::Data(record.device_certificate.clone()),
+        );
+        d.insert(
+            "RootCertificate".to_owned(),
+            Value::Data(record.root_certificate.clone()),
+        );
+        if let Some(key) = &record.root_private_key {
+            d.insert("RootPrivateKey".to_owned(), Value::Data(key.clone()));
+        }
+        if let Some(address) = &record.wifi_mac_address {
+            d.insert("WiFiMACAddress".to_owned(), Value::String(address.clone()));
+        }
+        if let Some(escrow_bag) = &record.escrow_bag {
+            d.insert("EscrowBag".to_owned(), Value::Data(escrow_bag.clone()));
+        }
+        Value::Dictionary(d)
+    }
+}
+
+/// Reads the pair record usbmuxd holds for a device, None if the device hasn't trusted this host
+pub fn read_pair_record(udid: &str) -> Result<Option<PairRecord>> {
+    MuxerClient::connect()?.read_pair_record(udid)
+}
+
+/// Stores a pair record with usbmuxd, so it's used for future sessions with the device
+pub fn save_pair_record(udid: &str, device_id: DeviceId, record: &PairRecord) -> Result<()> {
+    let mut data: Vec<u8> = Vec::new();
+    Value::from(record)
+        .to_writer_xml(&mut data)
+        .map_err(|_| ProtocolError::InvalidPlistEntry)?;
+    expect_success(muxer_request(Command::save_pair_record(
+        udid, device_id, data,
+    ))?)
+}
+
+/// Deletes the pair record usbmuxd holds for a device
+pub fn delete_pair_record(udid: &str) -> Result<()> {
+    expect_success(muxer_request(Command::delete_pair_record(udid))?)
+}
+
+/// Reads the host's system BUID from usbmuxd, which identifies the host in pair records
+pub fn read_system_buid() -> Result<String> {
+    MuxerClient::connect()?.read_buid()
+}
+
+/// Checks a muxer reply is a successful result
+fn expect_success(reply: Value) -> Result<()> {
+    let code = protocol::ResultMessage::try_from(&reply)?.code()?;
+    if code != protocol::ReplyCode::Ok {
+        return Err(Error::PairRecordError(code));
+    }
+    Ok(())
+}
+
+/// A device usbmuxd holds a pair record (trust relationship) for
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairRecordEntry {
+    /// Device's UDID, its [`Udid::serial`] is the ID the pair record is stored under
+    pub udid: Udid,
+    /// Current muxer ID of the device
+    pub device_id: DeviceId,
+    /// Host ID stored in the pair record, identifying the host the device trusts
+    pub host_id: Option<String>,
+}
+
+/// Lists devices connected to the host via usbmuxd/Apple Mobile Device Service
+pub fn list_devices() -> Result<Vec<protocol::DeviceAttachedInfo>> {
+    MuxerClient::connect()?.list_devices()
+}
+
+/// Lists the devices usbmuxd has a pair record for
+///
+/// usbmuxd has no request to enumerate its pair record store, so this checks every currently
+/// attached device and only reports those, devices that are paired but unplugged aren't listed.
+pub fn list_pair_records() -> Result<Vec<PairRecordEntry>> {
+    let mut entries = Vec::new();
+    for device in list_devices()? {
+        if let Some(record) = read_pair_record_value(device.identifier.serial())? {
+            let host_id = record
+                .as_dictionary()
+                .and_then(|d| d.get("HostID"))
+                .and_then(Value::as_string)
+                .map(str::to_owned);
+            entries.push(PairRecordEntry {
+                udid: device.identifier.clone(),
+                device_id: device.device_id,
+                host_id,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Checks if a device has trusted this host, by whether usbmuxd holds a pair record for it
+///
+/// An attached device that isn't paired still needs the user to tap "Trust" before services
+/// on it can be used.
+pub fn is_paired(udid: &str) -> Result<bool> {
+    MuxerClient::connect()?.is_paired(udid)
+}
+
+/// Reads the raw pair record plist for a device, None if the device isn't paired
+fn read_pair_record_value(udid: &str) -> Result<Option<Value>> {
+    pair_record_value(&muxer_request(Command::read_pair_record(udid))?)
+}
+
+/// Pair record plist in a `ReadPairRecord` reply, None if the device isn't paired
+pub(crate) fn pair_record_value(reply: &Value) -> Result<Option<Value>> {
+    let data = match reply
+        .as_dictionary()
+        .and_then(|d| d.get(PAIR_RECORD_DATA_KEY))
+    {
+        Some(data) => data
+            .as_data()
+            .ok_or(ProtocolError::InvalidPlistEntryForKey(PAIR_RECORD_DATA_KEY))?,
+        None => return missing_pair_record(reply),
+    };
+    let record = Value::from_reader(std::io::Cursor::new(data))
+        .map_err(|_| ProtocolError::InvalidPlistEntryForKey(PAIR_RECORD_DATA_KEY))?;
+    Ok(Some(record))
+}
+
+/// Reads a `ReadPairRecord` reply without a record, which usbmuxd sends as a `BadDevice` result
+///
+/// Any other result, such as `BadCommand` from muxers without pair record support, is an error
+/// rather than a sign the device isn't paired.
+fn missing_pair_record(reply: &Value) -> Result<Option<Value>> {
+    match protocol::ResultMessage::try_from(reply)?.code()? {
+        protocol::ReplyCode::BadDevice => Ok(None),
+        protocol::ReplyCode::Ok => {
+            Err(ProtocolError::InvalidPlistEntryForKey(PAIR_RECORD_DATA_KEY).into())
+        }
+        code => Err(Error::PairRecordError(code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn it_decodes_pair_records() {
+        let value = Value::from_file("test_data/pair-record.plist").unwrap();
+        let record = PairRecord::try_from(&value).unwrap();
+        assert_eq!(record.host_id, "2B5C7E9A-3F1D-4C8B-9E6A-1D2F3A4B5C6D");
+        assert_eq!(record.system_buid, "8E3F1A2B-7C4D-4E5F-9A0B-1C2D3E4F5A6B");
+        assert!(record
+            .host_certificate
+            .starts_with(b"-----BEGIN CERTIFICATE-----"));
+        assert_eq!(
+            record.wifi_mac_address.as_deref(),
+            Some("a4:83:e7:12:34:56")
+        );
+        assert!(record.escrow_bag.is_none());
+    }
+    #[test]
+    fn it_reads_failed_results_as_unpaired() {
+        let mut reply = plist::Dictionary::new();
+        reply.insert("MessageType".to_owned(), Value::String("Result".to_owned()));
+        reply.insert("Number".to_owned(), Value::Integer(2.into()));
+        assert!(pair_record_value(&Value::Dictionary(reply))
+            .unwrap()
+            .is_none());
+    }
+    #[test]
+    fn it_fails_on_unsupported_pair_record_requests() {
+        let mut reply = plist::Dictionary::new();
+        reply.insert("MessageType".to_owned(), Value::String("Result".to_owned()));
+        reply.insert("Number".to_owned(), Value::Integer(1.into()));
+        assert!(matches!(
+            pair_record_value(&Value::Dictionary(reply)),
+            Err(Error::PairRecordError(protocol::ReplyCode::BadCommand))
+        ));
+        assert!(pair_record_value(&Value::String("Result".to_owned())).is_err());
+        let mut reply = plist::Dictionary::new();
+        reply.insert("Number".to_owned(), Value::Integer(4.into()));
+        assert!(matches!(
+            pair_record_value(&Value::Dictionary(reply)),
+            Err(Error::ProtocolError(ProtocolError::InvalidReplyCode(4)))
+        ));
+    }
+    #[test]
+    fn it_encodes_pair_records() {
+        let value = Value::from_file("test_data/pair-record.plist").unwrap();
+        let record = PairRecord::try_from(&value).unwrap();
+        assert_eq!(Value::from(&record), value);
+    }
+}
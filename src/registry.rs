@@ -0,0 +1,198 @@
+//! Tracking devices by UDID, which unlike device IDs stays the same when a device is replugged
+use crate::{DeviceAttachedInfo, DeviceConnectionType, DeviceEvent, DeviceId, Udid};
+use std::collections::{BTreeMap, HashMap};
+
+/// Device event keyed by UDID, from [`DeviceRegistry::update`]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+// like DeviceEvent, attach info is kept inline as events are handled one at a time
+#[allow(clippy::large_enum_variant)]
+pub enum IdentityEvent {
+    /// Device attached while it had no other connection to the host
+    Attached {
+        /// The device's UDID
+        udid: Udid,
+        /// Connection that attached
+        info: DeviceAttachedInfo,
+        /// Whether the device was attached before, so its state may be kept from then
+        known: bool,
+    },
+    /// Device's last connection to the host detached
+    Detached {
+        /// The device's UDID
+        udid: Udid,
+    },
+    /// Device trusted the host
+    Paired {
+        /// The device's UDID
+        udid: Udid,
+    },
+}
+
+/// Maps the muxer's device IDs, which change whenever a device is replugged, to UDIDs
+///
+/// Feed it every event from a [`DeviceListener`](crate::DeviceListener) & it reports devices
+/// attaching & detaching by UDID. A device attached over both USB & the network is reported
+/// attached once, & detached once both connections are gone. Devices are remembered after
+/// detaching, so one attaching again is reported as `known`.
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    /// UDID of each attached connection
+    ids: HashMap<DeviceId, Udid>,
+    /// Attached connections of every device seen, empty for detached devices
+    devices: BTreeMap<Udid, Vec<DeviceAttachedInfo>>,
+}
+impl DeviceRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Takes a listener's event, returning what it means for the device it's about
+    ///
+    /// Events for devices the registry didn't see attach, & connections of already attached
+    /// devices, return None.
+    pub fn update(&mut self, event: &DeviceEvent) -> Option<IdentityEvent> {
+        match event {
+            DeviceEvent::Attached(info) => {
+                let udid = info.identifier.clone();
+                if let Some(previous) = self.ids.insert(info.device_id, udid.clone()) {
+                    self.remove_connection(&previous, info.device_id);
+                }
+                let known = self.devices.contains_key(&udid);
+                let connections = self.devices.entry(udid.clone()).or_default();
+                connections.push(info.clone());
+                (connections.len() == 1).then(|| IdentityEvent::Attached {
+                    udid,
+                    info: info.clone(),
+                    known,
+                })
+            }
+            DeviceEvent::Detached(device_id) => {
+                let udid = self.ids.remove(device_id)?;
+                self.remove_connection(&udid, *device_id)
+                    .then_some(IdentityEvent::Detached { udid })
+            }
+            DeviceEvent::Reattached { previous, info } => {
+                // the device stays attached, only its ID changes
+                if let Some(udid) = self.ids.remove(previous) {
+                    self.remove_connection(&udid, *previous);
+                }
+                match self.update(&DeviceEvent::Attached(info.clone())) {
+                    Some(IdentityEvent::Attached { known: true, .. }) => None,
+                    event => event,
+                }
+            }
+            DeviceEvent::Paired(device_id) | DeviceEvent::PairedWithRecord { device_id, .. } => {
+                Some(IdentityEvent::Paired {
+                    udid: self.ids.get(device_id)?.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+    /// UDID of the device with the given ID, if it's attached
+    pub fn udid(&self, device_id: DeviceId) -> Option<&Udid> {
+        self.ids.get(&device_id)
+    }
+    /// Current ID of the device with the given UDID, preferring its USB connection, None if it
+    /// isn't attached
+    pub fn device_id(&self, udid: &Udid) -> Option<DeviceId> {
+        self.connections(udid)
+            .iter()
+            .min_by_key(|info| info.connection_type != DeviceConnectionType::USB)
+            .map(|info| info.device_id)
+    }
+    /// Attached connections of the device with the given UDID
+    pub fn connections(&self, udid: &Udid) -> &[DeviceAttachedInfo] {
+        self.devices.get(udid).map_or(&[], Vec::as_slice)
+    }
+    /// Checks if the device with the given UDID is attached
+    pub fn is_attached(&self, udid: &Udid) -> bool {
+        !self.connections(udid).is_empty()
+    }
+    /// UDIDs of the devices attached now
+    pub fn attached(&self) -> impl Iterator<Item = &Udid> {
+        self.devices
+            .iter()
+            .filter(|(_, connections)| !connections.is_empty())
+            .map(|(udid, _)| udid)
+    }
+    /// UDIDs of every device seen, attached or not
+    pub fn known(&self) -> impl Iterator<Item = &Udid> {
+        self.devices.keys()
+    }
+    /// Forgets a detached device, so it's no longer `known` when it attaches again
+    pub fn forget(&mut self, udid: &Udid) {
+        if !self.is_attached(udid) {
+            self.devices.remove(udid);
+        }
+    }
+    /// Removes a connection, returning whether it was the device's last
+    fn remove_connection(&mut self, udid: &Udid, device_id: DeviceId) -> bool {
+        let connections = match self.devices.get_mut(udid) {
+            Some(connections) => connections,
+            None => return false,
+        };
+        connections.retain(|info| info.device_id != device_id);
+        connections.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProductType;
+
+    const UDID: &str = "00008030-001A35E22E88802E";
+
+    fn attached(device_id: u64, connection_type: DeviceConnectionType) -> DeviceEvent {
+        DeviceEvent::Attached(DeviceAttachedInfo {
+            connection_type,
+            device_id: DeviceId(device_id),
+            location_id: 0,
+            product_type: ProductType::IPhone,
+            product_id: 0x12A8,
+            identifier: Udid::parse(UDID).unwrap(),
+            network_address: None,
+            interface_index: None,
+            connection_speed: None,
+            usb_serial_number: None,
+            escrow_bag: None,
+            properties: Default::default(),
+        })
+    }
+
+    #[test]
+    fn it_tracks_devices_across_replugs() {
+        let mut registry = DeviceRegistry::new();
+        let udid = Udid::parse(UDID).unwrap();
+        assert!(matches!(
+            registry.update(&attached(1, DeviceConnectionType::USB)),
+            Some(IdentityEvent::Attached { known: false, .. })
+        ));
+        // the same device found on the network isn't attached again
+        assert!(registry
+            .update(&attached(2, DeviceConnectionType::Network))
+            .is_none());
+        assert_eq!(registry.device_id(&udid), Some(DeviceId(1)));
+        assert!(registry
+            .update(&DeviceEvent::Detached(DeviceId(1)))
+            .is_none());
+        assert_eq!(registry.device_id(&udid), Some(DeviceId(2)));
+        assert!(matches!(
+            registry.update(&DeviceEvent::Detached(DeviceId(2))),
+            Some(IdentityEvent::Detached { udid: ref u }) if *u == udid
+        ));
+        assert!(!registry.is_attached(&udid));
+        assert!(matches!(
+            registry.update(&attached(3, DeviceConnectionType::USB)),
+            Some(IdentityEvent::Attached { known: true, .. })
+        ));
+        assert!(matches!(
+            registry.update(&DeviceEvent::Paired(DeviceId(3))),
+            Some(IdentityEvent::Paired { .. })
+        ));
+        assert_eq!(registry.udid(DeviceId(3)), Some(&udid));
+        assert_eq!(registry.known().count(), 1);
+    }
+}
@@ -0,0 +1,243 @@
+//! Retrying with exponential backoff, for muxers & devices that aren't up yet
+use crate::{Error, Result};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+/// Decides how long to wait before retrying a failed connection, or when to give up
+///
+/// Used by the listener to reconnect to a muxer that went away, see
+/// [`DeviceListenerBuilder::reconnect_policy`](crate::DeviceListenerBuilder::reconnect_policy), &
+/// by connect retries, see [`ConnectOptions::retry`](crate::ConnectOptions::retry).
+/// [`RetryPolicy`] covers fixed delays, exponential backoff with jitter & giving up after a number
+/// of attempts.
+pub trait ReconnectPolicy: Send + Sync + fmt::Debug {
+    /// Delay before retry number `retry`, 0 being the first retry after a failed attempt, or None
+    /// to give up
+    ///
+    /// `elapsed` is the time since the first attempt.
+    fn next_delay(&self, retry: u32, elapsed: Duration) -> Option<Duration>;
+}
+impl<P: ReconnectPolicy + ?Sized> ReconnectPolicy for std::sync::Arc<P> {
+    fn next_delay(&self, retry: u32, elapsed: Duration) -> Option<Duration> {
+        (**self).next_delay(retry, elapsed)
+    }
+}
+
+/// Runs `operation` until it succeeds, fails with an error `is_retryable` rejects, or `policy`
+/// gives up, in which case the last error is returned
+///
+/// With a `deadline`, gives up with [`Error::Timeout`] once the next delay wouldn't end before it.
+pub(crate) fn retry<T, O, R>(
+    policy: &dyn ReconnectPolicy,
+    deadline: Option<Instant>,
+    mut operation: O,
+    is_retryable: R,
+) -> Result<T>
+where
+    O: FnMut() -> Result<T>,
+    R: Fn(&Error) -> bool,
+{
+    let start = Instant::now();
+    let mut retry = 0;
+    loop {
+        let error = match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) if is_retryable(&e) => e,
+            Err(e) => return Err(e),
+        };
+        let delay = match policy.next_delay(retry, start.elapsed()) {
+            Some(delay) => delay,
+            None => return Err(error),
+        };
+        if let Some(deadline) = deadline {
+            if Instant::now() + delay >= deadline {
+                debug!("Not retrying past the deadline after error: {}", error);
+                return Err(Error::Timeout);
+            }
+        }
+        debug!("Retrying in {:?} after error: {}", delay, error);
+        std::thread::sleep(delay);
+        retry += 1;
+    }
+}
+
+/// Delay before the first retry, unless configured otherwise
+const DEFAULT_INITIAL_DELAY: Duration = Duration::from_millis(100);
+/// Longest delay between retries, unless configured otherwise
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// How often & how long to retry a failing operation, doubling the delay after each attempt
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+    max_elapsed: Option<Duration>,
+    jitter: bool,
+}
+impl Default for RetryPolicy {
+    /// Retries indefinitely, starting at 100ms between attempts and backing off to 5s
+    fn default() -> Self {
+        RetryPolicy {
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            max_attempts: None,
+            max_elapsed: None,
+            jitter: false,
+        }
+    }
+}
+impl RetryPolicy {
+    /// Retries indefinitely, waiting `delay` between attempts
+    pub fn fixed(delay: Duration) -> Self {
+        Self::default().initial_delay(delay).max_delay(delay)
+    }
+    /// Waits `delay` before the first retry
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+    /// Never waits longer than `delay` between attempts
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+    /// Gives up after `attempts` attempts in total, including the first
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+    /// Gives up once `elapsed` has passed since the first attempt
+    pub fn max_elapsed(mut self, elapsed: Duration) -> Self {
+        self.max_elapsed = Some(elapsed);
+        self
+    }
+    /// Waits a random part of each delay, between half & all of it, so clients that lost the
+    /// muxer at the same moment don't all retry at once
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+    /// Delay before the given retry, 0 being the delay after the first attempt failed
+    ///
+    /// This is the delay before any jitter.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry).unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+    /// Runs `operation` until it succeeds, fails with an error `is_retryable` rejects, or the
+    /// policy's limits are reached, in which case the last error is returned
+    pub(crate) fn retry<T, O, R>(&self, operation: O, is_retryable: R) -> Result<T>
+    where
+        O: FnMut() -> Result<T>,
+        R: Fn(&Error) -> bool,
+    {
+        retry(self, None, operation, is_retryable)
+    }
+}
+impl ReconnectPolicy for RetryPolicy {
+    fn next_delay(&self, retry: u32, elapsed: Duration) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| retry + 1 >= max) {
+            return None;
+        }
+        let mut delay = self.delay(retry);
+        if self.jitter {
+            // RandomState is seeded randomly, which is plenty for spreading out retries
+            let random = RandomState::new().build_hasher().finish();
+            delay = delay / 2 + delay.mul_f64((random % 1000) as f64 / 2000.0);
+        }
+        match self.max_elapsed {
+            Some(max_elapsed) => {
+                let remaining = max_elapsed.saturating_sub(elapsed);
+                (!remaining.is_zero()).then(|| delay.min(remaining))
+            }
+            None => Some(delay),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn it_backs_off_exponentially() {
+        let policy = RetryPolicy::default()
+            .initial_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_secs(1));
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(400));
+        assert_eq!(policy.delay(4), Duration::from_secs(1));
+        assert_eq!(policy.delay(40), Duration::from_secs(1));
+    }
+    #[test]
+    fn it_stops_after_max_attempts() {
+        let policy = RetryPolicy::default()
+            .initial_delay(Duration::from_millis(1))
+            .max_attempts(3);
+        let mut attempts = 0;
+        let result: Result<()> = policy.retry(
+            || {
+                attempts += 1;
+                Err(Error::Timeout)
+            },
+            |_| true,
+        );
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert_eq!(attempts, 3);
+    }
+    #[test]
+    fn it_stops_retrying_at_the_deadline() {
+        let policy = RetryPolicy::fixed(Duration::from_secs(10));
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let mut attempts = 0;
+        let result: Result<()> = retry(
+            &policy,
+            Some(deadline),
+            || {
+                attempts += 1;
+                Err(Error::NotPaired)
+            },
+            |_| true,
+        );
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert_eq!(attempts, 1);
+        assert!(Instant::now() < deadline + Duration::from_secs(1));
+    }
+    #[test]
+    fn it_jitters_within_the_delay() {
+        let policy = RetryPolicy::fixed(Duration::from_millis(100)).jitter(true);
+        for retry in 0..20 {
+            let delay = policy.next_delay(retry, Duration::ZERO).unwrap();
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+        let policy =
+            RetryPolicy::fixed(Duration::from_millis(100)).max_elapsed(Duration::from_secs(1));
+        assert_eq!(
+            policy.next_delay(3, Duration::from_millis(950)),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(policy.next_delay(4, Duration::from_secs(1)), None);
+    }
+    #[test]
+    fn it_returns_first_success() {
+        let policy = RetryPolicy::default().initial_delay(Duration::from_millis(1));
+        let mut attempts = 0;
+        let result = policy.retry(
+            || {
+                attempts += 1;
+                if attempts < 2 {
+                    Err(Error::Timeout)
+                } else {
+                    Ok(attempts)
+                }
+            },
+            |_| true,
+        );
+        assert_eq!(result.unwrap(), 2);
+    }
+}
@@ -0,0 +1,382 @@
+//! File access on the device via AFC (Apple File Conduit)
+//!
+//! `com.apple.afc` gives access to the device's media directory, while `house_arrest` vends an app's
+//! container over the same protocol, see [`AfcClient::connect_app_container`].
+use crate::lockdown::{connect_to_service, recv_plist, send_plist, ServiceStream};
+use crate::{DeviceId, Error, ProtocolError, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use plist::{Dictionary, Value};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/// Name of the AFC lockdown service, for the device's media directory
+pub const SERVICE_NAME: &str = "com.apple.afc";
+/// Name of the lockdown service that vends app containers over AFC
+pub const HOUSE_ARREST_SERVICE_NAME: &str = "com.apple.mobile.house_arrest";
+
+/// Magic every AFC packet starts with
+const AFC_MAGIC: &[u8; 8] = b"CFA6LPAA";
+/// Size of the packet header: magic, entire length, this length, packet number & operation
+const AFC_HEADER_SIZE: u64 = 40;
+/// Largest chunk requested per read
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+/// Upper bound for a single packet, reads are chunked well below this
+const MAX_PACKET_SIZE: u64 = 16 * 1024 * 1024;
+
+/// AFC operation codes
+#[repr(u64)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Operation {
+    Status = 0x01,
+    Data = 0x02,
+    ReadDir = 0x03,
+    RemovePath = 0x08,
+    MakeDir = 0x09,
+    GetFileInfo = 0x0A,
+    FileOpen = 0x0D,
+    FileOpenResult = 0x0E,
+    FileRead = 0x0F,
+    FileWrite = 0x10,
+    FileClose = 0x14,
+}
+
+/// Mode to open a file with
+#[repr(u64)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OpenMode {
+    /// Read only, file must exist (`r`)
+    ReadOnly = 1,
+    /// Read & write, file must exist (`r+`)
+    ReadWrite = 2,
+    /// Write only, creating or truncating the file (`w`)
+    WriteOnly = 3,
+    /// Read & write, creating or truncating the file (`w+`)
+    WriteRead = 4,
+    /// Append, creating the file if needed (`a`)
+    Append = 5,
+    /// Read & append, creating the file if needed (`a+`)
+    ReadAppend = 6,
+}
+
+/// Kind of file system entry
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileType {
+    /// Regular file
+    File,
+    /// Directory
+    Directory,
+    /// Symbolic link
+    Symlink,
+    /// Anything else, holding the raw `st_ifmt` value
+    Other(String),
+}
+
+/// Info about a file or directory on the device
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    /// Size in bytes
+    pub size: u64,
+    /// Kind of entry
+    pub file_type: FileType,
+    /// Modification time in nanoseconds since the epoch
+    pub modified: Option<u64>,
+    /// All keys the device reported, such as `st_nlink` & `st_blocks`
+    pub raw: HashMap<String, String>,
+}
+
+/// Open file on the device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileHandle(u64);
+
+/// Single AFC packet
+#[derive(Debug, PartialEq)]
+struct AfcPacket {
+    packet_num: u64,
+    operation: u64,
+    /// Operation arguments, such as paths & handles
+    header_data: Vec<u8>,
+    /// Bulk data, such as file contents
+    payload: Vec<u8>,
+}
+impl AfcPacket {
+    fn write_into<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let this_length = AFC_HEADER_SIZE + self.header_data.len() as u64;
+        let entire_length = this_length + self.payload.len() as u64;
+        let mut buffer = Vec::with_capacity(entire_length as usize);
+        buffer.extend_from_slice(AFC_MAGIC);
+        buffer.write_u64::<LittleEndian>(entire_length)?;
+        buffer.write_u64::<LittleEndian>(this_length)?;
+        buffer.write_u64::<LittleEndian>(self.packet_num)?;
+        buffer.write_u64::<LittleEndian>(self.operation)?;
+        buffer.extend_from_slice(&self.header_data);
+        buffer.extend_from_slice(&self.payload);
+        writer.write_all(&buffer)?;
+        writer.flush()?;
+        Ok(())
+    }
+    fn from_reader<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != AFC_MAGIC {
+            return Err(Error::AfcError(AfcStatus::InvalidPacket));
+        }
+        let entire_length = reader.read_u64::<LittleEndian>()?;
+        let this_length = reader.read_u64::<LittleEndian>()?;
+        let packet_num = reader.read_u64::<LittleEndian>()?;
+        let operation = reader.read_u64::<LittleEndian>()?;
+        if this_length < AFC_HEADER_SIZE
+            || entire_length < this_length
+            || entire_length > MAX_PACKET_SIZE
+        {
+            return Err(Error::AfcError(AfcStatus::InvalidPacket));
+        }
+        let mut header_data = vec![0; (this_length - AFC_HEADER_SIZE) as usize];
+        reader.read_exact(&mut header_data)?;
+        let mut payload = vec![0; (entire_length - this_length) as usize];
+        reader.read_exact(&mut payload)?;
+        Ok(AfcPacket {
+            packet_num,
+            operation,
+            header_data,
+            payload,
+        })
+    }
+}
+
+/// Error status reported by the device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AfcStatus {
+    /// Packet from the device was malformed
+    InvalidPacket,
+    /// Device replied with an operation we didn't expect
+    UnexpectedReply(u64),
+    /// Non-zero status code from the device, such as 8 for object not found
+    Code(u64),
+}
+impl std::fmt::Display for AfcStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AfcStatus::InvalidPacket => write!(f, "invalid packet"),
+            AfcStatus::UnexpectedReply(op) => write!(f, "unexpected reply operation {}", op),
+            AfcStatus::Code(code) => write!(f, "status code {}", code),
+        }
+    }
+}
+
+fn path_bytes(path: &str) -> Vec<u8> {
+    let mut bytes = path.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+/// Splits a NUL separated list of strings, as used for directory listings & file info
+fn split_strings(data: &[u8]) -> Vec<String> {
+    data.split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// Connection to an AFC service
+pub struct AfcClient {
+    stream: ServiceStream,
+    packet_num: u64,
+}
+impl AfcClient {
+    /// Starts AFC on the device & connects to it, giving access to the media directory
+    ///
+    /// # Errors
+    /// Fails if the device isn't paired or the service can't be started
+    pub fn connect(device_id: DeviceId) -> Result<Self> {
+        Ok(Self::new(connect_to_service(device_id, SERVICE_NAME)?))
+    }
+    /// Connects to the data container of an app, which must be a development build or have
+    /// `UIFileSharingEnabled` set
+    pub fn connect_app_container(device_id: DeviceId, bundle_id: &str) -> Result<Self> {
+        let mut stream = connect_to_service(device_id, HOUSE_ARREST_SERVICE_NAME)?;
+        let mut request = Dictionary::new();
+        request.insert(
+            "Command".to_owned(),
+            Value::String("VendContainer".to_owned()),
+        );
+        request.insert("Identifier".to_owned(), Value::String(bundle_id.to_owned()));
+        send_plist(&mut stream, &Value::Dictionary(request))?;
+        let reply = recv_plist(&mut stream)?;
+        let reply = reply
+            .as_dictionary()
+            .ok_or(ProtocolError::InvalidPlistEntry)?;
+        if let Some(error) = reply.get("Error").and_then(Value::as_string) {
+            return Err(Error::ServiceError(error.to_owned()));
+        }
+        // once the container is vended the connection speaks AFC
+        Ok(Self::new(stream))
+    }
+    /// Wraps an existing connection to an AFC service
+    pub fn new(stream: ServiceStream) -> Self {
+        AfcClient {
+            stream,
+            packet_num: 0,
+        }
+    }
+    /// Lists the names of a directory's entries, excluding `.` & `..`
+    pub fn list_dir(&mut self, path: &str) -> Result<Vec<String>> {
+        let reply = self.request(Operation::ReadDir, path_bytes(path), Vec::new())?;
+        let entries = split_strings(&reply.payload)
+            .into_iter()
+            .filter(|e| e != "." && e != "..")
+            .collect();
+        Ok(entries)
+    }
+    /// Reads info about a file or directory
+    pub fn stat(&mut self, path: &str) -> Result<FileInfo> {
+        let reply = self.request(Operation::GetFileInfo, path_bytes(path), Vec::new())?;
+        let strings = split_strings(&reply.payload);
+        let raw: HashMap<String, String> = strings
+            .chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        let size = raw.get("st_size").and_then(|s| s.parse().ok()).unwrap_or(0);
+        let file_type = match raw.get("st_ifmt").map(String::as_str) {
+            Some("S_IFREG") => FileType::File,
+            Some("S_IFDIR") => FileType::Directory,
+            Some("S_IFLNK") => FileType::Symlink,
+            other => FileType::Other(other.unwrap_or_default().to_owned()),
+        };
+        let modified = raw.get("st_mtime").and_then(|s| s.parse().ok());
+        Ok(FileInfo {
+            size,
+            file_type,
+            modified,
+            raw,
+        })
+    }
+    /// Removes a file or empty directory
+    pub fn remove(&mut self, path: &str) -> Result<()> {
+        self.request(Operation::RemovePath, path_bytes(path), Vec::new())?;
+        Ok(())
+    }
+    /// Creates a directory, including missing parents
+    pub fn make_dir(&mut self, path: &str) -> Result<()> {
+        self.request(Operation::MakeDir, path_bytes(path), Vec::new())?;
+        Ok(())
+    }
+    /// Opens a file, which should be closed with [`AfcClient::close`] when done
+    pub fn open(&mut self, path: &str, mode: OpenMode) -> Result<FileHandle> {
+        let mut args = Vec::new();
+        args.write_u64::<LittleEndian>(mode as u64)?;
+        args.extend_from_slice(&path_bytes(path));
+        let reply = self.request(Operation::FileOpen, args, Vec::new())?;
+        if reply.operation != Operation::FileOpenResult as u64 {
+            return Err(Error::AfcError(AfcStatus::UnexpectedReply(reply.operation)));
+        }
+        let handle = (&reply.header_data[..]).read_u64::<LittleEndian>()?;
+        Ok(FileHandle(handle))
+    }
+    /// Reads up to `length` bytes from an open file, an empty result means end of file
+    pub fn read(&mut self, handle: FileHandle, length: u64) -> Result<Vec<u8>> {
+        let mut args = Vec::new();
+        args.write_u64::<LittleEndian>(handle.0)?;
+        args.write_u64::<LittleEndian>(length)?;
+        Ok(self.request(Operation::FileRead, args, Vec::new())?.payload)
+    }
+    /// Writes data to an open file
+    pub fn write(&mut self, handle: FileHandle, data: &[u8]) -> Result<()> {
+        let mut args = Vec::new();
+        args.write_u64::<LittleEndian>(handle.0)?;
+        self.request(Operation::FileWrite, args, data.to_vec())?;
+        Ok(())
+    }
+    /// Closes an open file
+    pub fn close(&mut self, handle: FileHandle) -> Result<()> {
+        let mut args = Vec::new();
+        args.write_u64::<LittleEndian>(handle.0)?;
+        self.request(Operation::FileClose, args, Vec::new())?;
+        Ok(())
+    }
+    /// Reads a whole file
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>> {
+        let handle = self.open(path, OpenMode::ReadOnly)?;
+        let mut contents = Vec::new();
+        let result = loop {
+            match self.read(handle, READ_CHUNK_SIZE) {
+                Ok(chunk) if chunk.is_empty() => break Ok(()),
+                Ok(chunk) => contents.extend_from_slice(&chunk),
+                Err(e) => break Err(e),
+            }
+        };
+        self.close(handle)?;
+        result.map(|_| contents)
+    }
+    /// Writes a whole file, replacing it if it exists
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        let handle = self.open(path, OpenMode::WriteOnly)?;
+        let result = self.write(handle, data);
+        self.close(handle)?;
+        result
+    }
+    /// Sends a request & reads its reply, turning error statuses into errors
+    fn request(
+        &mut self,
+        operation: Operation,
+        header_data: Vec<u8>,
+        payload: Vec<u8>,
+    ) -> Result<AfcPacket> {
+        let packet = AfcPacket {
+            packet_num: self.packet_num,
+            operation: operation as u64,
+            header_data,
+            payload,
+        };
+        self.packet_num += 1;
+        packet.write_into(&mut self.stream)?;
+        let reply = AfcPacket::from_reader(&mut self.stream)?;
+        if reply.operation == Operation::Status as u64 {
+            let status = (&reply.header_data[..]).read_u64::<LittleEndian>()?;
+            if status != 0 {
+                return Err(Error::AfcError(AfcStatus::Code(status)));
+            }
+        } else if reply.operation != Operation::Data as u64
+            && reply.operation != Operation::FileOpenResult as u64
+        {
+            return Err(Error::AfcError(AfcStatus::UnexpectedReply(reply.operation)));
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn it_encodes_packets() {
+        let packet = AfcPacket {
+            packet_num: 3,
+            operation: Operation::ReadDir as u64,
+            header_data: path_bytes("/"),
+            payload: vec![1, 2, 3],
+        };
+        let mut buffer = Vec::new();
+        packet.write_into(&mut buffer).unwrap();
+        assert_eq!(&buffer[0..8], AFC_MAGIC);
+        assert_eq!(buffer.len(), 40 + 2 + 3);
+        assert_eq!(buffer[8], 45); // entire length
+        assert_eq!(buffer[16], 42); // this length
+        let decoded = AfcPacket::from_reader(&mut std::io::Cursor::new(buffer)).unwrap();
+        assert_eq!(decoded, packet);
+    }
+    #[test]
+    fn it_rejects_bad_lengths() {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(AFC_MAGIC);
+        for value in &[10u64, 40, 0, 1] {
+            buffer.write_u64::<LittleEndian>(*value).unwrap();
+        }
+        assert!(AfcPacket::from_reader(&mut std::io::Cursor::new(buffer)).is_err());
+    }
+    #[test]
+    fn it_splits_strings() {
+        let entries = split_strings(b".\0..\0DCIM\0Books\0");
+        assert_eq!(entries, vec![".", "..", "DCIM", "Books"]);
+    }
+}
@@ -0,0 +1,133 @@
+//! Answers the device's heartbeat via `com.apple.mobile.heartbeat`
+//!
+//! iOS tears down some services when no host is answering heartbeats, so long lived sessions should
+//! keep a heartbeat running alongside them.
+use crate::lockdown::{connect_to_service, recv_plist, send_plist, ServiceStream};
+use crate::{DeviceId, ProtocolError, Result};
+use plist::{Dictionary, Value};
+use std::net::Shutdown;
+use std::thread::JoinHandle;
+
+/// Name of the heartbeat lockdown service
+pub const SERVICE_NAME: &str = "com.apple.mobile.heartbeat";
+
+/// Message received from the device's heartbeat service
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeartbeatMessage {
+    /// Device wants a reply, and will send the next one after the interval (in seconds)
+    Marco {
+        /// Seconds until the next heartbeat
+        interval: Option<u64>,
+    },
+    /// Device is going to sleep, no more heartbeats will be sent
+    SleepyTime,
+    /// Command we don't know about
+    Unknown(String),
+}
+
+/// Connection to the device's heartbeat service
+pub struct HeartbeatClient {
+    stream: ServiceStream,
+}
+impl HeartbeatClient {
+    /// Starts the heartbeat service on the device & connects to it
+    ///
+    /// # Errors
+    /// Fails if the device isn't paired or the service can't be started
+    pub fn connect(device_id: DeviceId) -> Result<Self> {
+        Ok(Self::new(connect_to_service(device_id, SERVICE_NAME)?))
+    }
+    /// Wraps an existing connection to the heartbeat service
+    pub fn new(stream: ServiceStream) -> Self {
+        HeartbeatClient { stream }
+    }
+    /// Waits for the next message from the device, answering `Marco` with `Polo`
+    pub fn respond_once(&mut self) -> Result<HeartbeatMessage> {
+        let message = recv_plist(&mut self.stream)?;
+        let message = message
+            .as_dictionary()
+            .ok_or(ProtocolError::InvalidPlistEntry)?;
+        let command = message
+            .get("Command")
+            .and_then(Value::as_string)
+            .ok_or(ProtocolError::InvalidPlistEntryForKey("Command"))?;
+        match command {
+            "Marco" => {
+                let mut reply = Dictionary::new();
+                reply.insert("Command".to_owned(), Value::String("Polo".to_owned()));
+                send_plist(&mut self.stream, &Value::Dictionary(reply))?;
+                let interval = message.get("Interval").and_then(Value::as_unsigned_integer);
+                Ok(HeartbeatMessage::Marco { interval })
+            }
+            "SleepyTime" => Ok(HeartbeatMessage::SleepyTime),
+            c => Ok(HeartbeatMessage::Unknown(c.to_owned())),
+        }
+    }
+    /// Answers heartbeats until the device goes to sleep or the connection closes
+    pub fn run(mut self) -> Result<()> {
+        loop {
+            match self.respond_once()? {
+                HeartbeatMessage::Marco { interval } => {
+                    trace!("Heartbeat answered, next in {:?}s", interval);
+                }
+                HeartbeatMessage::SleepyTime => return Ok(()),
+                HeartbeatMessage::Unknown(c) => warn!("Unknown heartbeat command: {}", c),
+            }
+        }
+    }
+    /// Answers heartbeats on a background thread until stopped
+    pub fn spawn(self) -> Result<HeartbeatHandle> {
+        let socket = self.stream.socket().try_clone()?;
+        let thread = std::thread::Builder::new()
+            .name("peertalk-heartbeat".to_owned())
+            .spawn(move || self.run())?;
+        Ok(HeartbeatHandle { socket, thread })
+    }
+}
+
+/// Handle to a heartbeat running on a background thread
+pub struct HeartbeatHandle {
+    socket: crate::UsbSocket,
+    thread: JoinHandle<Result<()>>,
+}
+impl HeartbeatHandle {
+    /// Checks if the heartbeat thread has stopped, due to an error or the device sleeping
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+    /// Stops answering heartbeats, closing the connection
+    pub fn stop(self) {
+        // unblocks the thread's read, so it ends with an error we don't care about
+        let _ = self.socket.shutdown(Shutdown::Both);
+        let _ = self.thread.join();
+    }
+}
+
+/// Starts answering heartbeats for the device on a background thread
+pub fn spawn(device_id: DeviceId) -> Result<HeartbeatHandle> {
+    HeartbeatClient::connect(device_id)?.spawn()
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    fn command(command: &str) -> Value {
+        let mut d = Dictionary::new();
+        d.insert("Command".to_owned(), Value::String(command.to_owned()));
+        Value::Dictionary(d)
+    }
+    #[test]
+    fn it_answers_marco() {
+        let (host, mut device) = UnixStream::pair().unwrap();
+        let mut client = HeartbeatClient::new(ServiceStream::Plain(host.into()));
+        send_plist(&mut device, &command("Marco")).unwrap();
+        assert_eq!(
+            client.respond_once().unwrap(),
+            HeartbeatMessage::Marco { interval: None }
+        );
+        assert_eq!(recv_plist(&mut device).unwrap(), command("Polo"));
+        send_plist(&mut device, &command("SleepyTime")).unwrap();
+        assert_eq!(client.respond_once().unwrap(), HeartbeatMessage::SleepyTime);
+    }
+}
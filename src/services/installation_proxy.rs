@@ -0,0 +1,211 @@
+//! Lists apps installed on the device via `com.apple.mobile.installation_proxy`
+use crate::lockdown::{connect_to_service, recv_plist, send_plist, ServiceStream};
+use crate::{DeviceId, Error, ProtocolError, Result};
+use plist::{Dictionary, Value};
+use std::convert::TryFrom;
+
+/// Name of the installation proxy lockdown service
+pub const SERVICE_NAME: &str = "com.apple.mobile.installation_proxy";
+/// Attributes requested for each app, enough to fill in [`AppInfo`]
+const RETURN_ATTRIBUTES: &[&str] = &[
+    "CFBundleIdentifier",
+    "CFBundleName",
+    "CFBundleDisplayName",
+    "CFBundleVersion",
+    "CFBundleShortVersionString",
+    "ApplicationType",
+    "Path",
+    "Container",
+];
+
+/// Which kind of apps to list
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApplicationType {
+    /// Apps installed by the user (App Store, TestFlight, development builds)
+    User,
+    /// Apps that are part of iOS
+    System,
+    /// All apps
+    Any,
+}
+impl ApplicationType {
+    fn as_str(self) -> &'static str {
+        match self {
+            ApplicationType::User => "User",
+            ApplicationType::System => "System",
+            ApplicationType::Any => "Any",
+        }
+    }
+}
+
+/// Info about an installed app
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppInfo {
+    /// Bundle identifier, such as `com.example.app`
+    pub bundle_id: String,
+    /// Name shown under the app's icon
+    pub name: Option<String>,
+    /// Build version (`CFBundleVersion`)
+    pub version: Option<String>,
+    /// Marketing version (`CFBundleShortVersionString`)
+    pub short_version: Option<String>,
+    /// `User` or `System`
+    pub application_type: Option<String>,
+    /// Path of the app bundle on the device
+    pub path: Option<String>,
+    /// Path of the app's data container on the device
+    pub container: Option<String>,
+}
+impl TryFrom<&Value> for AppInfo {
+    type Error = ProtocolError;
+    fn try_from(value: &Value) -> std::result::Result<Self, Self::Error> {
+        let d = value
+            .as_dictionary()
+            .ok_or(ProtocolError::InvalidPlistEntry)?;
+        let string = |key: &str| d.get(key).and_then(Value::as_string).map(str::to_owned);
+        Ok(AppInfo {
+            bundle_id: string("CFBundleIdentifier")
+                .ok_or(ProtocolError::InvalidPlistEntryForKey("CFBundleIdentifier"))?,
+            name: string("CFBundleDisplayName").or_else(|| string("CFBundleName")),
+            version: string("CFBundleVersion"),
+            short_version: string("CFBundleShortVersionString"),
+            application_type: string("ApplicationType"),
+            path: string("Path"),
+            container: string("Container"),
+        })
+    }
+}
+
+/// Connection to the device's installation proxy
+pub struct InstallationProxyClient {
+    stream: ServiceStream,
+}
+impl InstallationProxyClient {
+    /// Starts the installation proxy on the device & connects to it
+    ///
+    /// # Errors
+    /// Fails if the device isn't paired or the service can't be started
+    pub fn connect(device_id: DeviceId) -> Result<Self> {
+        Ok(Self::new(connect_to_service(device_id, SERVICE_NAME)?))
+    }
+    /// Wraps an existing connection to the installation proxy
+    pub fn new(stream: ServiceStream) -> Self {
+        InstallationProxyClient { stream }
+    }
+    /// Lists installed apps of the given type
+    pub fn browse(&mut self, application_type: ApplicationType) -> Result<Vec<AppInfo>> {
+        let mut options = client_options();
+        options.insert(
+            "ApplicationType".to_owned(),
+            Value::String(application_type.as_str().to_owned()),
+        );
+        self.send_command("Browse", options)?;
+        // the list arrives in batches, until a message with the Complete status
+        let mut apps = Vec::new();
+        loop {
+            let reply = self.receive_reply()?;
+            if let Some(list) = reply.get("CurrentList").and_then(Value::as_array) {
+                for app in list {
+                    apps.push(AppInfo::try_from(app)?);
+                }
+            }
+            if is_complete(&reply) {
+                return Ok(apps);
+            }
+        }
+    }
+    /// Looks up a single app by bundle ID, None if it isn't installed
+    pub fn lookup(&mut self, bundle_id: &str) -> Result<Option<AppInfo>> {
+        let mut options = client_options();
+        options.insert(
+            "BundleIDs".to_owned(),
+            Value::Array(vec![Value::String(bundle_id.to_owned())]),
+        );
+        self.send_command("Lookup", options)?;
+        let mut app = None;
+        loop {
+            let reply = self.receive_reply()?;
+            if let Some(info) = reply
+                .get("LookupResult")
+                .and_then(Value::as_dictionary)
+                .and_then(|r| r.get(bundle_id))
+            {
+                app = Some(AppInfo::try_from(info)?);
+            }
+            if is_complete(&reply) {
+                return Ok(app);
+            }
+        }
+    }
+    fn send_command(&mut self, command: &str, options: Dictionary) -> Result<()> {
+        let mut request = Dictionary::new();
+        request.insert("Command".to_owned(), Value::String(command.to_owned()));
+        request.insert("ClientOptions".to_owned(), Value::Dictionary(options));
+        send_plist(&mut self.stream, &Value::Dictionary(request))
+    }
+    fn receive_reply(&mut self) -> Result<Dictionary> {
+        let reply = match recv_plist(&mut self.stream)? {
+            Value::Dictionary(d) => d,
+            _ => return Err(ProtocolError::InvalidPlistEntry.into()),
+        };
+        if let Some(error) = reply.get("Error").and_then(Value::as_string) {
+            return Err(Error::ServiceError(error.to_owned()));
+        }
+        Ok(reply)
+    }
+}
+
+fn client_options() -> Dictionary {
+    let mut options = Dictionary::new();
+    let attributes = RETURN_ATTRIBUTES
+        .iter()
+        .map(|a| Value::String((*a).to_owned()))
+        .collect();
+    options.insert("ReturnAttributes".to_owned(), Value::Array(attributes));
+    options
+}
+
+fn is_complete(reply: &Dictionary) -> bool {
+    reply.get("Status").and_then(Value::as_string) == Some("Complete")
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    fn app(bundle_id: &str, name: &str) -> Value {
+        let mut d = Dictionary::new();
+        d.insert(
+            "CFBundleIdentifier".to_owned(),
+            Value::String(bundle_id.to_owned()),
+        );
+        d.insert("CFBundleName".to_owned(), Value::String(name.to_owned()));
+        Value::Dictionary(d)
+    }
+    #[test]
+    fn it_browses_batches() {
+        let (host, mut device) = UnixStream::pair().unwrap();
+        let mut client = InstallationProxyClient::new(ServiceStream::Plain(host.into()));
+        let mut batch = Dictionary::new();
+        batch.insert(
+            "CurrentList".to_owned(),
+            Value::Array(vec![app("com.example.a", "A"), app("com.example.b", "B")]),
+        );
+        batch.insert(
+            "Status".to_owned(),
+            Value::String("BrowsingApplications".to_owned()),
+        );
+        send_plist(&mut device, &Value::Dictionary(batch)).unwrap();
+        let mut complete = Dictionary::new();
+        complete.insert("Status".to_owned(), Value::String("Complete".to_owned()));
+        send_plist(&mut device, &Value::Dictionary(complete)).unwrap();
+
+        let apps = client.browse(ApplicationType::User).unwrap();
+        assert_eq!(apps.len(), 2);
+        assert_eq!(apps[1].bundle_id, "com.example.b");
+        assert_eq!(apps[1].name.as_deref(), Some("B"));
+        let request = recv_plist(&mut device).unwrap();
+        let request = request.as_dictionary().unwrap();
+        assert_eq!(request.get("Command").unwrap().as_string(), Some("Browse"));
+    }
+}
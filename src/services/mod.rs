@@ -0,0 +1,8 @@
+//! Clients for device services started through lockdownd
+#[cfg(feature = "afc")]
+pub mod afc;
+pub mod heartbeat;
+pub mod installation_proxy;
+pub mod notification_proxy;
+pub mod screenshot;
+pub mod syslog;
@@ -0,0 +1,122 @@
+//! Posts & observes darwin notifications via `com.apple.mobile.notification_proxy`
+use crate::lockdown::{connect_to_service, recv_plist, send_plist, ServiceStream};
+use crate::{DeviceId, ProtocolError, Result};
+use plist::{Dictionary, Value};
+
+/// Name of the notification proxy lockdown service
+pub const SERVICE_NAME: &str = "com.apple.mobile.notification_proxy";
+/// Posted after an app is installed
+pub const APPLICATION_INSTALLED: &str = "com.apple.mobile.application_installed";
+/// Posted after an app is uninstalled
+pub const APPLICATION_UNINSTALLED: &str = "com.apple.mobile.application_uninstalled";
+/// Posted when a sync with the host starts
+pub const SYNC_DID_START: &str = "com.apple.itunes-mobdev.syncDidStart";
+/// Posted when a sync with the host finishes
+pub const SYNC_DID_FINISH: &str = "com.apple.itunes-mobdev.syncDidFinish";
+/// Posted when the device's name changes
+pub const DEVICE_NAME_CHANGED: &str = "com.apple.mobile.lockdown.device_name_changed";
+
+/// Connection to the device's notification proxy, iterating over observed notification names
+pub struct NotificationProxyClient {
+    stream: ServiceStream,
+    closed: bool,
+}
+impl NotificationProxyClient {
+    /// Starts the notification proxy on the device & connects to it
+    ///
+    /// # Errors
+    /// Fails if the device isn't paired or the service can't be started
+    pub fn connect(device_id: DeviceId) -> Result<Self> {
+        Ok(Self::new(connect_to_service(device_id, SERVICE_NAME)?))
+    }
+    /// Wraps an existing connection to the notification proxy
+    pub fn new(stream: ServiceStream) -> Self {
+        NotificationProxyClient {
+            stream,
+            closed: false,
+        }
+    }
+    /// Posts a notification on the device
+    pub fn post(&mut self, name: &str) -> Result<()> {
+        self.send_command("PostNotification", Some(name))
+    }
+    /// Asks the device to relay a notification to us whenever it's posted
+    pub fn observe(&mut self, name: &str) -> Result<()> {
+        self.send_command("ObserveNotification", Some(name))
+    }
+    /// Asks the device to end the session, it confirms by closing the notification stream
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.send_command("Shutdown", None)
+    }
+    /// Waits for the next observed notification, None once the proxy shut down
+    pub fn next_notification(&mut self) -> Result<Option<String>> {
+        while !self.closed {
+            let message = recv_plist(&mut self.stream)?;
+            let message = message
+                .as_dictionary()
+                .ok_or(ProtocolError::InvalidPlistEntry)?;
+            let command = message
+                .get("Command")
+                .and_then(Value::as_string)
+                .ok_or(ProtocolError::InvalidPlistEntryForKey("Command"))?;
+            match command {
+                "RelayNotification" => {
+                    let name = message
+                        .get("Name")
+                        .and_then(Value::as_string)
+                        .ok_or(ProtocolError::InvalidPlistEntryForKey("Name"))?;
+                    return Ok(Some(name.to_owned()));
+                }
+                "ProxyDeath" => self.closed = true,
+                c => debug!("Ignoring unknown notification proxy command: {}", c),
+            }
+        }
+        Ok(None)
+    }
+    fn send_command(&mut self, command: &str, name: Option<&str>) -> Result<()> {
+        let mut message = Dictionary::new();
+        message.insert("Command".to_owned(), Value::String(command.to_owned()));
+        if let Some(name) = name {
+            message.insert("Name".to_owned(), Value::String(name.to_owned()));
+        }
+        send_plist(&mut self.stream, &Value::Dictionary(message))
+    }
+}
+impl Iterator for NotificationProxyClient {
+    type Item = Result<String>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_notification().transpose()
+    }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    fn command(command: &str, name: Option<&str>) -> Value {
+        let mut message = Dictionary::new();
+        message.insert("Command".to_owned(), Value::String(command.to_owned()));
+        if let Some(name) = name {
+            message.insert("Name".to_owned(), Value::String(name.to_owned()));
+        }
+        Value::Dictionary(message)
+    }
+    #[test]
+    fn it_relays_notifications() {
+        let (host, mut device) = UnixStream::pair().unwrap();
+        let mut client = NotificationProxyClient::new(ServiceStream::Plain(host.into()));
+        client.observe(APPLICATION_INSTALLED).unwrap();
+        assert_eq!(
+            recv_plist(&mut device).unwrap(),
+            command("ObserveNotification", Some(APPLICATION_INSTALLED))
+        );
+        send_plist(
+            &mut device,
+            &command("RelayNotification", Some(APPLICATION_INSTALLED)),
+        )
+        .unwrap();
+        send_plist(&mut device, &command("ProxyDeath", None)).unwrap();
+        let notifications: Vec<String> = client.map(|n| n.unwrap()).collect();
+        assert_eq!(notifications, vec![APPLICATION_INSTALLED.to_owned()]);
+    }
+}
@@ -0,0 +1,149 @@
+//! Takes screenshots via `com.apple.mobile.screenshotr`
+//!
+//! Requires the developer disk image to be mounted on the device, which Xcode does when the device
+//! is used for development.
+use crate::lockdown::{connect_to_service, recv_plist, send_binary_plist, ServiceStream};
+use crate::{DeviceId, Error, ProtocolError, Result};
+use plist::{Dictionary, Value};
+
+/// Name of the screenshot lockdown service
+pub const SERVICE_NAME: &str = "com.apple.mobile.screenshotr";
+/// DeviceLink protocol version we speak
+const DEVICE_LINK_VERSION_MAJOR: u64 = 300;
+
+/// Connection to the device's screenshot service
+pub struct ScreenshotClient {
+    stream: ServiceStream,
+}
+impl ScreenshotClient {
+    /// Starts the screenshot service on the device, connects to it & does the DeviceLink handshake
+    ///
+    /// # Errors
+    /// Fails if the device isn't paired, the service can't be started (no developer disk image) or
+    /// the handshake fails
+    pub fn connect(device_id: DeviceId) -> Result<Self> {
+        Self::new(connect_to_service(device_id, SERVICE_NAME)?)
+    }
+    /// Does the DeviceLink handshake on an existing connection to the screenshot service
+    pub fn new(stream: ServiceStream) -> Result<Self> {
+        let mut client = ScreenshotClient { stream };
+        client.version_exchange()?;
+        Ok(client)
+    }
+    /// Takes a screenshot, returning the image data (PNG on modern iOS, TIFF on very old versions)
+    pub fn take_screenshot(&mut self) -> Result<Vec<u8>> {
+        let mut request = Dictionary::new();
+        request.insert(
+            "MessageType".to_owned(),
+            Value::String("ScreenShotRequest".to_owned()),
+        );
+        self.send(vec![
+            Value::String("DLMessageProcessMessage".to_owned()),
+            Value::Dictionary(request),
+        ])?;
+        let reply = self.receive("DLMessageProcessMessage")?;
+        let mut reply = match reply.into_iter().next() {
+            Some(Value::Dictionary(d)) => d,
+            _ => return Err(ProtocolError::InvalidPlistEntry.into()),
+        };
+        reply
+            .remove("ScreenShotData")
+            .and_then(Value::into_data)
+            .ok_or_else(|| ProtocolError::InvalidPlistEntryForKey("ScreenShotData").into())
+    }
+    /// Exchanges DeviceLink versions, after which the device reports it's ready
+    fn version_exchange(&mut self) -> Result<()> {
+        let version = self.receive("DLMessageVersionExchange")?;
+        let major = version.get(1).and_then(Value::as_unsigned_integer).ok_or(
+            ProtocolError::InvalidPlistEntryForKey("DLMessageVersionExchange"),
+        )?;
+        if major > DEVICE_LINK_VERSION_MAJOR {
+            return Err(Error::ServiceError(format!(
+                "unsupported DeviceLink version {}",
+                major
+            )));
+        }
+        self.send(vec![
+            Value::String("DLMessageVersionExchange".to_owned()),
+            Value::String("DLVersionsOk".to_owned()),
+            Value::Integer(DEVICE_LINK_VERSION_MAJOR.into()),
+        ])?;
+        self.receive("DLMessageDeviceReady")?;
+        Ok(())
+    }
+    fn send(&mut self, message: Vec<Value>) -> Result<()> {
+        send_binary_plist(&mut self.stream, &Value::Array(message))
+    }
+    /// Receives a DeviceLink message, checking its type & returning the remaining elements
+    fn receive(&mut self, expected: &str) -> Result<Vec<Value>> {
+        let mut message = match recv_plist(&mut self.stream)? {
+            Value::Array(a) if !a.is_empty() => a,
+            _ => return Err(ProtocolError::InvalidPlistEntry.into()),
+        };
+        match message[0].as_string() {
+            Some(m) if m == expected => Ok(message.split_off(1)),
+            Some("DLMessageDisconnect") => Err(Error::ServiceError(
+                "device disconnected the DeviceLink session".to_owned(),
+            )),
+            Some(m) => Err(ProtocolError::InvalidMessageType(m.to_owned()).into()),
+            None => Err(ProtocolError::InvalidPlistEntry.into()),
+        }
+    }
+}
+impl Drop for ScreenshotClient {
+    fn drop(&mut self) {
+        // politely end the session, the connection is going away either way
+        let _ = self.send(vec![
+            Value::String("DLMessageDisconnect".to_owned()),
+            Value::String("___EmptyParameterString___".to_owned()),
+        ]);
+    }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::lockdown::send_plist;
+    use std::os::unix::net::UnixStream;
+    fn message(values: Vec<Value>) -> Value {
+        Value::Array(values)
+    }
+    #[test]
+    fn it_takes_screenshots() {
+        let (host, mut device) = UnixStream::pair().unwrap();
+        send_plist(
+            &mut device,
+            &message(vec![
+                Value::String("DLMessageVersionExchange".to_owned()),
+                Value::Integer(300.into()),
+                Value::Integer(0.into()),
+            ]),
+        )
+        .unwrap();
+        send_plist(
+            &mut device,
+            &message(vec![Value::String("DLMessageDeviceReady".to_owned())]),
+        )
+        .unwrap();
+        let mut reply = Dictionary::new();
+        reply.insert(
+            "ScreenShotData".to_owned(),
+            Value::Data(b"\x89PNG".to_vec()),
+        );
+        send_plist(
+            &mut device,
+            &message(vec![
+                Value::String("DLMessageProcessMessage".to_owned()),
+                Value::Dictionary(reply),
+            ]),
+        )
+        .unwrap();
+        let mut client = ScreenshotClient::new(ServiceStream::Plain(host.into())).unwrap();
+        assert_eq!(client.take_screenshot().unwrap(), b"\x89PNG");
+        let version = recv_plist(&mut device).unwrap();
+        assert_eq!(
+            version.as_array().unwrap()[1].as_string(),
+            Some("DLVersionsOk")
+        );
+    }
+}
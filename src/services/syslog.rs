@@ -0,0 +1,131 @@
+//! Streams the device's system log via `com.apple.syslog_relay`
+use crate::lockdown::{connect_to_service, ServiceStream};
+use crate::{DeviceId, Result};
+use std::io::{BufRead, BufReader};
+
+/// Name of the syslog relay lockdown service
+pub const SERVICE_NAME: &str = "com.apple.syslog_relay";
+/// Length of the timestamp at the start of each line, such as `Oct  6 09:41:00`
+const TIMESTAMP_LEN: usize = 15;
+
+/// A single line of the device's log
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLine {
+    /// Timestamp as formatted by the device, such as `Oct  6 09:41:00`
+    pub timestamp: Option<String>,
+    /// Name of the device the line was logged on
+    pub device_name: Option<String>,
+    /// Process (and subsystem) that logged the line
+    pub process: Option<String>,
+    /// Process ID that logged the line
+    pub pid: Option<u32>,
+    /// Log level, such as `Notice` or `Error`
+    pub level: Option<String>,
+    /// Log message, or the whole line if it couldn't be parsed
+    pub message: String,
+}
+impl LogLine {
+    /// Parses a line in the `<timestamp> <device> <process>[<pid>] <<level>>: <message>` format,
+    /// falling back to only the message when it doesn't match
+    pub fn parse(line: &str) -> Self {
+        Self::parse_fields(line).unwrap_or_else(|| LogLine {
+            timestamp: None,
+            device_name: None,
+            process: None,
+            pid: None,
+            level: None,
+            message: line.to_owned(),
+        })
+    }
+    fn parse_fields(line: &str) -> Option<Self> {
+        let timestamp = line.get(..TIMESTAMP_LEN)?;
+        let rest = line.get(TIMESTAMP_LEN..)?.strip_prefix(' ')?;
+        let (device_name, rest) = rest.split_once(' ')?;
+        let (process, rest) = rest.split_once('[')?;
+        let (pid, rest) = rest.split_once("] ")?;
+        let (level, message) = match rest.strip_prefix('<') {
+            Some(rest) => {
+                let (level, message) = rest.split_once(">: ")?;
+                (Some(level.to_owned()), message)
+            }
+            None => (None, rest.strip_prefix(": ").unwrap_or(rest)),
+        };
+        Some(LogLine {
+            timestamp: Some(timestamp.to_owned()),
+            device_name: Some(device_name.to_owned()),
+            process: Some(process.to_owned()),
+            pid: pid.parse().ok(),
+            level,
+            message: message.to_owned(),
+        })
+    }
+}
+
+/// Connection to the device's syslog relay, iterating over log lines as they're logged
+pub struct SyslogClient {
+    reader: BufReader<ServiceStream>,
+    buffer: Vec<u8>,
+}
+impl SyslogClient {
+    /// Starts the syslog relay on the device & connects to it
+    ///
+    /// # Errors
+    /// Fails if the device isn't paired or the service can't be started
+    pub fn connect(device_id: DeviceId) -> Result<Self> {
+        Ok(Self::new(connect_to_service(device_id, SERVICE_NAME)?))
+    }
+    /// Wraps an existing connection to the syslog relay
+    pub fn new(stream: ServiceStream) -> Self {
+        SyslogClient {
+            reader: BufReader::new(stream),
+            buffer: Vec::new(),
+        }
+    }
+    /// Waits for the next raw log line, None once the device closes the connection
+    pub fn next_line(&mut self) -> Result<Option<String>> {
+        loop {
+            self.buffer.clear();
+            if self.reader.read_until(b'\n', &mut self.buffer)? == 0 {
+                return Ok(None);
+            }
+            // messages are separated by NUL bytes, which aren't part of the text
+            self.buffer.retain(|b| *b != 0);
+            let line = String::from_utf8_lossy(&self.buffer);
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            if !line.is_empty() {
+                return Ok(Some(line.to_owned()));
+            }
+        }
+    }
+}
+impl Iterator for SyslogClient {
+    type Item = Result<LogLine>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_line()
+            .transpose()
+            .map(|line| line.map(|l| LogLine::parse(&l)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn it_parses_log_lines() {
+        let line = LogLine::parse(
+            "Oct  6 09:41:00 Alices-iPhone SpringBoard(FrontBoard)[58] <Notice>: Scene created",
+        );
+        assert_eq!(line.timestamp.as_deref(), Some("Oct  6 09:41:00"));
+        assert_eq!(line.device_name.as_deref(), Some("Alices-iPhone"));
+        assert_eq!(line.process.as_deref(), Some("SpringBoard(FrontBoard)"));
+        assert_eq!(line.pid, Some(58));
+        assert_eq!(line.level.as_deref(), Some("Notice"));
+        assert_eq!(line.message, "Scene created");
+    }
+    #[test]
+    fn it_keeps_unparsable_lines() {
+        let line = LogLine::parse("=== syslog relay started ===");
+        assert_eq!(line.timestamp, None);
+        assert_eq!(line.message, "=== syslog relay started ===");
+    }
+}
@@ -0,0 +1,131 @@
+//! Socket to the muxer, which is a Unix socket or TCP depending on platform & configuration
+use crate::TransportStream;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Connection to the muxer, or to a device port once a connection through the muxer is established
+///
+/// macOS & Linux muxers listen on a Unix socket, Windows' Apple Mobile Device Service on TCP, and
+/// either can be overridden with `USBMUXD_SOCKET_ADDRESS`. Custom transports provide their own
+/// connections, see [`MuxerTransport`](crate::MuxerTransport).
+#[derive(Debug)]
+pub enum UsbSocket {
+    /// Unix domain socket connection
+    #[cfg(not(target_os = "windows"))]
+    Unix(UnixStream),
+    /// TCP connection
+    Tcp(TcpStream),
+    /// Connection opened by a custom transport
+    Custom(Box<dyn TransportStream>),
+}
+impl UsbSocket {
+    /// Wraps a connection opened by a custom transport
+    pub fn custom<S: TransportStream + 'static>(stream: S) -> Self {
+        UsbSocket::Custom(Box::new(stream))
+    }
+    /// Creates a new handle to the same socket, for reading & writing from different threads
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(socket) => socket.try_clone().map(UsbSocket::Unix),
+            UsbSocket::Tcp(socket) => socket.try_clone().map(UsbSocket::Tcp),
+            UsbSocket::Custom(stream) => stream.try_clone().map(UsbSocket::Custom),
+        }
+    }
+    /// Shuts down the read, write, or both halves of the connection
+    pub fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(socket) => socket.shutdown(how),
+            UsbSocket::Tcp(socket) => socket.shutdown(how),
+            UsbSocket::Custom(stream) => stream.shutdown(how),
+        }
+    }
+    /// Moves the socket into or out of nonblocking mode
+    pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(socket) => socket.set_nonblocking(nonblocking),
+            UsbSocket::Tcp(socket) => socket.set_nonblocking(nonblocking),
+            UsbSocket::Custom(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+    /// Sets the read timeout, None blocks indefinitely
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(socket) => socket.set_read_timeout(timeout),
+            UsbSocket::Tcp(socket) => socket.set_read_timeout(timeout),
+            UsbSocket::Custom(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+    /// Sets the write timeout, None blocks indefinitely
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(socket) => socket.set_write_timeout(timeout),
+            UsbSocket::Tcp(socket) => socket.set_write_timeout(timeout),
+            UsbSocket::Custom(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+    /// Sets TCP_NODELAY on TCP connections, Unix sockets have no Nagle delay so are left alone, as
+    /// are custom transports
+    pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(_) => Ok(()),
+            UsbSocket::Tcp(socket) => socket.set_nodelay(nodelay),
+            UsbSocket::Custom(_) => Ok(()),
+        }
+    }
+}
+#[cfg(not(target_os = "windows"))]
+impl From<UnixStream> for UsbSocket {
+    fn from(socket: UnixStream) -> Self {
+        UsbSocket::Unix(socket)
+    }
+}
+impl From<TcpStream> for UsbSocket {
+    fn from(socket: TcpStream) -> Self {
+        UsbSocket::Tcp(socket)
+    }
+}
+impl Read for UsbSocket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(socket) => socket.read(buf),
+            UsbSocket::Tcp(socket) => socket.read(buf),
+            UsbSocket::Custom(stream) => stream.read(buf),
+        }
+    }
+}
+impl Write for UsbSocket {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(socket) => socket.write(buf),
+            UsbSocket::Tcp(socket) => socket.write(buf),
+            UsbSocket::Custom(stream) => stream.write(buf),
+        }
+    }
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(socket) => socket.write_vectored(bufs),
+            UsbSocket::Tcp(socket) => socket.write_vectored(bufs),
+            UsbSocket::Custom(stream) => stream.write_vectored(bufs),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            UsbSocket::Unix(socket) => socket.flush(),
+            UsbSocket::Tcp(socket) => socket.flush(),
+            UsbSocket::Custom(stream) => stream.flush(),
+        }
+    }
+}
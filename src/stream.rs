@@ -0,0 +1,202 @@
+//! Connection to a port on a device, with the same API on every platform
+#[cfg(feature = "tokio")]
+use crate::AsyncUsbSocket;
+use crate::{DeviceId, UsbSocket};
+use std::io::{self, IoSlice, Read, Write};
+use std::net::Shutdown;
+use std::time::Duration;
+
+/// Connection to a port on a device, through the muxer
+///
+/// Wraps whichever socket the platform's muxer hands out, remembering which device & port it's
+/// connected to.
+#[derive(Debug)]
+pub struct UsbStream {
+    socket: UsbSocket,
+    device_id: DeviceId,
+    port: u16,
+}
+impl UsbStream {
+    pub(crate) fn new(socket: UsbSocket, device_id: DeviceId, port: u16) -> Self {
+        UsbStream {
+            socket,
+            device_id,
+            port,
+        }
+    }
+    /// Device the stream is connected to
+    pub fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+    /// Device port the stream is connected to
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    /// Creates a new handle to the same connection, for reading & writing from different threads
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(UsbStream {
+            socket: self.socket.try_clone()?,
+            ..*self
+        })
+    }
+    /// Shuts down the read, write, or both halves of the connection
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket.shutdown(how)
+    }
+    /// Moves the stream into or out of nonblocking mode
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+    /// Sets the read timeout, None blocks indefinitely
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+    /// Sets the write timeout, None blocks indefinitely
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(timeout)
+    }
+    /// Sets TCP_NODELAY when the muxer connection is TCP, see [`UsbSocket::set_nodelay`]
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.socket.set_nodelay(nodelay)
+    }
+    /// Splits the stream into halves owned separately, to read on one thread & write on another
+    ///
+    /// # Errors
+    /// Fails if the socket can't be cloned, which custom transports may not support
+    pub fn split(self) -> io::Result<(UsbReadHalf, UsbWriteHalf)> {
+        let writer = self.try_clone()?;
+        Ok((UsbReadHalf(self), UsbWriteHalf(writer)))
+    }
+    /// Socket underneath, for platform specific calls
+    pub fn get_ref(&self) -> &UsbSocket {
+        &self.socket
+    }
+    /// Unwraps the socket underneath, dropping the device & port
+    pub fn into_socket(self) -> UsbSocket {
+        self.socket
+    }
+    /// Moves the stream to the tokio runtime, see [`UsbSocket::into_async`]
+    ///
+    /// # Panics
+    /// Panics when not called from within a tokio runtime with IO enabled
+    #[cfg(feature = "tokio")]
+    pub fn into_async(self) -> io::Result<AsyncUsbSocket> {
+        self.socket.into_async()
+    }
+}
+impl From<UsbStream> for UsbSocket {
+    fn from(stream: UsbStream) -> Self {
+        stream.socket
+    }
+}
+impl Read for UsbStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.read(buf)
+    }
+}
+impl Write for UsbStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.socket.write_vectored(bufs)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+/// Reading half of a [`UsbStream`], from [`UsbStream::split`]
+#[derive(Debug)]
+pub struct UsbReadHalf(UsbStream);
+impl UsbReadHalf {
+    /// Device the stream is connected to
+    pub fn device_id(&self) -> DeviceId {
+        self.0.device_id
+    }
+    /// Device port the stream is connected to
+    pub fn port(&self) -> u16 {
+        self.0.port
+    }
+    /// Sets the read timeout, None blocks indefinitely
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(timeout)
+    }
+}
+impl Read for UsbReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+/// Writing half of a [`UsbStream`], from [`UsbStream::split`]
+#[derive(Debug)]
+pub struct UsbWriteHalf(UsbStream);
+impl UsbWriteHalf {
+    /// Device the stream is connected to
+    pub fn device_id(&self) -> DeviceId {
+        self.0.device_id
+    }
+    /// Device port the stream is connected to
+    pub fn port(&self) -> u16 {
+        self.0.port
+    }
+    /// Sets the write timeout, None blocks indefinitely
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(timeout)
+    }
+    /// Shuts down the write half, so the device reads the end of the stream
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.0.shutdown(Shutdown::Write)
+    }
+}
+impl Write for UsbWriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.0.write_vectored(bufs)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn it_keeps_metadata_on_clones() {
+        let (local, mut remote) = UnixStream::pair().unwrap();
+        let stream = UsbStream::new(local.into(), DeviceId(3), 2345);
+        let mut clone = stream.try_clone().unwrap();
+        assert_eq!(clone.device_id(), DeviceId(3));
+        assert_eq!(clone.port(), 2345);
+        clone.write_all(b"ping").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"ping");
+    }
+    #[test]
+    fn it_splits_into_halves() {
+        let (local, mut remote) = UnixStream::pair().unwrap();
+        let (mut reader, mut writer) = UsbStream::new(local.into(), DeviceId(3), 2345)
+            .split()
+            .unwrap();
+        let echo = std::thread::spawn(move || {
+            let mut buf = [0; 4];
+            reader.read_exact(&mut buf).unwrap();
+            buf
+        });
+        writer.write_all(b"pong").unwrap();
+        let mut buf = [0; 4];
+        remote.read_exact(&mut buf).unwrap();
+        remote.write_all(&buf).unwrap();
+        assert_eq!(&echo.join().unwrap(), b"pong");
+        writer.shutdown().unwrap();
+        assert_eq!(remote.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,104 @@
+//! Reading the pair records stored on disk by the OS's muxer or libimobiledevice
+//!
+//! usbmuxd keeps pair records as `<UDID>.plist` files in a system directory, which
+//! [`read_pair_record`] asks it for. When the muxer isn't running or
+//! doesn't support `ReadPairRecord`, the files can be read directly, given permission to.
+use crate::{read_pair_record, Error, PairRecord, ProtocolError, Result};
+use plist::Value;
+use std::convert::TryFrom;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Directory the OS keeps pair records in, None if it can't be determined
+///
+/// This is `/var/db/lockdown` on macOS, `/var/lib/lockdown` on Linux & `Apple\Lockdown` in the
+/// ProgramData folder on Windows.
+pub fn lockdown_dir() -> Option<PathBuf> {
+    if cfg!(target_os = "macos") {
+        Some(PathBuf::from("/var/db/lockdown"))
+    } else if cfg!(target_os = "windows") {
+        let program_data =
+            std::env::var_os("ALLUSERSPROFILE").or_else(|| std::env::var_os("ProgramData"))?;
+        Some(Path::new(&program_data).join("Apple").join("Lockdown"))
+    } else {
+        Some(PathBuf::from("/var/lib/lockdown"))
+    }
+}
+
+/// Reads a device's pair record from the OS's lockdown directory, None if there's none
+///
+/// # Errors
+/// Fails if the file can't be read, usually as only root may, or isn't a valid pair record
+pub fn read_system_pair_record(udid: &str) -> Result<Option<PairRecord>> {
+    match lockdown_dir() {
+        Some(dir) => read_system_pair_record_in(&dir, udid),
+        None => Ok(None),
+    }
+}
+
+/// Same as [`read_system_pair_record`], reading from `dir` instead of the OS's directory
+///
+/// # Errors
+/// Fails with [`ProtocolError::InvalidUdid`] if `udid` could name a file outside `dir`, or if the
+/// file can't be read or isn't a valid pair record
+pub fn read_system_pair_record_in(dir: &Path, udid: &str) -> Result<Option<PairRecord>> {
+    // this usually runs as root, so the UDID mustn't lead outside the directory
+    if udid.is_empty() || udid.contains(['/', '\\']) || udid.contains("..") {
+        return Err(ProtocolError::InvalidUdid(udid.to_owned()).into());
+    }
+    let data = match std::fs::read(dir.join(format!("{}.plist", udid))) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let value = Value::from_reader(std::io::Cursor::new(data))
+        .map_err(|_| ProtocolError::InvalidPlistEntry)?;
+    Ok(Some(PairRecord::try_from(&value)?))
+}
+
+/// Reads a device's pair record from usbmuxd, falling back to the OS's lockdown directory if
+/// usbmuxd can't be asked or has none
+///
+/// # Errors
+/// Fails with the muxer's error if reading from disk fails as well
+pub fn read_pair_record_with_fallback(udid: &str) -> Result<Option<PairRecord>> {
+    let error: Error = match read_pair_record(udid) {
+        Ok(Some(record)) => return Ok(Some(record)),
+        Ok(None) => return read_system_pair_record(udid),
+        Err(e) => e,
+    };
+    debug!(
+        "Reading pair record of {} from usbmuxd failed: {}",
+        udid, error
+    );
+    match read_system_pair_record(udid) {
+        Ok(record) => Ok(record),
+        Err(e) => {
+            debug!("Reading pair record of {} from disk failed: {}", udid, e);
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_pair_records_from_disk() {
+        let dir = std::env::temp_dir().join(format!("peertalk-lockdown-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let udid = "00008030-001A35E22E88802E";
+        std::fs::copy(
+            "test_data/pair-record.plist",
+            dir.join(format!("{}.plist", udid)),
+        )
+        .unwrap();
+        let record = read_system_pair_record_in(&dir, udid).unwrap().unwrap();
+        assert_eq!(record.host_id, "2B5C7E9A-3F1D-4C8B-9E6A-1D2F3A4B5C6D");
+        assert!(read_system_pair_record_in(&dir, "missing")
+            .unwrap()
+            .is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
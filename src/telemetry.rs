@@ -0,0 +1,118 @@
+//! Metrics for watching USB link health, recorded through the `metrics` facade
+//!
+//! With the `metrics` feature, muxer traffic, device events, listener reconnects & refused
+//! connections are counted with the names below, for whichever recorder the app installs (such as
+//! a Prometheus exporter). Without the feature nothing is recorded.
+#[cfg(feature = "metrics")]
+use crate::{DeviceEvent, ReplyCode};
+
+/// Counter of packets sent to the muxer
+pub const PACKETS_SENT: &str = "peertalk_muxer_packets_sent_total";
+/// Counter of packets received from the muxer, replies & events
+pub const PACKETS_RECEIVED: &str = "peertalk_muxer_packets_received_total";
+/// Counter of bytes sent to the muxer
+pub const BYTES_SENT: &str = "peertalk_muxer_bytes_sent_total";
+/// Counter of bytes received from the muxer
+pub const BYTES_RECEIVED: &str = "peertalk_muxer_bytes_received_total";
+/// Counter of device events received, labeled with `event`: `attached`, `detached`, `paired` or
+/// `unknown`
+pub const DEVICE_EVENTS: &str = "peertalk_device_events_total";
+/// Counter of listeners reconnecting to a muxer that went away
+pub const RECONNECTS: &str = "peertalk_listener_reconnects_total";
+/// Counter of device connections the muxer refused, labeled with the reply `code`:
+/// `bad_command`, `bad_device`, `connection_refused` or `bad_version`
+pub const CONNECT_FAILURES: &str = "peertalk_connect_failures_total";
+/// Gauge of devices attached, as last seen by a listener
+pub const DEVICES_ATTACHED: &str = "peertalk_devices_attached";
+
+#[cfg(feature = "metrics")]
+pub(crate) fn sent(bytes: usize) {
+    metrics::counter!(PACKETS_SENT).increment(1);
+    metrics::counter!(BYTES_SENT).increment(bytes as u64);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn sent(_bytes: usize) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn received(bytes: usize) {
+    metrics::counter!(BYTES_RECEIVED).increment(bytes as u64);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn received(_bytes: usize) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn packet_received() {
+    metrics::counter!(PACKETS_RECEIVED).increment(1);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn packet_received() {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn event(event: &DeviceEvent) {
+    let name = match event {
+        DeviceEvent::Attached(_) => "attached",
+        DeviceEvent::Detached(_) => "detached",
+        DeviceEvent::Paired(_) => "paired",
+        _ => "unknown",
+    };
+    metrics::counter!(DEVICE_EVENTS, "event" => name).increment(1);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn event(_event: &crate::DeviceEvent) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn reconnected() {
+    metrics::counter!(RECONNECTS).increment(1);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn reconnected() {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn connect_failed(code: ReplyCode) {
+    let code = match code {
+        ReplyCode::Ok => "ok",
+        ReplyCode::BadCommand => "bad_command",
+        ReplyCode::BadDevice => "bad_device",
+        ReplyCode::ConnectionRefused => "connection_refused",
+        ReplyCode::BadVersion => "bad_version",
+    };
+    metrics::counter!(CONNECT_FAILURES, "code" => code).increment(1);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn connect_failed(_code: crate::ReplyCode) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn devices_attached(count: usize) {
+    metrics::gauge!(DEVICES_ATTACHED).set(count as f64);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn devices_attached(_count: usize) {}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    #[test]
+    fn it_counts_refusals_by_reply_code() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            connect_failed(ReplyCode::ConnectionRefused);
+            connect_failed(ReplyCode::ConnectionRefused);
+            sent(32);
+        });
+        let snapshot = snapshotter.snapshot().into_hashmap();
+        let refused = snapshot.iter().find(|(key, _)| {
+            key.key().name() == CONNECT_FAILURES
+                && key
+                    .key()
+                    .labels()
+                    .any(|l| l.key() == "code" && l.value() == "connection_refused")
+        });
+        assert!(matches!(refused, Some((_, (_, _, DebugValue::Counter(2))))));
+        let bytes = snapshot
+            .iter()
+            .find(|(key, _)| key.key().name() == BYTES_SENT);
+        assert!(matches!(bytes, Some((_, (_, _, DebugValue::Counter(32))))));
+    }
+}
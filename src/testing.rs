@@ -0,0 +1,428 @@
+//! In-process fake muxer, for testing hot-plug handling & connections without devices
+//!
+//! [`MockMuxer`] speaks the plist protocol over TCP (or a Unix socket), answering `Listen`,
+//! `ListDevices`, `Connect` & `ReadBUID`. Devices are attached & detached by the test, and each
+//! device port can refuse connections, echo, or hand the connection to the test:
+//!
+//! ```no_run
+//! use peertalk::testing::{ConnectOutcome, MockDevice, MockMuxer};
+//! use peertalk::DeviceListener;
+//!
+//! let muxer = MockMuxer::start().unwrap();
+//! let listener = DeviceListener::with_config(muxer.config()).unwrap();
+//! let id = muxer.attach(MockDevice::usb("00008030-001A35E22E88802E").port(2345, ConnectOutcome::Echo));
+//! // the listener now reports the device as attached & connecting to port 2345 echoes
+//! muxer.detach(id);
+//! ```
+use crate::forward::Local;
+use crate::protocol::{DeviceId, Packet, PacketType, Protocol, ReplyCode};
+use crate::{MuxerAddress, MuxerConfig, ProductType, Result, UsbSocket};
+use plist::{Dictionary, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(not(target_os = "windows"))]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// BUID the mock muxer reports for the host
+pub const MOCK_BUID: &str = "00000000-0000-0000-0000-000000000000";
+
+/// What happens when a client connects to a port on a mock device
+#[derive(Clone)]
+pub enum ConnectOutcome {
+    /// Muxer replies with the given code, such as [`ReplyCode::ConnectionRefused`]
+    Refuse(ReplyCode),
+    /// Connection succeeds & sends back everything it receives
+    Echo,
+    /// Connection succeeds & is handed to the function, on a thread of its own
+    Accept(Arc<dyn Fn(UsbSocket) + Send + Sync>),
+}
+impl ConnectOutcome {
+    /// Connection succeeds & is handed to `handler`, acting as the app on the device
+    pub fn accept<F>(handler: F) -> Self
+    where
+        F: Fn(UsbSocket) + Send + Sync + 'static,
+    {
+        ConnectOutcome::Accept(Arc::new(handler))
+    }
+}
+impl fmt::Debug for ConnectOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectOutcome::Refuse(code) => f.debug_tuple("Refuse").field(code).finish(),
+            ConnectOutcome::Echo => f.write_str("Echo"),
+            ConnectOutcome::Accept(_) => f.write_str("Accept(..)"),
+        }
+    }
+}
+
+/// Device to attach to a [`MockMuxer`]
+///
+/// Ports that weren't set up refuse connections.
+#[derive(Debug, Clone)]
+pub struct MockDevice {
+    udid: String,
+    network: bool,
+    product_type: ProductType,
+    ports: HashMap<u16, ConnectOutcome>,
+}
+impl MockDevice {
+    /// iPhone attached over USB
+    pub fn usb<S: Into<String>>(udid: S) -> Self {
+        MockDevice {
+            udid: udid.into(),
+            network: false,
+            product_type: ProductType::IPhone,
+            ports: HashMap::new(),
+        }
+    }
+    /// Device attached over the network, which doesn't report a product type
+    pub fn network<S: Into<String>>(udid: S) -> Self {
+        MockDevice {
+            network: true,
+            product_type: ProductType::Unknown(0),
+            ..Self::usb(udid)
+        }
+    }
+    /// Sets the product type of a USB device
+    pub fn product_type(mut self, product_type: ProductType) -> Self {
+        self.product_type = product_type;
+        self
+    }
+    /// Sets what connecting to `port` does
+    pub fn port(mut self, port: u16, outcome: ConnectOutcome) -> Self {
+        self.ports.insert(port, outcome);
+        self
+    }
+    /// `Attached` message for the device
+    fn attached(&self, device_id: DeviceId) -> Value {
+        let mut properties = Dictionary::new();
+        let connection_type = if self.network { "Network" } else { "USB" };
+        properties.insert("ConnectionType".to_owned(), connection_type.into());
+        properties.insert("DeviceID".to_owned(), device_id.0.into());
+        properties.insert("SerialNumber".to_owned(), self.udid.clone().into());
+        if !self.network {
+            properties.insert("LocationID".to_owned(), 0u64.into());
+            let product_id: u16 = self.product_type.into();
+            properties.insert("ProductID".to_owned(), u64::from(product_id).into());
+        }
+        let mut message = Dictionary::new();
+        message.insert("MessageType".to_owned(), "Attached".into());
+        message.insert("DeviceID".to_owned(), device_id.0.into());
+        message.insert("Properties".to_owned(), Value::Dictionary(properties));
+        Value::Dictionary(message)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    devices: Vec<(DeviceId, MockDevice)>,
+    /// Connections that sent `Listen`, to send events to
+    listeners: Vec<UsbSocket>,
+}
+impl State {
+    /// Sends an event to every listener, forgetting ones that hung up
+    fn broadcast(&mut self, event: &Value) {
+        self.listeners
+            .retain_mut(|listener| send(listener, 0, event).is_ok());
+    }
+}
+
+/// Fake usbmuxd running on a background thread, until dropped
+pub struct MockMuxer {
+    address: MuxerAddress,
+    state: Arc<Mutex<State>>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+impl MockMuxer {
+    /// Starts a muxer on a free loopback TCP port
+    pub fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = MuxerAddress::Tcp {
+            host: "127.0.0.1".to_owned(),
+            port: listener.local_addr()?.port(),
+        };
+        Self::spawn(Local::Tcp(listener), address)
+    }
+    /// Starts a muxer on a Unix socket at `path`, as usbmuxd listens on macOS & Linux
+    #[cfg(not(target_os = "windows"))]
+    pub fn start_unix<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let listener = UnixListener::bind(&path)?;
+        Self::spawn(Local::Unix(listener), MuxerAddress::Unix(path))
+    }
+    fn spawn(local: Local, address: MuxerAddress) -> Result<Self> {
+        let state = Arc::new(Mutex::new(State {
+            next_id: 1,
+            ..State::default()
+        }));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_state = state.clone();
+        let thread_stopped = stopped.clone();
+        let thread = std::thread::Builder::new()
+            .name("peertalk-mock-muxer".to_owned())
+            .spawn(move || loop {
+                let client = local.accept();
+                if thread_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                match client {
+                    Ok(client) => {
+                        let state = thread_state.clone();
+                        std::thread::spawn(move || {
+                            if let Err(e) = serve(client, &state) {
+                                debug!("Mock muxer connection closed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Mock muxer failed to accept connection: {}", e),
+                }
+            })?;
+        Ok(MockMuxer {
+            address,
+            state,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+    /// Address the muxer listens on
+    pub fn address(&self) -> &MuxerAddress {
+        &self.address
+    }
+    /// Config for connecting to this muxer, for listeners & connections under test
+    pub fn config(&self) -> MuxerConfig {
+        MuxerConfig::default().address(self.address.clone())
+    }
+    /// Attaches a device, telling listeners & returning its device id
+    pub fn attach(&self, device: MockDevice) -> DeviceId {
+        let mut state = self.state.lock().unwrap();
+        let device_id = DeviceId(state.next_id);
+        state.next_id += 1;
+        let event = device.attached(device_id);
+        state.devices.push((device_id, device));
+        state.broadcast(&event);
+        device_id
+    }
+    /// Detaches a device, telling listeners
+    pub fn detach(&self, device_id: DeviceId) {
+        let mut state = self.state.lock().unwrap();
+        state.devices.retain(|(id, _)| *id != device_id);
+        state.broadcast(&event("Detached", device_id));
+    }
+    /// Tells listeners the device was paired
+    pub fn pair(&self, device_id: DeviceId) {
+        let mut state = self.state.lock().unwrap();
+        state.broadcast(&event("Paired", device_id));
+    }
+    /// Changes what connecting to `port` on an attached device does, such as to start accepting
+    /// connections it refused before
+    pub fn set_port(&self, device_id: DeviceId, port: u16, outcome: ConnectOutcome) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, device)) = state.devices.iter_mut().find(|(id, _)| *id == device_id) {
+            device.ports.insert(port, outcome);
+        }
+    }
+    /// Closes the connections of every listener, as when the muxer restarts
+    pub fn disconnect_listeners(&self) {
+        for listener in self.state.lock().unwrap().listeners.drain(..) {
+            let _ = listener.shutdown(Shutdown::Both);
+        }
+    }
+}
+impl fmt::Debug for MockMuxer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockMuxer")
+            .field("address", &self.address)
+            .finish_non_exhaustive()
+    }
+}
+impl Drop for MockMuxer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.disconnect_listeners();
+        // wake up the accept loop so it sees the flag
+        match &self.address {
+            MuxerAddress::Tcp { host, port } => {
+                let _ = TcpStream::connect((host.as_str(), *port));
+            }
+            #[cfg(not(target_os = "windows"))]
+            MuxerAddress::Unix(path) => {
+                let _ = UnixStream::connect(path);
+            }
+            #[cfg(target_os = "windows")]
+            MuxerAddress::Unix(_) => {}
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        #[cfg(not(target_os = "windows"))]
+        if let MuxerAddress::Unix(path) = &self.address {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn event(message_type: &str, device_id: DeviceId) -> Value {
+    let mut message = Dictionary::new();
+    message.insert("MessageType".to_owned(), message_type.into());
+    message.insert("DeviceID".to_owned(), device_id.0.into());
+    Value::Dictionary(message)
+}
+
+fn result(code: ReplyCode) -> Value {
+    let mut message = Dictionary::new();
+    message.insert("MessageType".to_owned(), "Result".into());
+    message.insert("Number".to_owned(), u64::from(u32::from(code)).into());
+    Value::Dictionary(message)
+}
+
+fn send(socket: &mut UsbSocket, tag: u32, message: &Value) -> Result<()> {
+    let mut payload = Vec::new();
+    message
+        .to_writer_xml(&mut payload)
+        .map_err(crate::ProtocolError::PlistEncodeError)?;
+    Packet::new(Protocol::Plist, PacketType::PlistPayload, tag, payload)?.write_into(socket)?;
+    Ok(())
+}
+
+/// Answers requests on one client connection, until it hangs up or becomes something else
+fn serve(mut client: UsbSocket, state: &Mutex<State>) -> Result<()> {
+    loop {
+        let request = Packet::from_reader(&mut client)?;
+        if request.protocol != Protocol::Plist {
+            send(&mut client, request.tag, &result(ReplyCode::BadVersion))?;
+            continue;
+        }
+        let message = Value::from_reader(std::io::Cursor::new(&request.data))
+            .map_err(|_| crate::ProtocolError::InvalidPlistEntry)?;
+        let message = message.as_dictionary().cloned().unwrap_or_default();
+        let message_type = message.get("MessageType").and_then(Value::as_string);
+        match message_type {
+            Some("Listen") => {
+                send(&mut client, request.tag, &result(ReplyCode::Ok))?;
+                let mut state = state.lock().unwrap();
+                for (id, device) in &state.devices {
+                    send(&mut client, 0, &device.attached(*id))?;
+                }
+                state.listeners.push(client);
+                return Ok(());
+            }
+            Some("ListDevices") => {
+                let state = state.lock().unwrap();
+                let list = state
+                    .devices
+                    .iter()
+                    .map(|(id, device)| device.attached(*id))
+                    .collect();
+                let mut reply = Dictionary::new();
+                reply.insert("DeviceList".to_owned(), Value::Array(list));
+                drop(state);
+                send(&mut client, request.tag, &Value::Dictionary(reply))?;
+            }
+            Some("ReadBUID") => {
+                let mut reply = Dictionary::new();
+                reply.insert("BUID".to_owned(), MOCK_BUID.into());
+                send(&mut client, request.tag, &Value::Dictionary(reply))?;
+            }
+            Some("Connect") => {
+                let device_id = message
+                    .get("DeviceID")
+                    .and_then(Value::as_unsigned_integer)
+                    .map(DeviceId);
+                // the port is sent in network byte order
+                let port = message
+                    .get("PortNumber")
+                    .and_then(Value::as_unsigned_integer)
+                    .map(|port| u16::from_be(port as u16));
+                let outcome = {
+                    let state = state.lock().unwrap();
+                    let device = state.devices.iter().find(|(id, _)| Some(*id) == device_id);
+                    match (device, port) {
+                        (Some((_, device)), Some(port)) => device
+                            .ports
+                            .get(&port)
+                            .cloned()
+                            .unwrap_or(ConnectOutcome::Refuse(ReplyCode::ConnectionRefused)),
+                        _ => ConnectOutcome::Refuse(ReplyCode::BadDevice),
+                    }
+                };
+                match outcome {
+                    ConnectOutcome::Refuse(code) => send(&mut client, request.tag, &result(code))?,
+                    ConnectOutcome::Echo => {
+                        send(&mut client, request.tag, &result(ReplyCode::Ok))?;
+                        let mut echo = client.try_clone()?;
+                        std::io::copy(&mut client, &mut echo)?;
+                        return Ok(());
+                    }
+                    ConnectOutcome::Accept(handler) => {
+                        send(&mut client, request.tag, &result(ReplyCode::Ok))?;
+                        handler(client);
+                        return Ok(());
+                    }
+                }
+            }
+            _ => send(&mut client, request.tag, &result(ReplyCode::BadCommand))?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connect_to_device_with, ConnectOptions, Device, DeviceEvent, DeviceListener, Error,
+    };
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    #[test]
+    fn it_scripts_hot_plug_and_connections() {
+        let muxer = MockMuxer::start().unwrap();
+        let listener = DeviceListener::with_config(muxer.config()).unwrap();
+        let id = muxer
+            .attach(MockDevice::usb("00008030-001A35E22E88802E").port(2345, ConnectOutcome::Echo));
+        let event = listener.wait_event(Some(Duration::from_secs(5)));
+        let info = match event {
+            Some(DeviceEvent::Attached(info)) => info,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(info.identifier, "00008030-001A35E22E88802E");
+
+        let options = ConnectOptions::from(muxer.config());
+        let mut socket = connect_to_device_with(&options, id, 2345).unwrap();
+        socket.write_all(b"ping").unwrap();
+        let mut buffer = [0; 4];
+        socket.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+        assert!(matches!(
+            connect_to_device_with(&options, id, 1234),
+            Err(Error::ConnectionRefused {
+                code: ReplyCode::ConnectionRefused,
+                udid: None,
+                ..
+            })
+        ));
+        let error = Device::with_options(info, options.clone())
+            .connect(1234)
+            .unwrap_err();
+        assert!(error.is_port_not_open());
+        assert!(matches!(
+            error,
+            Error::ConnectionRefused { udid: Some(_), .. }
+        ));
+        assert!(error
+            .to_string()
+            .contains("nothing on the device is listening"));
+
+        muxer.detach(id);
+        let event = listener.wait_event(Some(Duration::from_secs(5)));
+        assert!(matches!(event, Some(DeviceEvent::Detached(detached)) if detached == id));
+    }
+}
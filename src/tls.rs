@@ -0,0 +1,122 @@
+//! TLS for lockdown sessions & services, authenticated with the keys from a pair record
+use crate::{Error, PairRecord, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme};
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+/// Stream wrapped in a TLS session
+pub type TlsStream<S> = rustls::StreamOwned<ClientConnection, S>;
+
+/// Name the device is addressed by, devices don't check it since SNI is disabled
+const DEVICE_SERVER_NAME: &str = "lockdown";
+
+/// Accepts only the device certificate stored in the pair record
+///
+/// Device certificates are issued by the host's own root CA during pairing, so there's no chain to
+/// verify against system roots; pinning the exact certificate is stricter anyway.
+#[derive(Debug)]
+struct PairedDeviceVerifier {
+    device_certificate: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+impl ServerCertVerifier for PairedDeviceVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.device_certificate.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn invalid_pem(what: &str) -> Error {
+    Error::TlsError(rustls::Error::General(format!(
+        "invalid {} in pair record",
+        what
+    )))
+}
+
+/// Builds a client config presenting the host certificate & trusting the device certificate
+fn client_config(pair_record: &PairRecord) -> Result<ClientConfig> {
+    let host_certificate = CertificateDer::from_pem_slice(&pair_record.host_certificate)
+        .map_err(|_| invalid_pem("host certificate"))?;
+    let host_private_key = PrivateKeyDer::from_pem_slice(&pair_record.host_private_key)
+        .map_err(|_| invalid_pem("host private key"))?;
+    let device_certificate = CertificateDer::from_pem_slice(&pair_record.device_certificate)
+        .map_err(|_| invalid_pem("device certificate"))?;
+    let provider = Arc::new(ring::default_provider());
+    let verifier = PairedDeviceVerifier {
+        device_certificate,
+        provider: provider.clone(),
+    };
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_client_auth_cert(vec![host_certificate], host_private_key)?;
+    config.enable_sni = false;
+    Ok(config)
+}
+
+/// Wraps a device connection in TLS using the pair record's keys, completing the handshake
+///
+/// # Errors
+/// Fails if the pair record's keys can't be used, or if the device's certificate doesn't match
+pub fn wrap_stream<S: Read + Write>(
+    mut stream: S,
+    pair_record: &PairRecord,
+) -> Result<TlsStream<S>> {
+    let config = client_config(pair_record)?;
+    let server_name = ServerName::try_from(DEVICE_SERVER_NAME)
+        .map_err(|e| Error::TlsError(rustls::Error::General(e.to_string())))?;
+    let mut connection = ClientConnection::new(Arc::new(config), server_name)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+    Ok(rustls::StreamOwned::new(connection, stream))
+}
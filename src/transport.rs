@@ -0,0 +1,133 @@
+//! Pluggable ways of reaching the muxer, such as in-memory pipes for tests, SSH tunnels or proxies
+//!
+//! [`MuxerAddress`] is the built-in transport, connecting over a Unix socket or TCP. Anything else
+//! implements [`MuxerTransport`] & hands back its connections wrapped with [`UsbSocket::custom`].
+use crate::{MuxerAddress, Result, UsbSocket};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::time::Duration;
+
+/// Opens connections to the muxer, set with [`MuxerConfig::transport`](crate::MuxerConfig::transport)
+pub trait MuxerTransport: Send + Sync + fmt::Debug {
+    /// Opens a new connection to the muxer, giving up after `timeout` if the transport can
+    fn connect(&self, timeout: Duration) -> Result<UsbSocket>;
+}
+impl MuxerTransport for MuxerAddress {
+    fn connect(&self, timeout: Duration) -> Result<UsbSocket> {
+        MuxerAddress::connect(self, timeout)
+    }
+}
+
+/// Connection opened by a custom [`MuxerTransport`]
+///
+/// Only reading & writing are required. The rest default to being unsupported, which the parts
+/// of the crate needing them report as an error: listeners need blocking read timeouts & framed
+/// sessions need `try_clone`.
+pub trait TransportStream: Read + Write + Send + fmt::Debug {
+    /// Creates a new handle to the same connection, for reading & writing from different threads
+    fn try_clone(&self) -> io::Result<Box<dyn TransportStream>> {
+        Err(unsupported("cloning"))
+    }
+    /// Shuts down the read, write, or both halves of the connection
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        Ok(())
+    }
+    /// Moves the connection into or out of nonblocking mode
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        if nonblocking {
+            return Err(unsupported("nonblocking mode"));
+        }
+        Ok(())
+    }
+    /// Sets the read timeout, None blocks indefinitely
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match timeout {
+            Some(_) => Err(unsupported("read timeouts")),
+            None => Ok(()),
+        }
+    }
+    /// Sets the write timeout, None blocks indefinitely
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match timeout {
+            Some(_) => Err(unsupported("write timeouts")),
+            None => Ok(()),
+        }
+    }
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("transport doesn't support {}", what),
+    )
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::{MuxerClient, MuxerConfig};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Unix socket to a fake muxer, counting connections
+    #[derive(Debug)]
+    struct Counting {
+        path: std::path::PathBuf,
+        connections: Arc<AtomicUsize>,
+    }
+    #[derive(Debug)]
+    struct Stream(UnixStream);
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+    impl TransportStream for Stream {}
+    impl MuxerTransport for Counting {
+        fn connect(&self, _timeout: Duration) -> Result<UsbSocket> {
+            self.connections.fetch_add(1, Ordering::SeqCst);
+            Ok(UsbSocket::custom(Stream(UnixStream::connect(&self.path)?)))
+        }
+    }
+
+    #[test]
+    fn it_connects_through_custom_transports() {
+        let path = std::env::temp_dir().join(format!("peertalk-transport-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let muxer = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let request = crate::protocol::Packet::from_reader(&mut socket).unwrap();
+            let reply = b"<plist><dict><key>BUID</key><string>ABCD</string></dict></plist>";
+            crate::protocol::Packet::new(
+                crate::protocol::Protocol::Plist,
+                crate::protocol::PacketType::PlistPayload,
+                request.tag,
+                reply.to_vec(),
+            )
+            .unwrap()
+            .write_into(&mut socket)
+            .unwrap();
+        });
+        let connections = Arc::new(AtomicUsize::new(0));
+        let config = MuxerConfig::default().transport(Counting {
+            path: path.clone(),
+            connections: connections.clone(),
+        });
+        let mut client = MuxerClient::with_config(config).unwrap();
+        assert_eq!(client.read_buid().unwrap(), "ABCD");
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        muxer.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,226 @@
+//! Helpers that wait for a device to become usable before connecting
+use crate::muxer::is_unavailable;
+use crate::{connect_to_device_with, is_paired, ConnectOptions, Device, DeviceEvent, DeviceId};
+use crate::{DeviceAttachedInfo, DeviceListener};
+use crate::{Error, MuxerConfig, Result, RetryPolicy, UsbStream};
+use std::time::{Duration, Instant};
+
+/// How long to wait between connect attempts while the device side service isn't listening yet
+const SERVICE_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+/// How often to check for a pair record while waiting for trust, for muxers that don't send `Paired`
+const PAIRING_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Progress reported while waiting for a device to become connectable
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectProgress {
+    /// Waiting for the device to be plugged in
+    WaitingForAttach,
+    /// Device is attached, but hasn't trusted this computer yet
+    WaitingForPairing,
+    /// Device is paired, waiting for the app on the device to listen on the port
+    WaitingForService,
+    /// Connection to the device port was established
+    Connected,
+}
+
+/// Waits up to `max_wait` for the device to be attached, paired and listening on `port`, then connects
+///
+/// # Errors
+/// Returns [`Error::Timeout`] if the device isn't ready in time, or any error from the muxer
+pub fn connect_when_ready(device_id: DeviceId, port: u16, max_wait: Duration) -> Result<UsbStream> {
+    connect_when_ready_with_progress(device_id, port, max_wait, |_| {})
+}
+
+/// Same as [`connect_when_ready`], calling `progress` each time the wait moves to a new stage
+///
+/// # Errors
+/// Returns [`Error::Timeout`] if the device isn't ready in time, or any error from the muxer
+pub fn connect_when_ready_with_progress<F>(
+    device_id: DeviceId,
+    port: u16,
+    max_wait: Duration,
+    mut progress: F,
+) -> Result<UsbStream>
+where
+    F: FnMut(ConnectProgress),
+{
+    let deadline = Instant::now() + max_wait;
+    let listener = DeviceListener::new()?;
+    // usbmuxd replays already attached devices right after listen, so this also sees those
+    progress(ConnectProgress::WaitingForAttach);
+    let mut udid = None;
+    let mut paired = false;
+    let mut checked = Instant::now();
+    while !paired {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Timeout);
+        }
+        match listener.wait_event(Some(PAIRING_POLL_INTERVAL.min(deadline - now))) {
+            Some(DeviceEvent::Attached(info)) if info.device_id == device_id => {
+                paired = is_paired(info.identifier.serial())?;
+                checked = Instant::now();
+                if !paired {
+                    progress(ConnectProgress::WaitingForPairing);
+                }
+                udid = Some(info.identifier);
+            }
+            Some(DeviceEvent::Detached(id)) if id == device_id => {
+                udid = None;
+                progress(ConnectProgress::WaitingForAttach);
+            }
+            Some(DeviceEvent::Paired(id)) if id == device_id => paired = true,
+            Some(DeviceEvent::PairedWithRecord { device_id: id, .. }) if id == device_id => {
+                paired = true
+            }
+            _ => {
+                // trust may have been granted before the listener was set up
+                if let Some(udid) = &udid {
+                    if checked.elapsed() >= PAIRING_POLL_INTERVAL {
+                        paired = is_paired(udid.serial())?;
+                        checked = Instant::now();
+                    }
+                }
+            }
+        }
+    }
+    progress(ConnectProgress::WaitingForService);
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Timeout);
+        }
+        // a stalled muxer mustn't keep this waiting past the deadline
+        let options = ConnectOptions::default().timeout(deadline - now);
+        match connect_to_device_with(&options, device_id, port) {
+            Ok(socket) => {
+                progress(ConnectProgress::Connected);
+                return Ok(socket);
+            }
+            Err(e) if e.is_port_not_open() => {
+                trace!("Device {} port {} not ready", device_id, port);
+            }
+            Err(e) => return Err(e),
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Timeout);
+        }
+        std::thread::sleep(SERVICE_RETRY_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Waits up to `timeout` for the device with the given UDID to be attached
+///
+/// Devices that are already attached are reported right away.
+///
+/// # Errors
+/// Returns [`Error::Timeout`] if the device isn't attached in time, or any error from the muxer
+pub fn wait_for_device(udid: &str, timeout: Duration) -> Result<DeviceAttachedInfo> {
+    let deadline = Instant::now() + timeout;
+    let listener = DeviceListener::builder().udids([udid]).build()?;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Timeout);
+        }
+        if let Some(DeviceEvent::Attached(info)) = listener.wait_event(Some(deadline - now)) {
+            return Ok(info);
+        }
+    }
+}
+
+/// Waits up to `timeout` for the device with the given UDID to trust this host
+///
+/// Meant for onboarding, while the user is asked to unlock the device & tap "Trust". Returns
+/// right away if the device is attached & already paired. Besides `Paired` events, the pair record
+/// is checked every so often, as some muxers don't send them.
+///
+/// # Errors
+/// Returns [`Error::Timeout`] if the device isn't paired in time, or any error from the muxer
+pub fn wait_for_pairing(udid: &str, timeout: Duration) -> Result<DeviceAttachedInfo> {
+    let deadline = Instant::now() + timeout;
+    let listener = DeviceListener::builder().udids([udid]).build()?;
+    let mut attached: Vec<DeviceAttachedInfo> = Vec::new();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Timeout);
+        }
+        let wait = PAIRING_POLL_INTERVAL.min(deadline - now);
+        match listener.wait_event(Some(wait)) {
+            Some(DeviceEvent::Attached(info)) => {
+                if is_paired(info.identifier.serial())? {
+                    return Ok(info);
+                }
+                attached.push(info);
+            }
+            Some(DeviceEvent::Detached(id)) => attached.retain(|info| info.device_id != id),
+            Some(DeviceEvent::Paired(id))
+            | Some(DeviceEvent::PairedWithRecord { device_id: id, .. }) => {
+                if let Some(info) = attached.iter().find(|info| info.device_id == id) {
+                    return Ok(info.clone());
+                }
+            }
+            _ => {
+                let serial = attached.first().map(|info| info.identifier.serial());
+                if serial.map(is_paired).transpose()? == Some(true) {
+                    return Ok(attached.swap_remove(0));
+                }
+            }
+        }
+    }
+}
+
+/// Same as [`wait_for_device`], waiting on tokio's blocking thread pool
+///
+/// # Errors
+/// Returns [`Error::Timeout`] if the device isn't attached in time, or any error from the muxer
+#[cfg(feature = "tokio")]
+pub async fn wait_for_device_async(udid: &str, timeout: Duration) -> Result<DeviceAttachedInfo> {
+    let udid = udid.to_owned();
+    match tokio::task::spawn_blocking(move || wait_for_device(&udid, timeout)).await {
+        Ok(result) => result,
+        // blocking tasks can't be cancelled, so this only fails if the wait panicked
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Waits up to `timeout` for any device to be attached over USB, then connects to `port` on it
+///
+/// Meant for setups with a single device plugged in; if several are attached, whichever the muxer
+/// reports first is used.
+///
+/// # Errors
+/// Returns [`Error::Timeout`] if no device is attached in time, or any error connecting to it
+pub fn connect_to_first_device(port: u16, timeout: Duration) -> Result<(Device, UsbStream)> {
+    let deadline = Instant::now() + timeout;
+    let listener = DeviceListener::builder().usb_only().build()?;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Timeout);
+        }
+        if let Some(DeviceEvent::Attached(info)) = listener.wait_event(Some(deadline - now)) {
+            let device = Device::new(info);
+            let socket = device.connect(port)?;
+            return Ok((device, socket));
+        }
+    }
+}
+
+/// Waits up to `timeout` for the muxer (usbmuxd/Apple Mobile Device Service) to accept connections
+///
+/// Useful for apps started at login, which may be up before the muxer is.
+///
+/// # Errors
+/// Returns [`Error::Timeout`] if the muxer isn't reachable in time
+pub fn wait_for_muxer(timeout: Duration) -> Result<()> {
+    let config = MuxerConfig::default();
+    let policy = RetryPolicy::default().max_elapsed(timeout);
+    match policy.retry(|| config.connect(), is_unavailable) {
+        Ok(_) => Ok(()),
+        Err(e) if is_unavailable(&e) => Err(Error::Timeout),
+        Err(e) => Err(e),
+    }
+}
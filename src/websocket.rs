@@ -0,0 +1,234 @@
+//! Bridging device ports to local WebSocket endpoints, so browser based tools can talk to the app
+use crate::forward::{serve_tcp, ForwardTarget, PortForwarder};
+use crate::frame::{Frame, FrameReader};
+use crate::{ConnectOptions, Error, Result, UsbSocket};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tungstenite::{HandshakeError, Message, WebSocket};
+
+/// How often the bridge stops waiting on the WebSocket to pass on data from the device
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long to wait for the client to acknowledge the close before dropping the connection
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What binary WebSocket messages carry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BridgeMode {
+    /// Raw bytes of the connection, split into messages arbitrarily
+    Raw,
+    /// One PeerTalk frame (header & payload) per message, in either direction
+    Frames,
+}
+
+/// Listens for WebSocket connections on `local_addr`, bridging each to `device_port` on the device
+///
+/// Any path is accepted. Text messages from the client are passed on as their UTF-8 bytes in
+/// [`BridgeMode::Raw`] & rejected in [`BridgeMode::Frames`]. The WebSocket is closed once the
+/// device closes the connection, or sends an end of stream frame.
+///
+/// # Errors
+/// Fails if the local address can't be bound; connection failures are logged & close the client
+pub fn bridge_websocket<A, T>(
+    local_addr: A,
+    device: T,
+    device_port: u16,
+    mode: BridgeMode,
+) -> Result<PortForwarder>
+where
+    A: ToSocketAddrs,
+    T: Into<ForwardTarget>,
+{
+    bridge_websocket_with(
+        ConnectOptions::default(),
+        local_addr,
+        device,
+        device_port,
+        mode,
+    )
+}
+
+/// Same as [`bridge_websocket`], connecting to the device with the muxer & options from `options`
+pub fn bridge_websocket_with<A, T>(
+    options: ConnectOptions,
+    local_addr: A,
+    device: T,
+    device_port: u16,
+    mode: BridgeMode,
+) -> Result<PortForwarder>
+where
+    A: ToSocketAddrs,
+    T: Into<ForwardTarget>,
+{
+    let bridge = Arc::new(move |client, device| bridge(client, device, mode));
+    serve_tcp(options, local_addr, device.into(), device_port, bridge)
+}
+
+/// Runs the WebSocket handshake, then passes messages both ways until either side closes
+fn bridge(client: UsbSocket, mut device: UsbSocket, mode: BridgeMode) -> Result<()> {
+    let mut websocket = tungstenite::accept(client).map_err(|e| match e {
+        HandshakeError::Failure(e) => Error::from(e),
+        HandshakeError::Interrupted(_) => std::io::Error::from(ErrorKind::WouldBlock).into(),
+    })?;
+    websocket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    let (messages, outgoing) = channel();
+    let reader = device.try_clone()?;
+    let download = std::thread::Builder::new()
+        .name("peertalk-websocket".to_owned())
+        .spawn(move || read_device(reader, mode, messages))?;
+    let result = pump(&mut websocket, &mut device, mode, &outgoing);
+    let _ = device.shutdown(Shutdown::Both);
+    let _ = download.join();
+    result
+}
+
+/// Reads from the device, queueing messages for the WebSocket until the device closes
+fn read_device(mut device: UsbSocket, mode: BridgeMode, messages: Sender<Vec<u8>>) {
+    let result = match mode {
+        BridgeMode::Raw => {
+            let mut buffer = vec![0; 64 * 1024];
+            loop {
+                match device.read(&mut buffer) {
+                    Ok(0) => break Ok(()),
+                    Ok(len) => {
+                        if messages.send(buffer[..len].to_vec()).is_err() {
+                            break Ok(());
+                        }
+                    }
+                    Err(e) => break Err(Error::from(e)),
+                }
+            }
+        }
+        BridgeMode::Frames => {
+            let mut frames = FrameReader::new(device);
+            loop {
+                match frames.read_frame() {
+                    Ok(Some(frame)) => {
+                        let mut message = Vec::with_capacity(16 + frame.payload.len());
+                        if let Err(e) = frame.write_into(&mut message) {
+                            break Err(Error::from(e));
+                        }
+                        if messages.send(message).is_err() {
+                            break Ok(());
+                        }
+                    }
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(Error::from(e)),
+                }
+            }
+        }
+    };
+    if let Err(e) = result {
+        debug!("Device side of WebSocket bridge closed: {}", e);
+    }
+}
+
+/// Passes client messages to the device & queued device data to the client
+fn pump(
+    websocket: &mut WebSocket<UsbSocket>,
+    device: &mut UsbSocket,
+    mode: BridgeMode,
+    outgoing: &Receiver<Vec<u8>>,
+) -> Result<()> {
+    loop {
+        loop {
+            match outgoing.try_recv() {
+                Ok(data) => websocket.send(Message::binary(data))?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    websocket.close(None)?;
+                    return finish(websocket);
+                }
+            }
+        }
+        let data = match websocket.read() {
+            Ok(Message::Binary(data)) => data,
+            Ok(Message::Text(text)) if mode == BridgeMode::Raw => text.into_bytes(),
+            Ok(Message::Text(_)) => {
+                websocket.close(None)?;
+                return finish(websocket);
+            }
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                continue
+            }
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        match mode {
+            BridgeMode::Raw => device.write_all(&data)?,
+            BridgeMode::Frames => {
+                let mut message = &data[..];
+                let frame = Frame::from_reader(&mut message)?;
+                frame.write_into(device)?;
+            }
+        }
+    }
+}
+
+/// Waits for the client to acknowledge the close, for up to [`CLOSE_TIMEOUT`]
+fn finish(websocket: &mut WebSocket<UsbSocket>) -> Result<()> {
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    while Instant::now() < deadline {
+        match websocket.read() {
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    debug!("WebSocket client didn't acknowledge the close, dropping the connection");
+    Ok(())
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::protocol::{Packet, PacketType, Protocol};
+    use crate::MuxerConfig;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn it_bridges_frames() {
+        let path =
+            std::env::temp_dir().join(format!("peertalk-websocket-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let muxer = UnixListener::bind(&path).unwrap();
+        // fake muxer accepting the connect request, then echoing frames like the device would
+        std::thread::spawn(move || {
+            let (mut socket, _) = muxer.accept().unwrap();
+            let request = Packet::from_reader(&mut socket).unwrap();
+            let reply = b"<plist version=\"1.0\"><dict><key>MessageType</key>\
+                <string>Result</string><key>Number</key><integer>0</integer></dict></plist>";
+            Packet::new(
+                Protocol::Plist,
+                PacketType::PlistPayload,
+                request.tag,
+                reply.to_vec(),
+            )
+            .unwrap()
+            .write_into(&mut socket)
+            .unwrap();
+            let frame = Frame::from_reader(&mut socket).unwrap();
+            frame.write_into(&mut socket).unwrap();
+        });
+        let options = ConnectOptions::from(MuxerConfig::default().socket_path(path.clone()));
+        let bridge =
+            bridge_websocket_with(options, "127.0.0.1:0", 3, 2345, BridgeMode::Frames).unwrap();
+        let url = format!("ws://{}/", bridge.local_addr().unwrap());
+        let stream = std::net::TcpStream::connect(bridge.local_addr().unwrap()).unwrap();
+        let (mut client, _) = tungstenite::client(url, stream).unwrap();
+        let mut message = Vec::new();
+        Frame::text("Hello").write_into(&mut message).unwrap();
+        client.send(Message::binary(message.clone())).unwrap();
+        assert_eq!(client.read().unwrap(), Message::binary(message));
+        // the fake device hung up
+        assert!(matches!(client.read().unwrap(), Message::Close(_)));
+        let _ = std::fs::remove_file(&path);
+    }
+}